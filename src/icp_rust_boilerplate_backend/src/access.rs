@@ -0,0 +1,209 @@
+// Unlisted events and private access links. An organizer can unlist an event: it drops out
+// of feeds, calendars and public pages, and `get_event` only returns its title and times to
+// anyone but the organizer, its attendees and admins. Share links carry a revocable access
+// token that unlocks the full event through `get_event_with_token` or `/event/{id}?token=`.
+// Listing state and tokens are kept on the router, so they cover events in buckets too.
+use crate::admin::_check_admin;
+use crate::{attendees, metrics, status, verification};
+use crate::{Error, Event, Memory, MEMORY_MANAGER, _call_failed, _fetch_event};
+use crate::{_is_on_team, _is_owner};
+use crate::clock::time;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_TOKENS_PER_EVENT: usize = 20;
+const MAX_LABEL_CHARS: usize = 64;
+// Random bytes in a token; hex encoded, so tokens are twice as long
+const TOKEN_BYTES: usize = 16;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct AccessTokenKey {
+    event_id: u64,
+    token: String,
+}
+
+impl_storable!(AccessTokenKey, 96);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AccessToken {
+    token: String,
+    // Note for the organizer, e.g. who the link was sent to
+    label: Option<String>,
+    created_at: u64,
+}
+
+impl_storable!(AccessToken, 256);
+
+thread_local! {
+    // Unlisted events, with the time they were unlisted
+    static UNLISTED: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59)))
+    ));
+
+    static ACCESS_TOKENS: RefCell<StableBTreeMap<AccessTokenKey, AccessToken, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60)))
+    ));
+}
+
+// Update function for an event's organizer to unlist it or list it again
+#[ic_cdk::update]
+async fn set_event_unlisted(event_id: u64, unlisted: bool) -> Result<bool, Error> {
+    let _metrics = metrics::track("set_event_unlisted");
+    _get_own_event(event_id).await?;
+    UNLISTED.with(|u| {
+        let mut u = u.borrow_mut();
+        if !unlisted {
+            u.remove(&event_id);
+        } else if !u.contains_key(&event_id) {
+            u.insert(event_id, time());
+        }
+    });
+    Ok(unlisted)
+}
+
+// Update function for an event's organizer to create an access token for a share link
+#[ic_cdk::update]
+async fn create_access_token(event_id: u64, label: Option<String>) -> Result<AccessToken, Error> {
+    let _metrics = metrics::track("create_access_token");
+    _get_own_event(event_id).await?;
+    let label = label.map(|label| label.trim().to_string()).filter(|label| !label.is_empty());
+    if label.as_ref().is_some_and(|label| label.chars().count() > MAX_LABEL_CHARS) {
+        return Err(Error::InvalidPayload {
+            msg: format!("a token label can have at most {} characters", MAX_LABEL_CHARS),
+        });
+    }
+    if tokens_of(event_id).len() >= MAX_TOKENS_PER_EVENT {
+        return Err(Error::InvalidPayload {
+            msg: format!("an event can have at most {} access tokens", MAX_TOKENS_PER_EVENT),
+        });
+    }
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|err| _call_failed("raw_rand", err))?;
+    let token: String = bytes
+        .iter()
+        .take(TOKEN_BYTES)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let access_token = AccessToken {
+        token: token.clone(),
+        label,
+        created_at: time(),
+    };
+    ACCESS_TOKENS.with(|t| {
+        t.borrow_mut()
+            .insert(AccessTokenKey { event_id, token }, access_token.clone())
+    });
+    Ok(access_token)
+}
+
+// Update function for an event's organizer to revoke an access token; links carrying it
+// stop working
+#[ic_cdk::update]
+async fn revoke_access_token(event_id: u64, token: String) -> Result<AccessToken, Error> {
+    let _metrics = metrics::track("revoke_access_token");
+    _get_own_event(event_id).await?;
+    if !is_valid_token(event_id, &token) {
+        return Err(Error::NotFound {
+            msg: format!("No such access token for the event with id={}", event_id),
+        });
+    }
+    Ok(ACCESS_TOKENS
+        .with(|t| t.borrow_mut().remove(&AccessTokenKey { event_id, token }))
+        .expect("the token was just found"))
+}
+
+// Query function for an event's organizer to list its access tokens
+#[ic_cdk::query(composite = true)]
+async fn list_access_tokens(event_id: u64) -> Result<Vec<AccessToken>, Error> {
+    _get_own_event(event_id).await?;
+    Ok(tokens_of(event_id))
+}
+
+// Query function returning the full details of an event to the holder of an access token
+#[ic_cdk::query(composite = true)]
+async fn get_event_with_token(id: u64, token: String) -> Result<Event, Error> {
+    let event = _fetch_event(id).await?;
+    status::_check_visible(&event)?;
+    if !is_unlisted(event.id) || is_valid_token(event.id, &token) {
+        return Ok(verification::with_badge(event));
+    }
+    Err(Error::NotAuthorized {
+        msg: format!("The access token is not valid for the event with id={}", id),
+        caller: caller(),
+    })
+}
+
+// Whether an event was unlisted by its organizer
+pub(crate) fn is_unlisted(event_id: u64) -> bool {
+    UNLISTED.with(|u| u.borrow().contains_key(&event_id))
+}
+
+// Whether a token from a share link unlocks the event
+pub(crate) fn is_valid_token(event_id: u64, token: &str) -> bool {
+    if token.len() != TOKEN_BYTES * 2 {
+        return false;
+    }
+    let key = AccessTokenKey {
+        event_id,
+        token: token.to_string(),
+    };
+    ACCESS_TOKENS.with(|t| t.borrow().contains_key(&key))
+}
+
+// Whether the caller may see the event in full: it is listed, or the caller organizes or
+// attends it, belongs to its organization, or is an admin
+pub(crate) fn can_view(event: &Event) -> bool {
+    let principal = caller();
+    !is_unlisted(event.id)
+        || _is_on_team(event, &principal)
+        || attendees::is_attending(event.id, &principal.to_string())
+        || _check_admin().is_ok()
+}
+
+// Strips an unlisted event down to its title and times for callers who can't view it
+pub(crate) fn redact(event: Event) -> Event {
+    if can_view(&event) {
+        return event;
+    }
+    Event {
+        id: event.id,
+        owner: event.owner,
+        event_title: event.event_title,
+        created_at: event.created_at,
+        starts_at: event.starts_at,
+        ends_at: event.ends_at,
+        slug: event.slug,
+        ..Default::default()
+    }
+}
+
+async fn _get_own_event(event_id: u64) -> Result<Event, Error> {
+    let event = _fetch_event(event_id).await?;
+    if !_is_owner(&event, &caller()) {
+        return Err(Error::NotAuthorized {
+            msg: format!("You're not the organizer of the event with id={}", event_id),
+            caller: caller(),
+        });
+    }
+    Ok(event)
+}
+
+fn tokens_of(event_id: u64) -> Vec<AccessToken> {
+    let start = AccessTokenKey {
+        event_id,
+        token: String::new(),
+    };
+    ACCESS_TOKENS.with(|t| {
+        t.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(_, token)| token)
+            .collect()
+    })
+}
@@ -0,0 +1,191 @@
+// Achievement badges. Milestones (a first event, ten events attended, five events organized)
+// mint a permanent badge record for the principal, and organizers can enable a
+// commemorative badge for an event that every attendee checked in at its door receives,
+// like a proof-of-attendance token.
+use crate::{attendees, tickets};
+use crate::{Error, EventPrincipalKey, Memory, PrincipalKey, MEMORY_MANAGER, _get_event, _get_organized_event};
+use crate::clock::time;
+use crate::metrics;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const EVENTS_ATTENDED_MILESTONE: u64 = 10;
+const EVENTS_ORGANIZED_MILESTONE: u64 = 5;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+enum Achievement {
+    FirstEvent,
+    TenEventsAttended,
+    FiveEventsOrganized,
+    // Minted per event, for events whose organizer enabled it
+    Commemorative,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct BadgeKey {
+    principal: String,
+    achievement: Achievement,
+    event_id: Option<u64>,
+}
+
+impl_storable!(BadgeKey, 128);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AchievementBadge {
+    achievement: Achievement,
+    // Set on commemorative badges
+    event_id: Option<u64>,
+    event_title: Option<String>,
+    minted_at: u64,
+}
+
+impl_storable!(AchievementBadge, 1024);
+
+thread_local! {
+    static ACHIEVEMENT_BADGES: RefCell<StableBTreeMap<BadgeKey, AchievementBadge, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))
+    ));
+
+    // Events whose organizer enabled a commemorative badge, with the time they did
+    static COMMEMORATIVE_EVENTS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+    ));
+
+    // How many events each principal has created
+    static EVENTS_ORGANIZED: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54)))
+    ));
+}
+
+// Query function listing the caller's achievement badges
+#[ic_cdk::query]
+fn my_badges() -> Vec<AchievementBadge> {
+    badges_of(&caller().to_string())
+}
+
+// Query function listing a principal's achievement badges
+#[ic_cdk::query]
+fn get_badges(principal: Principal) -> Vec<AchievementBadge> {
+    badges_of(&principal.to_string())
+}
+
+// Update function for an event's organizer to turn its commemorative badge on or off.
+// Enabling it mints the badge for attendees who were already checked in.
+#[ic_cdk::update]
+fn set_commemorative_badge(event_id: u64, enabled: bool) -> Result<bool, Error> {
+    let _metrics = metrics::track("set_commemorative_badge");
+    let event = _get_organized_event(event_id)?;
+    if !enabled {
+        COMMEMORATIVE_EVENTS.with(|c| c.borrow_mut().remove(&event_id));
+        return Ok(false);
+    }
+    if COMMEMORATIVE_EVENTS.with(|c| c.borrow_mut().insert(event_id, time())).is_none() {
+        for attendee in attendees::attendees_of(event_id) {
+            let key = EventPrincipalKey {
+                event_id,
+                principal: attendee.clone(),
+            };
+            if tickets::is_checked_in(&key) {
+                mint_commemorative(event_id, &event.event_title, &attendee);
+            }
+        }
+    }
+    Ok(true)
+}
+
+// Query function telling whether an event hands out a commemorative badge
+#[ic_cdk::query]
+fn has_commemorative_badge(event_id: u64) -> bool {
+    COMMEMORATIVE_EVENTS.with(|c| c.borrow().contains_key(&event_id))
+}
+
+// Mints the attendance milestones reached with a principal's latest event
+pub(crate) fn record_attendance(principal: &str, events_attended: u64) {
+    if events_attended >= 1 {
+        mint(principal, Achievement::FirstEvent);
+    }
+    if events_attended >= EVENTS_ATTENDED_MILESTONE {
+        mint(principal, Achievement::TenEventsAttended);
+    }
+}
+
+// Mints the event's commemorative badge for an attendee checked in at its door
+pub(crate) fn record_check_in(event_id: u64, principal: &str) {
+    if !COMMEMORATIVE_EVENTS.with(|c| c.borrow().contains_key(&event_id)) {
+        return;
+    }
+    if let Some(event) = _get_event(&event_id) {
+        mint_commemorative(event_id, &event.event_title, principal);
+    }
+}
+
+// Counts a newly created event towards its organizer's milestone
+pub(crate) fn record_event_organized(principal: &str) {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    let organized = EVENTS_ORGANIZED.with(|e| e.borrow().get(&key)).unwrap_or(0) + 1;
+    EVENTS_ORGANIZED.with(|e| e.borrow_mut().insert(key, organized));
+    if organized >= EVENTS_ORGANIZED_MILESTONE {
+        mint(principal, Achievement::FiveEventsOrganized);
+    }
+}
+
+fn mint(principal: &str, achievement: Achievement) {
+    insert_once(
+        principal,
+        AchievementBadge {
+            achievement,
+            event_id: None,
+            event_title: None,
+            minted_at: time(),
+        },
+    );
+}
+
+fn mint_commemorative(event_id: u64, event_title: &str, principal: &str) {
+    insert_once(
+        principal,
+        AchievementBadge {
+            achievement: Achievement::Commemorative,
+            event_id: Some(event_id),
+            event_title: Some(event_title.to_string()),
+            minted_at: time(),
+        },
+    );
+}
+
+fn insert_once(principal: &str, badge: AchievementBadge) {
+    let key = BadgeKey {
+        principal: principal.to_string(),
+        achievement: badge.achievement,
+        event_id: badge.event_id,
+    };
+    ACHIEVEMENT_BADGES.with(|b| {
+        let mut badges = b.borrow_mut();
+        if !badges.contains_key(&key) {
+            badges.insert(key, badge);
+        }
+    });
+}
+
+fn badges_of(principal: &str) -> Vec<AchievementBadge> {
+    let start = BadgeKey {
+        principal: principal.to_string(),
+        achievement: Achievement::FirstEvent,
+        event_id: None,
+    };
+    ACHIEVEMENT_BADGES.with(|b| {
+        b.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.principal == principal)
+            .map(|(_, badge)| badge)
+            .collect()
+    })
+}
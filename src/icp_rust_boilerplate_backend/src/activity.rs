@@ -0,0 +1,231 @@
+// User activity. Each principal has a stream of what they did (created an event, RSVPed,
+// commented) and the time they were last active, both recorded as things happen. Others
+// see the stream through `get_activity` unless the user made it private in their privacy
+// settings; entries about unpublished or unlisted events are only shown to the user.
+use crate::admin::_check_admin;
+use crate::{access, metrics, status};
+use crate::{Error, IdCell, Memory, PrincipalKey, MEMORY_MANAGER};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Older entries are dropped once a principal has this many
+const MAX_ACTIVITIES_PER_PRINCIPAL: usize = 200;
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 100;
+const MAX_TITLE_CHARS: usize = 100;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum ActivityKind {
+    CreatedEvent,
+    Rsvped,
+    // Asked a question or reviewed an event
+    Commented,
+}
+
+// Entries are keyed by principal first so a user's stream is a range scan; ids grow over
+// time, so the stream comes out oldest first
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct ActivityKey {
+    principal: String,
+    id: u64,
+}
+
+impl_storable!(ActivityKey, 128);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Activity {
+    id: u64,
+    kind: ActivityKind,
+    event_id: u64,
+    event_title: String,
+    at: u64,
+}
+
+impl_storable!(Activity, 512);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PrivacySettings {
+    // Whether others can read the activity stream
+    show_activity: bool,
+    // Whether others can see when the user was last active
+    show_last_active: bool,
+}
+
+impl_storable!(PrivacySettings, 32);
+
+impl Default for PrivacySettings {
+    fn default() -> Self {
+        Self {
+            show_activity: true,
+            show_last_active: true,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ActivityFeed {
+    principal: Principal,
+    // None when never active or hidden by the user
+    last_active_at: Option<u64>,
+    // Newest first
+    activities: Vec<Activity>,
+}
+
+thread_local! {
+    static ACTIVITY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(118))), 0)
+            .expect("Cannot create an activity counter")
+    );
+
+    static ACTIVITIES: RefCell<StableBTreeMap<ActivityKey, Activity, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(119)))
+    ));
+
+    static LAST_ACTIVE: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(120)))
+    ));
+
+    static PRIVACY_SETTINGS: RefCell<StableBTreeMap<PrincipalKey, PrivacySettings, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(121)))
+    ));
+}
+
+// Query function returning a principal's most recent activity, newest first. Users always
+// see their own stream in full; others get an error if it is private.
+#[ic_cdk::query]
+fn get_activity(principal: Principal, limit: Option<u32>) -> Result<ActivityFeed, Error> {
+    let settings = privacy_settings(&principal.to_string());
+    let own = caller() == principal || _check_admin().is_ok();
+    if !own && !settings.show_activity {
+        return Err(Error::NotAuthorized {
+            msg: format!("{} keeps their activity private", principal),
+            caller: caller(),
+        });
+    }
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let activities = activities_of(&principal.to_string())
+        .into_iter()
+        .rev()
+        .filter(|activity| own || is_public_event(activity.event_id))
+        .take(limit)
+        .collect();
+    let last_active_at = if own || settings.show_last_active {
+        last_active(&principal.to_string())
+    } else {
+        None
+    };
+    Ok(ActivityFeed {
+        principal,
+        last_active_at,
+        activities,
+    })
+}
+
+// Query function returning the caller's privacy settings
+#[ic_cdk::query]
+fn get_privacy_settings() -> PrivacySettings {
+    privacy_settings(&caller().to_string())
+}
+
+// Update function for the caller to choose who sees their activity and last-active time
+#[ic_cdk::update]
+fn set_privacy_settings(settings: PrivacySettings) -> PrivacySettings {
+    let _metrics = metrics::track("set_privacy_settings");
+    let key = PrincipalKey {
+        principal: caller().to_string(),
+    };
+    PRIVACY_SETTINGS.with(|s| s.borrow_mut().insert(key, settings.clone()));
+    settings
+}
+
+// Adds an entry to a principal's activity stream and marks them active now
+pub(crate) fn record(principal: &str, kind: ActivityKind, event_id: u64, event_title: &str) {
+    let id = ACTIVITY_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment activity id counter");
+    let activity = Activity {
+        id,
+        kind,
+        event_id,
+        event_title: event_title.chars().take(MAX_TITLE_CHARS).collect(),
+        at: time(),
+    };
+    let key = ActivityKey {
+        principal: principal.to_string(),
+        id,
+    };
+    ACTIVITIES.with(|a| a.borrow_mut().insert(key, activity));
+    prune(principal);
+    touch(principal);
+}
+
+// Marks a principal as active now
+fn touch(principal: &str) {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    LAST_ACTIVE.with(|l| l.borrow_mut().insert(key, time()));
+}
+
+fn last_active(principal: &str) -> Option<u64> {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    LAST_ACTIVE.with(|l| l.borrow().get(&key))
+}
+
+fn privacy_settings(principal: &str) -> PrivacySettings {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    PRIVACY_SETTINGS.with(|s| s.borrow().get(&key)).unwrap_or_default()
+}
+
+// Whether anyone may see an entry about the event
+fn is_public_event(event_id: u64) -> bool {
+    status::is_published(event_id) && !access::is_unlisted(event_id)
+}
+
+fn activities_of(principal: &str) -> Vec<Activity> {
+    let start = ActivityKey {
+        principal: principal.to_string(),
+        id: 0,
+    };
+    ACTIVITIES.with(|a| {
+        a.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.principal == principal)
+            .map(|(_, activity)| activity)
+            .collect()
+    })
+}
+
+// Drops a principal's oldest entries beyond the cap
+fn prune(principal: &str) {
+    let start = ActivityKey {
+        principal: principal.to_string(),
+        id: 0,
+    };
+    ACTIVITIES.with(|a| {
+        let mut a = a.borrow_mut();
+        let keys: Vec<ActivityKey> = a
+            .range(start..)
+            .take_while(|(key, _)| key.principal == principal)
+            .map(|(key, _)| key)
+            .collect();
+        let excess = keys.len().saturating_sub(MAX_ACTIVITIES_PER_PRINCIPAL);
+        for key in keys.into_iter().take(excess) {
+            a.remove(&key);
+        }
+    });
+}
@@ -0,0 +1,392 @@
+// Admin-level operations. Until a governance canister is configured they are open to
+// the canister's controllers and the admins in the install config; once an SNS governance
+// canister principal is set, only it can execute them, so a community can run the event
+// hub under DAO control.
+//
+// Each SNS-executable method has a `validate_*` companion that SNS generic proposals
+// call with the same arguments to render the proposal payload.
+use crate::notifications::{self, NotificationKind};
+use crate::{assets, attendees, cache, config, duplicates, metrics, noshows, questions};
+use crate::{schedule, shard, slugs, status, tiers, venues};
+use crate::{Error, Event, Memory, MEMORY_MANAGER, STORAGE, _get_event, do_insert};
+use crate::clock::time;
+use candid::{Encode, Principal};
+use ic_cdk::api::management_canister::main::{deposit_cycles, CanisterIdRecord};
+use ic_cdk::api::{canister_balance128, is_controller};
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap};
+use std::cell::RefCell;
+
+// Takedown reasons are stored with the audit record, in bytes
+const MAX_TAKEDOWN_REASON_BYTES: usize = 500;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AdminConfig {
+    // SNS governance canister that exclusively holds admin rights once set
+    governance: Option<Principal>,
+}
+
+impl_storable!(AdminConfig, 64);
+
+// Audit record of an event removed by an admin, alone or in bulk
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Takedown {
+    event_id: u64,
+    event_title: String,
+    owner: String,
+    reason: String,
+    taken_down_by: Principal,
+    taken_down_at: u64,
+}
+
+// Room for the longest title an event may have, its owner and the reason
+impl_storable!(Takedown, 2048);
+
+// Tombstone left behind by an event that was merged into another one
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct MergeRecord {
+    target_id: u64,
+    merged_by: Principal,
+    merged_at: u64,
+}
+
+impl_storable!(MergeRecord, 128);
+
+thread_local! {
+    static ADMIN_CONFIG: RefCell<Cell<AdminConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))), AdminConfig::default())
+            .expect("Cannot create the admin config cell")
+    );
+
+    static TAKEDOWNS: RefCell<StableBTreeMap<u64, Takedown, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    // Keyed by the id of the merged (removed) event
+    static MERGES: RefCell<StableBTreeMap<u64, MergeRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+}
+
+// Query function returning the governance canister that controls admin operations, if any
+#[ic_cdk::query]
+fn get_governance_canister() -> Option<Principal> {
+    ADMIN_CONFIG.with(|c| c.borrow().get().governance)
+}
+
+// Update function handing admin rights to an SNS governance canister, or back to the
+// controllers when called with null
+#[ic_cdk::update]
+fn set_governance_canister(governance: Option<Principal>) -> Result<(), Error> {
+    let _metrics = metrics::track("set_governance_canister");
+    _check_admin()?;
+    ADMIN_CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(AdminConfig { governance })
+            .expect("cannot update the admin config")
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn validate_set_governance_canister(governance: Option<Principal>) -> Result<String, String> {
+    Ok(match governance {
+        Some(governance) => format!("Hand admin rights to governance canister {}", governance),
+        None => "Return admin rights to the canister controllers".to_string(),
+    })
+}
+
+// Update function for admins to take down an event, keeping an audit record. The event is
+// soft-deleted like the ones taken down in bulk: attendees are told, paid tickets refunded
+// and the event hidden.
+#[ic_cdk::update]
+async fn takedown_event(id: u64, reason: String) -> Result<Takedown, Error> {
+    let _metrics = metrics::track("takedown_event");
+    _check_admin()?;
+    let invalid = |msg: String| Error::InvalidPayload { msg };
+
+    // Events spilled to a bucket are taken down there; the audit record is kept here
+    let takedown = match shard::bucket_of(id) {
+        Some(bucket) => {
+            _check_takedown_reason(&reason).map_err(invalid)?;
+            let event: Event = shard::forward(bucket, "bucket_get_event", (id,)).await?;
+            let takedown = _takedown_record(&event, &reason).map_err(invalid)?;
+            let event: Event =
+                shard::forward(bucket, "bucket_takedown", (id, reason.clone())).await?;
+            // The title may have changed in between; the record checked above still fits
+            _takedown_record(&event, &reason).unwrap_or(takedown)
+        }
+        None => {
+            let event = _get_takedown_target(id, &reason).map_err(invalid)?;
+            let takedown = _takedown_record(&event, &reason).map_err(invalid)?;
+            take_down(&event, &reason);
+            takedown
+        }
+    };
+    record_takedown(takedown.clone());
+    Ok(takedown)
+}
+
+// Soft-deletes an event taken down by an admin and tells its organizer why
+pub(crate) fn take_down(event: &Event, reason: &str) {
+    status::soft_delete(
+        event,
+        format!("\"{}\" has been removed by the moderators", event.event_title),
+    );
+    notifications::notify(
+        &event.owner,
+        NotificationKind::ReviewDecision,
+        Some(event.id),
+        format!("\"{}\" was taken down by the moderators: {}", event.event_title, reason),
+    );
+}
+
+#[ic_cdk::query]
+fn validate_takedown_event(id: u64, reason: String) -> Result<String, String> {
+    let event = _get_takedown_target(id, &reason)?;
+    _takedown_record(&event, &reason)?;
+    Ok(format!(
+        "Take down event {} \"{}\" owned by {}: {}",
+        id, event.event_title, event.owner, reason
+    ))
+}
+
+// Query function listing all admin takedowns
+#[ic_cdk::query]
+fn get_takedowns() -> Vec<Takedown> {
+    TAKEDOWNS.with(|t| t.borrow().iter().map(|(_, takedown)| takedown).collect())
+}
+
+// Update function for admins to fold an accidental duplicate into another event of the
+// same organizer. The source's attendees join the target, even beyond its capacity, along
+// with its questions and no-show waitlist, and the source is removed with its image and
+// slug; lookups of its id are redirected to the target.
+#[ic_cdk::update]
+fn merge_events(target_id: u64, source_id: u64) -> Result<Event, Error> {
+    let _metrics = metrics::track("merge_events");
+    _check_admin()?;
+    let (mut target, source) =
+        _get_merge_events(target_id, source_id).map_err(|msg| Error::InvalidPayload { msg })?;
+
+    let moved = attendees::attendees_of(source_id);
+    for attendee in &moved {
+        attendees::add(target_id, attendee);
+    }
+    target.updated_at = Some(time());
+    target.attendee_count = Some(attendees::count(target_id));
+    do_insert(&target);
+    for attendee in &moved {
+        schedule::record(&target, attendee);
+    }
+
+    notifications::notify_attendees(
+        &source,
+        NotificationKind::EventUpdated,
+        format!(
+            "\"{}\" has been merged into \"{}\" (id={}); your registration carries over",
+            source.event_title, target.event_title, target_id
+        ),
+    );
+    cache::remove(source_id);
+    schedule::forget(&source);
+    attendees::forget(source_id);
+    duplicates::forget(source_id);
+    venues::forget_booking(source_id);
+    questions::move_questions(source_id, target_id);
+    noshows::move_waitlist(source_id, target_id);
+    assets::remove_image(source_id);
+    slugs::release(&source);
+    let record = MergeRecord {
+        target_id,
+        merged_by: caller(),
+        merged_at: time(),
+    };
+    MERGES.with(|m| m.borrow_mut().insert(source_id, record));
+    Ok(target)
+}
+
+#[ic_cdk::query]
+fn validate_merge_events(target_id: u64, source_id: u64) -> Result<String, String> {
+    let (target, source) = _get_merge_events(target_id, source_id)?;
+    Ok(format!(
+        "Merge event {} \"{}\" ({} attendees) into event {} \"{}\"",
+        source_id,
+        source.event_title,
+        attendees::count(source_id),
+        target_id,
+        target.event_title
+    ))
+}
+
+// Query function returning the event a merged event id now redirects to, if any
+#[ic_cdk::query]
+fn get_merge_redirect(id: u64) -> Option<u64> {
+    MERGES.with(|m| m.borrow().get(&id)).map(|record| record.target_id)
+}
+
+// Follows merge redirects to the id of the event that is still live
+pub(crate) fn _resolve_merged(mut id: u64) -> u64 {
+    while let Some(record) = MERGES.with(|m| m.borrow().get(&id)) {
+        id = record.target_id;
+    }
+    id
+}
+
+// Update function for admins to drop and rebuild the secondary indexes kept next to the
+// event store (duplicate fingerprints by owner, venue bookings, slugs, attendee schedules
+// and the search index) from the events themselves, after a migration or when they look
+// corrupted. Entries of events spilled to buckets can't be rebuilt here and are kept, and
+// deleted events are only put back in the search index.
+#[ic_cdk::update]
+fn rebuild_indexes() -> Result<u64, Error> {
+    let _metrics = metrics::track("rebuild_indexes");
+    _check_admin()?;
+    Ok(_rebuild_indexes())
+}
+
+#[ic_cdk::query]
+fn validate_rebuild_indexes() -> Result<String, String> {
+    let events = STORAGE.with(|s| s.borrow().len());
+    Ok(format!("Rebuild the secondary indexes of {} stored events", events))
+}
+
+// Helper function rebuilding the secondary indexes; returns the number of events indexed
+pub(crate) fn _rebuild_indexes() -> u64 {
+    let is_remote = |id: u64| shard::bucket_of(id).is_some();
+    duplicates::clear_index(is_remote);
+    venues::clear_bookings(is_remote);
+    slugs::clear_index(is_remote);
+    schedule::clear_schedules(is_remote);
+    cache::rebuild();
+    let mut events = 0;
+    STORAGE.with(|s| {
+        for (_, event) in s.borrow().iter() {
+            events += 1;
+            if status::is_deleted(event.id) {
+                continue;
+            }
+            duplicates::remember(&event);
+            venues::remember_booking(&event);
+            slugs::restore(&event);
+            schedule::refresh(&event);
+        }
+    });
+    events
+}
+
+// Update function for admins to withdraw cycles from the canister's balance to another canister
+#[ic_cdk::update]
+async fn withdraw_cycles(to: Principal, amount: u128) -> Result<(), Error> {
+    let _metrics = metrics::track("withdraw_cycles");
+    _check_admin()?;
+    _check_withdrawal(amount).map_err(|msg| Error::InvalidPayload { msg })?;
+    deposit_cycles(CanisterIdRecord { canister_id: to }, amount)
+        .await
+        .map_err(|err| crate::_call_failed("deposit_cycles", err))
+}
+
+#[ic_cdk::query]
+fn validate_withdraw_cycles(to: Principal, amount: u128) -> Result<String, String> {
+    _check_withdrawal(amount)?;
+    Ok(format!("Withdraw {} cycles to canister {}", amount, to))
+}
+
+// Helper function to restrict admin operations to the governance canister, or to the
+// controllers and configured admins while none is configured
+pub(crate) fn _check_admin() -> Result<(), Error> {
+    let allowed = match ADMIN_CONFIG.with(|c| c.borrow().get().governance) {
+        Some(governance) => caller() == governance,
+        None => is_controller(&caller()) || config::is_admin(&caller()),
+    };
+    if !allowed {
+        return Err(Error::NotAuthorized {
+            msg: "Only the governance canister or controllers can perform admin operations".to_string(),
+            caller: caller(),
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn _get_takedown_target(id: u64, reason: &str) -> Result<Event, String> {
+    _check_takedown_reason(reason)?;
+    if status::is_deleted(id) {
+        return Err(format!("Event with id={} is already deleted", id));
+    }
+    _get_event(&id).ok_or(format!("Event with id={} not found", id))
+}
+
+// Helper function rejecting an empty or oversized takedown reason
+pub(crate) fn _check_takedown_reason(reason: &str) -> Result<(), String> {
+    if reason.trim().is_empty() {
+        return Err("a takedown reason is required".to_string());
+    }
+    if reason.len() > MAX_TAKEDOWN_REASON_BYTES {
+        return Err(format!(
+            "takedown reasons can be at most {} bytes long",
+            MAX_TAKEDOWN_REASON_BYTES
+        ));
+    }
+    Ok(())
+}
+
+// Helper function building the audit record of an event the caller takes down, refusing one
+// that wouldn't fit its storage bound
+pub(crate) fn _takedown_record(event: &Event, reason: &str) -> Result<Takedown, String> {
+    let takedown = Takedown {
+        event_id: event.id,
+        event_title: event.event_title.clone(),
+        owner: event.owner.clone(),
+        reason: reason.to_string(),
+        taken_down_by: caller(),
+        taken_down_at: time(),
+    };
+    let size = Encode!(&takedown).map_or(usize::MAX, |bytes| bytes.len());
+    if size > Takedown::MAX_SIZE as usize {
+        return Err(format!(
+            "the takedown record takes {} bytes but at most {} fit; shorten the reason",
+            size,
+            Takedown::MAX_SIZE
+        ));
+    }
+    Ok(takedown)
+}
+
+// Keeps the audit record of a takedown
+pub(crate) fn record_takedown(takedown: Takedown) {
+    TAKEDOWNS.with(|t| t.borrow_mut().insert(takedown.event_id, takedown));
+}
+
+fn _get_merge_events(target_id: u64, source_id: u64) -> Result<(Event, Event), String> {
+    if target_id == source_id {
+        return Err("an event cannot be merged into itself".to_string());
+    }
+    for id in [target_id, source_id] {
+        if status::is_deleted(id) {
+            return Err(format!("Event with id={} is deleted", id));
+        }
+    }
+    let target = _get_event(&target_id).ok_or(format!("Event with id={} not found", target_id))?;
+    let source = _get_event(&source_id).ok_or(format!("Event with id={} not found", source_id))?;
+    if target.owner != source.owner {
+        return Err("only events of the same organizer can be merged".to_string());
+    }
+    // Paid tickets are tied to their event's tiers and cannot be moved
+    if tiers::_check_free_registration(source_id).is_err() {
+        return Err(format!("Event with id={} sells tickets and cannot be merged", source_id));
+    }
+    Ok((target, source))
+}
+
+fn _check_withdrawal(amount: u128) -> Result<(), String> {
+    if amount == 0 || amount > canister_balance128() {
+        return Err(format!(
+            "cannot withdraw {} cycles from a balance of {}",
+            amount,
+            canister_balance128()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,301 @@
+// Sales analytics. Every tier purchase is counted per tier, UTC day and ledger, and sales
+// made with a promo code are also counted per code, so organizers can see which tiers sell
+// and which campaigns bring buyers in. Counts are taken when the sale completes and aren't
+// lowered by later refunds or resales; the revenue report has those. Events with a long
+// sales history are returned in pages: the scan stops at the scan budget and hands back a
+// continuation carrying the tier totals so far, which are complete on the last page.
+use crate::tiers::{self, HeldTicket};
+use crate::{budget, promos, views, Error, Memory, MEMORY_MANAGER, _get_organized_event};
+use crate::clock::time;
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct TierSalesKey {
+    event_id: u64,
+    // Days since the epoch, UTC
+    day: u64,
+    tier_id: u64,
+    ledger: Principal,
+}
+
+impl_storable!(TierSalesKey, 128);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct PromoSalesKey {
+    event_id: u64,
+    code: String,
+    ledger: Principal,
+}
+
+impl_storable!(PromoSalesKey, 160);
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct SalesCount {
+    tickets: u64,
+    // Tickets sold with a promo code
+    promo_tickets: u64,
+    // What buyers paid
+    revenue: u128,
+    // Taken off the tier prices by promo codes
+    discounts: u128,
+}
+
+impl_storable!(SalesCount, 96);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DailyTierSales {
+    // Start of the day, in nanoseconds since the epoch
+    day_start: u64,
+    tier_id: u64,
+    ledger: Principal,
+    sales: SalesCount,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TierSales {
+    tier_id: u64,
+    // None once the tier is gone
+    tier_name: Option<String>,
+    ledger: Principal,
+    sales: SalesCount,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PromoSales {
+    code: String,
+    ledger: Principal,
+    sales: SalesCount,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SalesAnalytics {
+    event_id: u64,
+    views: u64,
+    tickets: u64,
+    // Tickets sold per view, 0 before the first view
+    conversion_rate: f64,
+    tiers: Vec<TierSales>,
+    daily: Vec<DailyTierSales>,
+    promo_codes: Vec<PromoSales>,
+    // Pass back to `get_sales_analytics` for the next page; None on the last page
+    continuation: Option<AnalyticsContinuation>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AnalyticsContinuation {
+    // Next day row to return; None once they all were
+    next_day: Option<TierSalesKey>,
+    // Next promo code row to return; None once they all were
+    next_code: Option<PromoSalesKey>,
+    // Tier totals over the day rows returned so far
+    tiers: Vec<TierSales>,
+}
+
+thread_local! {
+    static TIER_SALES: RefCell<StableBTreeMap<TierSalesKey, SalesCount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(151)))
+    ));
+
+    static PROMO_SALES: RefCell<StableBTreeMap<PromoSalesKey, SalesCount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(152)))
+    ));
+}
+
+// Query function returning an event's tier sales per day and its sales per promo code;
+// restricted to its organizer. Pass the continuation of the previous page for the next one.
+#[ic_cdk::query]
+fn get_sales_analytics(
+    event_id: u64,
+    continuation: Option<AnalyticsContinuation>,
+) -> Result<SalesAnalytics, Error> {
+    _get_organized_event(event_id)?;
+    let (next_day, next_code, mut tier_lines) = match continuation {
+        Some(continuation) => {
+            let other_event = continuation
+                .next_day
+                .as_ref()
+                .is_some_and(|key| key.event_id != event_id)
+                || continuation
+                    .next_code
+                    .as_ref()
+                    .is_some_and(|key| key.event_id != event_id);
+            if other_event {
+                return Err(Error::InvalidPayload {
+                    msg: format!("the continuation isn't for the event with id={}", event_id),
+                });
+            }
+            (continuation.next_day, continuation.next_code, continuation.tiers)
+        }
+        None => {
+            let next_day = TierSalesKey {
+                event_id,
+                day: 0,
+                tier_id: 0,
+                ledger: Principal::management_canister(),
+            };
+            let next_code = PromoSalesKey {
+                event_id,
+                code: String::new(),
+                ledger: Principal::management_canister(),
+            };
+            (Some(next_day), Some(next_code), Vec::new())
+        }
+    };
+
+    let (daily, day_left) = match next_day {
+        Some(start) => day_rows(event_id, start),
+        None => (Vec::new(), None),
+    };
+    // Promo codes come after the day rows
+    let (promo_codes, code_left) = match (&day_left, next_code) {
+        (None, Some(start)) => promo_code_rows(event_id, start),
+        (_, next_code) => (Vec::new(), next_code),
+    };
+
+    for day in &daily {
+        let position = tier_lines
+            .iter()
+            .position(|line| line.tier_id == day.tier_id && line.ledger == day.ledger);
+        let position = match position {
+            Some(position) => position,
+            None => {
+                tier_lines.push(TierSales {
+                    tier_id: day.tier_id,
+                    tier_name: tiers::tier_name(event_id, day.tier_id),
+                    ledger: day.ledger,
+                    sales: SalesCount::default(),
+                });
+                tier_lines.len() - 1
+            }
+        };
+        tier_lines[position].sales = tier_lines[position].sales.plus(day.sales);
+    }
+    let continuation = if day_left.is_some() || code_left.is_some() {
+        Some(AnalyticsContinuation {
+            next_day: day_left,
+            next_code: code_left,
+            tiers: tier_lines.clone(),
+        })
+    } else {
+        None
+    };
+
+    let views = views::total_views(event_id);
+    let tickets: u64 = tier_lines.iter().map(|line| line.sales.tickets).sum();
+    let conversion_rate = if views == 0 {
+        0.0
+    } else {
+        tickets as f64 / views as f64
+    };
+    Ok(SalesAnalytics {
+        event_id,
+        views,
+        tickets,
+        conversion_rate,
+        tiers: tier_lines,
+        daily,
+        promo_codes,
+        continuation,
+    })
+}
+
+// An event's day rows from `start` on, up to the scan budget, with the row to resume from
+fn day_rows(event_id: u64, start: TierSalesKey) -> (Vec<DailyTierSales>, Option<TierSalesKey>) {
+    TIER_SALES.with(|s| {
+        let mut daily = Vec::new();
+        let sales = s.borrow();
+        let rows = sales.range(start..).take_while(|(key, _)| key.event_id == event_id);
+        for (key, sales) in rows {
+            if budget::exhausted() {
+                return (daily, Some(key));
+            }
+            daily.push(DailyTierSales {
+                day_start: key.day * NANOS_PER_DAY,
+                tier_id: key.tier_id,
+                ledger: key.ledger,
+                sales,
+            });
+        }
+        (daily, None)
+    })
+}
+
+// An event's promo code rows from `start` on, up to the scan budget, with the row to resume
+// from
+fn promo_code_rows(
+    event_id: u64,
+    start: PromoSalesKey,
+) -> (Vec<PromoSales>, Option<PromoSalesKey>) {
+    PROMO_SALES.with(|s| {
+        let mut promo_codes = Vec::new();
+        let sales = s.borrow();
+        let rows = sales.range(start..).take_while(|(key, _)| key.event_id == event_id);
+        for (key, sales) in rows {
+            if budget::exhausted() {
+                return (promo_codes, Some(key));
+            }
+            promo_codes.push(PromoSales {
+                code: key.code,
+                ledger: key.ledger,
+                sales,
+            });
+        }
+        (promo_codes, None)
+    })
+}
+
+// Counts a completed tier purchase
+pub(crate) fn record_sale(held: &HeldTicket) {
+    let key = TierSalesKey {
+        event_id: held.event_id,
+        day: time() / NANOS_PER_DAY,
+        tier_id: held.tier_id,
+        ledger: held.ledger,
+    };
+    TIER_SALES.with(|s| {
+        let mut sales = s.borrow_mut();
+        let count = sales.get(&key).unwrap_or_default();
+        sales.insert(key, count.with_sale(held));
+    });
+    if let Some(code) = &held.promo_code {
+        let key = PromoSalesKey {
+            event_id: held.event_id,
+            code: promos::normalize_code(code),
+            ledger: held.ledger,
+        };
+        PROMO_SALES.with(|s| {
+            let mut sales = s.borrow_mut();
+            let count = sales.get(&key).unwrap_or_default();
+            sales.insert(key, count.with_sale(held));
+        });
+    }
+}
+
+impl SalesCount {
+    fn with_sale(self, held: &HeldTicket) -> SalesCount {
+        SalesCount {
+            tickets: self.tickets + 1,
+            promo_tickets: self.promo_tickets + u64::from(held.promo_code.is_some()),
+            revenue: self.revenue.saturating_add(held.price),
+            discounts: self
+                .discounts
+                .saturating_add(held.tier_price.saturating_sub(held.price)),
+        }
+    }
+
+    fn plus(self, other: SalesCount) -> SalesCount {
+        SalesCount {
+            tickets: self.tickets + other.tickets,
+            promo_tickets: self.promo_tickets + other.promo_tickets,
+            revenue: self.revenue.saturating_add(other.revenue),
+            discounts: self.discounts.saturating_add(other.discounts),
+        }
+    }
+}
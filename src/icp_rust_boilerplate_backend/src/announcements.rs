@@ -0,0 +1,112 @@
+// Organizer announcements. Each announcement is kept on the event's board and copied into
+// the notification inbox of every attendee at the time it is posted.
+use crate::notifications::{self, NotificationKind};
+use crate::{metrics, Error, IdCell, Memory, MEMORY_MANAGER, _fetch_event, _is_owner};
+use crate::clock::time;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_ANNOUNCEMENT_BYTES: usize = 400;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Announcement {
+    id: u64,
+    event_id: u64,
+    author: String,
+    message: String,
+    posted_at: u64,
+    // Number of attendee inboxes the announcement was delivered to
+    recipients: u64,
+}
+
+impl_storable!(Announcement, 1024);
+
+// Announcements are keyed by event first so an event's board is a range scan
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct AnnouncementKey {
+    event_id: u64,
+    id: u64,
+}
+
+impl_storable!(AnnouncementKey, 32);
+
+thread_local! {
+    static ANNOUNCEMENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))), 0)
+            .expect("Cannot create an announcement counter")
+    );
+
+    static ANNOUNCEMENTS: RefCell<StableBTreeMap<AnnouncementKey, Announcement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+}
+
+// Update function for an event's organizer to post an announcement to its attendees
+#[ic_cdk::update]
+async fn post_announcement(event_id: u64, message: String) -> Result<Announcement, Error> {
+    let _metrics = metrics::track("post_announcement");
+    let message = message.trim().to_string();
+    if message.is_empty() || message.len() > MAX_ANNOUNCEMENT_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "an announcement must be between 1 and {} bytes",
+                MAX_ANNOUNCEMENT_BYTES
+            ),
+        });
+    }
+
+    let event = _fetch_event(event_id).await?;
+    if !_is_owner(&event, &caller()) {
+        return Err(Error::NotAuthorized {
+            msg: format!("You're not the organizer of the event with id={}", event_id),
+            caller: caller(),
+        });
+    }
+
+    let id = ANNOUNCEMENT_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment announcement id counter");
+    let announcement = Announcement {
+        id,
+        event_id,
+        author: caller().to_string(),
+        message: message.clone(),
+        posted_at: time(),
+        recipients: event.attendee_count.unwrap_or_default(),
+    };
+    ANNOUNCEMENTS.with(|a| {
+        a.borrow_mut()
+            .insert(AnnouncementKey { event_id, id }, announcement.clone())
+    });
+
+    notifications::notify_attendees(
+        &event,
+        NotificationKind::Announcement,
+        format!("{}: {}", event.event_title, message),
+    );
+    Ok(announcement)
+}
+
+// Query function listing an event's announcements, newest first
+#[ic_cdk::query]
+fn get_announcements(event_id: u64) -> Vec<Announcement> {
+    let start = AnnouncementKey { event_id, id: 0 };
+    let end = AnnouncementKey {
+        event_id,
+        id: u64::MAX,
+    };
+    let mut announcements: Vec<Announcement> = ANNOUNCEMENTS.with(|a| {
+        a.borrow()
+            .range(start..=end)
+            .map(|(_, announcement)| announcement)
+            .collect()
+    });
+    announcements.reverse();
+    announcements
+}
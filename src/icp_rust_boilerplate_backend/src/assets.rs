@@ -0,0 +1,645 @@
+// Event images stored on the canister. An organizer uploads an image with
+// `upload_event_image`; it becomes the event's card image and `http_request` serves it at
+// /image/<event id>. Responses carry response verification v2 headers so the HTTP gateway
+// verifies them instead of downgrading them as uncertified: the canister's certified data is
+// the root of a hash tree holding the hash of the response served at each image path.
+//
+// Requests aren't certified, only the status, body and the headers named in
+// CERTIFICATE_EXPRESSION. The query string is ignored, so image URLs carry the upload time
+// to get past caches.
+use crate::http::HttpResponse;
+use crate::images::ImageStatus;
+use crate::{metrics, Error, Event, Memory, MEMORY_MANAGER, _get_organized_event, do_insert};
+use crate::_check_event_fits;
+use crate::clock::time;
+use ic_cdk::api::{data_certificate, set_certified_data};
+use ic_cdk::id;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+const MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+// Largest part of an image sent in one call
+const MAX_PART_BYTES: usize = 1024 * 1024;
+const CHUNK_BYTES: usize = 64 * 1024;
+// SVG is left out since it can carry scripts
+const CONTENT_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+// Each upload gets a new URL, so a served image never changes
+const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+const CERTIFICATE_EXPRESSION: &str = concat!(
+    "default_certification(ValidationArgs{no_request_certification:Empty{},",
+    "response_certification:ResponseCertification{certified_response_headers:",
+    "ResponseHeaderList{headers:[\"content-type\",\"cache-control\"]}}})"
+);
+const STATUS_OK: u64 = 200;
+// Marks CBOR data as such, as the gateway expects
+const CBOR_SELF_DESCRIBE_TAG: [u8; 3] = [0xd9, 0xd9, 0xf7];
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+type Hash = [u8; 32];
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct ChunkKey {
+    event_id: u64,
+    index: u32,
+}
+
+impl_storable!(ChunkKey, 48);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ImageChunk {
+    bytes: Vec<u8>,
+}
+
+impl_storable!(ImageChunk, CHUNK_BYTES as u32 + 64);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StoredImage {
+    content_type: String,
+    size: u64,
+    chunks: u32,
+    uploaded_at: u64,
+    // Hash of the response serving the image, as certified
+    response_hash: Vec<u8>,
+}
+
+impl_storable!(StoredImage, 192);
+
+// Hash tree as defined by the IC interface specification
+#[derive(Clone)]
+enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+    Pruned(Hash),
+}
+
+thread_local! {
+    static IMAGES: RefCell<StableBTreeMap<u64, StoredImage, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(164)))
+    ));
+
+    static IMAGE_CHUNKS: RefCell<StableBTreeMap<ChunkKey, ImageChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(165)))
+    ));
+}
+
+// Update function for an event's organizer to upload its card image, replacing any image
+// uploaded before. Images larger than one call can carry are sent in parts: the first one
+// here and the rest with `append_event_image`.
+#[ic_cdk::update]
+fn upload_event_image(event_id: u64, content_type: String, data: Vec<u8>) -> Result<Event, Error> {
+    let _metrics = metrics::track("upload_event_image");
+    let event = _get_organized_event(event_id)?;
+    let content_type = content_type.trim().to_lowercase();
+    if !CONTENT_TYPES.contains(&content_type.as_str()) {
+        return Err(Error::InvalidPayload {
+            msg: format!("images must be one of {}", CONTENT_TYPES.join(", ")),
+        });
+    }
+    _check_part(&data, 0)?;
+    _check_event_fits(&with_image(event.clone(), &content_type, 0, time()))?;
+    remove_chunks(event_id);
+    let chunks = write_chunks(event_id, 0, &data);
+    Ok(save(event, content_type, &data, chunks))
+}
+
+// Update function for an event's organizer to add the next part of the image being uploaded
+#[ic_cdk::update]
+fn append_event_image(event_id: u64, data: Vec<u8>) -> Result<Event, Error> {
+    let _metrics = metrics::track("append_event_image");
+    let event = _get_organized_event(event_id)?;
+    let Some(image) = IMAGES.with(|i| i.borrow().get(&event_id)) else {
+        return Err(Error::NotFound {
+            msg: format!("The event with id={} has no uploaded image", event_id),
+        });
+    };
+    _check_part(&data, image.size as usize)?;
+    _check_event_fits(&with_image(event.clone(), &image.content_type, 0, time()))?;
+    let chunks = write_chunks(event_id, image.chunks, &data);
+    let body = read_body(event_id);
+    Ok(save(event, image.content_type, &body, chunks))
+}
+
+// Update function for an event's organizer to delete its uploaded image. The event loses its
+// card image if it was still showing the upload.
+#[ic_cdk::update]
+fn delete_event_image(event_id: u64) -> Result<Event, Error> {
+    let _metrics = metrics::track("delete_event_image");
+    let mut event = _get_organized_event(event_id)?;
+    if !IMAGES.with(|i| i.borrow().contains_key(&event_id)) {
+        return Err(Error::NotFound {
+            msg: format!("The event with id={} has no uploaded image", event_id),
+        });
+    }
+    remove_image(event_id);
+    if event.event_card_imgurl.starts_with(&image_path_url(event_id)) {
+        event.event_card_imgurl = String::new();
+        event.image_status = None;
+        event.updated_at = Some(time());
+        do_insert(&event);
+    }
+    Ok(event)
+}
+
+// Renders the uploaded image of an event, given the id from its path
+pub(crate) fn event_image(id: &str) -> HttpResponse {
+    let Some(event_id) = id.parse::<u64>().ok() else {
+        return HttpResponse::error(404, "Not found");
+    };
+    let Some(image) = IMAGES.with(|i| i.borrow().get(&event_id)) else {
+        return HttpResponse::error(404, "Not found");
+    };
+    let body = read_body(event_id);
+    let mut headers = certified_headers(&image.content_type);
+    if let Some(certificate) = certificate_header(event_id, &image) {
+        headers.push(("ic-certificate".to_string(), certificate));
+    }
+    HttpResponse::new(STATUS_OK as u16, headers, body)
+}
+
+// Whether the URL points at an image uploaded to this canister
+pub(crate) fn is_image_url(url: &str) -> bool {
+    url.starts_with(&format!("https://{}.icp0.io/image/", id()))
+}
+
+// Drops the uploaded image of an event, if it has one
+pub(crate) fn remove_image(event_id: u64) {
+    if IMAGES.with(|i| i.borrow_mut().remove(&event_id)).is_some() {
+        remove_chunks(event_id);
+        certify();
+    }
+}
+
+// Sets the canister's certified data to the root of the tree of image responses. Certified
+// data doesn't survive upgrades, so this also runs on init and post_upgrade.
+pub(crate) fn certify() {
+    set_certified_data(&image_tree().digest());
+}
+
+// Helper function rejecting an empty or oversized part of an image, given the size of the
+// parts before it
+fn _check_part(data: &[u8], uploaded: usize) -> Result<(), Error> {
+    if data.is_empty() || data.len() > MAX_PART_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("image parts must be between 1 and {} bytes", MAX_PART_BYTES),
+        });
+    }
+    if uploaded + data.len() > MAX_IMAGE_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("images can be at most {} bytes", MAX_IMAGE_BYTES),
+        });
+    }
+    Ok(())
+}
+
+// Stores an image part from the given chunk on, returning the number of chunks stored in all
+fn write_chunks(event_id: u64, first: u32, data: &[u8]) -> u32 {
+    IMAGE_CHUNKS.with(|c| {
+        let mut chunks = c.borrow_mut();
+        let mut index = first;
+        for bytes in data.chunks(CHUNK_BYTES) {
+            chunks.insert(ChunkKey { event_id, index }, ImageChunk { bytes: bytes.to_vec() });
+            index += 1;
+        }
+        index
+    })
+}
+
+fn read_body(event_id: u64) -> Vec<u8> {
+    let start = ChunkKey { event_id, index: 0 };
+    IMAGE_CHUNKS.with(|c| {
+        c.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .flat_map(|(_, chunk)| chunk.bytes)
+            .collect()
+    })
+}
+
+// Records and certifies the image now stored for an event and makes it the event's card
+// image, under a new URL
+fn save(event: Event, content_type: String, body: &[u8], chunks: u32) -> Event {
+    let image = StoredImage {
+        size: body.len() as u64,
+        chunks,
+        uploaded_at: time(),
+        response_hash: response_hash(&content_type, body).to_vec(),
+        content_type: content_type.clone(),
+    };
+    IMAGES.with(|i| i.borrow_mut().insert(event.id, image.clone()));
+    certify();
+
+    let event = with_image(event, &content_type, image.size, image.uploaded_at);
+    do_insert(&event);
+    event
+}
+
+// The event showing an uploaded image as its card image
+fn with_image(mut event: Event, content_type: &str, size: u64, uploaded_at: u64) -> Event {
+    event.event_card_imgurl = image_url(event.id, uploaded_at);
+    event.image_status = Some(ImageStatus::Verified {
+        content_type: content_type.to_string(),
+        size: Some(size),
+    });
+    event.updated_at = Some(uploaded_at);
+    event
+}
+
+fn remove_chunks(event_id: u64) {
+    let start = ChunkKey { event_id, index: 0 };
+    IMAGE_CHUNKS.with(|c| {
+        let mut chunks = c.borrow_mut();
+        let keys: Vec<ChunkKey> = chunks
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            chunks.remove(&key);
+        }
+    });
+}
+
+fn image_path_url(event_id: u64) -> String {
+    format!("https://{}.icp0.io/image/{}", id(), event_id)
+}
+
+fn image_url(event_id: u64, uploaded_at: u64) -> String {
+    format!("{}?v={}", image_path_url(event_id), uploaded_at)
+}
+
+fn certified_headers(content_type: &str) -> Vec<(String, String)> {
+    vec![
+        ("content-type".to_string(), content_type.to_string()),
+        ("cache-control".to_string(), CACHE_CONTROL.to_string()),
+        ("ic-certificateexpression".to_string(), CERTIFICATE_EXPRESSION.to_string()),
+    ]
+}
+
+// Hash of a response serving an image: the representation-independent hash of its certified
+// headers and status, followed by the hash of its body
+fn response_hash(content_type: &str, body: &[u8]) -> Hash {
+    let headers = certified_headers(content_type);
+    let mut fields: Vec<(&[u8], Vec<u8>)> = headers
+        .iter()
+        .map(|(name, value)| (name.as_bytes(), value.as_bytes().to_vec()))
+        .collect();
+    fields.push((b":ic-cert-status", leb128(STATUS_OK)));
+    sha256(&[map_hash(&fields), sha256(body)].concat())
+}
+
+// Representation-independent hash of a map, given its text and blob values as bytes and its
+// numbers LEB128-encoded
+fn map_hash(fields: &[(&[u8], Vec<u8>)]) -> Hash {
+    let mut fields: Vec<Vec<u8>> = fields
+        .iter()
+        .map(|(name, value)| [sha256(name), sha256(value)].concat())
+        .collect();
+    fields.sort();
+    sha256(&fields.concat())
+}
+
+// Path of an image response in the tree, from the top down to its leaf
+fn response_path(event_id: u64, image: &StoredImage) -> Vec<Vec<u8>> {
+    vec![
+        b"http_expr".to_vec(),
+        b"image".to_vec(),
+        event_id.to_string().into_bytes(),
+        b"<$>".to_vec(),
+        sha256(CERTIFICATE_EXPRESSION.as_bytes()).to_vec(),
+        Vec::new(),
+        image.response_hash.clone(),
+    ]
+}
+
+fn image_tree() -> HashTree {
+    let mut responses: Vec<Vec<Vec<u8>>> = IMAGES.with(|i| {
+        i.borrow()
+            .iter()
+            .map(|(event_id, image)| response_path(event_id, &image))
+            .collect()
+    });
+    // Labels are kept in order within each level
+    responses.sort();
+    let leaves: Vec<HashTree> = responses
+        .into_iter()
+        .map(|path| {
+            path[2..].iter().rev().fold(HashTree::Leaf(Vec::new()), |tree, label| {
+                HashTree::Labeled(label.clone(), Box::new(tree))
+            })
+        })
+        .collect();
+    HashTree::Labeled(
+        b"http_expr".to_vec(),
+        Box::new(HashTree::Labeled(b"image".to_vec(), Box::new(fork(&leaves)))),
+    )
+}
+
+fn fork(trees: &[HashTree]) -> HashTree {
+    match trees {
+        [] => HashTree::Empty,
+        [tree] => tree.clone(),
+        _ => {
+            let (left, right) = trees.split_at(trees.len() / 2);
+            HashTree::Fork(Box::new(fork(left)), Box::new(fork(right)))
+        }
+    }
+}
+
+// Value of the IC-Certificate header: the certificate with a witness of the image response.
+// None outside of query calls, where no certificate is available.
+fn certificate_header(event_id: u64, image: &StoredImage) -> Option<String> {
+    let certificate = data_certificate()?;
+    let witness = image_tree().reveal(&response_path(event_id, image));
+    let mut tree = CBOR_SELF_DESCRIBE_TAG.to_vec();
+    witness.write_cbor(&mut tree);
+
+    let mut expr_path = CBOR_SELF_DESCRIBE_TAG.to_vec();
+    let id = event_id.to_string();
+    let labels = ["http_expr", "image", id.as_str(), "<$>"];
+    cbor_head(&mut expr_path, 4, labels.len() as u64);
+    for label in labels {
+        cbor_head(&mut expr_path, 3, label.len() as u64);
+        expr_path.extend_from_slice(label.as_bytes());
+    }
+
+    Some(format!(
+        "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+        base64(&certificate),
+        base64(&tree),
+        base64(&expr_path)
+    ))
+}
+
+impl HashTree {
+    fn digest(&self) -> Hash {
+        match self {
+            HashTree::Empty => domain_hash("ic-hashtree-empty", &[]),
+            HashTree::Fork(left, right) => {
+                domain_hash("ic-hashtree-fork", &[&left.digest(), &right.digest()])
+            }
+            HashTree::Labeled(label, tree) => {
+                domain_hash("ic-hashtree-labeled", &[label, &tree.digest()])
+            }
+            HashTree::Leaf(value) => domain_hash("ic-hashtree-leaf", &[value]),
+            HashTree::Pruned(digest) => *digest,
+        }
+    }
+
+    // Prunes everything but the given path, keeping the digest unchanged
+    fn reveal(&self, path: &[Vec<u8>]) -> HashTree {
+        match self {
+            HashTree::Fork(left, right) => match (left.reveal(path), right.reveal(path)) {
+                (HashTree::Pruned(_), HashTree::Pruned(_)) => HashTree::Pruned(self.digest()),
+                (left, right) => HashTree::Fork(Box::new(left), Box::new(right)),
+            },
+            HashTree::Labeled(label, tree) => match path.split_first() {
+                Some((first, rest)) if first == label => {
+                    HashTree::Labeled(label.clone(), Box::new(tree.reveal(rest)))
+                }
+                _ => HashTree::Pruned(self.digest()),
+            },
+            HashTree::Leaf(_) if path.is_empty() => self.clone(),
+            HashTree::Empty => HashTree::Empty,
+            _ => HashTree::Pruned(self.digest()),
+        }
+    }
+
+    fn write_cbor(&self, out: &mut Vec<u8>) {
+        match self {
+            HashTree::Empty => {
+                cbor_head(out, 4, 1);
+                cbor_head(out, 0, 0);
+            }
+            HashTree::Fork(left, right) => {
+                cbor_head(out, 4, 3);
+                cbor_head(out, 0, 1);
+                left.write_cbor(out);
+                right.write_cbor(out);
+            }
+            HashTree::Labeled(label, tree) => {
+                cbor_head(out, 4, 3);
+                cbor_head(out, 0, 2);
+                cbor_bytes(out, label);
+                tree.write_cbor(out);
+            }
+            HashTree::Leaf(value) => {
+                cbor_head(out, 4, 2);
+                cbor_head(out, 0, 3);
+                cbor_bytes(out, value);
+            }
+            HashTree::Pruned(digest) => {
+                cbor_head(out, 4, 2);
+                cbor_head(out, 0, 4);
+                cbor_bytes(out, digest);
+            }
+        }
+    }
+}
+
+fn domain_hash(domain: &str, parts: &[&[u8]]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([domain.len() as u8]);
+    hasher.update(domain.as_bytes());
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn sha256(bytes: &[u8]) -> Hash {
+    Sha256::digest(bytes).into()
+}
+
+fn leb128(mut value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+// Writes the head of a CBOR data item of the given major type
+fn cbor_head(out: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    match value {
+        0..=23 => out.push(major | value as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, value as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+}
+
+fn cbor_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    cbor_head(out, 2, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let block = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let bits = u32::from_be_bytes([0, block[0], block[1], block[2]]);
+        for position in 0..4 {
+            if position <= chunk.len() {
+                let index = (bits >> (18 - 6 * position)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// Checked against the examples of the IC interface specification and the RFCs the encodings
+// come from
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn labeled(label: &str, tree: HashTree) -> HashTree {
+        HashTree::Labeled(label.as_bytes().to_vec(), Box::new(tree))
+    }
+
+    fn leaf(value: &str) -> HashTree {
+        HashTree::Leaf(value.as_bytes().to_vec())
+    }
+
+    fn fork(left: HashTree, right: HashTree) -> HashTree {
+        HashTree::Fork(Box::new(left), Box::new(right))
+    }
+
+    // The example tree of the specification's certification section
+    fn example_tree() -> HashTree {
+        fork(
+            fork(
+                labeled(
+                    "a",
+                    fork(
+                        fork(labeled("x", leaf("hello")), HashTree::Empty),
+                        labeled("y", leaf("world")),
+                    ),
+                ),
+                labeled("b", leaf("good")),
+            ),
+            fork(labeled("c", HashTree::Empty), labeled("d", leaf("morning"))),
+        )
+    }
+
+    #[test]
+    fn hash_tree_digest_matches_the_specification() {
+        assert_eq!(
+            hex(&example_tree().digest()),
+            "eb5c5b2195e62d996b84c9bcc8259d19a83786a2f59e0878cec84c811f669aa0"
+        );
+    }
+
+    #[test]
+    fn hash_tree_cbor_matches_the_specification() {
+        let mut cbor = Vec::new();
+        example_tree().write_cbor(&mut cbor);
+        assert_eq!(
+            hex(&cbor),
+            concat!(
+                "8301830183024161830183018302417882034568656c6c6f810083024179820345776f726c64",
+                "83024162820344676f6f648301830241638100830241648203476d6f726e696e67"
+            )
+        );
+    }
+
+    #[test]
+    fn witness_keeps_the_root_and_the_revealed_leaf() {
+        let tree = example_tree();
+        let path = [b"a".to_vec(), b"y".to_vec()];
+        let witness = tree.reveal(&path);
+        assert_eq!(witness.digest(), tree.digest());
+        let mut cbor = Vec::new();
+        witness.write_cbor(&mut cbor);
+        assert!(hex(&cbor).contains(&hex(b"world")));
+        assert!(!hex(&cbor).contains(&hex(b"hello")));
+    }
+
+    #[test]
+    fn map_hash_matches_the_specification_request_id() {
+        let fields: Vec<(&[u8], Vec<u8>)> = vec![
+            (b"request_type", b"call".to_vec()),
+            (b"sender", vec![0x04]),
+            (b"ingress_expiry", leb128(1_685_570_400_000_000_000)),
+            (b"canister_id", vec![0, 0, 0, 0, 0, 0, 0x04, 0xd2]),
+            (b"method_name", b"hello".to_vec()),
+            (b"arg", b"DIDL\x00\xfd*".to_vec()),
+        ];
+        assert_eq!(
+            hex(&map_hash(&fields)),
+            "1d1091364d6bb8a6c16b203ee75467d59ead468f523eb058880ae8ec80e2b101"
+        );
+    }
+
+    #[test]
+    fn leb128_encodes_unsigned_numbers() {
+        assert_eq!(leb128(0), vec![0x00]);
+        assert_eq!(leb128(127), vec![0x7f]);
+        assert_eq!(leb128(200), vec![0xc8, 0x01]);
+        assert_eq!(leb128(624_485), vec![0xe5, 0x8e, 0x26]);
+    }
+
+    #[test]
+    fn cbor_heads_match_rfc_8949() {
+        let encode = |major: u8, value: u64| {
+            let mut out = Vec::new();
+            cbor_head(&mut out, major, value);
+            hex(&out)
+        };
+        assert_eq!(encode(0, 23), "17");
+        assert_eq!(encode(0, 24), "1818");
+        assert_eq!(encode(0, 1000), "1903e8");
+        assert_eq!(encode(0, 1_000_000), "1a000f4240");
+        assert_eq!(encode(0, 1_000_000_000_000), "1b000000e8d4a51000");
+        assert_eq!(encode(4, 3), "83");
+        let mut bytes = Vec::new();
+        cbor_bytes(&mut bytes, &[1, 2, 3, 4]);
+        assert_eq!(hex(&bytes), "4401020304");
+    }
+
+    #[test]
+    fn base64_matches_rfc_4648() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (input, encoded) in vectors {
+            assert_eq!(base64(input.as_bytes()), encoded);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+// Attendance proofs. When an event's turnout is recorded, the principals that were checked in
+// are frozen into a Merkle tree whose root anyone can fetch, along with the inclusion proof
+// of any attendee, so airdrops and rewards programs can verify attendance off-chain.
+//
+// The tree is built with SHA-256 over the attendees in the order of their principal text:
+//   leaf = SHA-256(0x00 || event id as 8 big-endian bytes || principal bytes)
+//   node = SHA-256(0x01 || left || right)
+// A node without a sibling moves up a level unchanged. Hashes are hex encoded.
+use crate::{attendees, tickets, Error, Event, EventPrincipalKey, Memory, MEMORY_MANAGER};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+type Hash = [u8; 32];
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AttendanceRoot {
+    event_id: u64,
+    root: String,
+    attendees: u32,
+    computed_at: u64,
+}
+
+impl_storable!(AttendanceRoot, 192);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ProofStep {
+    sibling: String,
+    // Whether the sibling is hashed before the running hash
+    sibling_on_left: bool,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AttendanceProof {
+    event_id: u64,
+    principal: Principal,
+    leaf: String,
+    // Steps from the leaf up to the root
+    proof: Vec<ProofStep>,
+    root: String,
+}
+
+thread_local! {
+    static ATTENDANCE_ROOTS: RefCell<StableBTreeMap<u64, AttendanceRoot, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(162)))
+    ));
+
+    // Attendees the root was computed over, with when they were checked in
+    static PROVEN_ATTENDEES: RefCell<StableBTreeMap<EventPrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(163)))
+    ));
+}
+
+// Query function returning the attendance root of an ended event
+#[ic_cdk::query]
+fn get_attendance_root(event_id: u64) -> Result<AttendanceRoot, Error> {
+    _get_root(event_id)
+}
+
+// Query function returning the proof that a principal was checked in to an ended event
+#[ic_cdk::query]
+fn get_attendance_proof(event_id: u64, principal: Principal) -> Result<AttendanceProof, Error> {
+    let root = _get_root(event_id)?;
+    let attendees = attendees_of(event_id);
+    let Some(mut index) = attendees.iter().position(|a| *a == principal) else {
+        return Err(Error::NotFound {
+            msg: format!("{} wasn't checked in to the event with id={}", principal, event_id),
+        });
+    };
+    let mut level: Vec<Hash> = attendees.iter().map(|a| leaf(event_id, a)).collect();
+    let leaf_hash = level[index];
+    let mut proof = Vec::new();
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            proof.push(ProofStep {
+                sibling: hex(&level[sibling]),
+                sibling_on_left: sibling < index,
+            });
+        }
+        level = parent_level(&level);
+        index /= 2;
+    }
+    Ok(AttendanceProof {
+        event_id,
+        principal,
+        leaf: hex(&leaf_hash),
+        proof,
+        root: root.root,
+    })
+}
+
+// Computes and stores the attendance root of an ended event over the attendees that were
+// checked in; nothing is stored when nobody was
+pub(crate) fn record_root(event: &Event, now: u64) {
+    if ATTENDANCE_ROOTS.with(|r| r.borrow().contains_key(&event.id)) {
+        return;
+    }
+    let mut attendees: Vec<(Principal, u64)> = attendees::attendees_of(event.id)
+        .iter()
+        .filter_map(|attendee| {
+            let key = EventPrincipalKey {
+                event_id: event.id,
+                principal: attendee.clone(),
+            };
+            let checked_in_at = tickets::checked_in_at(&key)?;
+            Some((Principal::from_text(attendee).ok()?, checked_in_at))
+        })
+        .collect();
+    if attendees.is_empty() {
+        return;
+    }
+    attendees.sort_by_key(|(principal, _)| principal.to_text());
+    let mut level: Vec<Hash> = attendees
+        .iter()
+        .map(|(principal, _)| leaf(event.id, principal))
+        .collect();
+    while level.len() > 1 {
+        level = parent_level(&level);
+    }
+    PROVEN_ATTENDEES.with(|p| {
+        let mut proven = p.borrow_mut();
+        for (principal, checked_in_at) in &attendees {
+            let key = EventPrincipalKey {
+                event_id: event.id,
+                principal: principal.to_text(),
+            };
+            proven.insert(key, *checked_in_at);
+        }
+    });
+    let root = AttendanceRoot {
+        event_id: event.id,
+        root: hex(&level[0]),
+        attendees: attendees.len() as u32,
+        computed_at: now,
+    };
+    ATTENDANCE_ROOTS.with(|r| r.borrow_mut().insert(event.id, root));
+}
+
+fn _get_root(event_id: u64) -> Result<AttendanceRoot, Error> {
+    ATTENDANCE_ROOTS
+        .with(|r| r.borrow().get(&event_id))
+        .ok_or(Error::NotFound {
+            msg: format!("No attendance root has been recorded for the event with id={}", event_id),
+        })
+}
+
+// Attendees the root of an event was computed over, in tree order
+fn attendees_of(event_id: u64) -> Vec<Principal> {
+    let start = EventPrincipalKey {
+        event_id,
+        principal: String::new(),
+    };
+    PROVEN_ATTENDEES.with(|p| {
+        p.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .filter_map(|(key, _)| Principal::from_text(&key.principal).ok())
+            .collect()
+    })
+}
+
+fn leaf(event_id: u64, principal: &Principal) -> Hash {
+    Sha256::new()
+        .chain_update([LEAF_PREFIX])
+        .chain_update(event_id.to_be_bytes())
+        .chain_update(principal.as_slice())
+        .finalize()
+        .into()
+}
+
+fn parent_level(level: &[Hash]) -> Vec<Hash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => Sha256::new()
+                .chain_update([NODE_PREFIX])
+                .chain_update(left)
+                .chain_update(right)
+                .finalize()
+                .into(),
+            _ => pair[0],
+        })
+        .collect()
+}
+
+fn hex(hash: &Hash) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
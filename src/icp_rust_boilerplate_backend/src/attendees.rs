@@ -0,0 +1,387 @@
+// Event attendee lists, the records kept alongside them, and the CSV export organizers take
+// offline for door lists and logistics. Attendees are kept in their own map rather than in
+// the event record, so an event doesn't outgrow its storage bound however many people
+// register; lists stored in event records before that are moved over on upgrade. Attendees
+// are recorded by the canister holding the event, which answers `get_attendees` for it.
+//
+// Organizers can also create an export link that downloads the whole list over HTTP, e.g.
+// straight into a spreadsheet; the link carries a token, and creating a new one revokes it.
+use crate::http::{format_rfc3339, HttpResponse, PageCursor};
+use crate::{access, cache, metrics, profiles, resale, shard, status, tickets, tiers};
+use crate::{Error, Event, EventPrincipalKey, Memory, MEMORY_MANAGER, STORAGE};
+use crate::{_call_failed, _fetch_event, _get_event, _get_organized_event};
+use crate::clock::time;
+use ic_cdk::api::id;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+const EXPORT_ROWS_PER_CHUNK: u64 = 1000;
+const ATTENDEES_PER_PAGE: u64 = 1000;
+const EXPORT_HEADER: &str = "principal,name,registered_at,rsvp_status,tier,checked_in,checked_in_at";
+// Random bytes in an export token; hex encoded, so tokens are twice as long
+const EXPORT_TOKEN_BYTES: usize = 16;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AttendeeExport {
+    // Rows of the CSV; the first chunk starts with the header row
+    csv: String,
+    // Offset to pass for the next chunk, until the last one
+    next_offset: Option<u64>,
+    total_attendees: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ExportToken {
+    token: String,
+    created_at: u64,
+}
+
+impl_storable!(ExportToken, 128);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AttendeePage {
+    attendees: Vec<String>,
+    // Offset to pass for the next page, until the last one
+    next_offset: Option<u64>,
+    total_attendees: u64,
+}
+
+thread_local! {
+    // Value is when the principal became an attendee, or when its entry was moved out of the
+    // event record
+    static ATTENDEES: RefCell<StableBTreeMap<EventPrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(168)))
+    ));
+
+    static ATTENDEE_COUNTS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(169)))
+    ));
+
+    // When each attendee registered; attendees from before this was recorded have no entry
+    static REGISTRATIONS: RefCell<StableBTreeMap<EventPrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73)))
+    ));
+
+    // Event -> token of its attendee export link
+    static EXPORT_TOKENS: RefCell<StableBTreeMap<u64, ExportToken, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(176)))
+    ));
+}
+
+// Query function listing the attendees of an event the caller can view, in pages of up to
+// 1000 starting at `offset`, ordered by principal
+#[ic_cdk::query(composite = true)]
+async fn get_attendees(event_id: u64, offset: Option<u64>) -> Result<AttendeePage, Error> {
+    let event = _fetch_event(event_id).await?;
+    status::_check_visible(&event)?;
+    if !access::can_view(&event) {
+        return Err(Error::NotAuthorized {
+            msg: format!("You can't view the event with id={}", event_id),
+            caller: caller(),
+        });
+    }
+    if let Some(bucket) = shard::bucket_of(event_id) {
+        return shard::forward(bucket, "bucket_get_attendees", (event_id, offset)).await;
+    }
+    Ok(_get_attendees(event_id, offset))
+}
+
+pub(crate) fn _get_attendees(event_id: u64, offset: Option<u64>) -> AttendeePage {
+    let offset = offset.unwrap_or(0);
+    let total_attendees = count(event_id);
+    let next = offset + ATTENDEES_PER_PAGE;
+    AttendeePage {
+        attendees: page(event_id, offset, ATTENDEES_PER_PAGE),
+        next_offset: (next < total_attendees).then_some(next),
+        total_attendees,
+    }
+}
+
+// Query function for an event's organizer to export its attendee list as CSV, in chunks of
+// up to 1000 attendees starting at `offset`
+#[ic_cdk::query]
+fn export_attendees_csv(event_id: u64, offset: Option<u64>) -> Result<AttendeeExport, Error> {
+    _get_organized_event(event_id)?;
+    let offset = offset.unwrap_or(0);
+    let mut csv = String::new();
+    if offset == 0 {
+        csv.push_str(EXPORT_HEADER);
+        csv.push_str("\r\n");
+    }
+    csv.push_str(&csv_rows(event_id, &page(event_id, offset, EXPORT_ROWS_PER_CHUNK)));
+    let total_attendees = count(event_id);
+    let next = offset + EXPORT_ROWS_PER_CHUNK;
+    Ok(AttendeeExport {
+        csv,
+        next_offset: (next < total_attendees).then_some(next),
+        total_attendees,
+    })
+}
+
+// Update function for an event's organizer to create a link downloading its whole attendee
+// list as CSV over HTTP. The link replaces the event's previous one.
+#[ic_cdk::update]
+async fn create_attendee_export_link(event_id: u64) -> Result<String, Error> {
+    let _metrics = metrics::track("create_attendee_export_link");
+    _get_organized_event(event_id)?;
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|err| _call_failed("raw_rand", err))?;
+    let token: String = bytes
+        .iter()
+        .take(EXPORT_TOKEN_BYTES)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let export = ExportToken {
+        token: token.clone(),
+        created_at: time(),
+    };
+    EXPORT_TOKENS.with(|t| t.borrow_mut().insert(event_id, export));
+    Ok(format!(
+        "https://{}.raw.icp0.io/event/{}/attendees.csv?token={}",
+        id(),
+        event_id,
+        token
+    ))
+}
+
+// Update function for an event's organizer to revoke its attendee export link
+#[ic_cdk::update]
+fn revoke_attendee_export_link(event_id: u64) -> Result<bool, Error> {
+    let _metrics = metrics::track("revoke_attendee_export_link");
+    _get_organized_event(event_id)?;
+    Ok(EXPORT_TOKENS.with(|t| t.borrow_mut().remove(&event_id)).is_some())
+}
+
+// Renders the attendee list of an event stored here for the holder of its export link, a
+// chunk of rows at a time; a page picks up after the last principal sent
+pub(crate) fn attendees_csv(
+    event_id: &str,
+    token: Option<&str>,
+    cursor: Option<&PageCursor>,
+) -> HttpResponse {
+    let Some(event_id) = event_id.parse().ok().filter(|event_id| _get_event(event_id).is_some())
+    else {
+        return HttpResponse::error(404, "Not found");
+    };
+    let export = EXPORT_TOKENS.with(|t| t.borrow().get(&event_id));
+    if export.is_none_or(|export| token != Some(export.token.as_str())) {
+        return HttpResponse::error(404, "Not found");
+    }
+    let principals = page_after(event_id, cursor.map(|cursor| cursor.after.as_str()));
+    let mut csv = String::new();
+    if cursor.is_none() {
+        csv.push_str(EXPORT_HEADER);
+        csv.push_str("\r\n");
+    }
+    csv.push_str(&csv_rows(event_id, &principals));
+    let sent = cursor.map_or(0, |cursor| cursor.sent) + principals.len() as u64;
+    let next = principals
+        .last()
+        .filter(|_| principals.len() as u64 == EXPORT_ROWS_PER_CHUNK)
+        .map(|last| PageCursor {
+            after: last.clone(),
+            sent,
+        });
+    HttpResponse::new(
+        200,
+        vec![
+            ("Content-Type".to_string(), "text/csv; charset=utf-8".to_string()),
+            ("Cache-Control".to_string(), "private, no-store".to_string()),
+        ],
+        csv.into_bytes(),
+    )
+    .paged(next)
+}
+
+// CSV rows for the given attendees of an event
+fn csv_rows(event_id: u64, principals: &[String]) -> String {
+    let mut csv = String::new();
+    for principal in principals {
+        let key = EventPrincipalKey {
+            event_id,
+            principal: principal.clone(),
+        };
+        let checked_in_at = tickets::checked_in_at(&key);
+        let rsvp_status = if resale::is_listed(&key) {
+            "listed for resale"
+        } else {
+            "going"
+        };
+        let row = [
+            principal.clone(),
+            profiles::display_name(principal),
+            registered_at(&key).map(format_rfc3339).unwrap_or_default(),
+            rsvp_status.to_string(),
+            tiers::tier_name_of(&key).unwrap_or_default(),
+            checked_in_at.is_some().to_string(),
+            checked_in_at.map(format_rfc3339).unwrap_or_default(),
+        ];
+        let fields: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+// Records that the principal just registered for the event
+pub(crate) fn record_registration(event_id: u64, principal: &str) {
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    REGISTRATIONS.with(|r| r.borrow_mut().insert(key, time()));
+}
+
+fn registered_at(key: &EventPrincipalKey) -> Option<u64> {
+    REGISTRATIONS.with(|r| r.borrow().get(key))
+}
+
+// Whether the principal attends the event
+pub(crate) fn is_attending(event_id: u64, principal: &str) -> bool {
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    ATTENDEES.with(|a| a.borrow().contains_key(&key))
+}
+
+pub(crate) fn count(event_id: u64) -> u64 {
+    ATTENDEE_COUNTS
+        .with(|c| c.borrow().get(&event_id))
+        .unwrap_or_default()
+}
+
+// Every attendee of the event, ordered by principal
+pub(crate) fn attendees_of(event_id: u64) -> Vec<String> {
+    page(event_id, 0, u64::MAX)
+}
+
+// Up to a chunk of the event's attendees following the given principal, or its first ones
+fn page_after(event_id: u64, after: Option<&str>) -> Vec<String> {
+    let start = EventPrincipalKey {
+        event_id,
+        principal: after.unwrap_or_default().to_string(),
+    };
+    let start = match after {
+        Some(_) => Bound::Excluded(start),
+        None => Bound::Included(start),
+    };
+    ATTENDEES.with(|a| {
+        a.borrow()
+            .range((start, Bound::Unbounded))
+            .take_while(|(key, _)| key.event_id == event_id)
+            .take(EXPORT_ROWS_PER_CHUNK as usize)
+            .map(|(key, _)| key.principal)
+            .collect()
+    })
+}
+
+fn page(event_id: u64, offset: u64, limit: u64) -> Vec<String> {
+    let start = EventPrincipalKey {
+        event_id,
+        principal: String::new(),
+    };
+    ATTENDEES.with(|a| {
+        a.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|(key, _)| key.principal)
+            .collect()
+    })
+}
+
+// Adds the principal to the event's attendees; returns false if it already attended
+pub(crate) fn add(event_id: u64, principal: &str) -> bool {
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    if ATTENDEES.with(|a| a.borrow_mut().insert(key, time())).is_some() {
+        return false;
+    }
+    ATTENDEE_COUNTS.with(|c| {
+        let mut counts = c.borrow_mut();
+        let count = counts.get(&event_id).unwrap_or_default();
+        counts.insert(event_id, count + 1);
+    });
+    true
+}
+
+// Removes the principal from the event's attendees; returns false if it didn't attend
+pub(crate) fn remove(event_id: u64, principal: &str) -> bool {
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    if ATTENDEES.with(|a| a.borrow_mut().remove(&key)).is_none() {
+        return false;
+    }
+    ATTENDEE_COUNTS.with(|c| {
+        let mut counts = c.borrow_mut();
+        match counts.get(&event_id).unwrap_or_default() {
+            0 | 1 => counts.remove(&event_id),
+            count => counts.insert(event_id, count - 1),
+        };
+    });
+    true
+}
+
+// Drops the attendees of a removed event
+pub(crate) fn forget(event_id: u64) {
+    for principal in attendees_of(event_id) {
+        remove(event_id, &principal);
+    }
+}
+
+// The event with its number of attendees. Events fetched from a bucket already have it,
+// counted there.
+pub(crate) fn with_count(mut event: Event) -> Event {
+    if event.attendee_count.is_none() {
+        event.attendee_count = Some(count(event.id));
+    }
+    event
+}
+
+// Moves the attendee lists still stored in event records to the attendee map; called from
+// post_upgrade
+pub(crate) fn migrate() {
+    let events: Vec<Event> = STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|event| !event.attendees.is_empty())
+            .collect()
+    });
+    for event in events {
+        for principal in &event.attendees {
+            add(event.id, principal);
+        }
+        cache::store(&event);
+    }
+}
+
+// Quotes a field when needed. Fields starting like a formula are prefixed with a quote so
+// spreadsheets show display names as text rather than evaluating them.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
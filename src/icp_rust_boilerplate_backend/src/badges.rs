@@ -0,0 +1,46 @@
+// Badge data for printing. The organizer or door staff export one badge per attendee with
+// the name to print, the attendee's ticket tier and a check-in code: the attendee's ticket
+// token, which can be printed as a QR code and scanned with `check_in`.
+use crate::tickets::{self, _get_door_event, _get_ticket};
+use crate::{attendees, metrics, profiles, tiers};
+use crate::{Error, EventPrincipalKey};
+use candid::Principal;
+use ic_cdk::caller;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct Badge {
+    attendee: String,
+    display_name: String,
+    // Name of the ticket tier, for events that sell tickets
+    tier: Option<String>,
+    check_in_code: String,
+    checked_in: bool,
+}
+
+// Update function exporting badge data for every attendee of an event. Attendees who have
+// not fetched their ticket yet get one issued.
+#[ic_cdk::update]
+async fn export_badges(event_id: u64) -> Result<Vec<Badge>, Error> {
+    let _metrics = metrics::track("export_badges");
+    _get_door_event(caller(), event_id)?;
+    let attendees = attendees::attendees_of(event_id);
+    let mut badges = Vec::with_capacity(attendees.len());
+    for attendee in attendees {
+        let Ok(principal) = Principal::from_text(&attendee) else {
+            continue;
+        };
+        let ticket = _get_ticket(principal, event_id).await?;
+        let key = EventPrincipalKey {
+            event_id,
+            principal: attendee.clone(),
+        };
+        badges.push(Badge {
+            display_name: profiles::display_name(&attendee),
+            tier: tiers::tier_name_of(&key),
+            check_in_code: ticket.token,
+            checked_in: tickets::is_checked_in(&key),
+            attendee,
+        });
+    }
+    Ok(badges)
+}
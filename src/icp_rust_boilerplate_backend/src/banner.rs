@@ -0,0 +1,100 @@
+// Site-wide announcement banner. Admins set a message with a severity and an optional
+// expiry; frontends poll `get_system_banner` to show maintenance notices and the like
+// without a deployment of their own. Expired banners are simply no longer returned.
+use crate::admin::_check_admin;
+use crate::{metrics, Error, Memory, MEMORY_MANAGER};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Cell as StableCell;
+use std::cell::RefCell;
+
+const MAX_MESSAGE_BYTES: usize = 500;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum BannerSeverity {
+    Info,
+    Warning,
+    Critical,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SystemBanner {
+    message: String,
+    severity: BannerSeverity,
+    // None for a banner that stays until it is cleared
+    expires_at: Option<u64>,
+    set_at: u64,
+    set_by: Principal,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SystemBannerPayload {
+    message: String,
+    severity: BannerSeverity,
+    expires_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct BannerConfig {
+    banner: Option<SystemBanner>,
+}
+
+impl_storable!(BannerConfig, 1024);
+
+thread_local! {
+    static BANNER_CONFIG: RefCell<StableCell<BannerConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(122))), BannerConfig::default())
+            .expect("Cannot create the banner config cell")
+    );
+}
+
+// Query function returning the site-wide banner, unless there is none or it has expired
+#[ic_cdk::query]
+fn get_system_banner() -> Option<SystemBanner> {
+    BANNER_CONFIG
+        .with(|c| c.borrow().get().banner.clone())
+        .filter(|banner| banner.expires_at.is_none_or(|expires_at| expires_at > time()))
+}
+
+// Update function for admins to set the site-wide banner, or to clear it with None
+#[ic_cdk::update]
+fn set_system_banner(payload: Option<SystemBannerPayload>) -> Result<Option<SystemBanner>, Error> {
+    let _metrics = metrics::track("set_system_banner");
+    _check_admin()?;
+    let banner = match payload {
+        Some(payload) => {
+            let message = payload.message.trim().to_string();
+            if message.is_empty() || message.len() > MAX_MESSAGE_BYTES {
+                return Err(Error::InvalidPayload {
+                    msg: format!(
+                        "a banner message must be between 1 and {} bytes",
+                        MAX_MESSAGE_BYTES
+                    ),
+                });
+            }
+            if payload.expires_at.is_some_and(|expires_at| expires_at <= time()) {
+                return Err(Error::InvalidPayload {
+                    msg: "a banner's expiry must be in the future".to_string(),
+                });
+            }
+            Some(SystemBanner {
+                message,
+                severity: payload.severity,
+                expires_at: payload.expires_at,
+                set_at: time(),
+                set_by: caller(),
+            })
+        }
+        None => None,
+    };
+    BANNER_CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(BannerConfig {
+                banner: banner.clone(),
+            })
+            .expect("cannot update the banner config");
+    });
+    Ok(banner)
+}
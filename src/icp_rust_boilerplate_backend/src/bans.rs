@@ -0,0 +1,161 @@
+// Ban list. Admins, who act as moderators, can ban a principal with a reason; every update
+// call from a banned principal is refused, by `inspect_message` for ingress and again when
+// the call executes. A ban can also hide the principal's public content in one go: the
+// events they organize stop being listed or shown, and their questions and reviews are left
+// out of listings. Nothing is deleted, so unbanning brings all of it back.
+use crate::admin::_check_admin;
+use crate::clock::time;
+use crate::{cache, metrics, Error, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_REASON_BYTES: usize = 500;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Ban {
+    principal: Principal,
+    reason: String,
+    banned_at: u64,
+    banned_by: Principal,
+    // Whether the principal's events, questions and reviews are hidden
+    content_hidden: bool,
+    // Events hidden along with the ban
+    hidden_events: u32,
+}
+
+impl_storable!(Ban, 768);
+
+thread_local! {
+    static BANS: RefCell<StableBTreeMap<PrincipalKey, Ban, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(131)))
+    ));
+
+    // Events hidden by a ban, with their organizer
+    static HIDDEN_EVENTS: RefCell<StableBTreeMap<u64, PrincipalKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(132)))
+    ));
+}
+
+// Query function for admins listing banned principals, most recently banned first
+#[ic_cdk::query]
+fn get_bans() -> Result<Vec<Ban>, Error> {
+    _check_admin()?;
+    let mut bans: Vec<Ban> = BANS.with(|b| b.borrow().iter().map(|(_, ban)| ban).collect());
+    bans.sort_by(|a, b| b.banned_at.cmp(&a.banned_at));
+    Ok(bans)
+}
+
+// Update function for admins to ban a principal, optionally hiding their public content.
+// Banning an already banned principal updates the reason and whether content is hidden.
+#[ic_cdk::update]
+fn ban_principal(
+    principal: Principal,
+    reason: String,
+    hide_content: Option<bool>,
+) -> Result<Ban, Error> {
+    let _metrics = metrics::track("ban_principal");
+    _check_admin()?;
+    let reason = reason.trim().to_string();
+    if reason.is_empty() || reason.len() > MAX_REASON_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("a ban reason must be between 1 and {} bytes", MAX_REASON_BYTES),
+        });
+    }
+    if principal == caller() || principal == Principal::anonymous() {
+        return Err(Error::InvalidPayload {
+            msg: "this principal can't be banned".to_string(),
+        });
+    }
+    let content_hidden = hide_content.unwrap_or(false);
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    unhide_events(&key);
+    let hidden_events = if content_hidden { hide_events(&key) } else { 0 };
+    let ban = Ban {
+        principal,
+        reason,
+        banned_at: time(),
+        banned_by: caller(),
+        content_hidden,
+        hidden_events,
+    };
+    BANS.with(|b| b.borrow_mut().insert(key, ban.clone()));
+    Ok(ban)
+}
+
+// Update function for admins to lift a ban and show the principal's content again
+#[ic_cdk::update]
+fn unban_principal(principal: Principal) -> Result<Ban, Error> {
+    let _metrics = metrics::track("unban_principal");
+    _check_admin()?;
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    let ban = BANS.with(|b| b.borrow_mut().remove(&key)).ok_or(Error::NotFound {
+        msg: format!("{} is not banned", principal),
+    })?;
+    unhide_events(&key);
+    Ok(ban)
+}
+
+// Whether the principal is banned
+pub(crate) fn is_banned(principal: &Principal) -> bool {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    BANS.with(|b| b.borrow().contains_key(&key))
+}
+
+// Refuses the current update call when the caller is banned, trapping so nothing is written
+pub(crate) fn _check_not_banned() {
+    if is_banned(&caller()) {
+        ic_cdk::trap("You are banned from this canister");
+    }
+}
+
+// Whether the content of the principal, given as text, is hidden by a ban
+pub(crate) fn is_content_hidden(principal: &str) -> bool {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    BANS.with(|b| b.borrow().get(&key)).is_some_and(|ban| ban.content_hidden)
+}
+
+// Whether the event is hidden by its organizer's ban
+pub(crate) fn is_event_hidden(id: u64) -> bool {
+    HIDDEN_EVENTS.with(|h| h.borrow().contains_key(&id))
+}
+
+// Hides the events the principal organizes; returns how many were hidden
+fn hide_events(key: &PrincipalKey) -> u32 {
+    let ids = cache::events_owned_by(&key.principal);
+    HIDDEN_EVENTS.with(|h| {
+        let mut hidden = h.borrow_mut();
+        for id in &ids {
+            hidden.insert(*id, key.clone());
+        }
+    });
+    ids.len() as u32
+}
+
+fn unhide_events(key: &PrincipalKey) {
+    let ids: Vec<u64> = HIDDEN_EVENTS.with(|h| {
+        h.borrow()
+            .iter()
+            .filter(|(_, owner)| owner == key)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    HIDDEN_EVENTS.with(|h| {
+        let mut hidden = h.borrow_mut();
+        for id in ids {
+            hidden.remove(&id);
+        }
+    });
+}
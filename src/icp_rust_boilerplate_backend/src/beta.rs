@@ -0,0 +1,166 @@
+// Closed beta. While admins have the beta mode on, only allowlisted principals can create
+// events (directly, from a template or by import) and register for them (RSVP or ticket
+// purchase), so a deployment can run an invite-only pilot and later open up by switching the
+// mode off. Everything else, such as browsing events, stays open. Admins are always let
+// through, and the allowlist is kept when the mode is switched off.
+use crate::admin::_check_admin;
+use crate::clock::time;
+use crate::{metrics, Error, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+
+const MAX_PRINCIPALS_PER_CALL: usize = 500;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct BetaConfig {
+    enabled: bool,
+}
+
+impl_storable!(BetaConfig, 16);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AllowlistEntry {
+    principal: Principal,
+    added_at: u64,
+    added_by: Principal,
+}
+
+impl_storable!(AllowlistEntry, 192);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct BetaMode {
+    enabled: bool,
+    allowlisted: u64,
+}
+
+thread_local! {
+    static BETA_CONFIG: RefCell<Cell<BetaConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(133))), BetaConfig::default())
+            .expect("Cannot create the beta config cell")
+    );
+
+    static ALLOWLIST: RefCell<StableBTreeMap<PrincipalKey, AllowlistEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(134)))
+    ));
+}
+
+// Query function returning whether the closed beta is on and how many principals are in it
+#[ic_cdk::query]
+fn get_beta_mode() -> BetaMode {
+    BetaMode {
+        enabled: BETA_CONFIG.with(|c| c.borrow().get().enabled),
+        allowlisted: ALLOWLIST.with(|a| a.borrow().len()),
+    }
+}
+
+// Query function telling whether the caller can create events and register while the
+// closed beta is on
+#[ic_cdk::query]
+fn is_beta_allowlisted() -> bool {
+    is_allowlisted(&caller()) || _check_admin().is_ok()
+}
+
+// Query function for admins listing the allowlisted principals
+#[ic_cdk::query]
+fn get_beta_allowlist() -> Result<Vec<AllowlistEntry>, Error> {
+    _check_admin()?;
+    Ok(ALLOWLIST.with(|a| a.borrow().iter().map(|(_, entry)| entry).collect()))
+}
+
+// Update function for admins to switch the closed beta on or off
+#[ic_cdk::update]
+fn set_beta_mode(enabled: bool) -> Result<BetaMode, Error> {
+    let _metrics = metrics::track("set_beta_mode");
+    _check_admin()?;
+    BETA_CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(BetaConfig { enabled })
+            .expect("cannot update the beta config")
+    });
+    Ok(get_beta_mode())
+}
+
+// Update function for admins to allowlist principals; returns how many were newly added
+#[ic_cdk::update]
+fn add_to_beta_allowlist(principals: Vec<Principal>) -> Result<u64, Error> {
+    let _metrics = metrics::track("add_to_beta_allowlist");
+    _check_admin()?;
+    _check_batch(&principals)?;
+    let mut added = 0;
+    ALLOWLIST.with(|a| {
+        let mut allowlist = a.borrow_mut();
+        for principal in principals {
+            let key = PrincipalKey {
+                principal: principal.to_string(),
+            };
+            if allowlist.contains_key(&key) {
+                continue;
+            }
+            let entry = AllowlistEntry {
+                principal,
+                added_at: time(),
+                added_by: caller(),
+            };
+            allowlist.insert(key, entry);
+            added += 1;
+        }
+    });
+    Ok(added)
+}
+
+// Update function for admins to take principals off the allowlist; returns how many were
+// removed
+#[ic_cdk::update]
+fn remove_from_beta_allowlist(principals: Vec<Principal>) -> Result<u64, Error> {
+    let _metrics = metrics::track("remove_from_beta_allowlist");
+    _check_admin()?;
+    _check_batch(&principals)?;
+    let removed = ALLOWLIST.with(|a| {
+        let mut allowlist = a.borrow_mut();
+        principals
+            .iter()
+            .filter(|principal| {
+                let key = PrincipalKey {
+                    principal: principal.to_string(),
+                };
+                allowlist.remove(&key).is_some()
+            })
+            .count()
+    });
+    Ok(removed as u64)
+}
+
+// Helper function refusing event creation and registration to principals left out of the
+// closed beta
+pub(crate) fn _check_allowlisted() -> Result<(), Error> {
+    if !BETA_CONFIG.with(|c| c.borrow().get().enabled) {
+        return Ok(());
+    }
+    if is_allowlisted(&caller()) || _check_admin().is_ok() {
+        return Ok(());
+    }
+    Err(Error::NotAuthorized {
+        msg: "This deployment is in a closed beta; ask an admin for an invite".to_string(),
+        caller: caller(),
+    })
+}
+
+fn is_allowlisted(principal: &Principal) -> bool {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    ALLOWLIST.with(|a| a.borrow().contains_key(&key))
+}
+
+fn _check_batch(principals: &[Principal]) -> Result<(), Error> {
+    if principals.is_empty() || principals.len() > MAX_PRINCIPALS_PER_CALL {
+        return Err(Error::InvalidPayload {
+            msg: format!("pass between 1 and {} principals", MAX_PRINCIPALS_PER_CALL),
+        });
+    }
+    Ok(())
+}
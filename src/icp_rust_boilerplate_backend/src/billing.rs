@@ -0,0 +1,346 @@
+// Paid organizer plans. Admins define plans with a monthly price on a ledger; an organizer
+// subscribes by approving this canister on that ledger (ICRC-2) for as many months as they
+// like, and a billing timer pulls each month's price with `icrc2_transfer_from`. A renewal
+// that fails is retried daily through a grace period, during which the plan stays in force;
+// once it runs out the organizer is downgraded to the free plan and told so.
+use crate::admin::_check_admin;
+use crate::ledger::{self, Account};
+use crate::notifications::{self, NotificationKind};
+use crate::{metrics, Error, Memory, PrincipalKey, MEMORY_MANAGER};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+const BILLING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const BILLING_BATCH_SIZE: usize = 20;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const BILLING_PERIOD_NANOS: u64 = 30 * NANOS_PER_DAY;
+const GRACE_PERIOD_NANOS: u64 = 7 * NANOS_PER_DAY;
+const RETRY_INTERVAL_NANOS: u64 = NANOS_PER_DAY;
+const MAX_PLAN_NAME_CHARS: usize = 32;
+const MAX_PLAN_DESCRIPTION_CHARS: usize = 300;
+const MAX_ERROR_CHARS: usize = 200;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Plan {
+    name: String,
+    description: String,
+    // Charged every 30 days, in the ledger's smallest unit
+    monthly_price: u128,
+    ledger: Principal,
+    // Plans no longer offered keep billing their current subscribers
+    available: bool,
+    updated_at: u64,
+}
+
+impl_storable!(Plan, 1024);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PlanPayload {
+    name: String,
+    description: String,
+    monthly_price: u128,
+    ledger: Principal,
+    available: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum SubscriptionStatus {
+    Active,
+    // The last renewal failed; the plan stays in force until the grace period ends
+    PastDue { since: u64, last_error: String },
+    // Cancelled by the organizer; the plan runs until the end of the paid period
+    Cancelled,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Subscription {
+    plan: String,
+    status: SubscriptionStatus,
+    started_at: u64,
+    // End of the period paid for, when the next renewal is due
+    paid_until: u64,
+    next_attempt_at: u64,
+    // Ledger block of the last successful charge
+    last_block_index: Option<u64>,
+}
+
+impl_storable!(Subscription, 1024);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct PlanKey {
+    name: String,
+}
+
+impl_storable!(PlanKey, 64);
+
+thread_local! {
+    static PLANS: RefCell<StableBTreeMap<PlanKey, Plan, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(91)))
+    ));
+
+    static SUBSCRIPTIONS: RefCell<StableBTreeMap<PrincipalKey, Subscription, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(92)))
+    ));
+
+    // Guards against two timer runs charging the same subscriptions concurrently
+    static BILLING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Starts the periodic billing run; called from init and post_upgrade
+pub(crate) fn start_billing() {
+    ic_cdk_timers::set_timer_interval(BILLING_INTERVAL, || ic_cdk::spawn(bill_due_subscriptions()));
+}
+
+// Query function listing the plans organizers can subscribe to
+#[ic_cdk::query]
+fn get_plans() -> Vec<Plan> {
+    PLANS.with(|p| {
+        p.borrow()
+            .iter()
+            .map(|(_, plan)| plan)
+            .filter(|plan| plan.available)
+            .collect()
+    })
+}
+
+// Update function for admins to create or change a plan. A new price applies from each
+// subscriber's next renewal.
+#[ic_cdk::update]
+fn set_plan(payload: PlanPayload) -> Result<Plan, Error> {
+    let _metrics = metrics::track("set_plan");
+    _check_admin()?;
+    let name = payload.name.trim().to_lowercase();
+    if name.is_empty()
+        || name.chars().count() > MAX_PLAN_NAME_CHARS
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "a plan name must be 1 to {} letters, digits or hyphens",
+                MAX_PLAN_NAME_CHARS
+            ),
+        });
+    }
+    if payload.monthly_price == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "a paid plan needs a monthly price".to_string(),
+        });
+    }
+    let plan = Plan {
+        name: name.clone(),
+        description: payload
+            .description
+            .trim()
+            .chars()
+            .take(MAX_PLAN_DESCRIPTION_CHARS)
+            .collect(),
+        monthly_price: payload.monthly_price,
+        ledger: payload.ledger,
+        available: payload.available,
+        updated_at: time(),
+    };
+    PLANS.with(|p| p.borrow_mut().insert(PlanKey { name }, plan.clone()));
+    Ok(plan)
+}
+
+// Query function returning the caller's subscription, if they have one
+#[ic_cdk::query]
+fn my_subscription() -> Option<Subscription> {
+    subscription_of(&caller().to_string())
+}
+
+// Update function for an organizer to subscribe to a plan, or switch to another one. The
+// first month is charged right away from the caller's ICRC-2 allowance on the plan's ledger;
+// later months are charged by the billing timer while the allowance lasts.
+#[ic_cdk::update]
+async fn subscribe(plan: String) -> Result<Subscription, Error> {
+    let _metrics = metrics::track("subscribe");
+    let organizer = caller();
+    if organizer == Principal::anonymous() {
+        return Err(Error::NotAuthorized {
+            msg: "Sign in to subscribe to a plan".to_string(),
+            caller: organizer,
+        });
+    }
+    let plan = _get_plan(&plan)?;
+    if !plan.available {
+        return Err(Error::InvalidPayload {
+            msg: format!("the {} plan is no longer offered", plan.name),
+        });
+    }
+    let key = PrincipalKey {
+        principal: organizer.to_string(),
+    };
+    // Switching plans, or taking back a cancellation, keeps what is already paid for; the
+    // plan's price applies from the next renewal
+    if let Some(mut subscription) = subscription_of(&key.principal) {
+        subscription.plan = plan.name;
+        if subscription.status == SubscriptionStatus::Cancelled {
+            subscription.status = SubscriptionStatus::Active;
+        }
+        do_insert_subscription(&key, &subscription);
+        return Ok(subscription);
+    }
+    let block_index = charge(organizer, &plan)
+        .await
+        .map_err(|msg| Error::PaymentFailed { msg })?;
+    let now = time();
+    let subscription = Subscription {
+        plan: plan.name,
+        status: SubscriptionStatus::Active,
+        started_at: now,
+        paid_until: now + BILLING_PERIOD_NANOS,
+        next_attempt_at: now + BILLING_PERIOD_NANOS,
+        last_block_index: Some(block_index),
+    };
+    do_insert_subscription(&key, &subscription);
+    Ok(subscription)
+}
+
+// Update function for an organizer to stop renewing their plan; it stays in force until the
+// end of the period already paid for
+#[ic_cdk::update]
+fn cancel_subscription() -> Result<Subscription, Error> {
+    let _metrics = metrics::track("cancel_subscription");
+    let key = PrincipalKey {
+        principal: caller().to_string(),
+    };
+    let mut subscription = subscription_of(&key.principal).ok_or(Error::NotFound {
+        msg: "You don't have a subscription".to_string(),
+    })?;
+    subscription.status = SubscriptionStatus::Cancelled;
+    do_insert_subscription(&key, &subscription);
+    Ok(subscription)
+}
+
+// The paid plan a principal is on, if any. Subscriptions are removed once they end, so one
+// that is past due or cancelled still counts until then.
+pub(crate) fn plan_of(principal: &str) -> Option<String> {
+    subscription_of(principal).map(|subscription| subscription.plan)
+}
+
+async fn bill_due_subscriptions() {
+    if BILLING.with(|b| b.replace(true)) {
+        return;
+    }
+    let now = time();
+    let due: Vec<(PrincipalKey, Subscription)> = SUBSCRIPTIONS.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, subscription)| subscription.next_attempt_at <= now)
+            .take(BILLING_BATCH_SIZE)
+            .collect()
+    });
+    for (key, subscription) in due {
+        renew(key, subscription).await;
+    }
+    BILLING.with(|b| b.set(false));
+}
+
+// Charges the next period of a subscription, or ends it when it was cancelled, its plan is
+// gone, or the grace period ran out
+async fn renew(key: PrincipalKey, subscription: Subscription) {
+    let Ok(organizer) = Principal::from_text(&key.principal) else {
+        return;
+    };
+    let plan = _get_plan(&subscription.plan).ok();
+    let (Some(plan), false) = (plan, subscription.status == SubscriptionStatus::Cancelled) else {
+        end(&key, &subscription, "Your subscription has ended; you're back on the free plan");
+        return;
+    };
+    let result = charge(organizer, &plan).await;
+
+    // The organizer may have switched plans while the charge was in flight
+    let Some(mut subscription) = subscription_of(&key.principal) else {
+        return;
+    };
+    let now = time();
+    match result {
+        Ok(block_index) => {
+            subscription.paid_until =
+                subscription.paid_until.max(now).saturating_add(BILLING_PERIOD_NANOS);
+            subscription.next_attempt_at = subscription.paid_until;
+            subscription.last_block_index = Some(block_index);
+            subscription.status = SubscriptionStatus::Active;
+        }
+        Err(error) => {
+            let last_error: String = error.chars().take(MAX_ERROR_CHARS).collect();
+            let (since, first_failure) = match subscription.status {
+                SubscriptionStatus::PastDue { since, .. } => (since, false),
+                _ => (now, true),
+            };
+            if now.saturating_sub(since) >= GRACE_PERIOD_NANOS {
+                end(
+                    &key,
+                    &subscription,
+                    "We couldn't renew your subscription, so you're back on the free plan",
+                );
+                return;
+            }
+            if first_failure {
+                notifications::notify(
+                    &key.principal,
+                    NotificationKind::Billing,
+                    None,
+                    format!(
+                        "We couldn't renew your {} plan: {}. Renew your allowance within 7 days \
+                         to keep it.",
+                        plan.name, last_error
+                    ),
+                );
+            }
+            subscription.status = SubscriptionStatus::PastDue { since, last_error };
+            subscription.next_attempt_at = now.saturating_add(RETRY_INTERVAL_NANOS);
+        }
+    }
+    do_insert_subscription(&key, &subscription);
+}
+
+fn end(key: &PrincipalKey, subscription: &Subscription, message: &str) {
+    SUBSCRIPTIONS.with(|s| s.borrow_mut().remove(key));
+    notifications::notify(
+        &key.principal,
+        NotificationKind::Billing,
+        None,
+        format!("{} (was: {}).", message, subscription.plan),
+    );
+}
+
+async fn charge(organizer: Principal, plan: &Plan) -> Result<u64, String> {
+    let from = Account {
+        owner: organizer,
+        subaccount: None,
+    };
+    // Ledgers commonly cap memos at 32 bytes; plan names are ASCII
+    let mut memo = format!("plan:{}", plan.name).into_bytes();
+    memo.truncate(32);
+    ledger::transfer_from(plan.ledger, from, plan.monthly_price, memo).await
+}
+
+fn _get_plan(name: &str) -> Result<Plan, Error> {
+    let name = name.trim().to_lowercase();
+    let plan = (name.chars().count() <= MAX_PLAN_NAME_CHARS)
+        .then(|| PLANS.with(|p| p.borrow().get(&PlanKey { name: name.clone() })))
+        .flatten();
+    plan.ok_or(Error::NotFound {
+        msg: format!("Plan \"{}\" not found", name),
+    })
+}
+
+fn subscription_of(principal: &str) -> Option<Subscription> {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    SUBSCRIPTIONS.with(|s| s.borrow().get(&key))
+}
+
+fn do_insert_subscription(key: &PrincipalKey, subscription: &Subscription) {
+    SUBSCRIPTIONS.with(|s| s.borrow_mut().insert(key.clone(), subscription.clone()));
+}
@@ -0,0 +1,15 @@
+// Instruction budget of long scans. A query is cut off once it runs past the subnet's
+// instruction limit, which large enough data sets would eventually reach. Scans over data that
+// keeps growing check `exhausted` as they go and stop well short of the limit, handing the
+// client a continuation token to resume from in the next call.
+use ic_cdk::api::performance_counter;
+
+// Instructions used by the current message
+const MESSAGE_COUNTER: u32 = 0;
+// A fifth of the query instruction limit
+const SCAN_BUDGET: u64 = 1_000_000_000;
+
+// Whether the current message has used up its scan budget
+pub(crate) fn exhausted() -> bool {
+    performance_counter(MESSAGE_COUNTER) >= SCAN_BUDGET
+}
@@ -0,0 +1,374 @@
+// Heap caches in front of stable memory: the most recently written events, already decoded,
+// and a word index of event titles, locations and tags behind `search_events`. Stable memory
+// stays the source of truth; every write to the event store goes through here so the caches
+// never disagree with it. Both are saved to a stable cell in `pre_upgrade` and loaded back
+// in `post_upgrade`, so the first queries after a deployment don't pay to rebuild them.
+// Searches stop at the scan budget and return a continuation token to pick up from.
+// An index of events by owner is kept in stable memory alongside, so an organizer's events
+// can be listed without scanning the store.
+// Events spilled to bucket canisters are cached and indexed by their bucket.
+use crate::logs::{self, LogLevel};
+use crate::{access, attendees, budget, status};
+use crate::{Event, Memory, MEMORY_MANAGER, STORAGE};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell as StableCell, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+
+const MAX_CACHED_EVENTS: usize = 500;
+const MIN_WORD_CHARS: usize = 2;
+const MAX_QUERY_WORDS: usize = 8;
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 50;
+// Larger indexes are rebuilt on upgrade rather than saved, to keep pre_upgrade well within
+// its instruction limit
+const MAX_SAVED_POSTINGS: usize = 1_000_000;
+
+#[derive(Default)]
+struct EventCache {
+    // Event and the write sequence number it was cached at; the oldest is evicted first
+    events: HashMap<u64, (Event, u64)>,
+    next_seq: u64,
+}
+
+#[derive(Default)]
+struct SearchIndex {
+    postings: HashMap<String, BTreeSet<u64>>,
+    // Words each event is indexed under, to unindex it on update or removal
+    words: HashMap<u64, Vec<String>>,
+}
+
+// What the caches are saved as across an upgrade
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct CacheSnapshot {
+    // Oldest first
+    events: Vec<Event>,
+    // None when the index was too large to save
+    index: Option<Vec<(u64, Vec<String>)>>,
+}
+
+impl Storable for CacheSnapshot {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::Decode!(bytes.as_ref(), Self).unwrap_or_default()
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct OwnerEventKey {
+    owner: String,
+    event_id: u64,
+}
+
+impl_storable!(OwnerEventKey, 128);
+
+// A page of search results
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SearchPage {
+    events: Vec<Event>,
+    // Pass back to `search_events_page` for more results; None once the search is done
+    continuation: Option<u64>,
+}
+
+thread_local! {
+    static EVENT_CACHE: RefCell<EventCache> = RefCell::new(EventCache::default());
+
+    static SEARCH_INDEX: RefCell<SearchIndex> = RefCell::new(SearchIndex::default());
+
+    static SNAPSHOT: RefCell<StableCell<CacheSnapshot, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(125))), CacheSnapshot::default())
+            .expect("Cannot create the cache snapshot cell")
+    );
+
+    // Stored events by owner, with when they were created
+    static OWNED_EVENTS: RefCell<StableBTreeMap<OwnerEventKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(167)))
+    ));
+}
+
+// Query function searching published, listed events by words of their title, location and
+// tags; events matching every word are returned, newest first. Returns the first page only.
+#[ic_cdk::query]
+pub(crate) fn search_events(query: String, limit: Option<u32>) -> Vec<Event> {
+    search(&query, limit, None).events
+}
+
+// Query function searching like `search_events`, resuming from the continuation of the
+// previous page if given
+#[ic_cdk::query]
+fn search_events_page(
+    query: String,
+    limit: Option<u32>,
+    continuation: Option<u64>,
+) -> SearchPage {
+    search(&query, limit, continuation)
+}
+
+fn search(query: &str, limit: Option<u32>, continuation: Option<u64>) -> SearchPage {
+    let mut page = SearchPage {
+        events: Vec::new(),
+        continuation: None,
+    };
+    let words: Vec<String> = words_of(query).into_iter().take(MAX_QUERY_WORDS).collect();
+    if words.is_empty() {
+        return page;
+    }
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    SEARCH_INDEX.with(|i| {
+        let index = i.borrow();
+        let mut sets: Vec<&BTreeSet<u64>> = Vec::new();
+        for word in &words {
+            match index.postings.get(word) {
+                Some(ids) => sets.push(ids),
+                None => return,
+            }
+        }
+        sets.sort_by_key(|ids| ids.len());
+        let (smallest, rest) = sets.split_first().expect("there is at least one word");
+        // The continuation is the id to look at next, going down
+        let candidates = match continuation {
+            Some(next) => smallest.range(..=next),
+            None => smallest.range(..),
+        };
+        for id in candidates.rev() {
+            if page.events.len() == limit || budget::exhausted() {
+                page.continuation = Some(*id);
+                return;
+            }
+            if !rest.iter().all(|ids| ids.contains(id))
+                || !status::is_published(*id)
+                || access::is_unlisted(*id)
+            {
+                continue;
+            }
+            if let Some(event) = event(*id) {
+                page.events.push(event);
+            }
+        }
+    });
+    page
+}
+
+// An event from the cache, or else from stable memory, with its attendee count
+pub(crate) fn event(id: u64) -> Option<Event> {
+    EVENT_CACHE
+        .with(|c| c.borrow().events.get(&id).map(|(event, _)| event.clone()))
+        .or_else(|| STORAGE.with(|s| s.borrow().get(&id)))
+        .map(attendees::with_count)
+}
+
+// Writes an event to stable memory and the caches. Attendees are kept apart, so neither
+// they nor their count are written with it.
+pub(crate) fn store(event: &Event) {
+    let event = &Event {
+        attendees: Vec::new(),
+        attendee_count: None,
+        ..event.clone()
+    };
+    let previous = STORAGE.with(|s| s.borrow_mut().insert(event.id, event.clone()));
+    if let Some(previous) = previous
+        && previous.owner != event.owner
+    {
+        disown(&previous);
+    }
+    own(event);
+    cache(event.clone());
+    index(event);
+}
+
+// Removes an event from stable memory and the caches
+pub(crate) fn remove(id: u64) -> Option<Event> {
+    EVENT_CACHE.with(|c| c.borrow_mut().events.remove(&id));
+    unindex(id);
+    let event = STORAGE.with(|s| s.borrow_mut().remove(&id))?;
+    disown(&event);
+    Some(event)
+}
+
+// Ids of the events stored for an owner, oldest first
+pub(crate) fn events_owned_by(owner: &str) -> Vec<u64> {
+    let start = OwnerEventKey {
+        owner: owner.to_string(),
+        event_id: 0,
+    };
+    OWNED_EVENTS.with(|o| {
+        o.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.owner == owner)
+            .map(|(key, _)| key.event_id)
+            .collect()
+    })
+}
+
+// Saves the caches ahead of an upgrade; called from pre_upgrade
+pub(crate) fn save() {
+    let mut events: Vec<(Event, u64)> =
+        EVENT_CACHE.with(|c| c.borrow().events.values().cloned().collect());
+    events.sort_by_key(|(_, seq)| *seq);
+    let index = SEARCH_INDEX.with(|i| {
+        let index = i.borrow();
+        let postings: usize = index.words.values().map(Vec::len).sum();
+        (postings <= MAX_SAVED_POSTINGS).then(|| {
+            index
+                .words
+                .iter()
+                .map(|(id, words)| (*id, words.clone()))
+                .collect()
+        })
+    });
+    let snapshot = CacheSnapshot {
+        events: events.into_iter().map(|(event, _)| event).collect(),
+        index,
+    };
+    // Failing to save must not block the upgrade; the index is then rebuilt instead
+    if let Err(err) = SNAPSHOT.with(|s| s.borrow_mut().set(snapshot)) {
+        logs::write(LogLevel::Error, module_path!(), format!("cannot save the caches: {:?}", err));
+    }
+}
+
+// Loads the caches saved by the previous version, rebuilding the index from stable memory
+// if it wasn't saved; called from post_upgrade. The snapshot is cleared so a later upgrade
+// can't load stale caches.
+pub(crate) fn restore() {
+    let snapshot = SNAPSHOT.with(|s| {
+        s.borrow_mut()
+            .set(CacheSnapshot::default())
+            .expect("cannot clear the cache snapshot")
+    });
+    match snapshot.index {
+        Some(entries) => SEARCH_INDEX.with(|i| {
+            let mut index = i.borrow_mut();
+            for (id, words) in entries {
+                for word in &words {
+                    index.postings.entry(word.clone()).or_default().insert(id);
+                }
+                index.words.insert(id, words);
+            }
+        }),
+        None => rebuild(),
+    }
+    for event in snapshot.events {
+        cache(event);
+    }
+    // Events stored before the owner index existed
+    let unindexed = OWNED_EVENTS.with(|o| o.borrow().is_empty())
+        && STORAGE.with(|s| !s.borrow().is_empty());
+    if unindexed {
+        rebuild_owners();
+    }
+}
+
+// Number of events in the search index
+pub(crate) fn indexed_count() -> u64 {
+    SEARCH_INDEX.with(|i| i.borrow().words.len() as u64)
+}
+
+// Empties the caches and reindexes every event in stable memory
+pub(crate) fn rebuild() {
+    EVENT_CACHE.with(|c| *c.borrow_mut() = EventCache::default());
+    SEARCH_INDEX.with(|i| *i.borrow_mut() = SearchIndex::default());
+    STORAGE.with(|s| {
+        for (_, event) in s.borrow().iter() {
+            index(&event);
+        }
+    });
+    rebuild_owners();
+}
+
+fn rebuild_owners() {
+    OWNED_EVENTS.with(|o| {
+        let mut owned = o.borrow_mut();
+        let keys: Vec<OwnerEventKey> = owned.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            owned.remove(&key);
+        }
+    });
+    STORAGE.with(|s| {
+        for (_, event) in s.borrow().iter() {
+            own(&event);
+        }
+    });
+}
+
+fn own(event: &Event) {
+    let key = OwnerEventKey {
+        owner: event.owner.clone(),
+        event_id: event.id,
+    };
+    OWNED_EVENTS.with(|o| o.borrow_mut().insert(key, event.created_at));
+}
+
+fn disown(event: &Event) {
+    let key = OwnerEventKey {
+        owner: event.owner.clone(),
+        event_id: event.id,
+    };
+    OWNED_EVENTS.with(|o| o.borrow_mut().remove(&key));
+}
+
+fn cache(event: Event) {
+    EVENT_CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        let seq = cache.next_seq;
+        cache.next_seq += 1;
+        cache.events.insert(event.id, (event, seq));
+        if cache.events.len() > MAX_CACHED_EVENTS {
+            let oldest = cache
+                .events
+                .iter()
+                .min_by_key(|(_, (_, seq))| *seq)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                cache.events.remove(&oldest);
+            }
+        }
+    });
+}
+
+fn index(event: &Event) {
+    unindex(event.id);
+    let mut text = format!("{} {}", event.event_title, event.event_location);
+    for tag in event.tags.iter().flatten() {
+        text.push(' ');
+        text.push_str(tag);
+    }
+    let words = words_of(&text);
+    SEARCH_INDEX.with(|i| {
+        let mut index = i.borrow_mut();
+        for word in &words {
+            index.postings.entry(word.clone()).or_default().insert(event.id);
+        }
+        index.words.insert(event.id, words);
+    });
+}
+
+fn unindex(id: u64) {
+    SEARCH_INDEX.with(|i| {
+        let mut index = i.borrow_mut();
+        for word in index.words.remove(&id).unwrap_or_default() {
+            if let Some(ids) = index.postings.get_mut(&word) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    index.postings.remove(&word);
+                }
+            }
+        }
+    });
+}
+
+// Distinct lowercase words of a text
+fn words_of(text: &str) -> Vec<String> {
+    let mut words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_WORD_CHARS)
+        .map(str::to_lowercase)
+        .collect();
+    words.sort();
+    words.dedup();
+    words
+}
@@ -0,0 +1,97 @@
+// iCalendar feeds. Each organizer has a subscribable feed of their published, upcoming
+// events at /organizer/{principal}/calendar.ics. Only events stored on this canister
+// and with a start time are included.
+use crate::http::{HttpResponse, UtcDateTime};
+use crate::{access, status};
+use crate::{Event, STORAGE};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::api::id;
+
+// iCalendar content lines are folded at 75 octets
+const MAX_LINE_OCTETS: usize = 75;
+
+// Renders the feed of an organizer's upcoming events, soonest first
+pub(crate) fn organizer_calendar(organizer: Principal) -> HttpResponse {
+    let owner = organizer.to_string();
+    let now = time();
+    let mut events: Vec<Event> = STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|event| event.owner == owner && status::is_published(event.id) && !access::is_unlisted(event.id))
+            .filter(|event| event.ends_at.or(event.starts_at).is_some_and(|t| t >= now))
+            .collect()
+    });
+    events.sort_by_key(|event| event.starts_at);
+
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//ICP-TIME//Event Hub//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        format!("X-WR-CALNAME:{}", escape(&format!("Events by {}", owner))),
+    ];
+    for event in &events {
+        lines.extend(vevent(event));
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    let body: String = lines.iter().map(|line| fold(line) + "\r\n").collect();
+    HttpResponse::ok("text/calendar; charset=utf-8", body)
+}
+
+fn vevent(event: &Event) -> Vec<String> {
+    let mut lines = vec![
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:event-{}@{}", event.id, id()),
+        format!("DTSTAMP:{}", timestamp(event.updated_at.unwrap_or(event.created_at))),
+    ];
+    if let Some(starts_at) = event.starts_at {
+        lines.push(format!("DTSTART:{}", timestamp(starts_at)));
+    }
+    if let Some(ends_at) = event.ends_at {
+        lines.push(format!("DTEND:{}", timestamp(ends_at)));
+    }
+    lines.push(format!("SUMMARY:{}", escape(&event.event_title)));
+    if !event.event_description.is_empty() {
+        lines.push(format!("DESCRIPTION:{}", escape(&event.event_description)));
+    }
+    if !event.event_location.is_empty() {
+        lines.push(format!("LOCATION:{}", escape(&event.event_location)));
+    }
+    lines.push("END:VEVENT".to_string());
+    lines
+}
+
+fn timestamp(nanos: u64) -> String {
+    let t = UtcDateTime::from_nanos(nanos);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        t.year, t.month, t.day, t.hour, t.minute, t.second
+    )
+}
+
+// Escapes a TEXT property value
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+// Splits a content line into 75-octet chunks, continuing each with a leading space
+fn fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            folded.push_str("\r\n ");
+            octets = 1;
+        }
+        folded.push(c);
+        octets += c.len_utf8();
+    }
+    folded
+}
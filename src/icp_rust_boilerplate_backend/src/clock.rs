@@ -0,0 +1,84 @@
+// Time source. The canister reads the current time through `time()` rather than the system
+// API, so admins of a dev mode canister can move it forward or back with `set_time_offset`
+// and watch reminders fire, early-bird prices end and events get archived without waiting.
+// The offset lives on the heap, so any upgrade resets it. Ledger transfers and credential
+// checks keep reading the system time, since other canisters judge those timestamps.
+use crate::admin::_check_admin;
+use crate::{config, metrics, Error};
+use std::cell::RefCell;
+
+pub(crate) trait Clock {
+    // Nanoseconds since the epoch
+    fn now(&self) -> u64;
+}
+
+// The replica's time
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+}
+
+// Another clock shifted by a fixed number of nanoseconds
+struct OffsetClock<C: Clock> {
+    inner: C,
+    offset_nanos: i64,
+}
+
+impl<C: Clock> Clock for OffsetClock<C> {
+    fn now(&self) -> u64 {
+        self.inner.now().saturating_add_signed(self.offset_nanos)
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CanisterTime {
+    system_time: u64,
+    offset_nanos: i64,
+    // The time the canister works with: the system time plus the offset
+    now: u64,
+}
+
+thread_local! {
+    static CLOCK: RefCell<OffsetClock<SystemClock>> = const {
+        RefCell::new(OffsetClock {
+            inner: SystemClock,
+            offset_nanos: 0,
+        })
+    };
+}
+
+// Query function returning the time the canister works with
+#[ic_cdk::query]
+fn get_canister_time() -> CanisterTime {
+    CLOCK.with(|c| {
+        let clock = c.borrow();
+        CanisterTime {
+            system_time: clock.inner.now(),
+            offset_nanos: clock.offset_nanos,
+            now: clock.now(),
+        }
+    })
+}
+
+// Update function for admins of a dev mode canister to shift the canister's time by the given
+// number of nanoseconds; 0 goes back to the system time
+#[ic_cdk::update]
+fn set_time_offset(offset_nanos: i64) -> Result<CanisterTime, Error> {
+    let _metrics = metrics::track("set_time_offset");
+    _check_admin()?;
+    if !config::is_dev_mode() {
+        return Err(Error::FeatureDisabled {
+            msg: "the time can only be shifted on a canister installed in dev mode".to_string(),
+        });
+    }
+    CLOCK.with(|c| c.borrow_mut().offset_nanos = offset_nanos);
+    Ok(get_canister_time())
+}
+
+// The current time in nanoseconds since the epoch, shifted in dev mode
+pub(crate) fn time() -> u64 {
+    CLOCK.with(|c| c.borrow().now())
+}
@@ -0,0 +1,101 @@
+// Install and upgrade configuration. A `Config` record passed in the canister arguments sets
+// the payment ledger, extra admins, the free plan's platform fee and its event limit, so a
+// deployment is usable without a round of admin calls. Fields left out keep their current
+// value, and the admin endpoints can still change everything afterwards; the merged config
+// is kept so `get_config` shows what the canister was last installed or upgraded with.
+use crate::logs::{self, LogLevel};
+use crate::{payments, plans, Memory, MEMORY_MANAGER};
+use crate::clock::time;
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Cell as StableCell;
+use std::cell::RefCell;
+
+const MAX_ADMINS: usize = 10;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Config {
+    // ICRC-1/ICRC-2 ledger ticket prices are charged on by default
+    payment_ledger: Option<Principal>,
+    // Principals allowed admin operations alongside the controllers, until a governance
+    // canister takes over
+    admins: Option<Vec<Principal>>,
+    // Platform fee on the free plan, in basis points
+    platform_fee_bps: Option<u32>,
+    // Events an organizer on the free plan may have
+    max_events_per_organizer: Option<u32>,
+    // Allows developer tooling such as `seed_demo_data`; never set on a production canister
+    dev_mode: Option<bool>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct StoredConfig {
+    config: Config,
+    // None until a config was first passed
+    applied_at: Option<u64>,
+}
+
+impl_storable!(StoredConfig, 1024);
+
+thread_local! {
+    static INSTALL_CONFIG: RefCell<StableCell<StoredConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(124))), StoredConfig::default())
+            .expect("Cannot create the install config cell")
+    );
+}
+
+// Query function returning the configuration the canister was last installed or upgraded
+// with, merged over the previous ones
+#[ic_cdk::query]
+fn get_config() -> Config {
+    INSTALL_CONFIG.with(|c| c.borrow().get().config.clone())
+}
+
+// Applies the config from the install or upgrade arguments; traps on an invalid one, so a
+// bad upgrade is rolled back rather than half applied
+pub(crate) fn apply(config: Config) {
+    if config.admins.as_ref().is_some_and(|admins| admins.len() > MAX_ADMINS) {
+        ic_cdk::trap(&format!("at most {} admins can be configured", MAX_ADMINS));
+    }
+    if config.platform_fee_bps.is_some() || config.max_events_per_organizer.is_some() {
+        plans::configure_free_plan(config.platform_fee_bps, config.max_events_per_organizer)
+            .unwrap_or_else(|msg| ic_cdk::trap(&msg));
+    }
+    if config.payment_ledger.is_some() {
+        payments::configure_ledger(config.payment_ledger);
+    }
+    let current = get_config();
+    let merged = Config {
+        payment_ledger: config.payment_ledger.or(current.payment_ledger),
+        admins: config.admins.or(current.admins),
+        platform_fee_bps: config.platform_fee_bps.or(current.platform_fee_bps),
+        max_events_per_organizer: config
+            .max_events_per_organizer
+            .or(current.max_events_per_organizer),
+        dev_mode: config.dev_mode.or(current.dev_mode),
+    };
+    INSTALL_CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(StoredConfig {
+                config: merged,
+                applied_at: Some(time()),
+            })
+            .expect("cannot update the install config");
+    });
+    logs::write(LogLevel::Info, module_path!(), "applied the install config".to_string());
+}
+
+// Whether the canister was installed or upgraded in dev mode
+pub(crate) fn is_dev_mode() -> bool {
+    get_config().dev_mode.unwrap_or(false)
+}
+
+// Whether the principal is one of the configured admins
+pub(crate) fn is_admin(principal: &Principal) -> bool {
+    get_config().admins.is_some_and(|admins| admins.contains(principal))
+}
+
+// The admins in the install config
+pub(crate) fn admins() -> Vec<Principal> {
+    get_config().admins.unwrap_or_default()
+}
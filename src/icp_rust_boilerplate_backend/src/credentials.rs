@@ -0,0 +1,281 @@
+// Credential-gated events. An organizer can require registrants to hold a verifiable
+// credential, such as a membership or an age attestation, from an issuer of their choice.
+// The frontend obtains it through Internet Identity's attribute-sharing flow and passes the
+// resulting presentation JWT to `attend_event`, which checks the Internet Identity and issuer
+// signatures, that it was issued to the caller and that its claims match the requirement.
+//
+// The Internet Identity canister and the IC root key the signatures chain up to are set by
+// admins, so local deployments can point at their own replica.
+//
+// ic-verifiable-credentials is built on candid 0.10 while the canister is on candid 0.9, so
+// both are linked and principals are converted where they are handed to it.
+use crate::admin::_check_admin;
+use crate::{metrics, Error, Memory, MEMORY_MANAGER, _get_organized_event};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell as StableCell, StableBTreeMap};
+use ic_verifiable_credentials::issuer_api_types::{ArgumentValue, CredentialSpec};
+use ic_verifiable_credentials::{validate_ii_presentation_and_claims, VcFlowSigners};
+use std::cell::RefCell;
+
+const MAX_ORIGIN_CHARS: usize = 256;
+const MAX_CREDENTIAL_TYPE_CHARS: usize = 64;
+const MAX_ARGUMENTS: usize = 8;
+const MAX_ARGUMENT_CHARS: usize = 128;
+const MAX_PRESENTATION_BYTES: usize = 16 * 1024;
+// DER encoding of the IC root key: a fixed prefix followed by the 96 byte BLS key
+const ROOT_KEY_DER_LEN: usize = 133;
+const ROOT_KEY_RAW_LEN: usize = 96;
+
+const MAINNET_II_CANISTER_ID: &str = "rdmx6-jaaaa-aaaaa-aaadq-cai";
+const MAINNET_II_ORIGIN: &str = "https://identity.ic0.app";
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum CredentialArgument {
+    String(String),
+    Int(i32),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CredentialRequirement {
+    issuer_canister_id: Principal,
+    // Origin of the issuer's frontend, as the attribute-sharing flow reports it
+    issuer_origin: String,
+    // E.g. "VerifiedAdult" or "VerifiedEmployee"
+    credential_type: String,
+    // Claims the credential must carry, e.g. ("employerName", String("DFINITY"))
+    arguments: Vec<(String, CredentialArgument)>,
+    updated_at: u64,
+}
+
+impl_storable!(CredentialRequirement, 4096);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CredentialRequirementPayload {
+    issuer_canister_id: Principal,
+    issuer_origin: String,
+    credential_type: String,
+    arguments: Option<Vec<(String, CredentialArgument)>>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CredentialConfig {
+    ii_canister_id: Principal,
+    ii_origin: String,
+    // DER encoded; None until admins set it, which leaves gated events closed
+    root_key: Option<Vec<u8>>,
+}
+
+impl_storable!(CredentialConfig, 512);
+
+impl Default for CredentialConfig {
+    fn default() -> Self {
+        Self {
+            ii_canister_id: Principal::from_text(MAINNET_II_CANISTER_ID)
+                .expect("invalid Internet Identity canister id"),
+            ii_origin: MAINNET_II_ORIGIN.to_string(),
+            root_key: None,
+        }
+    }
+}
+
+thread_local! {
+    static CREDENTIAL_REQUIREMENTS: RefCell<StableBTreeMap<u64, CredentialRequirement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(116)))
+    ));
+
+    static CREDENTIAL_CONFIG: RefCell<StableCell<CredentialConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(117))), CredentialConfig::default())
+            .expect("Cannot create the credential config cell")
+    );
+}
+
+// Query function returning the credential an event requires of registrants, if any
+#[ic_cdk::query]
+fn get_credential_requirement(event_id: u64) -> Option<CredentialRequirement> {
+    CREDENTIAL_REQUIREMENTS.with(|r| r.borrow().get(&event_id))
+}
+
+// Update function for an event's organizer to require a credential of registrants, or to
+// stop requiring one with None. Existing attendees are not asked again.
+#[ic_cdk::update]
+fn set_credential_requirement(
+    event_id: u64,
+    payload: Option<CredentialRequirementPayload>,
+) -> Result<Option<CredentialRequirement>, Error> {
+    let _metrics = metrics::track("set_credential_requirement");
+    _get_organized_event(event_id)?;
+    let Some(payload) = payload else {
+        CREDENTIAL_REQUIREMENTS.with(|r| r.borrow_mut().remove(&event_id));
+        return Ok(None);
+    };
+    let issuer_origin = payload.issuer_origin.trim().trim_end_matches('/').to_string();
+    if !issuer_origin.starts_with("https://") && !issuer_origin.starts_with("http://") {
+        return Err(Error::InvalidPayload {
+            msg: "the issuer origin must be an http(s) URL".to_string(),
+        });
+    }
+    if issuer_origin.chars().count() > MAX_ORIGIN_CHARS {
+        return Err(Error::InvalidPayload {
+            msg: format!("the issuer origin can have at most {} characters", MAX_ORIGIN_CHARS),
+        });
+    }
+    let credential_type = payload.credential_type.trim().to_string();
+    if credential_type.is_empty() || credential_type.chars().count() > MAX_CREDENTIAL_TYPE_CHARS {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "the credential type must have between 1 and {} characters",
+                MAX_CREDENTIAL_TYPE_CHARS
+            ),
+        });
+    }
+    let arguments = payload.arguments.unwrap_or_default();
+    if arguments.len() > MAX_ARGUMENTS {
+        return Err(Error::InvalidPayload {
+            msg: format!("a credential can be checked for at most {} claims", MAX_ARGUMENTS),
+        });
+    }
+    for (name, value) in &arguments {
+        let value_chars = match value {
+            CredentialArgument::String(value) => value.chars().count(),
+            CredentialArgument::Int(_) => 0,
+        };
+        if name.is_empty()
+            || name.chars().count() > MAX_ARGUMENT_CHARS
+            || value_chars > MAX_ARGUMENT_CHARS
+        {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "claim names must have between 1 and {0} characters, and values at most {0}",
+                    MAX_ARGUMENT_CHARS
+                ),
+            });
+        }
+        if arguments.iter().filter(|(other, _)| other == name).count() > 1 {
+            return Err(Error::InvalidPayload {
+                msg: format!("the claim {} is given more than once", name),
+            });
+        }
+    }
+    let requirement = CredentialRequirement {
+        issuer_canister_id: payload.issuer_canister_id,
+        issuer_origin,
+        credential_type,
+        arguments,
+        updated_at: time(),
+    };
+    CREDENTIAL_REQUIREMENTS.with(|r| r.borrow_mut().insert(event_id, requirement.clone()));
+    Ok(Some(requirement))
+}
+
+// Query function returning the Internet Identity canister and root key presentations are
+// checked against
+#[ic_cdk::query]
+fn get_credential_config() -> CredentialConfig {
+    CREDENTIAL_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+// Update function for admins to set the Internet Identity canister and the DER encoded IC
+// root key, as `dfx ping` reports it, that presentations are checked against
+#[ic_cdk::update]
+fn set_credential_config(
+    ii_canister_id: Principal,
+    ii_origin: String,
+    root_key: Vec<u8>,
+) -> Result<CredentialConfig, Error> {
+    let _metrics = metrics::track("set_credential_config");
+    _check_admin()?;
+    let ii_origin = ii_origin.trim().trim_end_matches('/').to_string();
+    if ii_origin.is_empty() || ii_origin.chars().count() > MAX_ORIGIN_CHARS {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "the Internet Identity origin must have between 1 and {} characters",
+                MAX_ORIGIN_CHARS
+            ),
+        });
+    }
+    if root_key.len() != ROOT_KEY_DER_LEN {
+        return Err(Error::InvalidPayload {
+            msg: format!("the root key must be {} bytes of DER", ROOT_KEY_DER_LEN),
+        });
+    }
+    let config = CredentialConfig {
+        ii_canister_id,
+        ii_origin,
+        root_key: Some(root_key),
+    };
+    CREDENTIAL_CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(config.clone())
+            .expect("cannot update the credential config");
+    });
+    Ok(config)
+}
+
+// Helper function checking the presentation a registrant passed to `attend_event` against
+// the credential the event requires. Events without a requirement accept anyone.
+pub(crate) fn _check_credential(event_id: u64, presentation: Option<&str>) -> Result<(), Error> {
+    let Some(requirement) = get_credential_requirement(event_id) else {
+        return Ok(());
+    };
+    let Some(presentation) = presentation else {
+        return Err(Error::InvalidCredential {
+            msg: format!(
+                "This event requires a {} credential from {}",
+                requirement.credential_type, requirement.issuer_origin
+            ),
+        });
+    };
+    if presentation.len() > MAX_PRESENTATION_BYTES {
+        return Err(Error::InvalidCredential {
+            msg: "the credential presentation is too large".to_string(),
+        });
+    }
+    let config = get_credential_config();
+    let Some(root_key) = config.root_key else {
+        return Err(Error::InvalidCredential {
+            msg: "credentials can't be checked until admins configure the IC root key".to_string(),
+        });
+    };
+    let signers = VcFlowSigners {
+        ii_canister_id: vc_principal(config.ii_canister_id),
+        ii_origin: config.ii_origin,
+        issuer_canister_id: vc_principal(requirement.issuer_canister_id),
+        issuer_origin: requirement.issuer_origin,
+    };
+    let spec = CredentialSpec {
+        credential_type: requirement.credential_type,
+        arguments: (!requirement.arguments.is_empty()).then(|| {
+            requirement
+                .arguments
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = match value {
+                        CredentialArgument::String(value) => ArgumentValue::String(value),
+                        CredentialArgument::Int(value) => ArgumentValue::Int(value),
+                    };
+                    (name, value)
+                })
+                .collect()
+        }),
+    };
+    validate_ii_presentation_and_claims(
+        presentation,
+        vc_principal(caller()),
+        &signers,
+        &spec,
+        &root_key[ROOT_KEY_DER_LEN - ROOT_KEY_RAW_LEN..],
+        time() as u128,
+    )
+    .map_err(|err| Error::InvalidCredential {
+        msg: format!("the credential could not be verified: {:?}", err),
+    })
+}
+
+// The principal as the candid version ic-verifiable-credentials is built on
+fn vc_principal(principal: Principal) -> candid_0_10::Principal {
+    candid_0_10::Principal::from_slice(principal.as_slice())
+}
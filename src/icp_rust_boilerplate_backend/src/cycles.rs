@@ -0,0 +1,528 @@
+// Cycle balance monitoring. A timer samples the balance periodically; when it drops
+// below the configured threshold an alert is recorded, sent to the inboxes of the admins in
+// the install config and, optionally, posted to an HTTPS webhook so the canister doesn't
+// silently freeze. Every replica posts the webhook, so each alert carries an idempotency key
+// for the receiver to drop the copies.
+//
+// Community members can top the canister up through `wallet_receive` or
+// `deposit_cycles`; every deposit is recorded with per-donor totals. Deposits are indexed by
+// donor and donors by total, so both lists are read a page at a time.
+use crate::admin::_check_admin;
+use crate::logs::{self, LogLevel};
+use crate::notifications::{self, NotificationKind};
+use crate::{config, metrics, Error, Memory, PrincipalKey, MEMORY_MANAGER};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::api::call::{msg_cycles_accept128, msg_cycles_available128};
+use ic_cdk::api::canister_balance128;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::time::Duration;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Keep 30 days of hourly samples
+const MAX_SAMPLES: u64 = 24 * 30;
+const DEFAULT_THRESHOLD: u128 = 1_000_000_000_000;
+const WEBHOOK_CYCLES: u128 = 2_000_000_000;
+const WEBHOOK_MAX_RESPONSE_BYTES: u64 = 1024;
+// Webhook URLs are stored in the monitor state, in bytes
+const MAX_WEBHOOK_URL_BYTES: usize = 400;
+const MAX_HISTORY_PAGE: u64 = 100;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CyclesMonitorConfig {
+    threshold: u128,
+    // Optional HTTPS endpoint that receives a JSON POST for every alert
+    webhook_url: Option<String>,
+}
+
+impl Default for CyclesMonitorConfig {
+    fn default() -> Self {
+        CyclesMonitorConfig {
+            threshold: DEFAULT_THRESHOLD,
+            webhook_url: None,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CyclesMonitorState {
+    config: CyclesMonitorConfig,
+    // Set while the balance is below the threshold so each crossing alerts only once
+    alerting: bool,
+}
+
+impl_storable!(CyclesMonitorState, 512);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CyclesSample {
+    timestamp: u64,
+    balance: u128,
+}
+
+impl_storable!(CyclesSample, 64);
+
+// Low-balance alert kept in the admins' inbox
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CyclesAlert {
+    id: u64,
+    balance: u128,
+    threshold: u128,
+    raised_at: u64,
+    webhook_delivered: bool,
+}
+
+impl_storable!(CyclesAlert, 128);
+
+// A single top-up received by the canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CyclesDeposit {
+    id: u64,
+    donor: Principal,
+    amount: u128,
+    received_at: u64,
+}
+
+impl_storable!(CyclesDeposit, 128);
+
+// Running totals for a donor
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DonorSummary {
+    donor: Principal,
+    total: u128,
+    deposits: u64,
+    first_deposit_at: u64,
+    last_deposit_at: u64,
+}
+
+impl_storable!(DonorSummary, 128);
+
+// A donor's deposits numbered from 0, oldest first
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct DonorDepositKey {
+    donor: String,
+    seq: u64,
+}
+
+impl_storable!(DonorDepositKey, 128);
+
+// Donors by total contribution, largest first
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct DonorRankKey {
+    // u128::MAX minus the donor's total, so larger totals sort first
+    rank: u128,
+    donor: String,
+}
+
+impl_storable!(DonorRankKey, 128);
+
+impl DonorRankKey {
+    fn of(summary: &DonorSummary) -> Self {
+        DonorRankKey {
+            rank: u128::MAX - summary.total,
+            donor: summary.donor.to_string(),
+        }
+    }
+}
+
+// Reply format expected by cycles wallets calling wallet_receive
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct WalletReceiveResult {
+    accepted: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CyclesMetrics {
+    balance: u128,
+    threshold: u128,
+    below_threshold: bool,
+    // Average cycles burned per day over the sampled window, if it can be estimated
+    burn_rate_per_day: Option<u128>,
+    samples: Vec<CyclesSample>,
+}
+
+thread_local! {
+    static CYCLES_MONITOR: RefCell<Cell<CyclesMonitorState, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))), CyclesMonitorState::default())
+            .expect("Cannot create the cycles monitor cell")
+    );
+
+    // Timestamp -> sample
+    static CYCLES_SAMPLES: RefCell<StableBTreeMap<u64, CyclesSample, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+
+    static CYCLES_ALERTS: RefCell<StableBTreeMap<u64, CyclesAlert, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    static CYCLES_DEPOSITS: RefCell<StableBTreeMap<u64, CyclesDeposit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    static CYCLES_DONORS: RefCell<StableBTreeMap<PrincipalKey, DonorSummary, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    // A donor's deposit number -> deposit id
+    static DONOR_DEPOSITS: RefCell<StableBTreeMap<DonorDepositKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(174)))
+    ));
+
+    static DONOR_RANKS: RefCell<StableBTreeMap<DonorRankKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(175)))
+    ));
+}
+
+// Starts the periodic balance sampling; called from init and post_upgrade
+pub(crate) fn start_monitor() {
+    ic_cdk_timers::set_timer_interval(SAMPLE_INTERVAL, || ic_cdk::spawn(check_cycles()));
+}
+
+// Query function exposing the current balance, its recent history and the burn rate
+#[ic_cdk::query]
+fn get_cycles_metrics() -> CyclesMetrics {
+    let threshold = CYCLES_MONITOR.with(|m| m.borrow().get().config.threshold);
+    let samples: Vec<CyclesSample> =
+        CYCLES_SAMPLES.with(|s| s.borrow().iter().map(|(_, sample)| sample).collect());
+    let balance = canister_balance128();
+    CyclesMetrics {
+        balance,
+        threshold,
+        below_threshold: balance < threshold,
+        burn_rate_per_day: burn_rate_per_day(&samples),
+        samples,
+    }
+}
+
+// Update function for admins to set the alert threshold and webhook
+#[ic_cdk::update]
+fn set_cycles_monitor_config(config: CyclesMonitorConfig) -> Result<(), Error> {
+    let _metrics = metrics::track("set_cycles_monitor_config");
+    _check_admin()?;
+    if config
+        .webhook_url
+        .as_ref()
+        .is_some_and(|url| !url.starts_with("https://"))
+    {
+        return Err(Error::InvalidPayload {
+            msg: "the webhook url must use https".to_string(),
+        });
+    }
+    if config
+        .webhook_url
+        .as_ref()
+        .is_some_and(|url| url.len() > MAX_WEBHOOK_URL_BYTES)
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!("the webhook url can be at most {} bytes long", MAX_WEBHOOK_URL_BYTES),
+        });
+    }
+    update_state(|state| state.config = config);
+    Ok(())
+}
+
+// Query function returning the admins' low-balance alerts, newest first
+#[ic_cdk::query]
+fn get_cycles_alerts() -> Result<Vec<CyclesAlert>, Error> {
+    _check_admin()?;
+    let mut alerts: Vec<CyclesAlert> =
+        CYCLES_ALERTS.with(|a| a.borrow().iter().map(|(_, alert)| alert).collect());
+    alerts.reverse();
+    Ok(alerts)
+}
+
+// Update function following the cycles wallet convention; accepts all attached cycles
+#[ic_cdk::update]
+fn wallet_receive() -> WalletReceiveResult {
+    let _metrics = metrics::track("wallet_receive");
+    let accepted = accept_deposit().map(|deposit| deposit.amount).unwrap_or_default();
+    WalletReceiveResult {
+        accepted: accepted.min(u64::MAX as u128) as u64,
+    }
+}
+
+// Update function accepting all attached cycles and recording the contribution
+#[ic_cdk::update]
+fn deposit_cycles() -> Result<CyclesDeposit, Error> {
+    let _metrics = metrics::track("deposit_cycles");
+    accept_deposit().ok_or(Error::InvalidPayload {
+        msg: "no cycles were attached to the call".to_string(),
+    })
+}
+
+// Query function returning a page of the deposit history, or of one donor's, newest first
+#[ic_cdk::query]
+fn get_cycles_deposits(donor: Option<Principal>, offset: u64, limit: u64) -> Vec<CyclesDeposit> {
+    // Deposits are numbered from 0 both overall and per donor, so the page is a range of them
+    let count = match donor {
+        Some(donor) => summary_of(&donor).map(|summary| summary.deposits).unwrap_or_default(),
+        None => CYCLES_DEPOSITS.with(|d| d.borrow().len()),
+    };
+    let end = count.saturating_sub(offset);
+    let start = end.saturating_sub(limit.min(MAX_HISTORY_PAGE));
+    let ids: Vec<u64> = match donor {
+        Some(donor) => {
+            let key = |seq| DonorDepositKey {
+                donor: donor.to_string(),
+                seq,
+            };
+            DONOR_DEPOSITS.with(|d| {
+                d.borrow()
+                    .range(key(start)..key(end))
+                    .map(|(_, id)| id)
+                    .collect()
+            })
+        }
+        None => (start..end).collect(),
+    };
+    CYCLES_DEPOSITS.with(|d| {
+        let deposits = d.borrow();
+        ids.into_iter().rev().filter_map(|id| deposits.get(&id)).collect()
+    })
+}
+
+// Query function listing donors ordered by total contribution, a page at a time. Pass the
+// last donor of a page as `start_after` for the next one.
+#[ic_cdk::query]
+fn get_cycles_donors(start_after: Option<Principal>, limit: u64) -> Vec<DonorSummary> {
+    let start = match start_after.and_then(|donor| summary_of(&donor)) {
+        Some(summary) => Bound::Excluded(DonorRankKey::of(&summary)),
+        None => Bound::Unbounded,
+    };
+    let ranked: Vec<DonorRankKey> = DONOR_RANKS.with(|r| {
+        r.borrow()
+            .range((start, Bound::Unbounded))
+            .take(limit.min(MAX_HISTORY_PAGE) as usize)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    CYCLES_DONORS.with(|d| {
+        let donors = d.borrow();
+        ranked
+            .into_iter()
+            .filter_map(|key| donors.get(&PrincipalKey { principal: key.donor }))
+            .collect()
+    })
+}
+
+// Indexes deposits and donors recorded before the indexes existed; called from post_upgrade
+pub(crate) fn migrate() {
+    let unindexed = DONOR_RANKS.with(|r| r.borrow().is_empty())
+        && CYCLES_DONORS.with(|d| !d.borrow().is_empty());
+    if !unindexed {
+        return;
+    }
+    CYCLES_DONORS.with(|d| {
+        for (_, summary) in d.borrow().iter() {
+            DONOR_RANKS.with(|r| r.borrow_mut().insert(DonorRankKey::of(&summary), ()));
+        }
+    });
+    let mut seqs: BTreeMap<Principal, u64> = BTreeMap::new();
+    CYCLES_DEPOSITS.with(|d| {
+        for (id, deposit) in d.borrow().iter() {
+            let seq = seqs.entry(deposit.donor).or_default();
+            let key = DonorDepositKey {
+                donor: deposit.donor.to_string(),
+                seq: *seq,
+            };
+            DONOR_DEPOSITS.with(|i| i.borrow_mut().insert(key, id));
+            *seq += 1;
+        }
+    });
+}
+
+// Strips headers from webhook responses so replicas agree on the outcall result
+#[ic_cdk::query]
+fn transform_webhook_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+// Helper function to accept the attached cycles and update the donor's totals
+fn accept_deposit() -> Option<CyclesDeposit> {
+    let available = msg_cycles_available128();
+    if available == 0 {
+        return None;
+    }
+    let amount = msg_cycles_accept128(available);
+    let now = time();
+    let deposit = CyclesDeposit {
+        id: CYCLES_DEPOSITS.with(|d| d.borrow().len()),
+        donor: caller(),
+        amount,
+        received_at: now,
+    };
+    CYCLES_DEPOSITS.with(|d| d.borrow_mut().insert(deposit.id, deposit.clone()));
+
+    let key = PrincipalKey {
+        principal: deposit.donor.to_string(),
+    };
+    CYCLES_DONORS.with(|d| {
+        let mut donors = d.borrow_mut();
+        let previous = donors.get(&key);
+        if let Some(previous) = &previous {
+            DONOR_RANKS.with(|r| r.borrow_mut().remove(&DonorRankKey::of(previous)));
+        }
+        let summary = match previous {
+            Some(summary) => DonorSummary {
+                total: summary.total + amount,
+                deposits: summary.deposits + 1,
+                last_deposit_at: now,
+                ..summary
+            },
+            None => DonorSummary {
+                donor: deposit.donor,
+                total: amount,
+                deposits: 1,
+                first_deposit_at: now,
+                last_deposit_at: now,
+            },
+        };
+        let index_key = DonorDepositKey {
+            donor: key.principal.clone(),
+            seq: summary.deposits - 1,
+        };
+        DONOR_DEPOSITS.with(|i| i.borrow_mut().insert(index_key, deposit.id));
+        DONOR_RANKS.with(|r| r.borrow_mut().insert(DonorRankKey::of(&summary), ()));
+        donors.insert(key, summary);
+    });
+    Some(deposit)
+}
+
+// Records a sample and raises an alert when the balance first drops below the threshold
+async fn check_cycles() {
+    let balance = canister_balance128();
+    let now = time();
+    CYCLES_SAMPLES.with(|s| {
+        let mut samples = s.borrow_mut();
+        samples.insert(now, CyclesSample { timestamp: now, balance });
+        while samples.len() > MAX_SAMPLES {
+            let Some((oldest, _)) = samples.iter().next() else { break };
+            samples.remove(&oldest);
+        }
+    });
+
+    let state = CYCLES_MONITOR.with(|m| m.borrow().get().clone());
+    if balance >= state.config.threshold {
+        if state.alerting {
+            update_state(|state| state.alerting = false);
+        }
+        return;
+    }
+    if state.alerting {
+        return;
+    }
+    update_state(|state| state.alerting = true);
+    let message = format!("cycles balance {} is below {}", balance, state.config.threshold);
+    logs::write(LogLevel::Warn, module_path!(), message);
+
+    let id = CYCLES_ALERTS.with(|a| a.borrow().len());
+    let mut alert = CyclesAlert {
+        id,
+        balance,
+        threshold: state.config.threshold,
+        raised_at: now,
+        webhook_delivered: false,
+    };
+    CYCLES_ALERTS.with(|a| a.borrow_mut().insert(id, alert.clone()));
+    for admin in config::admins() {
+        notifications::notify(
+            &admin.to_string(),
+            NotificationKind::LowCycles,
+            None,
+            format!(
+                "The canister's cycles balance of {} dropped below the threshold of {}",
+                balance, state.config.threshold
+            ),
+        );
+    }
+
+    if let Some(url) = state.config.webhook_url {
+        alert.webhook_delivered = post_webhook(url, &alert).await;
+        if !alert.webhook_delivered {
+            logs::write(LogLevel::Warn, module_path!(), "the alert webhook failed".to_string());
+        }
+        CYCLES_ALERTS.with(|a| a.borrow_mut().insert(id, alert));
+    }
+}
+
+async fn post_webhook(url: String, alert: &CyclesAlert) -> bool {
+    let body = serde_json::json!({
+        "canister_id": ic_cdk::id().to_text(),
+        "balance": alert.balance.to_string(),
+        "threshold": alert.threshold.to_string(),
+        "raised_at": alert.raised_at,
+    });
+    let request = CanisterHttpRequestArgument {
+        url,
+        max_response_bytes: Some(WEBHOOK_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/json".to_string(),
+            },
+            HttpHeader {
+                name: "Idempotency-Key".to_string(),
+                value: idempotency_key(alert.id),
+            },
+        ],
+        body: Some(body.to_string().into_bytes()),
+        transform: Some(TransformContext::from_name(
+            "transform_webhook_response".to_string(),
+            Vec::new(),
+        )),
+    };
+    match http_request(request, WEBHOOK_CYCLES).await {
+        Ok((response,)) => response.status >= 200u64.into() && response.status < 300u64.into(),
+        Err(_) => false,
+    }
+}
+
+// Same on every replica for the same alert, so the receiver can drop duplicates
+fn idempotency_key(alert_id: u64) -> String {
+    let digest = Sha256::digest(format!("cycles-alert|{}|{}", ic_cdk::id(), alert_id));
+    digest.iter().take(16).map(|b| format!("{:02x}", b)).collect()
+}
+
+fn burn_rate_per_day(samples: &[CyclesSample]) -> Option<u128> {
+    let (first, last) = (samples.first()?, samples.last()?);
+    let elapsed = last.timestamp.checked_sub(first.timestamp).filter(|e| *e > 0)?;
+    let burned = first.balance.checked_sub(last.balance)?;
+    const NANOS_PER_DAY: u128 = 24 * 60 * 60 * 1_000_000_000;
+    Some(burned * NANOS_PER_DAY / elapsed as u128)
+}
+
+fn update_state(f: impl FnOnce(&mut CyclesMonitorState)) {
+    CYCLES_MONITOR.with(|m| {
+        let mut state = m.borrow().get().clone();
+        f(&mut state);
+        m.borrow_mut().set(state).expect("cannot update the cycles monitor state");
+    });
+}
+
+fn summary_of(donor: &Principal) -> Option<DonorSummary> {
+    let key = PrincipalKey {
+        principal: donor.to_string(),
+    };
+    CYCLES_DONORS.with(|d| d.borrow().get(&key))
+}
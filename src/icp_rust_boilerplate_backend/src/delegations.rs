@@ -0,0 +1,173 @@
+// Delegated service principals. A user can let a bot or service principal act on their
+// behalf for a few actions, such as RSVPing when a new event matches their interests, until
+// a set time. The delegate passes the user's principal as `on_behalf_of` to the update
+// methods supporting it, which check the delegation and attribute the action to the user.
+// Delegations can be revoked at any time, and a banned user's delegations stop working.
+use crate::{bans, metrics, Error, Memory, MEMORY_MANAGER};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_DELEGATES: usize = 20;
+const MAX_DELEGATION_NANOS: u64 = 365 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+    Debug,
+)]
+pub(crate) enum DelegatedAction {
+    // `attend_event`
+    Rsvp,
+    // `ask_question`
+    AskQuestion,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct DelegationKey {
+    delegator: String,
+    delegate: String,
+}
+
+impl_storable!(DelegationKey, 160);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Delegation {
+    delegator: Principal,
+    delegate: Principal,
+    actions: Vec<DelegatedAction>,
+    expires_at: u64,
+    granted_at: u64,
+}
+
+impl_storable!(Delegation, 256);
+
+thread_local! {
+    static DELEGATIONS: RefCell<StableBTreeMap<DelegationKey, Delegation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(147)))
+    ));
+}
+
+// Query function listing the delegations the caller granted, expired ones included
+#[ic_cdk::query]
+fn get_my_delegations() -> Vec<Delegation> {
+    let delegator = caller().to_string();
+    DELEGATIONS.with(|d| {
+        d.borrow()
+            .range(DelegationKey { delegator: delegator.clone(), delegate: String::new() }..)
+            .take_while(|(key, _)| key.delegator == delegator)
+            .map(|(_, delegation)| delegation)
+            .collect()
+    })
+}
+
+// Query function listing the unexpired delegations granted to the caller, for services to
+// find out whom they can act for
+#[ic_cdk::query]
+fn get_delegations_to_me() -> Vec<Delegation> {
+    let delegate = caller().to_string();
+    DELEGATIONS.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(key, delegation)| key.delegate == delegate && delegation.expires_at > time())
+            .map(|(_, delegation)| delegation)
+            .collect()
+    })
+}
+
+// Update function letting a principal act for the caller for the given actions until
+// `expires_at`; granting again to the same principal replaces the delegation
+#[ic_cdk::update]
+fn grant_delegation(
+    delegate: Principal,
+    actions: Vec<DelegatedAction>,
+    expires_at: u64,
+) -> Result<Delegation, Error> {
+    let _metrics = metrics::track("grant_delegation");
+    if caller() == Principal::anonymous() {
+        return Err(Error::NotAuthorized {
+            msg: "Sign in to delegate actions".to_string(),
+            caller: caller(),
+        });
+    }
+    if delegate == caller() || delegate == Principal::anonymous() {
+        return Err(Error::InvalidPayload {
+            msg: "actions can't be delegated to this principal".to_string(),
+        });
+    }
+    if actions.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "delegate at least one action".to_string(),
+        });
+    }
+    if expires_at <= time() || expires_at - time() > MAX_DELEGATION_NANOS {
+        return Err(Error::InvalidPayload {
+            msg: "a delegation must expire in the future and within a year".to_string(),
+        });
+    }
+    let key = delegation_key(&caller(), &delegate);
+    let exists = DELEGATIONS.with(|d| d.borrow().contains_key(&key));
+    if !exists && get_my_delegations().len() >= MAX_DELEGATES {
+        return Err(Error::InvalidPayload {
+            msg: format!("you can delegate to at most {} principals", MAX_DELEGATES),
+        });
+    }
+    let mut actions = actions;
+    actions.sort();
+    actions.dedup();
+    let delegation = Delegation {
+        delegator: caller(),
+        delegate,
+        actions,
+        expires_at,
+        granted_at: time(),
+    };
+    DELEGATIONS.with(|d| d.borrow_mut().insert(key, delegation.clone()));
+    Ok(delegation)
+}
+
+// Update function revoking the caller's delegation to a principal
+#[ic_cdk::update]
+fn revoke_delegation(delegate: Principal) -> Result<Delegation, Error> {
+    let _metrics = metrics::track("revoke_delegation");
+    DELEGATIONS
+        .with(|d| d.borrow_mut().remove(&delegation_key(&caller(), &delegate)))
+        .ok_or(Error::NotFound {
+            msg: format!("You have no delegation to {}", delegate),
+        })
+}
+
+// Helper function returning the principal an action is attributed to: the caller, or the
+// principal they act for when they hold an unexpired delegation for the action
+pub(crate) fn _acting_for(
+    on_behalf_of: Option<Principal>,
+    action: DelegatedAction,
+) -> Result<Principal, Error> {
+    let Some(delegator) = on_behalf_of else {
+        return Ok(caller());
+    };
+    if delegator == caller() {
+        return Ok(caller());
+    }
+    let delegation = DELEGATIONS.with(|d| d.borrow().get(&delegation_key(&delegator, &caller())));
+    let allowed = delegation.is_some_and(|delegation| {
+        delegation.expires_at > time() && delegation.actions.contains(&action)
+    });
+    if !allowed || bans::is_banned(&delegator) {
+        return Err(Error::NotAuthorized {
+            msg: format!("You can't perform {:?} on behalf of {}", action, delegator),
+            caller: caller(),
+        });
+    }
+    Ok(delegator)
+}
+
+fn delegation_key(delegator: &Principal, delegate: &Principal) -> DelegationKey {
+    DelegationKey {
+        delegator: delegator.to_string(),
+        delegate: delegate.to_string(),
+    }
+}
@@ -0,0 +1,359 @@
+// Attendance deposits for free events. An organizer can ask registrants to stake a small,
+// refundable deposit in the event's main token when they RSVP; it comes back to them
+// automatically when they are checked in at the door. Once the event is over, the deposits
+// of those who never showed up are forfeited to the organizer or to the platform treasury,
+// as the organizer chose. Cancelling the event returns every deposit still held.
+//
+// Deposits are pulled through ICRC-2 like ticket payments, and returned through the payout
+// queue, which takes the ledger fee out of them.
+use crate::admin::_check_admin;
+use crate::history::{HistoryEntry, HistoryItem, HistoryStatus};
+use crate::ledger::{self, Account};
+use crate::payments::{self, PayoutKind};
+use crate::{attendees, metrics, schedule, tiers, tokens};
+use crate::{Error, EventPrincipalKey, Memory, MEMORY_MANAGER, _get_event, _get_organized_event};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell as StableCell, StableBTreeMap};
+use std::cell::RefCell;
+use std::time::Duration;
+
+const SETTLEMENT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Late check-ins are still accepted for a while after the event ends
+const NO_SHOW_GRACE_NANOS: u64 = 6 * 60 * 60 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum ForfeitTo {
+    Organizer,
+    // The treasury admins configured; deposits stay on the canister until they do
+    Treasury,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DepositPolicy {
+    amount: u128,
+    ledger: Principal,
+    forfeit_to: ForfeitTo,
+    updated_at: u64,
+    // Set once the no-shows' deposits have been forfeited
+    settled_at: Option<u64>,
+}
+
+impl_storable!(DepositPolicy, 128);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DepositPolicyPayload {
+    // In the smallest unit of the event's main token
+    amount: u128,
+    forfeit_to: ForfeitTo,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum DepositStatus {
+    Held,
+    // Queued back to the attendee, after check-in or a cancellation
+    Returned { payout_id: u64 },
+    // Kept for a no-show; the payout is None when no treasury was configured
+    Forfeited { payout_id: Option<u64> },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Deposit {
+    event_id: u64,
+    principal: String,
+    ledger: Principal,
+    amount: u128,
+    block_index: u64,
+    paid_at: u64,
+    status: DepositStatus,
+}
+
+impl_storable!(Deposit, 256);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DepositConfig {
+    treasury: Option<Principal>,
+}
+
+impl_storable!(DepositConfig, 64);
+
+thread_local! {
+    static DEPOSIT_POLICIES: RefCell<StableBTreeMap<u64, DepositPolicy, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(104)))
+    ));
+
+    static DEPOSITS: RefCell<StableBTreeMap<EventPrincipalKey, Deposit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(105)))
+    ));
+
+    static DEPOSIT_CONFIG: RefCell<StableCell<DepositConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(106))), DepositConfig::default())
+            .expect("Cannot create the deposit config cell")
+    );
+
+    // Guards against a registration being charged twice while its first charge is in flight
+    static CHARGING: RefCell<Vec<EventPrincipalKey>> = const { RefCell::new(Vec::new()) };
+}
+
+// Starts the periodic forfeiting of no-shows' deposits; called from init and post_upgrade
+pub(crate) fn start_deposit_settlement() {
+    ic_cdk_timers::set_timer_interval(SETTLEMENT_INTERVAL, settle_ended_events);
+}
+
+// Query function returning the deposit an event asks of registrants, if any
+#[ic_cdk::query]
+fn get_deposit_policy(event_id: u64) -> Option<DepositPolicy> {
+    DEPOSIT_POLICIES.with(|p| p.borrow().get(&event_id))
+}
+
+// Update function for an event's organizer to ask registrants for a deposit, or to stop
+// asking with None. Deposits already taken keep their amount.
+#[ic_cdk::update]
+fn set_deposit_policy(
+    event_id: u64,
+    payload: Option<DepositPolicyPayload>,
+) -> Result<Option<DepositPolicy>, Error> {
+    let _metrics = metrics::track("set_deposit_policy");
+    _get_organized_event(event_id)?;
+    let Some(payload) = payload else {
+        DEPOSIT_POLICIES.with(|p| p.borrow_mut().remove(&event_id));
+        return Ok(None);
+    };
+    if tiers::_check_free_registration(event_id).is_err() {
+        return Err(Error::InvalidPayload {
+            msg: "deposits are for free events; paid tickets can be refunded instead".to_string(),
+        });
+    }
+    if payload.amount == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "a deposit must be more than zero".to_string(),
+        });
+    }
+    let ledger = tokens::_payment_ledger(event_id, None)?;
+    tokens::_check_min_amount(ledger, payload.amount)?;
+    let policy = DepositPolicy {
+        amount: payload.amount,
+        ledger,
+        forfeit_to: payload.forfeit_to,
+        updated_at: time(),
+        settled_at: None,
+    };
+    DEPOSIT_POLICIES.with(|p| p.borrow_mut().insert(event_id, policy.clone()));
+    Ok(Some(policy))
+}
+
+// Query function returning the caller's deposit for an event, if they made one
+#[ic_cdk::query]
+fn my_deposit(event_id: u64) -> Option<Deposit> {
+    let key = EventPrincipalKey {
+        event_id,
+        principal: caller().to_string(),
+    };
+    DEPOSITS.with(|d| d.borrow().get(&key))
+}
+
+// Query function listing the deposits made for an event; restricted to its organizer
+#[ic_cdk::query]
+fn get_deposits(event_id: u64) -> Result<Vec<Deposit>, Error> {
+    _get_organized_event(event_id)?;
+    Ok(deposits_of(event_id))
+}
+
+// Query function returning where forfeited deposits go for events that chose the treasury
+#[ic_cdk::query]
+fn get_deposit_treasury() -> Option<Principal> {
+    DEPOSIT_CONFIG.with(|c| c.borrow().get().treasury)
+}
+
+// Update function for admins to set the treasury forfeited deposits are paid to
+#[ic_cdk::update]
+fn set_deposit_treasury(treasury: Option<Principal>) -> Result<(), Error> {
+    let _metrics = metrics::track("set_deposit_treasury");
+    _check_admin()?;
+    DEPOSIT_CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(DepositConfig { treasury })
+            .expect("cannot update the deposit config");
+    });
+    Ok(())
+}
+
+// Helper function charging the registrant the event's deposit, if it asks for one, before
+// they are added to its attendees. Nothing is asked once the event is over and settled.
+pub(crate) async fn _take_deposit(event_id: u64, principal: Principal) -> Result<(), Error> {
+    let Some(policy) = get_deposit_policy(event_id).filter(|policy| policy.settled_at.is_none())
+    else {
+        return Ok(());
+    };
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    let attending = attendees::is_attending(event_id, &key.principal);
+    let held = DEPOSITS
+        .with(|d| d.borrow().get(&key))
+        .is_some_and(|deposit| deposit.status == DepositStatus::Held);
+    if attending || held || CHARGING.with(|c| c.borrow().contains(&key)) {
+        return Err(Error::InvalidPayload {
+            msg: "You are already an attendee".to_string(),
+        });
+    }
+    let from = Account {
+        owner: principal,
+        subaccount: None,
+    };
+    let mut memo = b"deposit:".to_vec();
+    memo.extend_from_slice(&event_id.to_be_bytes());
+    CHARGING.with(|c| c.borrow_mut().push(key.clone()));
+    let charged = ledger::transfer_from(policy.ledger, from, policy.amount, memo).await;
+    CHARGING.with(|c| c.borrow_mut().retain(|charging| *charging != key));
+    let block_index = charged.map_err(|msg| Error::PaymentFailed {
+        msg: format!("the deposit could not be taken: {}", msg),
+    })?;
+    let deposit = Deposit {
+        event_id,
+        principal: key.principal.clone(),
+        ledger: policy.ledger,
+        amount: policy.amount,
+        block_index,
+        paid_at: time(),
+        status: DepositStatus::Held,
+    };
+    DEPOSITS.with(|d| d.borrow_mut().insert(key, deposit));
+    Ok(())
+}
+
+// Queues a held deposit back to the attendee: after check-in, a cancellation, or a
+// registration that didn't go through
+pub(crate) fn return_deposit(event_id: u64, principal: &str) {
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    let Some(mut deposit) = DEPOSITS
+        .with(|d| d.borrow().get(&key))
+        .filter(|deposit| deposit.status == DepositStatus::Held)
+    else {
+        return;
+    };
+    let Ok(attendee) = Principal::from_text(principal) else {
+        return;
+    };
+    let payout_id = payments::enqueue_payout(
+        event_id,
+        attendee,
+        deposit.ledger,
+        deposit.amount,
+        PayoutKind::DepositReturn,
+    );
+    deposit.status = DepositStatus::Returned { payout_id };
+    DEPOSITS.with(|d| d.borrow_mut().insert(key, deposit));
+}
+
+// Returns every deposit still held for a cancelled event
+pub(crate) fn return_deposits(event_id: u64) {
+    for deposit in deposits_of(event_id) {
+        return_deposit(event_id, &deposit.principal);
+    }
+}
+
+// Forfeits the deposits still held for events that ended a while ago: their holders were
+// never checked in. Events without a start time are never settled.
+fn settle_ended_events() {
+    let now = time();
+    let due: Vec<u64> = DEPOSIT_POLICIES.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_, policy)| policy.settled_at.is_none())
+            .map(|(event_id, _)| event_id)
+            .collect()
+    });
+    for event_id in due {
+        let Some(event) = _get_event(&event_id) else {
+            continue;
+        };
+        let Some(starts_at) = event.starts_at else {
+            continue;
+        };
+        let ends_at = event
+            .ends_at
+            .unwrap_or_else(|| starts_at.saturating_add(schedule::DEFAULT_DURATION_NANOS));
+        if ends_at.saturating_add(NO_SHOW_GRACE_NANOS) > now {
+            continue;
+        }
+        forfeit_no_shows(event_id, &event.owner);
+    }
+}
+
+fn forfeit_no_shows(event_id: u64, organizer: &str) {
+    let Some(mut policy) = get_deposit_policy(event_id) else {
+        return;
+    };
+    let recipient = match policy.forfeit_to {
+        ForfeitTo::Organizer => Principal::from_text(organizer).ok(),
+        ForfeitTo::Treasury => get_deposit_treasury(),
+    };
+    for mut deposit in deposits_of(event_id) {
+        if deposit.status != DepositStatus::Held {
+            continue;
+        }
+        let payout_id = recipient.map(|recipient| {
+            payments::enqueue_payout(
+                event_id,
+                recipient,
+                deposit.ledger,
+                deposit.amount,
+                PayoutKind::DepositForfeit,
+            )
+        });
+        deposit.status = DepositStatus::Forfeited { payout_id };
+        let key = EventPrincipalKey {
+            event_id,
+            principal: deposit.principal.clone(),
+        };
+        DEPOSITS.with(|d| d.borrow_mut().insert(key, deposit));
+    }
+    policy.settled_at = Some(time());
+    DEPOSIT_POLICIES.with(|p| p.borrow_mut().insert(event_id, policy));
+}
+
+// A principal's deposits as purchase history entries
+pub(crate) fn history_of(principal: &str) -> Vec<HistoryEntry> {
+    DEPOSITS.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(key, _)| key.principal == principal)
+            .map(|(_, deposit)| HistoryEntry {
+                item: HistoryItem::Deposit,
+                event_id: deposit.event_id,
+                ledger: Some(deposit.ledger),
+                amount: deposit.amount,
+                status: match deposit.status {
+                    DepositStatus::Held => HistoryStatus::Held,
+                    DepositStatus::Returned { payout_id } => HistoryStatus::Returned { payout_id },
+                    DepositStatus::Forfeited { .. } => HistoryStatus::Forfeited,
+                },
+                block_index: Some(deposit.block_index),
+                at: deposit.paid_at,
+            })
+            .collect()
+    })
+}
+
+fn deposits_of(event_id: u64) -> Vec<Deposit> {
+    let start = EventPrincipalKey {
+        event_id,
+        principal: String::new(),
+    };
+    DEPOSITS.with(|d| {
+        d.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(_, deposit)| deposit)
+            .collect()
+    })
+}
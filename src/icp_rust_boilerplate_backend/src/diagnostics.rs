@@ -0,0 +1,152 @@
+// Stable memory diagnostics for operators. Every virtual memory the canister allocated is
+// reported with its size, and what it holds is read from the structure's own header: a
+// StableBTreeMap's entry count and key/value bounds, or a Cell's value size. Average sizes
+// are allocated bytes divided by entries, so they include node overhead and free space.
+//
+// `profile` runs one representative operation and reports the instructions it took, to see
+// how far typical reads are from the message limits as the data grows.
+use crate::admin::_check_admin;
+use crate::{cache, slugs};
+use crate::{Error, MEMORY_MANAGER, STORAGE, _get_event};
+use ic_cdk::api::performance_counter;
+use ic_cdk::api::stable::stable64_size;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Memory as _;
+
+const WASM_PAGE_BYTES: u64 = 64 * 1024;
+const BTREE_MAGIC: &[u8; 3] = b"BTR";
+const CELL_MAGIC: &[u8; 3] = b"SCL";
+// Enough for the StableBTreeMap header: magic, version, max key and value sizes, root
+// address and length
+const HEADER_BYTES: usize = 28;
+// Instructions executed by the current message
+const MESSAGE_COUNTER: u32 = 0;
+const QUERY_INSTRUCTION_LIMIT: u64 = 5_000_000_000;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum MemoryContents {
+    BTreeMap {
+        entries: u64,
+        max_key_size: u32,
+        max_value_size: u32,
+        // None while the map is empty
+        avg_bytes_per_entry: Option<u64>,
+    },
+    Cell {
+        value_size: u32,
+    },
+    Unknown,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MemoryUsage {
+    memory_id: u8,
+    pages: u64,
+    bytes: u64,
+    contents: MemoryContents,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MemoryReport {
+    // Pages of the canister's whole stable memory, including the memory manager's own
+    total_pages: u64,
+    total_bytes: u64,
+    // Virtual memories with pages allocated, by id
+    memories: Vec<MemoryUsage>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum ProfiledOp {
+    // Decodes every event in stable memory
+    FullScan,
+    // Searches events through the word index
+    Search { query: String },
+    // Resolves a slug through the slug index and loads the event
+    SlugLookup { slug: String },
+    // Loads an event by id, from the heap cache if it is there
+    EventLookup { id: u64 },
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ProfileReport {
+    instructions: u64,
+    // Events the operation read or returned
+    items: u64,
+    // None when no event was read
+    instructions_per_item: Option<u64>,
+    // Share of a query's instruction limit used, in basis points
+    query_limit_bps: u64,
+}
+
+// Query function for admins reporting what is using stable memory
+#[ic_cdk::query]
+fn get_memory_report() -> Result<MemoryReport, Error> {
+    _check_admin()?;
+    let memories = (0..u8::MAX)
+        .filter_map(|memory_id| {
+            let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id)));
+            let pages = memory.size();
+            if pages == 0 {
+                return None;
+            }
+            let mut header = [0u8; HEADER_BYTES];
+            memory.read(0, &mut header);
+            let bytes = pages * WASM_PAGE_BYTES;
+            Some(MemoryUsage {
+                memory_id,
+                pages,
+                bytes,
+                contents: contents_of(&header, bytes),
+            })
+        })
+        .collect();
+    let total_pages = stable64_size();
+    Ok(MemoryReport {
+        total_pages,
+        total_bytes: total_pages * WASM_PAGE_BYTES,
+        memories,
+    })
+}
+
+fn contents_of(header: &[u8; HEADER_BYTES], bytes: u64) -> MemoryContents {
+    let u32_at = |offset: usize| {
+        u32::from_le_bytes(header[offset..offset + 4].try_into().expect("4 bytes"))
+    };
+    if &header[0..3] == BTREE_MAGIC {
+        let entries = u64::from_le_bytes(header[20..28].try_into().expect("8 bytes"));
+        return MemoryContents::BTreeMap {
+            entries,
+            max_key_size: u32_at(4),
+            max_value_size: u32_at(8),
+            avg_bytes_per_entry: (entries > 0).then(|| bytes / entries),
+        };
+    }
+    if &header[0..3] == CELL_MAGIC {
+        return MemoryContents::Cell {
+            value_size: u32_at(4),
+        };
+    }
+    MemoryContents::Unknown
+}
+
+// Query function for admins measuring the instructions one representative operation takes
+#[ic_cdk::query]
+fn profile(op: ProfiledOp) -> Result<ProfileReport, Error> {
+    _check_admin()?;
+    let start = performance_counter(MESSAGE_COUNTER);
+    let items = match op {
+        ProfiledOp::FullScan => STORAGE.with(|s| s.borrow().iter().count()),
+        ProfiledOp::Search { query } => cache::search_events(query, None).len(),
+        ProfiledOp::SlugLookup { slug } => slugs::event_id_of(&slug)
+            .and_then(|id| _get_event(&id))
+            .map_or(0, |_| 1),
+        ProfiledOp::EventLookup { id } => _get_event(&id).map_or(0, |_| 1),
+    } as u64;
+    let instructions = performance_counter(MESSAGE_COUNTER) - start;
+    Ok(ProfileReport {
+        instructions,
+        items,
+        instructions_per_item: (items > 0).then(|| instructions / items),
+        query_limit_bps: instructions * 10_000 / QUERY_INSTRUCTION_LIMIT,
+    })
+}
@@ -0,0 +1,383 @@
+// Refund disputes. A buyer who wants their money back opens a dispute on their order with a
+// reason; the organizer and the buyer then talk it through in the dispute's thread, and the
+// organizer can settle it by refunding the order. When they can't agree, admins step in and
+// either force the refund or reject the dispute. Each step notifies the other party.
+use crate::admin::_check_admin;
+use crate::notifications::{self, NotificationKind};
+use crate::orders::{self, Order};
+use crate::{metrics, Error, IdCell, Memory, MEMORY_MANAGER};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_MESSAGE_CHARS: usize = 500;
+const MAX_MESSAGES_PER_DISPUTE: usize = 50;
+// Length of the snippet of a message quoted in notifications
+const SNIPPET_CHARS: usize = 100;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum DisputeStatus {
+    AwaitingOrganizer,
+    AwaitingBuyer,
+    // Settled by refunding the order, by its organizer or an admin
+    Refunded { refund_id: u64 },
+    // Closed by an admin without a refund
+    Rejected,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Dispute {
+    id: u64,
+    order_id: u64,
+    event_id: u64,
+    event_title: String,
+    buyer: Principal,
+    organizer: String,
+    reason: String,
+    status: DisputeStatus,
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl_storable!(Dispute, 1536);
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum DisputeRole {
+    Buyer,
+    Organizer,
+    Admin,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DisputeMessage {
+    author: Principal,
+    role: DisputeRole,
+    message: String,
+    sent_at: u64,
+}
+
+impl_storable!(DisputeMessage, 2560);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct DisputeMessageKey {
+    dispute_id: u64,
+    id: u64,
+}
+
+impl_storable!(DisputeMessageKey, 32);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DisputeThread {
+    dispute: Dispute,
+    messages: Vec<DisputeMessage>,
+}
+
+thread_local! {
+    static DISPUTE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(100))), 0)
+            .expect("Cannot create a dispute counter")
+    );
+
+    static DISPUTES: RefCell<StableBTreeMap<u64, Dispute, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(101)))
+    ));
+
+    static DISPUTE_MESSAGES: RefCell<StableBTreeMap<DisputeMessageKey, DisputeMessage, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(102)))
+    ));
+
+    // The dispute opened on each order; an order can only be disputed once
+    static ORDER_DISPUTES: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(103)))
+    ));
+}
+
+// Update function for a buyer to dispute one of their paid orders and ask for a refund
+#[ic_cdk::update]
+fn open_dispute(order_id: u64, reason: String) -> Result<Dispute, Error> {
+    let _metrics = metrics::track("open_dispute");
+    let order = orders::_get_refundable_order(order_id)?;
+    if order.buyer != caller() {
+        return Err(Error::NotAuthorized {
+            msg: format!("You didn't place the order with id={}", order_id),
+            caller: caller(),
+        });
+    }
+    if ORDER_DISPUTES.with(|d| d.borrow().contains_key(&order_id)) {
+        return Err(Error::InvalidPayload {
+            msg: format!("Order with id={} has already been disputed", order_id),
+        });
+    }
+    let reason = validate_message(reason)?;
+    let id = DISPUTE_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment dispute id counter");
+    let now = time();
+    let Order {
+        event_id,
+        event_title,
+        organizer,
+        buyer,
+        ..
+    } = order;
+    let dispute = Dispute {
+        id,
+        order_id,
+        event_id,
+        event_title,
+        buyer,
+        organizer,
+        reason,
+        status: DisputeStatus::AwaitingOrganizer,
+        created_at: now,
+        updated_at: now,
+    };
+    do_insert_dispute(&dispute);
+    ORDER_DISPUTES.with(|d| d.borrow_mut().insert(order_id, id));
+    notifications::notify(
+        &dispute.organizer,
+        NotificationKind::Dispute,
+        Some(event_id),
+        format!(
+            "A buyer disputed order #{} for \"{}\": {}",
+            order_id,
+            dispute.event_title,
+            snippet(&dispute.reason)
+        ),
+    );
+    Ok(dispute)
+}
+
+// Update function for the buyer, the organizer or an admin to add a message to an open
+// dispute; the other party is notified
+#[ic_cdk::update]
+fn reply_to_dispute(dispute_id: u64, message: String) -> Result<DisputeThread, Error> {
+    let _metrics = metrics::track("reply_to_dispute");
+    let mut dispute = _get_open_dispute(dispute_id)?;
+    let role = _role_in(&dispute)?;
+    let message = validate_message(message)?;
+    if messages_of(dispute_id).len() >= MAX_MESSAGES_PER_DISPUTE {
+        return Err(Error::InvalidPayload {
+            msg: format!("a dispute can have at most {} messages", MAX_MESSAGES_PER_DISPUTE),
+        });
+    }
+    let text = format!(
+        "New message on the dispute about order #{}: {}",
+        dispute.order_id,
+        snippet(&message)
+    );
+    add_message(&dispute, role, message);
+    match role {
+        DisputeRole::Buyer => dispute.status = DisputeStatus::AwaitingOrganizer,
+        DisputeRole::Organizer => dispute.status = DisputeStatus::AwaitingBuyer,
+        DisputeRole::Admin => {}
+    }
+    dispute.updated_at = time();
+    do_insert_dispute(&dispute);
+    notify_parties(&dispute, role, text);
+    Ok(thread(dispute))
+}
+
+// Update function for the organizer to settle a dispute by refunding the order in full
+#[ic_cdk::update]
+fn accept_dispute(dispute_id: u64, message: Option<String>) -> Result<DisputeThread, Error> {
+    let _metrics = metrics::track("accept_dispute");
+    let dispute = _get_open_dispute(dispute_id)?;
+    if _role_in(&dispute)? != DisputeRole::Organizer {
+        return Err(Error::NotAuthorized {
+            msg: "Only the organizer can accept a dispute".to_string(),
+            caller: caller(),
+        });
+    }
+    settle(dispute, DisputeRole::Organizer, true, message)
+}
+
+// Update function for admins to decide a dispute, either forcing a refund of the order or
+// rejecting it
+#[ic_cdk::update]
+fn resolve_dispute(
+    dispute_id: u64,
+    refund: bool,
+    message: Option<String>,
+) -> Result<DisputeThread, Error> {
+    let _metrics = metrics::track("resolve_dispute");
+    _check_admin()?;
+    let dispute = _get_open_dispute(dispute_id)?;
+    settle(dispute, DisputeRole::Admin, refund, message)
+}
+
+// Query function returning a dispute with its messages; restricted to its parties and admins
+#[ic_cdk::query]
+fn get_dispute(dispute_id: u64) -> Result<DisputeThread, Error> {
+    let dispute = _get_dispute(dispute_id)?;
+    _role_in(&dispute)?;
+    Ok(thread(dispute))
+}
+
+// Query function listing the disputes the caller opened or that concern their events, most
+// recently updated first
+#[ic_cdk::query]
+fn my_disputes() -> Vec<Dispute> {
+    let principal = caller().to_string();
+    let mut disputes: Vec<Dispute> = DISPUTES.with(|d| {
+        d.borrow()
+            .iter()
+            .map(|(_, dispute)| dispute)
+            .filter(|dispute| {
+                dispute.buyer.to_string() == principal || dispute.organizer == principal
+            })
+            .collect()
+    });
+    disputes.sort_by(|a, b| b.updated_at.cmp(&a.updated_at));
+    disputes
+}
+
+// Query function for admins listing the disputes still waiting for a decision, oldest first
+#[ic_cdk::query]
+fn get_open_disputes() -> Result<Vec<Dispute>, Error> {
+    _check_admin()?;
+    Ok(DISPUTES.with(|d| {
+        d.borrow()
+            .iter()
+            .map(|(_, dispute)| dispute)
+            .filter(|dispute| is_open(dispute))
+            .collect()
+    }))
+}
+
+// Closes a dispute with or without a refund, records the closing message and tells both
+// parties
+fn settle(
+    mut dispute: Dispute,
+    role: DisputeRole,
+    refund: bool,
+    message: Option<String>,
+) -> Result<DisputeThread, Error> {
+    let message = message.map(validate_message).transpose()?;
+    let outcome = if refund {
+        let refund_id = orders::refund(dispute.order_id)?;
+        dispute.status = DisputeStatus::Refunded { refund_id };
+        "the order is being refunded"
+    } else {
+        dispute.status = DisputeStatus::Rejected;
+        "it was rejected"
+    };
+    let text = format!("The dispute about order #{} was closed: {}", dispute.order_id, outcome);
+    if let Some(message) = message {
+        add_message(&dispute, role, message);
+    }
+    dispute.updated_at = time();
+    do_insert_dispute(&dispute);
+    notify_parties(&dispute, role, text);
+    Ok(thread(dispute))
+}
+
+// Notifies the parties other than the one who acted; both of them when it was an admin
+fn notify_parties(dispute: &Dispute, actor: DisputeRole, message: String) {
+    let buyer = dispute.buyer.to_string();
+    let recipients = [(DisputeRole::Buyer, &buyer), (DisputeRole::Organizer, &dispute.organizer)];
+    for (role, recipient) in recipients {
+        if role != actor {
+            notifications::notify(
+                recipient,
+                NotificationKind::Dispute,
+                Some(dispute.event_id),
+                message.clone(),
+            );
+        }
+    }
+}
+
+// Helper function returning the caller's part in a dispute, refusing anyone else
+fn _role_in(dispute: &Dispute) -> Result<DisputeRole, Error> {
+    if dispute.buyer == caller() {
+        Ok(DisputeRole::Buyer)
+    } else if dispute.organizer == caller().to_string() {
+        Ok(DisputeRole::Organizer)
+    } else {
+        _check_admin().map(|_| DisputeRole::Admin)
+    }
+}
+
+fn _get_dispute(dispute_id: u64) -> Result<Dispute, Error> {
+    DISPUTES.with(|d| d.borrow().get(&dispute_id)).ok_or(Error::NotFound {
+        msg: format!("Dispute with id={} not found", dispute_id),
+    })
+}
+
+fn _get_open_dispute(dispute_id: u64) -> Result<Dispute, Error> {
+    let dispute = _get_dispute(dispute_id)?;
+    if !is_open(&dispute) {
+        return Err(Error::InvalidPayload {
+            msg: format!("Dispute with id={} has already been closed", dispute_id),
+        });
+    }
+    Ok(dispute)
+}
+
+fn is_open(dispute: &Dispute) -> bool {
+    matches!(dispute.status, DisputeStatus::AwaitingOrganizer | DisputeStatus::AwaitingBuyer)
+}
+
+fn validate_message(message: String) -> Result<String, Error> {
+    let message = message.trim().to_string();
+    if message.is_empty() || message.chars().count() > MAX_MESSAGE_CHARS {
+        return Err(Error::InvalidPayload {
+            msg: format!("a message must be between 1 and {} characters", MAX_MESSAGE_CHARS),
+        });
+    }
+    Ok(message)
+}
+
+fn snippet(message: &str) -> String {
+    if message.chars().count() <= SNIPPET_CHARS {
+        return message.to_string();
+    }
+    let cut: String = message.chars().take(SNIPPET_CHARS).collect();
+    format!("{}...", cut)
+}
+
+fn add_message(dispute: &Dispute, role: DisputeRole, message: String) {
+    let key = DisputeMessageKey {
+        dispute_id: dispute.id,
+        id: messages_of(dispute.id).len() as u64,
+    };
+    let message = DisputeMessage {
+        author: caller(),
+        role,
+        message,
+        sent_at: time(),
+    };
+    DISPUTE_MESSAGES.with(|m| m.borrow_mut().insert(key, message));
+}
+
+fn messages_of(dispute_id: u64) -> Vec<DisputeMessage> {
+    let start = DisputeMessageKey { dispute_id, id: 0 };
+    DISPUTE_MESSAGES.with(|m| {
+        m.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.dispute_id == dispute_id)
+            .map(|(_, message)| message)
+            .collect()
+    })
+}
+
+fn thread(dispute: Dispute) -> DisputeThread {
+    DisputeThread {
+        messages: messages_of(dispute.id),
+        dispute,
+    }
+}
+
+fn do_insert_dispute(dispute: &Dispute) {
+    DISPUTES.with(|d| d.borrow_mut().insert(dispute.id, dispute.clone()));
+}
@@ -0,0 +1,128 @@
+// Duplicate event detection. Every event is indexed by a fingerprint of its owner,
+// normalized title, start day and normalized location, so a second submission of the
+// same event (a double click, a retried call) can be caught and pointed at the original.
+use crate::shard;
+use crate::{Error, Event, EventPayload, Memory, MEMORY_MANAGER, _get_event};
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct FingerprintKey {
+    owner: String,
+    fingerprint: String,
+}
+
+impl_storable!(FingerprintKey, 192);
+
+thread_local! {
+    static FINGERPRINTS: RefCell<StableBTreeMap<FingerprintKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+
+    // Reverse index so an event's old fingerprint can be dropped when it changes
+    static EVENT_FINGERPRINTS: RefCell<StableBTreeMap<u64, FingerprintKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+    ));
+}
+
+// Query function returning the id of an existing event of the caller that the payload
+// would duplicate, so clients can warn before creating it
+#[ic_cdk::query]
+fn find_duplicate_event(payload: EventPayload) -> Option<u64> {
+    _find_duplicate(&payload)
+}
+
+// Helper function to reject a payload duplicating one of the caller's events, unless the
+// payload explicitly allows duplicates
+pub(crate) fn _check_duplicate(payload: &EventPayload) -> Result<(), Error> {
+    if payload.allow_duplicate.unwrap_or(false) {
+        return Ok(());
+    }
+    match _find_duplicate(payload) {
+        Some(existing_id) => Err(Error::DuplicateEvent {
+            msg: format!("You already created this event with id={}", existing_id),
+            existing_id,
+        }),
+        None => Ok(()),
+    }
+}
+
+// Indexes an event under its current fingerprint, replacing any previous one
+pub(crate) fn remember(event: &Event) {
+    forget(event.id);
+    let key = FingerprintKey {
+        owner: event.owner.clone(),
+        fingerprint: fingerprint(&event.event_title, event.starts_at, &event.event_location),
+    };
+    FINGERPRINTS.with(|f| f.borrow_mut().insert(key.clone(), event.id));
+    EVENT_FINGERPRINTS.with(|f| f.borrow_mut().insert(event.id, key));
+}
+
+// Drops a removed event from the index
+pub(crate) fn forget(id: u64) {
+    if let Some(key) = EVENT_FINGERPRINTS.with(|f| f.borrow_mut().remove(&id)) {
+        FINGERPRINTS.with(|f| f.borrow_mut().remove(&key));
+    }
+}
+
+// Events the fingerprint index points to, and events with a reverse entry
+pub(crate) fn indexed_events() -> (Vec<u64>, Vec<u64>) {
+    let forward = FINGERPRINTS.with(|f| f.borrow().iter().map(|(_, id)| id).collect());
+    let reverse = EVENT_FINGERPRINTS.with(|f| f.borrow().iter().map(|(id, _)| id).collect());
+    (forward, reverse)
+}
+
+// Drops the index entries of every event but those kept, ahead of a rebuild
+pub(crate) fn clear_index(keep: impl Fn(u64) -> bool) {
+    FINGERPRINTS.with(|f| {
+        let mut f = f.borrow_mut();
+        let dropped: Vec<FingerprintKey> = f
+            .iter()
+            .filter(|(_, id)| !keep(*id))
+            .map(|(key, _)| key)
+            .collect();
+        for key in dropped {
+            f.remove(&key);
+        }
+    });
+    EVENT_FINGERPRINTS.with(|f| {
+        let mut f = f.borrow_mut();
+        let dropped: Vec<u64> = f.iter().map(|(id, _)| id).filter(|id| !keep(*id)).collect();
+        for id in dropped {
+            f.remove(&id);
+        }
+    });
+}
+
+fn _find_duplicate(payload: &EventPayload) -> Option<u64> {
+    let key = FingerprintKey {
+        owner: caller().to_string(),
+        fingerprint: fingerprint(&payload.event_title, payload.starts_at, &payload.event_location),
+    };
+    FINGERPRINTS
+        .with(|f| f.borrow().get(&key))
+        .filter(|id| shard::bucket_of(*id).is_some() || _get_event(id).is_some())
+}
+
+// Hash of the normalized title, start day and location. Case, punctuation and spacing
+// are ignored, and any two start times on the same UTC day are treated as equal.
+fn fingerprint(title: &str, starts_at: Option<u64>, location: &str) -> String {
+    let day = starts_at.map(|t| (t / NANOS_PER_DAY).to_string()).unwrap_or_default();
+    let digest = Sha256::digest(format!("{}|{}|{}", normalize(title), day, normalize(location)));
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
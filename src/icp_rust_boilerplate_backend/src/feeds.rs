@@ -0,0 +1,132 @@
+// RSS feed of new events at /feed.xml, newest first, so aggregators and chat bots can pick
+// up new events without speaking candid. `/feed.xml?tag=music` narrows it to one tag.
+// `/events.json` lists the same events as JSON, beyond the feed's item limit, a page at a
+// time. Only published events stored on this canister are listed, newest id first.
+use crate::http::{escape_xml, HttpResponse, PageCursor, UtcDateTime, PAGE_BYTES};
+use crate::{access, pages, status, tags};
+use crate::{Event, STORAGE};
+use ic_cdk::api::id;
+
+const MAX_FEED_ITEMS: usize = 50;
+const MAX_JSON_EVENTS: u64 = 10_000;
+// Keeps rendering a page of the listing within a query's instruction limit
+const JSON_EVENTS_PER_PAGE: u64 = 500;
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Renders the feed of recently created events, optionally limited to a tag
+pub(crate) fn new_events_feed(tag: Option<&str>) -> HttpResponse {
+    let events: Vec<Event> = listed_events(tag, u64::MAX).take(MAX_FEED_ITEMS).collect();
+
+    let site = format!("https://{}.raw.icp0.io/", id());
+    let title = match tag {
+        Some(tag) => format!("New events tagged \"{}\"", tag),
+        None => "New events".to_string(),
+    };
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<rss version=\"2.0\">\n<channel>\n");
+    xml.push_str(&format!("<title>{}</title>\n", escape_xml(&title)));
+    xml.push_str(&format!("<link>{}</link>\n", site));
+    xml.push_str("<description>Events recently published on ICP-TIME</description>\n");
+    if let Some(event) = events.first() {
+        xml.push_str(&format!("<lastBuildDate>{}</lastBuildDate>\n", rfc822(event.created_at)));
+    }
+    for event in &events {
+        xml.push_str(&item(event));
+    }
+    xml.push_str("</channel>\n</rss>\n");
+    HttpResponse::ok("application/rss+xml; charset=utf-8", xml)
+}
+
+// Renders a page of the listed events as part of a JSON array, newest first, optionally
+// limited to a tag. The first page opens the array and the last one closes it; a page
+// picks up below the id of the last event sent.
+pub(crate) fn events_json(tag: Option<&str>, cursor: Option<&PageCursor>) -> HttpResponse {
+    let (before, mut sent) = match cursor {
+        Some(cursor) => (cursor.after.parse().unwrap_or_default(), cursor.sent),
+        None => (u64::MAX, 0),
+    };
+    let mut json = String::new();
+    if cursor.is_none() {
+        json.push('[');
+    }
+    let mut on_page = 0;
+    let mut last = before;
+    let mut next = None;
+    for event in listed_events(tag, before) {
+        if sent >= MAX_JSON_EVENTS {
+            break;
+        }
+        if on_page == JSON_EVENTS_PER_PAGE || json.len() >= PAGE_BYTES {
+            next = Some(PageCursor {
+                after: last.to_string(),
+                sent,
+            });
+            break;
+        }
+        let Ok(item) = serde_json::to_string(&event) else {
+            continue;
+        };
+        if sent > 0 {
+            json.push(',');
+        }
+        json.push_str(&item);
+        sent += 1;
+        on_page += 1;
+        last = event.id;
+    }
+    if next.is_none() {
+        json.push(']');
+    }
+    HttpResponse::ok("application/json", json).paged(next)
+}
+
+// Published, listed events stored on this canister with ids below `before`, newest first.
+// Events are read one at a time, so taking a few doesn't read them all.
+fn listed_events(tag: Option<&str>, before: u64) -> impl Iterator<Item = Event> {
+    let mut before = before;
+    std::iter::from_fn(move || {
+        let (id, event) = STORAGE.with(|s| s.borrow().iter_upper_bound(&before).next())?;
+        before = id;
+        Some(event)
+    })
+    .filter(|event| status::is_published(event.id) && !access::is_unlisted(event.id))
+    .filter(move |event| tag.is_none_or(|tag| tags::has_tag(event, tag)))
+}
+
+fn item(event: &Event) -> String {
+    let mut item = String::from("<item>\n");
+    item.push_str(&format!("<title>{}</title>\n", escape_xml(&event.event_title)));
+    item.push_str(&format!("<link>{}</link>\n", pages::event_url(event)));
+    item.push_str(&format!(
+        "<guid isPermaLink=\"false\">event-{}@{}</guid>\n",
+        event.id,
+        id()
+    ));
+    item.push_str(&format!("<pubDate>{}</pubDate>\n", rfc822(event.created_at)));
+    item.push_str(&format!(
+        "<description>{}</description>\n",
+        escape_xml(&event.event_description)
+    ));
+    for tag in event.tags.iter().flatten() {
+        item.push_str(&format!("<category>{}</category>\n", escape_xml(tag)));
+    }
+    item.push_str("</item>\n");
+    item
+}
+
+fn rfc822(nanos: u64) -> String {
+    let t = UtcDateTime::from_nanos(nanos);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[t.weekday as usize],
+        t.day,
+        MONTHS[t.month as usize - 1],
+        t.year,
+        t.hour,
+        t.minute,
+        t.second
+    )
+}
@@ -0,0 +1,266 @@
+// Approximate fiat prices for paid events. Ticket prices are set in the event's main
+// token; to show attendees what a ticket costs, the token is priced in USD and EUR through
+// the Exchange Rate Canister (XRC). Every XRC call costs cycles, so rates are cached for a
+// few minutes and only refreshed when someone asks for prices after they went stale.
+use crate::{ledger, metrics, tiers, tokens};
+use crate::{Error, Memory, PrincipalKey, MEMORY_MANAGER, _get_event};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::api::call::call_with_payment128;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const XRC_CANISTER: &str = "uxrrr-q7777-77774-qaaaq-cai";
+// XRC charges 1B cycles per request and refunds what it doesn't use
+const XRC_CYCLES: u128 = 1_000_000_000;
+const RATE_TTL_NANOS: u64 = 10 * 60 * 1_000_000_000;
+const TOKEN_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const QUOTE_CURRENCIES: [&str; 2] = ["USD", "EUR"];
+const MAX_SYMBOL_CHARS: usize = 16;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct RateKey {
+    base: String,
+    quote: String,
+}
+
+impl_storable!(RateKey, 64);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CachedRate {
+    // Price of one base unit in the quote currency, scaled by 10^decimals
+    rate: u64,
+    decimals: u32,
+    // When XRC computed the rate, and when it was fetched
+    rate_timestamp: u64,
+    fetched_at: u64,
+}
+
+impl_storable!(CachedRate, 64);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LedgerToken {
+    symbol: String,
+    decimals: u8,
+    fetched_at: u64,
+}
+
+impl_storable!(LedgerToken, 64);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct FiatPrice {
+    currency: String,
+    // Rounded to cents
+    amount: f64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TierFiatPrice {
+    tier_id: u64,
+    tier_name: String,
+    // Price in the token's smallest unit, as charged
+    price: u128,
+    fiat: Vec<FiatPrice>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct EventFiatPrices {
+    event_id: u64,
+    token_symbol: String,
+    token_decimals: u8,
+    tiers: Vec<TierFiatPrice>,
+    // Timestamp (in seconds) of the oldest exchange rate used; None when no rate was available
+    rates_as_of: Option<u64>,
+}
+
+// Exchange Rate Canister interface, as far as it is used here
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum AssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Asset {
+    symbol: String,
+    class: AssetClass,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct GetExchangeRateRequest {
+    base_asset: Asset,
+    quote_asset: Asset,
+    timestamp: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ExchangeRateMetadata {
+    decimals: u32,
+    base_asset_num_received_rates: u64,
+    base_asset_num_queried_sources: u64,
+    quote_asset_num_received_rates: u64,
+    quote_asset_num_queried_sources: u64,
+    standard_deviation: u64,
+    forex_timestamp: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ExchangeRate {
+    base_asset: Asset,
+    quote_asset: Asset,
+    timestamp: u64,
+    rate: u64,
+    metadata: ExchangeRateMetadata,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum ExchangeRateError {
+    AnonymousPrincipalNotAllowed,
+    Pending,
+    CryptoBaseAssetNotFound,
+    CryptoQuoteAssetNotFound,
+    StablecoinRateNotFound,
+    StablecoinRateTooFewRates,
+    StablecoinRateZeroRate,
+    ForexInvalidTimestamp,
+    ForexBaseAssetNotFound,
+    ForexQuoteAssetNotFound,
+    ForexAssetsNotFound,
+    RateLimited,
+    NotEnoughCycles,
+    FailedToAcceptCycles,
+    InconsistentRatesReceived,
+    Other { code: u32, description: String },
+}
+
+thread_local! {
+    static RATES: RefCell<StableBTreeMap<RateKey, CachedRate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(86)))
+    ));
+
+    // Symbol and decimals of each payment ledger's token, by ledger principal
+    static LEDGER_TOKENS: RefCell<StableBTreeMap<PrincipalKey, LedgerToken, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(87)))
+    ));
+}
+
+// Update function returning an event's ticket tier prices with their approximate USD and EUR
+// value. It is an update so stale exchange rates can be refreshed from XRC; a currency whose
+// rate can't be fetched is left out.
+#[ic_cdk::update]
+async fn get_fiat_prices(event_id: u64) -> Result<EventFiatPrices, Error> {
+    let _metrics = metrics::track("get_fiat_prices");
+    _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    let ledger = tokens::main_ledger(event_id).ok_or(Error::InvalidPayload {
+        msg: "no payment ledger has been configured".to_string(),
+    })?;
+    let token = _ledger_token(ledger).await?;
+
+    let mut rates: Vec<(&str, CachedRate)> = Vec::new();
+    for quote in QUOTE_CURRENCIES {
+        if let Some(rate) = _rate(&token.symbol, quote).await {
+            rates.push((quote, rate));
+        }
+    }
+    let tiers = tiers::prices_of(event_id)
+        .into_iter()
+        .map(|(tier_id, tier_name, price)| TierFiatPrice {
+            tier_id,
+            tier_name,
+            price,
+            fiat: rates
+                .iter()
+                .map(|(currency, rate)| FiatPrice {
+                    currency: currency.to_string(),
+                    amount: convert(price, token.decimals, rate),
+                })
+                .collect(),
+        })
+        .collect();
+    Ok(EventFiatPrices {
+        event_id,
+        token_symbol: token.symbol,
+        token_decimals: token.decimals,
+        tiers,
+        rates_as_of: rates.iter().map(|(_, rate)| rate.rate_timestamp).min(),
+    })
+}
+
+// Helper function returning the ledger's token, asking the ledger once a day
+async fn _ledger_token(ledger: Principal) -> Result<LedgerToken, Error> {
+    let key = PrincipalKey {
+        principal: ledger.to_string(),
+    };
+    let cached = LEDGER_TOKENS.with(|t| t.borrow().get(&key));
+    if let Some(token) = cached.filter(|token| time() - token.fetched_at < TOKEN_TTL_NANOS) {
+        return Ok(token);
+    }
+    let (symbol, decimals) = ledger::token(ledger)
+        .await
+        .map_err(|msg| Error::CallFailed { msg })?;
+    let token = LedgerToken {
+        symbol: symbol.trim().chars().take(MAX_SYMBOL_CHARS).collect(),
+        decimals,
+        fetched_at: time(),
+    };
+    LEDGER_TOKENS.with(|t| t.borrow_mut().insert(key, token.clone()));
+    Ok(token)
+}
+
+// Helper function returning the token's rate in the quote currency, from the cache while it
+// is fresh. A stale rate is still used when XRC can't be reached.
+async fn _rate(symbol: &str, quote: &str) -> Option<CachedRate> {
+    let base = xrc_symbol(symbol);
+    let key = RateKey {
+        base: base.clone(),
+        quote: quote.to_string(),
+    };
+    let cached = RATES.with(|r| r.borrow().get(&key));
+    if let Some(rate) = cached.as_ref().filter(|rate| time() - rate.fetched_at < RATE_TTL_NANOS) {
+        return Some(rate.clone());
+    }
+    let request = GetExchangeRateRequest {
+        base_asset: Asset {
+            symbol: base,
+            class: AssetClass::Cryptocurrency,
+        },
+        quote_asset: Asset {
+            symbol: quote.to_string(),
+            class: AssetClass::FiatCurrency,
+        },
+        timestamp: None,
+    };
+    let xrc = Principal::from_text(XRC_CANISTER).expect("the XRC canister id is valid");
+    let result: Result<(Result<ExchangeRate, ExchangeRateError>,), _> =
+        call_with_payment128(xrc, "get_exchange_rate", (request,), XRC_CYCLES).await;
+    let Ok((Ok(exchange_rate),)) = result else {
+        return cached;
+    };
+    let rate = CachedRate {
+        rate: exchange_rate.rate,
+        decimals: exchange_rate.metadata.decimals,
+        rate_timestamp: exchange_rate.timestamp,
+        fetched_at: time(),
+    };
+    RATES.with(|r| r.borrow_mut().insert(key, rate.clone()));
+    Some(rate)
+}
+
+// Chain-key tokens are priced as the asset they wrap, e.g. ckBTC as BTC
+fn xrc_symbol(symbol: &str) -> String {
+    match symbol.strip_prefix("ck") {
+        Some(wrapped) if !wrapped.is_empty() => wrapped.to_uppercase(),
+        _ => symbol.to_uppercase(),
+    }
+}
+
+fn convert(amount: u128, token_decimals: u8, rate: &CachedRate) -> f64 {
+    let tokens = amount as f64 / 10f64.powi(token_decimals as i32);
+    let value = tokens * rate.rate as f64 / 10f64.powi(rate.decimals as i32);
+    (value * 100.0).round() / 100.0
+}
@@ -0,0 +1,116 @@
+// Feature flags. Admins can switch risky features off at runtime, or roll them out to a
+// share of principals, without an upgrade; gated code paths call `_check_enabled` before
+// doing anything. Features are on for everyone until a flag says otherwise, and admins are
+// always let through so they can try a feature before rolling it out.
+use crate::admin::_check_admin;
+use crate::{metrics, Error, Memory, MEMORY_MANAGER};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Cell as StableCell;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub(crate) enum FlaggedFeature {
+    // Ticket purchases
+    Payments,
+    // Event questions and reviews
+    Comments,
+    // Listing and buying resale tickets
+    Resale,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FeatureFlag {
+    feature: FlaggedFeature,
+    enabled: bool,
+    // Share of principals the feature is enabled for, from 0 to 100; None for everyone
+    rollout_percent: Option<u8>,
+    updated_at: u64,
+    updated_by: Principal,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct FlagConfig {
+    flags: Vec<FeatureFlag>,
+}
+
+impl_storable!(FlagConfig, 2048);
+
+thread_local! {
+    static FLAG_CONFIG: RefCell<StableCell<FlagConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(123))), FlagConfig::default())
+            .expect("Cannot create the feature flag cell")
+    );
+}
+
+// Query function listing the features that have a flag set
+#[ic_cdk::query]
+fn get_feature_flags() -> Vec<FeatureFlag> {
+    FLAG_CONFIG.with(|c| c.borrow().get().flags.clone())
+}
+
+// Query function telling whether a feature is enabled for the caller
+#[ic_cdk::query]
+fn is_feature_enabled(feature: FlaggedFeature) -> bool {
+    _check_enabled(feature).is_ok()
+}
+
+// Update function for admins to switch a feature on or off, optionally for only a share of
+// principals
+#[ic_cdk::update]
+fn set_feature_flag(
+    feature: FlaggedFeature,
+    enabled: bool,
+    rollout_percent: Option<u8>,
+) -> Result<FeatureFlag, Error> {
+    let _metrics = metrics::track("set_feature_flag");
+    _check_admin()?;
+    if rollout_percent.is_some_and(|percent| percent > 100) {
+        return Err(Error::InvalidPayload {
+            msg: "a rollout must be between 0 and 100 percent".to_string(),
+        });
+    }
+    let flag = FeatureFlag {
+        feature,
+        enabled,
+        rollout_percent,
+        updated_at: time(),
+        updated_by: caller(),
+    };
+    FLAG_CONFIG.with(|c| {
+        let mut config = c.borrow().get().clone();
+        config.flags.retain(|existing| existing.feature != feature);
+        config.flags.push(flag.clone());
+        c.borrow_mut()
+            .set(config)
+            .expect("cannot update the feature flags");
+    });
+    Ok(flag)
+}
+
+// Helper function refusing a call into a feature that is switched off for the caller
+pub(crate) fn _check_enabled(feature: FlaggedFeature) -> Result<(), Error> {
+    let Some(flag) = get_feature_flags().into_iter().find(|flag| flag.feature == feature) else {
+        return Ok(());
+    };
+    let enabled = flag.enabled
+        && flag
+            .rollout_percent
+            .is_none_or(|percent| rollout_bucket(feature, &caller()) < percent);
+    if enabled || _check_admin().is_ok() {
+        return Ok(());
+    }
+    Err(Error::FeatureDisabled {
+        msg: format!("{:?} is currently unavailable", feature),
+    })
+}
+
+// Stable bucket from 0 to 99 of a principal for a feature, so a growing rollout keeps the
+// principals it already included
+fn rollout_bucket(feature: FlaggedFeature, principal: &Principal) -> u8 {
+    let digest = Sha256::digest(format!("{:?}|{}", feature, principal));
+    (u16::from_be_bytes([digest[0], digest[1]]) % 100) as u8
+}
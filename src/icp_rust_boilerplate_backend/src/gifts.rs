@@ -0,0 +1,318 @@
+// Ticket gifting. The holder of a ticket, or the buyer of a group with tickets left to hand
+// out, can offer one to another user with `gift_ticket`. Nothing moves until the recipient
+// accepts; the attendee record, the purchase and any reserved seat then change hands in one
+// call. Both sides are notified at each step, and the giver can take a pending gift back.
+// The payment stays with the giver, so refunds still go to whoever paid.
+use crate::notifications::{self, NotificationKind};
+use crate::status::_check_open_for_registration;
+use crate::{attendees, bans, groups, metrics, orders, resale, tickets, tiers};
+use crate::{Error, EventPrincipalKey, IdCell, Memory, MEMORY_MANAGER, _get_event};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum GiftStatus {
+    Pending,
+    Accepted { at: u64 },
+    Declined { at: u64 },
+    Cancelled { at: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Gift {
+    id: u64,
+    order_id: u64,
+    event_id: u64,
+    from: Principal,
+    to: Principal,
+    // Ticket of a group order set aside for the gift; None for the giver's own ticket
+    group_index: Option<u32>,
+    created_at: u64,
+    status: GiftStatus,
+}
+
+impl_storable!(Gift, 256);
+
+// Gifts of an order, in the order they were offered
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct OrderGiftKey {
+    order_id: u64,
+    id: u64,
+}
+
+impl_storable!(OrderGiftKey, 48);
+
+// Gifts a principal gave or received, in the order they were offered
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct PrincipalGiftKey {
+    principal: String,
+    id: u64,
+}
+
+impl_storable!(PrincipalGiftKey, 128);
+
+thread_local! {
+    static GIFT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(159))), 0)
+            .expect("Cannot create a gift counter")
+    );
+
+    static GIFTS: RefCell<StableBTreeMap<u64, Gift, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(160)))
+    ));
+
+    static ORDER_GIFTS: RefCell<StableBTreeMap<OrderGiftKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(180)))
+    ));
+
+    static PRINCIPAL_GIFTS: RefCell<StableBTreeMap<PrincipalGiftKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(181)))
+    ));
+}
+
+// Query function listing the gifts the caller gave or received, newest first
+#[ic_cdk::query]
+fn get_my_gifts() -> Vec<Gift> {
+    let principal = caller().to_string();
+    let start = PrincipalGiftKey {
+        principal: principal.clone(),
+        id: 0,
+    };
+    let ids: Vec<u64> = PRINCIPAL_GIFTS.with(|p| {
+        p.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.principal == principal)
+            .map(|(key, _)| key.id)
+            .collect()
+    });
+    GIFTS.with(|g| {
+        let gifts = g.borrow();
+        ids.into_iter().rev().filter_map(|id| gifts.get(&id)).collect()
+    })
+}
+
+// Update function offering the ticket of an order to another user: the caller's own ticket,
+// or one of the unassigned tickets of their group order. The recipient has to accept it.
+#[ic_cdk::update]
+fn gift_ticket(order_id: u64, to: Principal) -> Result<Gift, Error> {
+    let _metrics = metrics::track("gift_ticket");
+    let from = caller();
+    if to == from || to == Principal::anonymous() || bans::is_banned(&to) {
+        return Err(Error::InvalidPayload {
+            msg: "tickets can't be given to this principal".to_string(),
+        });
+    }
+    let order = orders::_get_held_order(order_id, from)?;
+    let event_id = order.event_id;
+    _check_open_for_registration(event_id)?;
+    if pending_for(order_id, from) && !order.is_group() {
+        return Err(Error::InvalidPayload {
+            msg: "This ticket is already offered as a gift".to_string(),
+        });
+    }
+    let event = _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    if attendees::is_attending(event.id, &to.to_string()) {
+        return Err(Error::InvalidPayload {
+            msg: format!("{} already attends this event", to),
+        });
+    }
+    if !order.is_group() {
+        _check_giftable(&holder_key(event_id, from))?;
+    }
+
+    let id = GIFT_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment gift id counter");
+    let group_index = if order.is_group() {
+        Some(groups::_reserve_for_gift(order_id, id)?)
+    } else {
+        None
+    };
+    let gift = Gift {
+        id,
+        order_id,
+        event_id,
+        from,
+        to,
+        group_index,
+        created_at: time(),
+        status: GiftStatus::Pending,
+    };
+    GIFTS.with(|g| g.borrow_mut().insert(id, gift.clone()));
+    index_gift(&gift);
+    notifications::notify(
+        &to.to_string(),
+        NotificationKind::TicketGift,
+        Some(event_id),
+        format!(
+            "{} wants to give you a ticket to \"{}\"; accept gift {} to attend",
+            from, event.event_title, id
+        ),
+    );
+    notifications::notify(
+        &from.to_string(),
+        NotificationKind::TicketGift,
+        Some(event_id),
+        format!("Your ticket to \"{}\" was offered to {}", event.event_title, to),
+    );
+    Ok(gift)
+}
+
+// Update function for the recipient of a gift to accept it, registering them for the event
+// in place of the giver
+#[ic_cdk::update]
+fn accept_gift(id: u64) -> Result<Gift, Error> {
+    let _metrics = metrics::track("accept_gift");
+    let mut gift = _get_pending_gift(id, |gift| gift.to == caller())?;
+    _check_open_for_registration(gift.event_id)?;
+    match gift.group_index {
+        Some(index) => {
+            groups::_hand_out_gift(gift.order_id, index, gift.id, gift.to)?;
+        }
+        None => {
+            let from = holder_key(gift.event_id, gift.from);
+            let to = holder_key(gift.event_id, gift.to);
+            // The giver may have used, listed or lost the ticket since offering it
+            orders::_get_held_order(gift.order_id, gift.from)?;
+            _check_giftable(&from)?;
+            resale::swap_attendee(gift.event_id, &from, &to)?;
+            tiers::give_purchase(&from, to);
+            orders::release_holder(&from);
+        }
+    }
+    gift.status = GiftStatus::Accepted { at: time() };
+    GIFTS.with(|g| g.borrow_mut().insert(id, gift.clone()));
+    notify_giver(&gift, "accepted");
+    Ok(gift)
+}
+
+// Update function for the recipient of a gift to turn it down
+#[ic_cdk::update]
+fn decline_gift(id: u64) -> Result<Gift, Error> {
+    let _metrics = metrics::track("decline_gift");
+    let gift = _get_pending_gift(id, |gift| gift.to == caller())?;
+    let gift = close(gift, GiftStatus::Declined { at: time() });
+    notify_giver(&gift, "declined");
+    Ok(gift)
+}
+
+// Update function for the giver to take back a gift that hasn't been accepted yet
+#[ic_cdk::update]
+fn cancel_gift(id: u64) -> Result<Gift, Error> {
+    let _metrics = metrics::track("cancel_gift");
+    let gift = _get_pending_gift(id, |gift| gift.from == caller())?;
+    let gift = close(gift, GiftStatus::Cancelled { at: time() });
+    notifications::notify(
+        &gift.to.to_string(),
+        NotificationKind::TicketGift,
+        Some(gift.event_id),
+        format!("{} took back the ticket gift {}", gift.from, gift.id),
+    );
+    Ok(gift)
+}
+
+fn _get_pending_gift(id: u64, involves: impl Fn(&Gift) -> bool) -> Result<Gift, Error> {
+    GIFTS
+        .with(|g| g.borrow().get(&id))
+        .filter(|gift| involves(gift) && gift.status == GiftStatus::Pending)
+        .ok_or(Error::NotFound {
+            msg: format!("Pending gift with id={} not found", id),
+        })
+}
+
+// Helper function rejecting the gift of a ticket that was used at the door or is up for resale
+fn _check_giftable(key: &EventPrincipalKey) -> Result<(), Error> {
+    if tickets::is_checked_in(key) {
+        return Err(Error::InvalidPayload {
+            msg: "A ticket that was used at the door can't be given".to_string(),
+        });
+    }
+    if resale::is_listed(key) {
+        return Err(Error::InvalidPayload {
+            msg: "Cancel the resale listing of this ticket before giving it".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Ends a pending gift, giving a group ticket set aside for it back to the group
+fn close(mut gift: Gift, status: GiftStatus) -> Gift {
+    if let Some(index) = gift.group_index {
+        groups::release_gift(gift.order_id, index);
+    }
+    gift.status = status;
+    GIFTS.with(|g| g.borrow_mut().insert(gift.id, gift.clone()));
+    gift
+}
+
+fn notify_giver(gift: &Gift, outcome: &str) {
+    notifications::notify(
+        &gift.from.to_string(),
+        NotificationKind::TicketGift,
+        Some(gift.event_id),
+        format!("{} {} your ticket gift {}", gift.to, outcome, gift.id),
+    );
+}
+
+// Whether the holder already offered the ticket of an order
+fn pending_for(order_id: u64, from: Principal) -> bool {
+    let start = OrderGiftKey { order_id, id: 0 };
+    let ids: Vec<u64> = ORDER_GIFTS.with(|o| {
+        o.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.order_id == order_id)
+            .map(|(key, _)| key.id)
+            .collect()
+    });
+    ids.into_iter()
+        .filter_map(|id| GIFTS.with(|g| g.borrow().get(&id)))
+        .any(|gift| gift.from == from && gift.status == GiftStatus::Pending)
+}
+
+fn index_gift(gift: &Gift) {
+    let key = OrderGiftKey {
+        order_id: gift.order_id,
+        id: gift.id,
+    };
+    ORDER_GIFTS.with(|o| o.borrow_mut().insert(key, ()));
+    for principal in [gift.from, gift.to] {
+        let key = PrincipalGiftKey {
+            principal: principal.to_string(),
+            id: gift.id,
+        };
+        PRINCIPAL_GIFTS.with(|p| p.borrow_mut().insert(key, ()));
+    }
+}
+
+// Indexes gifts offered before the indexes existed; called from post_upgrade
+pub(crate) fn migrate() {
+    let unindexed = ORDER_GIFTS.with(|o| o.borrow().is_empty())
+        && GIFTS.with(|g| !g.borrow().is_empty());
+    if !unindexed {
+        return;
+    }
+    GIFTS.with(|g| {
+        for (_, gift) in g.borrow().iter() {
+            index_gift(&gift);
+        }
+    });
+}
+
+fn holder_key(event_id: u64, principal: Principal) -> EventPrincipalKey {
+    EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    }
+}
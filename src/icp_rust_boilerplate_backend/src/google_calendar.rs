@@ -0,0 +1,605 @@
+// Google Calendar push. A user's frontend completes Google's OAuth flow and hands the
+// canister an access token; from then on events the user organizes or attends are pushed to
+// their calendar, and updated or removed there when they change or are deleted.
+//
+// Pushes are queued and sent by a timer. Every replica makes the same outcall, so calendar
+// entries use a fixed id derived from the event and writes are idempotent: an update is
+// tried first, an insert of an entry that already exists counts as success, and the
+// transform keeps only the status.
+//
+// Users who also hand over a refresh token get their access token renewed by the canister,
+// through the OAuth client an admin configures. Google returns a different access token to
+// every caller, so replicas making the same refresh would never agree; the refresh is a
+// non-replicated outcall, made by a single replica whose response the others accept. Without
+// a refresh token, or once a refresh is refused, the connection is flagged, pushes wait, and
+// the frontend submits a fresh token with `connect_google_calendar`.
+//
+// Tokens are stored encrypted (see the secrets module). Connections live on the router,
+// so they cover events stored in buckets too.
+use crate::admin::_check_admin;
+use crate::http::format_rfc3339;
+use crate::logs::{self, LogLevel};
+use crate::secrets::{self, Sealed};
+use crate::sms::form_encode;
+use crate::{Error, Event, EventPrincipalKey, Memory, PrincipalKey, MEMORY_MANAGER, _fetch_event};
+use crate::clock::time;
+use crate::{attendees, metrics};
+use candid::Principal;
+use ic_cdk::api::call::call_with_payment128;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell as StableCell, StableBTreeMap};
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+const CALENDAR_API: &str = "https://www.googleapis.com/calendar/v3/calendars";
+const TOKEN_ENDPOINT: &str = "https://oauth2.googleapis.com/token";
+const SYNC_INTERVAL: Duration = Duration::from_secs(60);
+const SYNC_BATCH_SIZE: usize = 10;
+const SYNC_CYCLES: u128 = 2_000_000_000;
+const SYNC_MAX_RESPONSE_BYTES: u64 = 16 * 1024;
+const MAX_SYNC_ATTEMPTS: u32 = 5;
+const RETRY_BASE_NANOS: u64 = 60 * 1_000_000_000;
+const MAX_TOKEN_CHARS: usize = 2048;
+const MAX_REFRESH_TOKEN_BYTES: usize = 512;
+const MAX_CLIENT_FIELD_BYTES: usize = 256;
+// Access tokens are refreshed this long before they expire
+const REFRESH_MARGIN_NANOS: u64 = 5 * 60 * 1_000_000_000;
+const MAX_CALENDAR_ID_CHARS: usize = 256;
+// Calendar entries of events without an end time last an hour
+const DEFAULT_DURATION_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+// Token payload submitted by the frontend after the OAuth flow
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct GoogleCalendarToken {
+    access_token: String,
+    // Expiry of the access token, in nanoseconds since the epoch
+    expires_at: u64,
+    // Calendar to push to; the user's primary calendar by default
+    calendar_id: Option<String>,
+    // Lets the canister renew the access token once the OAuth client is configured
+    refresh_token: Option<String>,
+}
+
+// OAuth client the frontend obtains tokens with; refreshing needs its credentials
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct GoogleOAuthClient {
+    // Empty disables refreshing
+    client_id: String,
+    client_secret: String,
+}
+
+impl_storable!(GoogleOAuthClient, 1024);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct GoogleCalendarLink {
+    token: Sealed,
+    expires_at: u64,
+    // Links from before refreshing, or made without a refresh token, have none
+    refresh_token: Option<Sealed>,
+    calendar_id: String,
+    // Set when the token expired or was rejected and can't be refreshed; pushes wait for a
+    // new one
+    needs_new_token: bool,
+    last_error: Option<String>,
+    connected_at: u64,
+}
+
+impl_storable!(GoogleCalendarLink, 4096);
+
+// What a user sees of their connection; the token itself is never returned
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct GoogleCalendarConnection {
+    calendar_id: String,
+    expires_at: u64,
+    refreshable: bool,
+    needs_new_token: bool,
+    last_error: Option<String>,
+    pending_pushes: u64,
+    connected_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SyncJob {
+    attempts: u32,
+    next_attempt_at: u64,
+}
+
+impl_storable!(SyncJob, 32);
+
+// Outcome of one push
+enum Push {
+    Done,
+    TokenRejected,
+    Failed(String),
+}
+
+thread_local! {
+    static CALENDAR_LINKS: RefCell<StableBTreeMap<PrincipalKey, GoogleCalendarLink, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68)))
+    ));
+
+    // Events waiting to be pushed to a user's calendar
+    static SYNC_QUEUE: RefCell<StableBTreeMap<EventPrincipalKey, SyncJob, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69)))
+    ));
+
+    static OAUTH_CLIENT: RefCell<StableCell<GoogleOAuthClient, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(171))), GoogleOAuthClient::default())
+            .expect("Cannot create the Google OAuth client cell")
+    );
+
+    // Guards against two timer runs pushing the same events concurrently
+    static SYNCING: Cell<bool> = const { Cell::new(false) };
+}
+
+// The management canister's http_request argument with the flag asking for a single replica
+// to make the request, which this CDK's type doesn't carry yet
+#[derive(candid::CandidType)]
+struct SingleReplicaRequest {
+    url: String,
+    max_response_bytes: Option<u64>,
+    method: HttpMethod,
+    headers: Vec<HttpHeader>,
+    body: Option<Vec<u8>>,
+    transform: Option<TransformContext>,
+    is_replicated: Option<bool>,
+}
+
+// The part of Google's token response the canister uses
+#[derive(Deserialize)]
+struct RefreshedToken {
+    access_token: String,
+    // Lifetime of the access token, in seconds
+    expires_in: u64,
+}
+
+// Starts the periodic push run; called from init and post_upgrade
+pub(crate) fn start_calendar_sync() {
+    ic_cdk_timers::set_timer_interval(SYNC_INTERVAL, || ic_cdk::spawn(sync_calendars()));
+}
+
+// Query function for admins returning the OAuth client tokens are refreshed with, without
+// its secret
+#[ic_cdk::query]
+fn get_google_oauth_client() -> Result<GoogleOAuthClient, Error> {
+    _check_admin()?;
+    let mut client = OAUTH_CLIENT.with(|c| c.borrow().get().clone());
+    if !client.client_secret.is_empty() {
+        client.client_secret = "(set)".to_string();
+    }
+    Ok(client)
+}
+
+// Update function for admins to configure the OAuth client tokens are refreshed with; an
+// empty client id turns refreshing off
+#[ic_cdk::update]
+fn set_google_oauth_client(client: GoogleOAuthClient) -> Result<(), Error> {
+    let _metrics = metrics::track("set_google_oauth_client");
+    _check_admin()?;
+    if client.client_id.len() > MAX_CLIENT_FIELD_BYTES
+        || client.client_secret.len() > MAX_CLIENT_FIELD_BYTES
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "the client id and secret can be at most {} bytes long",
+                MAX_CLIENT_FIELD_BYTES
+            ),
+        });
+    }
+    OAUTH_CLIENT.with(|c| {
+        c.borrow_mut()
+            .set(client)
+            .expect("cannot update the Google OAuth client")
+    });
+    Ok(())
+}
+
+// Update function storing the caller's Google OAuth access token, and optionally a refresh
+// token to renew it with, or replacing an expired one; pushes that were waiting resume
+#[ic_cdk::update]
+async fn connect_google_calendar(token: GoogleCalendarToken) -> Result<GoogleCalendarConnection, Error> {
+    let _metrics = metrics::track("connect_google_calendar");
+    let calendar_id = token
+        .calendar_id
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| "primary".to_string());
+    let refresh_token = token.refresh_token.filter(|refresh_token| !refresh_token.is_empty());
+    if token.access_token.is_empty()
+        || token.access_token.len() > MAX_TOKEN_CHARS
+        || calendar_id.len() > MAX_CALENDAR_ID_CHARS
+        || refresh_token.as_ref().is_some_and(|token| token.len() > MAX_REFRESH_TOKEN_BYTES)
+    {
+        return Err(Error::InvalidPayload {
+            msg: "a token or the calendar id is missing or too long".to_string(),
+        });
+    }
+    if token.expires_at <= time() {
+        return Err(Error::InvalidPayload {
+            msg: "the access token has already expired".to_string(),
+        });
+    }
+    secrets::ensure_key().await?;
+    let principal = caller().to_string();
+    let link = GoogleCalendarLink {
+        token: secrets::seal(token.access_token.as_bytes()),
+        expires_at: token.expires_at,
+        refresh_token: refresh_token.map(|token| secrets::seal(token.as_bytes())),
+        calendar_id,
+        needs_new_token: false,
+        last_error: None,
+        connected_at: time(),
+    };
+    let key = PrincipalKey { principal };
+    CALENDAR_LINKS.with(|l| l.borrow_mut().insert(key.clone(), link.clone()));
+    Ok(connection(&key.principal, link))
+}
+
+// Update function removing the caller's token and any pushes still waiting
+#[ic_cdk::update]
+fn disconnect_google_calendar() -> bool {
+    let _metrics = metrics::track("disconnect_google_calendar");
+    let principal = caller().to_string();
+    let queued: Vec<EventPrincipalKey> = SYNC_QUEUE.with(|q| {
+        q.borrow()
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| key.principal == principal)
+            .collect()
+    });
+    SYNC_QUEUE.with(|q| {
+        let mut queue = q.borrow_mut();
+        for key in queued {
+            queue.remove(&key);
+        }
+    });
+    CALENDAR_LINKS
+        .with(|l| l.borrow_mut().remove(&PrincipalKey { principal }))
+        .is_some()
+}
+
+// Query function returning the state of the caller's Google Calendar connection
+#[ic_cdk::query]
+fn get_google_calendar_connection() -> Option<GoogleCalendarConnection> {
+    let principal = caller().to_string();
+    let link = link_of(&principal)?;
+    Some(connection(&principal, link))
+}
+
+// Keeps only the status, so replicas agree on the outcall result
+#[ic_cdk::query]
+fn transform_calendar_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+// Queues a push of the event to the calendar of its organizer and of every attendee who
+// connected one
+pub(crate) fn enqueue_event(event: &Event) {
+    enqueue(event.id, &event.owner);
+    for attendee in attendees::attendees_of(event.id) {
+        enqueue(event.id, &attendee);
+    }
+}
+
+// Queues a push of the event to one user's calendar, if they connected one
+pub(crate) fn enqueue(event_id: u64, principal: &str) {
+    if link_of(principal).is_none() {
+        return;
+    }
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    let job = SyncJob {
+        attempts: 0,
+        next_attempt_at: time(),
+    };
+    SYNC_QUEUE.with(|q| q.borrow_mut().insert(key, job));
+}
+
+async fn sync_calendars() {
+    if SYNCING.with(|s| s.replace(true)) {
+        return;
+    }
+    let now = time();
+    let due: Vec<(EventPrincipalKey, SyncJob)> = SYNC_QUEUE.with(|q| {
+        q.borrow()
+            .iter()
+            .filter(|(key, job)| {
+                job.next_attempt_at <= now
+                    && link_of(&key.principal).is_some_and(|link| !link.needs_new_token)
+            })
+            .take(SYNC_BATCH_SIZE)
+            .collect()
+    });
+    for (key, job) in due {
+        sync_one(key, job).await;
+    }
+    SYNCING.with(|s| s.set(false));
+}
+
+async fn sync_one(key: EventPrincipalKey, mut job: SyncJob) {
+    let Some(mut link) = link_of(&key.principal) else {
+        SYNC_QUEUE.with(|q| q.borrow_mut().remove(&key));
+        return;
+    };
+    if link.expires_at <= time() + REFRESH_MARGIN_NANOS
+        && let Err(reason) = refresh(&mut link).await
+    {
+        link.last_error = Some(reason);
+        // A token that is still valid keeps being used until it expires
+        if link.expires_at <= time() {
+            save_link(&key.principal, link);
+            return;
+        }
+    }
+    let Some(token) = secrets::open(&link.token).and_then(|token| String::from_utf8(token).ok()) else {
+        SYNC_QUEUE.with(|q| q.borrow_mut().remove(&key));
+        return;
+    };
+
+    // Deleted events are removed from the calendar; the router no longer knows them
+    let outcome = match _fetch_event(key.event_id).await {
+        Ok(event) if event.starts_at.is_some() => push_event(&link.calendar_id, &token, &event).await,
+        Ok(_) => Push::Done,
+        Err(Error::NotFound { .. }) => remove_event(&link.calendar_id, &token, key.event_id).await,
+        Err(_) => Push::Failed("the event could not be loaded".to_string()),
+    };
+
+    // The event may have been queued again while this push was in flight
+    let requeued = SYNC_QUEUE
+        .with(|q| q.borrow().get(&key))
+        .is_some_and(|queued| queued.attempts == 0 && queued.next_attempt_at > job.next_attempt_at);
+    match outcome {
+        Push::Done => {
+            if !requeued {
+                SYNC_QUEUE.with(|q| q.borrow_mut().remove(&key));
+            }
+            link.last_error = None;
+        }
+        // A link with a refresh token is refreshed on the next run, and flagged only if
+        // that fails
+        Push::TokenRejected => {
+            if link.refresh_token.is_some() {
+                link.expires_at = 0;
+            } else {
+                link.needs_new_token = true;
+            }
+            link.last_error = Some("Google rejected the access token".to_string());
+        }
+        Push::Failed(reason) => {
+            let message = format!("sync of event {} failed: {}", key.event_id, reason);
+            logs::write(LogLevel::Warn, module_path!(), message);
+            job.attempts += 1;
+            if job.attempts >= MAX_SYNC_ATTEMPTS {
+                SYNC_QUEUE.with(|q| q.borrow_mut().remove(&key));
+            } else if !requeued {
+                job.next_attempt_at = time() + RETRY_BASE_NANOS * (1 << (job.attempts - 1));
+                SYNC_QUEUE.with(|q| q.borrow_mut().insert(key.clone(), job));
+            }
+            link.last_error = Some(reason);
+        }
+    }
+    save_link(&key.principal, link);
+}
+
+// Renews the link's access token with its refresh token, through a single replica. A link
+// that can't be refreshed is flagged once its token expires; one whose refresh failed for
+// another reason is tried again on the next run.
+async fn refresh(link: &mut GoogleCalendarLink) -> Result<(), String> {
+    let client = OAUTH_CLIENT.with(|c| c.borrow().get().clone());
+    let refresh_token = link
+        .refresh_token
+        .as_ref()
+        .and_then(secrets::open)
+        .and_then(|token| String::from_utf8(token).ok())
+        .filter(|_| !client.client_id.is_empty());
+    let Some(refresh_token) = refresh_token else {
+        link.needs_new_token = link.expires_at <= time();
+        return Err("the access token can't be refreshed".to_string());
+    };
+    let body = format!(
+        "client_id={}&client_secret={}&refresh_token={}&grant_type=refresh_token",
+        form_encode(&client.client_id),
+        form_encode(&client.client_secret),
+        form_encode(&refresh_token)
+    );
+    let request = SingleReplicaRequest {
+        url: TOKEN_ENDPOINT.to_string(),
+        max_response_bytes: Some(SYNC_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/x-www-form-urlencoded".to_string(),
+        }],
+        body: Some(body.into_bytes()),
+        transform: None,
+        is_replicated: Some(false),
+    };
+    let management = Principal::management_canister();
+    let (response,): (HttpResponse,) =
+        call_with_payment128(management, "http_request", (request,), SYNC_CYCLES)
+            .await
+            .map_err(|(_, msg)| msg)?;
+    let status = u16::try_from(response.status.0).unwrap_or(u16::MAX);
+    // Google answers 400 with invalid_grant once the refresh token is revoked or expired
+    if status == 400 || status == 401 {
+        link.refresh_token = None;
+        link.needs_new_token = link.expires_at <= time();
+        return Err("Google refused to refresh the access token".to_string());
+    }
+    if !(200..=299).contains(&status) {
+        return Err(format!("the token refresh answered with status {}", status));
+    }
+    let refreshed: RefreshedToken = serde_json::from_slice(&response.body)
+        .map_err(|_| "the token refresh returned no access token".to_string())?;
+    if refreshed.access_token.is_empty() || refreshed.access_token.len() > MAX_TOKEN_CHARS {
+        return Err("the token refresh returned no usable access token".to_string());
+    }
+    link.token = secrets::seal(refreshed.access_token.as_bytes());
+    link.expires_at = time() + refreshed.expires_in.saturating_mul(1_000_000_000);
+    link.last_error = None;
+    Ok(())
+}
+
+// Updates the calendar entry, inserting it if it doesn't exist yet
+async fn push_event(calendar_id: &str, token: &str, event: &Event) -> Push {
+    let entry_id = entry_id(event.id);
+    let starts_at = event.starts_at.unwrap_or_default();
+    let ends_at = event.ends_at.unwrap_or(starts_at + DEFAULT_DURATION_NANOS);
+    let body = serde_json::json!({
+        "id": entry_id,
+        "summary": event.event_title,
+        "description": event.event_description,
+        "location": event.event_location,
+        "start": { "dateTime": format_rfc3339(starts_at) },
+        "end": { "dateTime": format_rfc3339(ends_at) },
+    })
+    .to_string()
+    .into_bytes();
+
+    let entry_url = format!("{}/{}/events/{}", CALENDAR_API, path_encode(calendar_id), entry_id);
+    match send(Some("PUT"), entry_url, token, Some(body.clone())).await {
+        Ok(404) => {}
+        Ok(status) => return outcome(status),
+        Err(reason) => return Push::Failed(reason),
+    }
+    let insert_url = format!("{}/{}/events", CALENDAR_API, path_encode(calendar_id));
+    match send(None, insert_url, token, Some(body)).await {
+        // Another attempt already inserted it
+        Ok(409) => Push::Done,
+        Ok(status) => outcome(status),
+        Err(reason) => Push::Failed(reason),
+    }
+}
+
+async fn remove_event(calendar_id: &str, token: &str, event_id: u64) -> Push {
+    let url = format!(
+        "{}/{}/events/{}",
+        CALENDAR_API,
+        path_encode(calendar_id),
+        entry_id(event_id)
+    );
+    match send(Some("DELETE"), url, token, None).await {
+        // Never pushed, or already removed
+        Ok(404) | Ok(410) => Push::Done,
+        Ok(status) => outcome(status),
+        Err(reason) => Push::Failed(reason),
+    }
+}
+
+fn outcome(status: u16) -> Push {
+    match status {
+        200..=299 => Push::Done,
+        401 => Push::TokenRejected,
+        status => Push::Failed(format!("Google Calendar answered with status {}", status)),
+    }
+}
+
+// Sends a request to the Calendar API. Outcalls can only GET, HEAD or POST, so other
+// methods are POSTed with Google's method override header.
+async fn send(
+    method_override: Option<&str>,
+    url: String,
+    token: &str,
+    body: Option<Vec<u8>>,
+) -> Result<u16, String> {
+    let mut headers = vec![
+        HttpHeader {
+            name: "Authorization".to_string(),
+            value: format!("Bearer {}", token),
+        },
+        HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        },
+    ];
+    if let Some(method) = method_override {
+        headers.push(HttpHeader {
+            name: "X-HTTP-Method-Override".to_string(),
+            value: method.to_string(),
+        });
+    }
+    let request = CanisterHttpRequestArgument {
+        url,
+        max_response_bytes: Some(SYNC_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers,
+        body,
+        transform: Some(TransformContext::from_name(
+            "transform_calendar_response".to_string(),
+            Vec::new(),
+        )),
+    };
+    match http_request(request, SYNC_CYCLES).await {
+        Ok((response,)) => Ok(u16::try_from(response.status.0).unwrap_or(u16::MAX)),
+        Err((_, msg)) => Err(msg),
+    }
+}
+
+// Google accepts client-chosen ids of lowercase base32hex characters (a-v, 0-9)
+fn entry_id(event_id: u64) -> String {
+    format!("icptime{}", event_id)
+}
+
+fn path_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn connection(principal: &str, link: GoogleCalendarLink) -> GoogleCalendarConnection {
+    let pending_pushes = SYNC_QUEUE.with(|q| {
+        q.borrow()
+            .iter()
+            .filter(|(key, _)| key.principal == principal)
+            .count() as u64
+    });
+    GoogleCalendarConnection {
+        calendar_id: link.calendar_id,
+        expires_at: link.expires_at,
+        refreshable: link.refresh_token.is_some(),
+        needs_new_token: link.needs_new_token,
+        last_error: link.last_error,
+        pending_pushes,
+        connected_at: link.connected_at,
+    }
+}
+
+fn link_of(principal: &str) -> Option<GoogleCalendarLink> {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    CALENDAR_LINKS.with(|l| l.borrow().get(&key))
+}
+
+// Saves a link's state unless the user disconnected or submitted a new token while a push
+// was in flight
+fn save_link(principal: &str, link: GoogleCalendarLink) {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    CALENDAR_LINKS.with(|l| {
+        let mut links = l.borrow_mut();
+        if links.get(&key).is_some_and(|current| current.connected_at == link.connected_at) {
+            links.insert(key, link);
+        }
+    });
+}
@@ -0,0 +1,420 @@
+// Group ticket purchases. A buyer can pay for several tickets of a tier at once and hand
+// them out later, either by assigning each ticket to a principal or by sharing a claim link
+// whose code registers whoever redeems it. The group shows up in `my_orders` with the count
+// of tickets still to hand out, and unassigned tickets keep their seats in the tier and the
+// event. Refunding the order voids the tickets that weren't handed out.
+use crate::flags::{self, FlaggedFeature};
+use crate::tiers::{self, TicketPurchase};
+use crate::{analytics, beta, limits, metrics, orders, payments};
+use crate::{Error, EventPrincipalKey, Memory, MEMORY_MANAGER, _call_failed};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MIN_GROUP_TICKETS: u32 = 2;
+const MAX_GROUP_TICKETS: u32 = 20;
+const CODE_BYTES: usize = 16;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct GroupTicketKey {
+    order_id: u64,
+    index: u32,
+}
+
+impl_storable!(GroupTicketKey, 32);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct ClaimCodeKey {
+    code: String,
+}
+
+impl_storable!(ClaimCodeKey, 64);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct GroupTicket {
+    order_id: u64,
+    // From 0 to the group's quantity, exclusive
+    index: u32,
+    event_id: u64,
+    tier_id: u64,
+    buyer: Principal,
+    // Share of the order's amount
+    price: u128,
+    block_index: Option<u64>,
+    // Set once the ticket is assigned or claimed
+    holder: Option<Principal>,
+    assigned_at: Option<u64>,
+    // Code of the ticket's claim link, while it is unassigned
+    claim_code: Option<String>,
+    // Set when the order was refunded before the ticket was handed out
+    voided: bool,
+    // Gift waiting for its recipient to accept the ticket
+    gift_id: Option<u64>,
+}
+
+impl_storable!(GroupTicket, 512);
+
+thread_local! {
+    static GROUP_TICKETS: RefCell<StableBTreeMap<GroupTicketKey, GroupTicket, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(155)))
+    ));
+
+    static CLAIM_CODES: RefCell<StableBTreeMap<ClaimCodeKey, GroupTicketKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(156)))
+    ));
+
+    // Group order of each buyer for an event
+    static GROUP_BUYERS: RefCell<StableBTreeMap<EventPrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(157)))
+    ));
+
+    // Seats held by unassigned group tickets, per event
+    static UNASSIGNED_SEATS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(158)))
+    ));
+}
+
+// Query function listing the tickets of one of the caller's group orders, with their
+// holders and claim codes
+#[ic_cdk::query]
+fn get_group_tickets(order_id: u64) -> Result<Vec<GroupTicket>, Error> {
+    let tickets = tickets_of(order_id);
+    if tickets.first().is_none_or(|ticket| ticket.buyer != caller()) {
+        return Err(Error::NotFound {
+            msg: format!("Group order with id={} not found", order_id),
+        });
+    }
+    Ok(tickets)
+}
+
+// Update function buying `quantity` tickets of a tier in a single payment, on the given
+// ledger or else the event's main token; the tickets are then handed out with
+// `assign_group_ticket` or `create_group_claim_link`. Returns the order's id.
+#[ic_cdk::update]
+async fn purchase_group_tickets(
+    event_id: u64,
+    tier_id: u64,
+    quantity: u32,
+    ledger: Option<Principal>,
+) -> Result<u64, Error> {
+    let _metrics = metrics::track("purchase_group_tickets");
+    beta::_check_allowlisted()?;
+    flags::_check_enabled(FlaggedFeature::Payments)?;
+    if !(MIN_GROUP_TICKETS..=MAX_GROUP_TICKETS).contains(&quantity) {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "a group has between {} and {} tickets",
+                MIN_GROUP_TICKETS, MAX_GROUP_TICKETS
+            ),
+        });
+    }
+    let buyer = caller();
+    let key = EventPrincipalKey {
+        event_id,
+        principal: buyer.to_string(),
+    };
+    // Orders are tracked per holder, so a buyer who already paid can't buy a group on top
+    if GROUP_BUYERS.with(|g| g.borrow().contains_key(&key)) || payments::has_paid(&key) {
+        return Err(Error::InvalidPayload {
+            msg: "You already paid for tickets to this event".to_string(),
+        });
+    }
+    let held = tiers::_hold_group(event_id, tier_id, quantity, ledger, buyer)?;
+    // Keeps the seats from the event's capacity while the payment is in flight
+    hold_seats(event_id, quantity);
+    let total = held.price.saturating_mul(u128::from(quantity));
+    let block_index = if total > 0 {
+        // A second purchase started while this one awaits the ledger is refused until it settles
+        tiers::start_purchasing(&key);
+        let charged = payments::charge(event_id, buyer, held.ledger, total).await;
+        tiers::finish_purchasing(&key);
+        match charged {
+            Ok(block_index) => Some(block_index),
+            Err(err) => {
+                free_seats(event_id, quantity);
+                tiers::release_group(event_id, tier_id, quantity);
+                return Err(err);
+            }
+        }
+    } else {
+        None
+    };
+
+    let Some(order_id) = orders::record_group(&key, &held, quantity, block_index) else {
+        // The event was deleted while the payment was in flight
+        free_seats(event_id, quantity);
+        tiers::release_group(event_id, tier_id, quantity);
+        if let Some(block_index) = block_index {
+            payments::enqueue_refund(&key, block_index);
+        }
+        return Err(Error::NotFound {
+            msg: format!("Event with id={} not found", event_id),
+        });
+    };
+    limits::record_purchase(event_id, buyer, Some(tier_id), quantity);
+    for index in 0..quantity {
+        analytics::record_sale(&held);
+        let ticket = GroupTicket {
+            order_id,
+            index,
+            event_id,
+            tier_id,
+            buyer,
+            price: held.price,
+            block_index,
+            holder: None,
+            assigned_at: None,
+            claim_code: None,
+            voided: false,
+            gift_id: None,
+        };
+        GROUP_TICKETS.with(|g| g.borrow_mut().insert(GroupTicketKey { order_id, index }, ticket));
+    }
+    GROUP_BUYERS.with(|g| g.borrow_mut().insert(key, order_id));
+    Ok(order_id)
+}
+
+// Update function for a group's buyer to give one of its unassigned tickets to a principal,
+// who is registered for the event right away
+#[ic_cdk::update]
+fn assign_group_ticket(
+    order_id: u64,
+    index: u32,
+    principal: Principal,
+) -> Result<TicketPurchase, Error> {
+    let _metrics = metrics::track("assign_group_ticket");
+    let ticket = _get_unassigned_ticket(order_id, index)?;
+    if principal == Principal::anonymous() {
+        return Err(Error::InvalidPayload {
+            msg: "tickets can't be assigned to the anonymous principal".to_string(),
+        });
+    }
+    hand_out(ticket, principal)
+}
+
+// Update function for a group's buyer to create a claim link for one of its unassigned
+// tickets; the first principal to redeem the returned code with `claim_group_ticket` gets it.
+// Creating a link again replaces the previous code.
+#[ic_cdk::update]
+async fn create_group_claim_link(order_id: u64, index: u32) -> Result<GroupTicket, Error> {
+    let _metrics = metrics::track("create_group_claim_link");
+    _get_unassigned_ticket(order_id, index)?;
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|err| _call_failed("raw_rand", err))?;
+    let code: String = bytes
+        .iter()
+        .take(CODE_BYTES)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    // The ticket may have been handed out while the randomness was fetched
+    let mut ticket = _get_unassigned_ticket(order_id, index)?;
+    if let Some(previous) = ticket.claim_code.take() {
+        CLAIM_CODES.with(|c| c.borrow_mut().remove(&ClaimCodeKey { code: previous }));
+    }
+    ticket.claim_code = Some(code.clone());
+    CLAIM_CODES.with(|c| {
+        c.borrow_mut()
+            .insert(ClaimCodeKey { code }, GroupTicketKey { order_id, index })
+    });
+    let key = GroupTicketKey { order_id, index };
+    GROUP_TICKETS.with(|g| g.borrow_mut().insert(key, ticket.clone()));
+    Ok(ticket)
+}
+
+// Update function redeeming a group ticket's claim link, registering the caller for its event
+#[ic_cdk::update]
+fn claim_group_ticket(code: String) -> Result<TicketPurchase, Error> {
+    let _metrics = metrics::track("claim_group_ticket");
+    if caller() == Principal::anonymous() {
+        return Err(Error::NotAuthorized {
+            msg: "Sign in to claim a ticket".to_string(),
+            caller: caller(),
+        });
+    }
+    let not_found = || Error::NotFound {
+        msg: "This claim link is invalid or was already used".to_string(),
+    };
+    let key = CLAIM_CODES
+        .with(|c| c.borrow().get(&ClaimCodeKey { code: code.trim().to_lowercase() }))
+        .ok_or_else(not_found)?;
+    let ticket = GROUP_TICKETS
+        .with(|g| g.borrow().get(&key))
+        .filter(|ticket| ticket.holder.is_none() && !ticket.voided && ticket.gift_id.is_none())
+        .filter(|ticket| orders::is_paid(ticket.order_id))
+        .ok_or_else(not_found)?;
+    hand_out(ticket, caller())
+}
+
+// Whether the principal bought a group of tickets for the event
+pub(crate) fn has_group(event_id: u64, principal: Principal) -> bool {
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    GROUP_BUYERS.with(|g| g.borrow().contains_key(&key))
+}
+
+// Seats of an event held by group tickets that weren't handed out yet
+pub(crate) fn unassigned_seats(event_id: u64) -> u64 {
+    UNASSIGNED_SEATS
+        .with(|u| u.borrow().get(&event_id))
+        .unwrap_or(0)
+}
+
+// Voids the tickets of a refunded group order that weren't handed out, giving their seats
+// back to the tier and the event
+pub(crate) fn void_unassigned(order_id: u64) {
+    for mut ticket in tickets_of(order_id) {
+        if ticket.holder.is_some() || ticket.voided {
+            continue;
+        }
+        if let Some(code) = ticket.claim_code.take() {
+            CLAIM_CODES.with(|c| c.borrow_mut().remove(&ClaimCodeKey { code }));
+        }
+        ticket.voided = true;
+        free_seats(ticket.event_id, 1);
+        tiers::release_group(ticket.event_id, ticket.tier_id, 1);
+        let key = GroupTicketKey {
+            order_id,
+            index: ticket.index,
+        };
+        GROUP_TICKETS.with(|g| g.borrow_mut().insert(key, ticket));
+    }
+}
+
+// Helper function setting aside an unassigned ticket of a group order for a gift; its claim
+// link stops working. Returns the ticket's index.
+pub(crate) fn _reserve_for_gift(order_id: u64, gift_id: u64) -> Result<u32, Error> {
+    let mut ticket = tickets_of(order_id)
+        .into_iter()
+        .find(|ticket| ticket.holder.is_none() && !ticket.voided && ticket.gift_id.is_none())
+        .ok_or(Error::InvalidPayload {
+            msg: format!("Order with id={} has no tickets left to give", order_id),
+        })?;
+    if let Some(code) = ticket.claim_code.take() {
+        CLAIM_CODES.with(|c| c.borrow_mut().remove(&ClaimCodeKey { code }));
+    }
+    ticket.gift_id = Some(gift_id);
+    let index = ticket.index;
+    GROUP_TICKETS.with(|g| g.borrow_mut().insert(GroupTicketKey { order_id, index }, ticket));
+    Ok(index)
+}
+
+// Puts a group ticket set aside for a gift back with the unassigned ones
+pub(crate) fn release_gift(order_id: u64, index: u32) {
+    let key = GroupTicketKey { order_id, index };
+    if let Some(mut ticket) = GROUP_TICKETS.with(|g| g.borrow().get(&key)) {
+        ticket.gift_id = None;
+        GROUP_TICKETS.with(|g| g.borrow_mut().insert(key, ticket));
+    }
+}
+
+// Helper function handing a group ticket set aside for a gift to its recipient
+pub(crate) fn _hand_out_gift(
+    order_id: u64,
+    index: u32,
+    gift_id: u64,
+    recipient: Principal,
+) -> Result<TicketPurchase, Error> {
+    let ticket = GROUP_TICKETS
+        .with(|g| g.borrow().get(&GroupTicketKey { order_id, index }))
+        .filter(|ticket| ticket.gift_id == Some(gift_id) && !ticket.voided)
+        .filter(|_| orders::is_paid(order_id))
+        .ok_or(Error::InvalidPayload {
+            msg: "this ticket can no longer be given".to_string(),
+        })?;
+    hand_out(ticket, recipient)
+}
+
+// Helper function returning a ticket of the caller's group order that can still be handed out
+fn _get_unassigned_ticket(order_id: u64, index: u32) -> Result<GroupTicket, Error> {
+    let ticket = GROUP_TICKETS
+        .with(|g| g.borrow().get(&GroupTicketKey { order_id, index }))
+        .filter(|ticket| ticket.buyer == caller())
+        .ok_or(Error::NotFound {
+            msg: format!("Ticket {} of group order with id={} not found", index, order_id),
+        })?;
+    if ticket.holder.is_some()
+        || ticket.voided
+        || ticket.gift_id.is_some()
+        || !orders::is_paid(order_id)
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!("Ticket {} of order with id={} can't be handed out", index, order_id),
+        });
+    }
+    Ok(ticket)
+}
+
+// Registers the new holder of a group ticket and frees its claim link
+fn hand_out(mut ticket: GroupTicket, holder: Principal) -> Result<TicketPurchase, Error> {
+    // Its seat was counted as taken until now
+    free_seats(ticket.event_id, 1);
+    let registered = tiers::_register_group_ticket(
+        ticket.event_id,
+        ticket.tier_id,
+        holder,
+        ticket.price,
+        ticket.block_index,
+    );
+    let purchase = match registered {
+        Ok(purchase) => purchase,
+        Err(err) => {
+            hold_seats(ticket.event_id, 1);
+            return Err(err);
+        }
+    };
+    if let Some(code) = ticket.claim_code.take() {
+        CLAIM_CODES.with(|c| c.borrow_mut().remove(&ClaimCodeKey { code }));
+    }
+    ticket.holder = Some(holder);
+    ticket.gift_id = None;
+    ticket.assigned_at = Some(time());
+    let order_id = ticket.order_id;
+    let key = GroupTicketKey {
+        order_id,
+        index: ticket.index,
+    };
+    GROUP_TICKETS.with(|g| g.borrow_mut().insert(key, ticket));
+    let unassigned = tickets_of(order_id)
+        .iter()
+        .filter(|ticket| ticket.holder.is_none() && !ticket.voided)
+        .count();
+    orders::set_unassigned(order_id, unassigned as u32);
+    Ok(purchase)
+}
+
+fn tickets_of(order_id: u64) -> Vec<GroupTicket> {
+    GROUP_TICKETS.with(|g| {
+        g.borrow()
+            .range(GroupTicketKey { order_id, index: 0 }..)
+            .take_while(|(key, _)| key.order_id == order_id)
+            .map(|(_, ticket)| ticket)
+            .collect()
+    })
+}
+
+fn hold_seats(event_id: u64, seats: u32) {
+    let held = unassigned_seats(event_id) + u64::from(seats);
+    UNASSIGNED_SEATS.with(|u| u.borrow_mut().insert(event_id, held));
+}
+
+fn free_seats(event_id: u64, seats: u32) {
+    let held = unassigned_seats(event_id).saturating_sub(u64::from(seats));
+    UNASSIGNED_SEATS.with(|u| {
+        if held == 0 {
+            u.borrow_mut().remove(&event_id)
+        } else {
+            u.borrow_mut().insert(event_id, held)
+        }
+    });
+}
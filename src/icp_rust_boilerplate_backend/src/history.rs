@@ -0,0 +1,91 @@
+// Purchase history. Brings together everything a principal paid or was paid back on this
+// canister: tickets bought from organizers or on resale, deposits, and the refunds and
+// payouts sent to them, each with its status and ledger block, so wallets can show it and
+// support staff can audit what happened. Entries come from the records the orders,
+// deposits and payments modules keep; nothing is stored here.
+use crate::admin::_check_admin;
+use crate::payments::PayoutKind;
+use crate::{deposits, orders, payments, Error};
+use candid::Principal;
+use ic_cdk::caller;
+use std::cmp::Reverse;
+
+const DEFAULT_PAGE: u32 = 50;
+const MAX_PAGE: u32 = 200;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum HistoryItem {
+    Ticket { order_id: u64, tier_id: Option<u64> },
+    ResaleTicket { order_id: u64, seller: Principal },
+    GroupTickets { order_id: u64, tier_id: Option<u64>, quantity: u32 },
+    Deposit,
+    // Refunds, returned deposits and resale proceeds sent to the principal
+    Payout { payout_id: u64, kind: PayoutKind },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum HistoryStatus {
+    Paid,
+    // A refund of the whole amount was queued; see the matching payout entry
+    Refunded { refund_id: u64 },
+    // A deposit kept until the event
+    Held,
+    Returned { payout_id: u64 },
+    Forfeited,
+    // A payout waiting to be sent or retried
+    Pending,
+    Completed,
+    Failed,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct HistoryEntry {
+    pub(crate) item: HistoryItem,
+    pub(crate) event_id: u64,
+    // None for free tickets
+    pub(crate) ledger: Option<Principal>,
+    pub(crate) amount: u128,
+    pub(crate) status: HistoryStatus,
+    // Block of the payment, or of the payout once sent
+    pub(crate) block_index: Option<u64>,
+    pub(crate) at: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PurchaseHistory {
+    // Newest first
+    entries: Vec<HistoryEntry>,
+    total: u64,
+}
+
+// Query function returning a page of the caller's purchase history, newest first
+#[ic_cdk::query]
+fn my_purchase_history(limit: Option<u32>, offset: Option<u32>) -> PurchaseHistory {
+    history_of(caller(), limit, offset)
+}
+
+// Query function for admins returning a page of a principal's purchase history
+#[ic_cdk::query]
+fn get_purchase_history(
+    principal: Principal,
+    limit: Option<u32>,
+    offset: Option<u32>,
+) -> Result<PurchaseHistory, Error> {
+    _check_admin()?;
+    Ok(history_of(principal, limit, offset))
+}
+
+fn history_of(principal: Principal, limit: Option<u32>, offset: Option<u32>) -> PurchaseHistory {
+    let mut entries = orders::history_of(principal);
+    entries.extend(deposits::history_of(&principal.to_string()));
+    entries.extend(payments::history_of(&principal.to_string()));
+    entries.sort_by_key(|entry| Reverse(entry.at));
+    let total = entries.len() as u64;
+    let limit = limit.unwrap_or(DEFAULT_PAGE).min(MAX_PAGE) as usize;
+    let entries = entries
+        .into_iter()
+        .skip(offset.unwrap_or(0) as usize)
+        .take(limit)
+        .collect();
+    PurchaseHistory { entries, total }
+}
@@ -0,0 +1,392 @@
+// HTTP gateway interface. Browsers, calendar apps and bots reach the canister over plain
+// HTTP through the boundary nodes' raw domain; `http_request` routes the path to the
+// module rendering each document.
+//
+// Bodies too large for a single reply are streamed: the first chunk goes out with the
+// response and the gateway fetches the rest from `http_request_streaming_callback`, which
+// renders the document again and returns the chunk the token asks for. Long listings are
+// instead rendered a page at a time: the token carries the cursor of the next page, and
+// each callback renders only that page.
+use crate::{assets, attendees, calendar, feeds, pages};
+use candid::Principal;
+use ic_cdk::id;
+use sha2::{Digest, Sha256};
+
+// Largest body sent in one reply, well under the response size limit
+const STREAM_CHUNK_BYTES: usize = 1024 * 1024;
+// Size past which a paged document ends its page; the item that crosses it still fits a reply
+pub(crate) const PAGE_BYTES: usize = STREAM_CHUNK_BYTES / 2;
+
+// The gateway also sends headers and a body; no route needs them, so they are not decoded
+#[derive(candid::CandidType, Deserialize)]
+struct HttpRequest {
+    method: String,
+    url: String,
+}
+
+#[derive(candid::CandidType)]
+pub(crate) struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    streaming_strategy: Option<StreamingStrategy>,
+}
+
+candid::define_function!(StreamingCallback : (StreamingToken) -> (StreamingCallbackResponse) query);
+
+#[derive(candid::CandidType)]
+enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingToken,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Deserialize)]
+struct StreamingToken {
+    url: String,
+    // Chunk to send next
+    index: u64,
+    // Hash of the whole body, so a document that changes while it is streamed ends the stream
+    body_hash: Vec<u8>,
+    // Where the next page starts, for documents rendered a page at a time
+    cursor: Option<PageCursor>,
+}
+
+// Position of the next page of a paged document: where the module rendering it left off,
+// and how many items it sent before
+#[derive(candid::CandidType, Clone, Deserialize)]
+pub(crate) struct PageCursor {
+    pub(crate) after: String,
+    pub(crate) sent: u64,
+}
+
+#[derive(candid::CandidType)]
+struct StreamingCallbackResponse {
+    body: Vec<u8>,
+    token: Option<StreamingToken>,
+}
+
+impl HttpResponse {
+    pub(crate) fn new(
+        status_code: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) -> HttpResponse {
+        HttpResponse {
+            status_code,
+            headers,
+            body,
+            streaming_strategy: None,
+        }
+    }
+
+    pub(crate) fn ok(content_type: &str, body: String) -> HttpResponse {
+        HttpResponse {
+            status_code: 200,
+            headers: vec![
+                ("Content-Type".to_string(), content_type.to_string()),
+                ("Cache-Control".to_string(), "public, max-age=300".to_string()),
+            ],
+            body: body.into_bytes(),
+            streaming_strategy: None,
+        }
+    }
+
+    pub(crate) fn error(status_code: u16, message: &str) -> HttpResponse {
+        HttpResponse {
+            status_code,
+            headers: vec![(
+                "Content-Type".to_string(),
+                "text/plain; charset=utf-8".to_string(),
+            )],
+            body: message.as_bytes().to_vec(),
+            streaming_strategy: None,
+        }
+    }
+
+    // Marks the response as a page of a paged document, followed by the page at `next`
+    pub(crate) fn paged(mut self, next: Option<PageCursor>) -> HttpResponse {
+        self.streaming_strategy = next.map(|cursor| StreamingStrategy::Callback {
+            callback: StreamingCallback::new(id(), "http_request_streaming_callback".to_string()),
+            token: StreamingToken {
+                url: String::new(),
+                index: 1,
+                body_hash: Vec::new(),
+                cursor: Some(cursor),
+            },
+        });
+        self
+    }
+
+    fn next_page(&self) -> Option<PageCursor> {
+        let Some(StreamingStrategy::Callback { token, .. }) = &self.streaming_strategy else {
+            return None;
+        };
+        token.cursor.clone()
+    }
+
+    // Keeps the first chunk of a body too large for one reply and points the gateway at the
+    // callback for the rest; a paged document only needs the URL to render its next page
+    fn streamed(mut self, url: String) -> HttpResponse {
+        if let Some(StreamingStrategy::Callback { token, .. }) = &mut self.streaming_strategy {
+            token.url = url;
+            return self;
+        }
+        if self.body.len() <= STREAM_CHUNK_BYTES {
+            return self;
+        }
+        let token = StreamingToken {
+            url,
+            index: 1,
+            body_hash: Sha256::digest(&self.body).to_vec(),
+            cursor: None,
+        };
+        self.body.truncate(STREAM_CHUNK_BYTES);
+        self.streaming_strategy = Some(StreamingStrategy::Callback {
+            callback: StreamingCallback::new(id(), "http_request_streaming_callback".to_string()),
+            token,
+        });
+        self
+    }
+}
+
+// Query function serving the canister's HTTP routes
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" && request.method != "HEAD" {
+        return HttpResponse::error(405, "Method not allowed");
+    }
+    route(&request.url, None).streamed(request.url)
+}
+
+// Query function returning the next chunk of a streamed response body
+#[ic_cdk::query]
+fn http_request_streaming_callback(token: StreamingToken) -> StreamingCallbackResponse {
+    if token.cursor.is_some() {
+        let page = route(&token.url, token.cursor.as_ref());
+        // A page that can no longer be rendered, e.g. once an export link is revoked, ends it
+        if page.status_code != 200 {
+            return StreamingCallbackResponse {
+                body: Vec::new(),
+                token: None,
+            };
+        }
+        let next = page.next_page().map(|cursor| StreamingToken {
+            cursor: Some(cursor),
+            ..token
+        });
+        return StreamingCallbackResponse {
+            body: page.body,
+            token: next,
+        };
+    }
+    let body = route(&token.url, None).body;
+    let start = (token.index as usize).saturating_mul(STREAM_CHUNK_BYTES);
+    if start >= body.len() || Sha256::digest(&body).as_slice() != token.body_hash {
+        return StreamingCallbackResponse {
+            body: Vec::new(),
+            token: None,
+        };
+    }
+    let end = body.len().min(start + STREAM_CHUNK_BYTES);
+    let next = (end < body.len()).then(|| StreamingToken {
+        index: token.index + 1,
+        ..token
+    });
+    StreamingCallbackResponse {
+        body: body[start..end].to_vec(),
+        token: next,
+    }
+}
+
+// Renders the document at a URL, or for a paged document the page at the cursor
+fn route(url: &str, cursor: Option<&PageCursor>) -> HttpResponse {
+    let url = url.split('#').next().unwrap_or_default();
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    match segments.as_slice() {
+        ["image", id] => assets::event_image(id),
+        ["feed.xml"] => feeds::new_events_feed(query_param(query, "tag").as_deref()),
+        ["events.json"] => feeds::events_json(query_param(query, "tag").as_deref(), cursor),
+        ["event", id_or_slug] => pages::event_page(id_or_slug, query_param(query, "token").as_deref()),
+        ["event", id, "attendees.csv"] => {
+            attendees::attendees_csv(id, query_param(query, "token").as_deref(), cursor)
+        }
+        ["organizer", principal, "calendar.ics"] => match Principal::from_text(principal) {
+            Ok(organizer) => calendar::organizer_calendar(organizer),
+            Err(_) => HttpResponse::error(400, "Invalid organizer principal"),
+        },
+        _ => HttpResponse::error(404, "Not found"),
+    }
+}
+
+// Calendar date and time in UTC, derived from a timestamp in nanoseconds since the epoch
+pub(crate) struct UtcDateTime {
+    pub(crate) year: i64,
+    pub(crate) month: u32,
+    pub(crate) day: u32,
+    pub(crate) hour: u32,
+    pub(crate) minute: u32,
+    pub(crate) second: u32,
+    // 0 is Sunday
+    pub(crate) weekday: u32,
+}
+
+impl UtcDateTime {
+    pub(crate) fn from_nanos(nanos: u64) -> UtcDateTime {
+        let secs = nanos / 1_000_000_000;
+        let days = (secs / 86_400) as i64;
+        let time_of_day = (secs % 86_400) as u32;
+
+        // Civil-from-days conversion for the proleptic Gregorian calendar
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        UtcDateTime {
+            year,
+            month,
+            day,
+            hour: time_of_day / 3_600,
+            minute: time_of_day / 60 % 60,
+            second: time_of_day % 60,
+            weekday: (days + 4).rem_euclid(7) as u32,
+        }
+    }
+}
+
+// Formats a timestamp in nanoseconds since the epoch as an RFC 3339 UTC date and time
+pub(crate) fn format_rfc3339(nanos: u64) -> String {
+    let t = UtcDateTime::from_nanos(nanos);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        t.year, t.month, t.day, t.hour, t.minute, t.second
+    )
+}
+
+// Parses an RFC 3339 timestamp into nanoseconds since the epoch. Also accepts a space
+// instead of the `T`, no seconds, and no offset, which is read as UTC.
+pub(crate) fn parse_rfc3339(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let number = |from: usize, len: usize| -> Option<i64> {
+        let digits = text.get(from..from + len)?;
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    };
+    let year = number(0, 4)?;
+    let month = number(5, 2)?;
+    let day = number(8, 2)?;
+    let separators = (text.get(4..5)?, text.get(7..8)?, text.get(10..11)?, text.get(13..14)?);
+    if !matches!(separators, ("-", "-", "T" | "t" | " ", ":")) {
+        return None;
+    }
+    let hour = number(11, 2)?;
+    let minute = number(14, 2)?;
+    let mut rest = text.get(16..)?;
+    let mut second = 0;
+    if let Some(after) = rest.strip_prefix(':') {
+        second = number(17, 2)?;
+        rest = after.get(2..)?;
+    }
+    let mut nanos = 0;
+    if let Some(after) = rest.strip_prefix('.') {
+        let digits = after.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return None;
+        }
+        let fraction = &after[..digits.min(9)];
+        nanos = fraction.parse::<i64>().ok()? * 10_i64.pow(9 - fraction.len() as u32);
+        rest = &after[digits..];
+    }
+    let offset_secs = match rest {
+        "" | "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.get(..1)? {
+                "+" => 1,
+                "-" => -1,
+                _ => return None,
+            };
+            let (hours, minutes) = rest.get(1..)?.split_once(':')?;
+            if hours.len() != 2 || minutes.len() != 2 {
+                return None;
+            }
+            sign * (hours.parse::<i64>().ok()? * 3_600 + minutes.parse::<i64>().ok()? * 60)
+        }
+    };
+    let days_in_month = match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if day < 1 || day > days_in_month || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // Days-from-civil conversion, the inverse of `UtcDateTime::from_nanos`
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + hour * 3_600 + minute * 60 + second - offset_secs;
+    u64::try_from(secs).ok()?.checked_mul(1_000_000_000)?.checked_add(nanos as u64)
+}
+
+// Escapes text for use in XML and HTML content and attribute values
+pub(crate) fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Returns the percent-decoded value of a query string parameter
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+        .filter(|value| !value.is_empty())
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,252 @@
+// Event card image URLs. Frontends render `event_card_imgurl` directly, so only plain
+// https URLs are accepted; javascript:, data: and other schemes are rejected. Admins can
+// further restrict images to an allowlist of hosts (a host also covers its subdomains).
+//
+// Whenever an event gets a new image, the canister holding it sends a HEAD request to the
+// URL in the background and records on the event whether it serves a small enough image.
+use crate::admin::_check_admin;
+use crate::imports::truncate;
+use crate::{assets, metrics, Error, Event, Memory, MEMORY_MANAGER, _get_event, do_insert};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Cell;
+use std::cell::RefCell;
+
+const MAX_URL_CHARS: usize = 512;
+const MAX_ALLOWED_HOSTS: usize = 50;
+const MAX_IMAGE_BYTES: u64 = 5 * 1024 * 1024;
+const IMAGE_CHECK_CYCLES: u128 = 1_000_000_000;
+const IMAGE_CHECK_MAX_RESPONSE_BYTES: u64 = 4096;
+// The status is stored in the event record, within the room kept there for it
+const MAX_CONTENT_TYPE_BYTES: usize = 32;
+const MAX_REASON_BYTES: usize = 40;
+
+// Outcome of checking an event's image URL
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum ImageStatus {
+    Pending,
+    Verified { content_type: String, size: Option<u64> },
+    Failed { reason: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ImageConfig {
+    // Empty means any host is allowed
+    allowed_hosts: Vec<String>,
+}
+
+impl_storable!(ImageConfig, 4096);
+
+thread_local! {
+    static IMAGE_CONFIG: RefCell<Cell<ImageConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))), ImageConfig::default())
+            .expect("Cannot create the image config cell")
+    );
+}
+
+// Query function listing the hosts event images may be served from; empty allows any host
+#[ic_cdk::query]
+fn get_image_host_allowlist() -> Vec<String> {
+    IMAGE_CONFIG.with(|c| c.borrow().get().allowed_hosts.clone())
+}
+
+// Update function for admins to replace the image host allowlist. Existing events keep
+// their image until they are next updated.
+#[ic_cdk::update]
+fn set_image_host_allowlist(hosts: Vec<String>) -> Result<(), Error> {
+    let _metrics = metrics::track("set_image_host_allowlist");
+    _check_admin()?;
+    if hosts.len() > MAX_ALLOWED_HOSTS {
+        return Err(Error::InvalidPayload {
+            msg: format!("the allowlist can hold at most {} hosts", MAX_ALLOWED_HOSTS),
+        });
+    }
+    let mut allowed_hosts: Vec<String> = Vec::new();
+    for host in hosts {
+        let host = host.trim().trim_start_matches("*.").to_lowercase();
+        if !is_valid_host(&host) {
+            return Err(Error::InvalidPayload {
+                msg: format!("\"{}\" is not a valid host name", host),
+            });
+        }
+        if !allowed_hosts.contains(&host) {
+            allowed_hosts.push(host);
+        }
+    }
+    IMAGE_CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(ImageConfig { allowed_hosts })
+            .expect("cannot update the image config")
+    });
+    Ok(())
+}
+
+// Helper function to reject image URLs that are malformed, not https, or from a host
+// outside the allowlist. An empty URL means the event has no image.
+pub(crate) fn _validate_image_url(url: &str) -> Result<(), Error> {
+    // Images uploaded to the canister itself are always allowed
+    if url.is_empty() || assets::is_image_url(url) {
+        return Ok(());
+    }
+    let invalid = |msg: &str| {
+        Err(Error::InvalidPayload {
+            msg: format!("invalid image URL: {}", msg),
+        })
+    };
+    if url.chars().count() > MAX_URL_CHARS {
+        return invalid("too long");
+    }
+    if url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return invalid("it must not contain spaces or control characters");
+    }
+    let Some(rest) = strip_prefix_ignore_case(url, "https://") else {
+        return invalid("only https URLs are allowed");
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    if authority.contains('@') {
+        return invalid("credentials are not allowed");
+    }
+    let host = match authority.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        Some(_) => return invalid("bad port"),
+        None => authority,
+    }
+    .to_lowercase();
+    if !is_valid_host(&host) {
+        return invalid("bad host name");
+    }
+
+    let allowed_hosts = get_image_host_allowlist();
+    let allowed = allowed_hosts.is_empty()
+        || allowed_hosts.iter().any(|allowed| {
+            host == *allowed
+                || host
+                    .strip_suffix(allowed.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        });
+    if !allowed {
+        return invalid(&format!("images from {} are not allowed", host));
+    }
+    Ok(())
+}
+
+// Status a freshly set image URL starts with; None when the event has no image
+pub(crate) fn initial_status(url: &str) -> Option<ImageStatus> {
+    (!url.is_empty()).then_some(ImageStatus::Pending)
+}
+
+// Starts checking a stored event's image if it is still pending
+pub(crate) fn verify_image(event: &Event) {
+    if let Some(ImageStatus::Pending) = event.image_status {
+        ic_cdk::spawn(check_image(event.id, event.event_card_imgurl.clone()));
+    }
+}
+
+// Keeps only the status and the headers the check looks at, so replicas agree on the
+// outcall result
+#[ic_cdk::query]
+fn transform_image_response(args: TransformArgs) -> HttpResponse {
+    let headers = args
+        .response
+        .headers
+        .into_iter()
+        .filter(|header| {
+            header.name.eq_ignore_ascii_case("content-type")
+                || header.name.eq_ignore_ascii_case("content-length")
+        })
+        .map(|mut header| {
+            header.name = header.name.to_lowercase();
+            header
+        })
+        .collect();
+    HttpResponse {
+        status: args.response.status,
+        headers,
+        body: Vec::new(),
+    }
+}
+
+async fn check_image(event_id: u64, url: String) {
+    let request = CanisterHttpRequestArgument {
+        url: url.clone(),
+        max_response_bytes: Some(IMAGE_CHECK_MAX_RESPONSE_BYTES),
+        method: HttpMethod::HEAD,
+        headers: Vec::new(),
+        body: None,
+        transform: Some(TransformContext::from_name(
+            "transform_image_response".to_string(),
+            Vec::new(),
+        )),
+    };
+    let status = match http_request(request, IMAGE_CHECK_CYCLES).await {
+        Ok((response,)) => image_status(&response),
+        Err((_, msg)) => ImageStatus::Failed {
+            reason: format!("the image could not be fetched: {}", msg),
+        },
+    };
+    let status = match status {
+        ImageStatus::Verified { content_type, size } => ImageStatus::Verified {
+            content_type: truncate(&content_type, MAX_CONTENT_TYPE_BYTES),
+            size,
+        },
+        ImageStatus::Failed { reason } => ImageStatus::Failed {
+            reason: truncate(&reason, MAX_REASON_BYTES),
+        },
+        status => status,
+    };
+
+    // The image may have been replaced, or the event removed, while the check was running
+    if let Some(mut event) = _get_event(&event_id).filter(|event| event.event_card_imgurl == url) {
+        event.image_status = Some(status);
+        do_insert(&event);
+    }
+}
+
+fn image_status(response: &HttpResponse) -> ImageStatus {
+    if response.status < 200u64.into() || response.status >= 300u64.into() {
+        return ImageStatus::Failed {
+            reason: format!("the image URL answered with status {}", response.status),
+        };
+    }
+    let header = |name: &str| {
+        response
+            .headers
+            .iter()
+            .find(|header| header.name == name)
+            .map(|header| header.value.trim().to_string())
+    };
+    let content_type = header("content-type").unwrap_or_default();
+    if !content_type.to_lowercase().starts_with("image/") {
+        return ImageStatus::Failed {
+            reason: format!("the URL does not serve an image (content type \"{}\")", content_type),
+        };
+    }
+    let size = header("content-length").and_then(|length| length.parse::<u64>().ok());
+    if size.is_some_and(|size| size > MAX_IMAGE_BYTES) {
+        return ImageStatus::Failed {
+            reason: format!("the image is larger than {} bytes", MAX_IMAGE_BYTES),
+        };
+    }
+    ImageStatus::Verified { content_type, size }
+}
+
+fn strip_prefix_ignore_case<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = text.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix).then(|| &text[prefix.len()..])
+}
+
+// Dot-separated labels of letters, digits and hyphens
+fn is_valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
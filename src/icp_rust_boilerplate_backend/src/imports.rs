@@ -0,0 +1,727 @@
+// Event import from external platforms. An organizer points the canister at a JSON feed
+// from a Meetup- or Eventbrite-style API; the canister fetches it, maps each entry to an
+// event payload and keeps the result as a preview of what would be created. Nothing is
+// created until the organizer commits the preview, optionally picking rows from it.
+//
+// The mapping runs in the outcall transform, so replicas agree on the mapped events rather
+// than on the raw response. APIs that need a key take it in the URL (e.g. `?token=`).
+//
+// Organizers moving over from spreadsheets can upload a CSV instead, in chunks that fit the
+// message size limit; it is imported in one go once the last chunk arrives. Either way,
+// event records are small, so long text is shortened on import. Imported events are stored
+// on this canister, so imports are refused once new events spill to bucket canisters.
+use crate::http::parse_rfc3339;
+use crate::{beta, google_calendar, metrics, organizers, shard};
+use crate::{Error, EventPayload, Memory, PrincipalKey, MEMORY_MANAGER};
+use crate::{_check_new_event, _create_event};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use serde_json::Value;
+use std::cell::RefCell;
+
+const MAX_IMPORT_ROWS: usize = 50;
+const MAX_SOURCE_URL_CHARS: usize = 2048;
+const IMPORT_CYCLES: u128 = 10_000_000_000;
+const IMPORT_MAX_RESPONSE_BYTES: u64 = 512 * 1024;
+// A preview has to be committed within a day of fetching it
+const PREVIEW_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_TITLE_BYTES: usize = 100;
+const MAX_DESCRIPTION_BYTES: usize = 300;
+const MAX_LOCATION_BYTES: usize = 100;
+const MAX_SOURCE_ID_BYTES: usize = 64;
+const MAX_CSV_BYTES: usize = 1024 * 1024;
+const MAX_CSV_ROWS: usize = 500;
+// Uploaded CSV is kept in pieces of at most this size until the last chunk arrives
+const CSV_PIECE_BYTES: usize = 8 * 1024;
+const CSV_COLUMNS: &str =
+    "title, description, location, image_url, starts_at, ends_at, capacity, venue_id, tags";
+
+// An event as read from the external feed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ImportedEvent {
+    // The event's id on the external platform
+    source_id: Option<String>,
+    title: String,
+    description: String,
+    location: String,
+    image_url: String,
+    starts_at: Option<u64>,
+    ends_at: Option<u64>,
+    capacity: Option<u32>,
+    venue_id: Option<u64>,
+    tags: Option<Vec<String>>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ImportRow {
+    row: u32,
+    event: ImportedEvent,
+    // Why the row can't be imported; rows with an error are skipped on commit
+    error: Option<String>,
+}
+
+impl_storable!(ImportRow, 2048);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct ImportRowKey {
+    owner: String,
+    row: u32,
+}
+
+impl_storable!(ImportRowKey, 112);
+
+// A principal's pending import; its rows are kept separately
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PendingImport {
+    url: String,
+    fetched_at: u64,
+    rows: u32,
+}
+
+impl_storable!(PendingImport, 2304);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ImportPreview {
+    url: String,
+    fetched_at: u64,
+    rows: Vec<ImportRow>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ImportResult {
+    // Row of the preview, or line of the CSV the event was on
+    row: u32,
+    event_id: Option<u64>,
+    error: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct CsvPieceKey {
+    owner: String,
+    index: u32,
+}
+
+impl_storable!(CsvPieceKey, 112);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CsvPiece {
+    data: String,
+}
+
+impl_storable!(CsvPiece, CSV_PIECE_BYTES as u32 + 64);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CsvImport {
+    // Size of the upload so far
+    received_bytes: u64,
+    // Outcome of each row, once the last chunk is in
+    results: Vec<ImportResult>,
+}
+
+thread_local! {
+    static PENDING_IMPORTS: RefCell<StableBTreeMap<PrincipalKey, PendingImport, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70)))
+    ));
+
+    static IMPORT_ROWS: RefCell<StableBTreeMap<ImportRowKey, ImportRow, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71)))
+    ));
+
+    static CSV_UPLOADS: RefCell<StableBTreeMap<CsvPieceKey, CsvPiece, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72)))
+    ));
+}
+
+// Update function that fetches events from an external API and stores them as the caller's
+// import preview, replacing any previous one. Nothing is created yet.
+#[ic_cdk::update]
+async fn preview_event_import(url: String) -> Result<ImportPreview, Error> {
+    let _metrics = metrics::track("preview_event_import");
+    let owner = _check_importer()?;
+    let url = url.trim().to_string();
+    if url.chars().count() > MAX_SOURCE_URL_CHARS
+        || !url.to_ascii_lowercase().starts_with("https://")
+        || url.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!("the source must be an https URL of at most {} characters", MAX_SOURCE_URL_CHARS),
+        });
+    }
+    let events = fetch_events(&url).await?;
+
+    clear_import(&owner);
+    let pending = PendingImport {
+        url,
+        fetched_at: time(),
+        rows: events.len() as u32,
+    };
+    for (row, event) in events.into_iter().enumerate() {
+        let error = payload_of(&event).err();
+        let row = ImportRow {
+            row: row as u32,
+            event,
+            error,
+        };
+        let key = ImportRowKey {
+            owner: owner.clone(),
+            row: row.row,
+        };
+        IMPORT_ROWS.with(|r| r.borrow_mut().insert(key, row));
+    }
+    PENDING_IMPORTS.with(|p| p.borrow_mut().insert(PrincipalKey { principal: owner.clone() }, pending));
+    Ok(preview_of(&owner).expect("the preview was just stored"))
+}
+
+// Query function returning the caller's pending import preview
+#[ic_cdk::query]
+fn get_event_import_preview() -> Option<ImportPreview> {
+    preview_of(&caller().to_string())
+}
+
+// Update function that creates events from the caller's import preview: the given rows, or
+// every row without an error. Rows are checked again, since events may have changed since
+// the preview. The preview is discarded afterwards.
+#[ic_cdk::update]
+fn commit_event_import(rows: Option<Vec<u32>>) -> Result<Vec<ImportResult>, Error> {
+    let _metrics = metrics::track("commit_event_import");
+    shard::_check_not_bucket()?;
+    shard::_check_room_for_local_events()?;
+    let owner = _check_importer()?;
+    let pending = PENDING_IMPORTS
+        .with(|p| p.borrow().get(&PrincipalKey { principal: owner.clone() }))
+        .ok_or(Error::NotFound {
+            msg: "You have no pending import; preview one first".to_string(),
+        })?;
+    if time().saturating_sub(pending.fetched_at) > PREVIEW_TTL_NANOS {
+        clear_import(&owner);
+        return Err(Error::InvalidPayload {
+            msg: "the import preview expired; preview it again".to_string(),
+        });
+    }
+    let selected: Vec<ImportRow> = rows_of(&owner)
+        .into_iter()
+        .filter(|row| match &rows {
+            Some(rows) => rows.contains(&row.row),
+            None => row.error.is_none(),
+        })
+        .collect();
+
+    let results = selected.iter().map(|row| create(row.row, &row.event)).collect();
+    clear_import(&owner);
+    Ok(results)
+}
+
+// Update function discarding the caller's pending import preview and unfinished CSV upload
+#[ic_cdk::update]
+fn cancel_event_import() -> bool {
+    let _metrics = metrics::track("cancel_event_import");
+    let owner = caller().to_string();
+    let key = PrincipalKey {
+        principal: owner.clone(),
+    };
+    let pending = PENDING_IMPORTS.with(|p| p.borrow().contains_key(&key))
+        || !csv_pieces_of(&owner).is_empty();
+    clear_import(&owner);
+    clear_csv(&owner);
+    pending
+}
+
+// Update function for uploading a CSV of events. Chunks are added to the caller's upload
+// until a call with `last` set, which parses the whole file, creates an event owned by the
+// caller for every valid row and reports the others with their error. The header row names
+// the columns; only `title` is required.
+#[ic_cdk::update]
+fn import_events_csv(chunks: Vec<String>, last: bool) -> Result<CsvImport, Error> {
+    let _metrics = metrics::track("import_events_csv");
+    shard::_check_room_for_local_events()?;
+    let owner = _check_importer()?;
+    let mut pieces = csv_pieces_of(&owner);
+    let received_bytes = pieces.iter().chain(&chunks).map(String::len).sum::<usize>();
+    if received_bytes > MAX_CSV_BYTES {
+        clear_csv(&owner);
+        return Err(Error::InvalidPayload {
+            msg: format!("a CSV upload can be at most {} bytes", MAX_CSV_BYTES),
+        });
+    }
+    if !last {
+        for piece in chunks.iter().flat_map(|chunk| split_pieces(chunk)) {
+            let key = CsvPieceKey {
+                owner: owner.clone(),
+                index: pieces.len() as u32,
+            };
+            CSV_UPLOADS.with(|u| u.borrow_mut().insert(key, CsvPiece { data: piece.clone() }));
+            pieces.push(piece);
+        }
+        return Ok(CsvImport {
+            received_bytes: received_bytes as u64,
+            results: Vec::new(),
+        });
+    }
+
+    clear_csv(&owner);
+    pieces.extend(chunks);
+    let results = import_csv(&pieces.concat())?;
+    Ok(CsvImport {
+        received_bytes: received_bytes as u64,
+        results,
+    })
+}
+
+// Maps the external API's response to imported events, so replicas agree on the mapping
+// rather than on the raw response. Unrecognized responses map to an empty body.
+#[ic_cdk::query]
+fn transform_import_response(args: TransformArgs) -> HttpResponse {
+    let succeeded = args.response.status >= 200u64.into() && args.response.status < 300u64.into();
+    let body = match map_events(&args.response.body) {
+        Some(events) if succeeded => serde_json::to_vec(&events).unwrap_or_default(),
+        _ => Vec::new(),
+    };
+    HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body,
+    }
+}
+
+// Helper function rejecting anonymous callers, who couldn't manage the imported events
+fn _check_importer() -> Result<String, Error> {
+    if caller() == Principal::anonymous() {
+        return Err(Error::NotAuthorized {
+            msg: "Sign in to import events".to_string(),
+            caller: caller(),
+        });
+    }
+    beta::_check_allowlisted()?;
+    organizers::_check_organizer()?;
+    Ok(caller().to_string())
+}
+
+async fn fetch_events(url: &str) -> Result<Vec<ImportedEvent>, Error> {
+    let request = CanisterHttpRequestArgument {
+        url: url.to_string(),
+        max_response_bytes: Some(IMPORT_MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: vec![HttpHeader {
+            name: "Accept".to_string(),
+            value: "application/json".to_string(),
+        }],
+        body: None,
+        transform: Some(TransformContext::from_name(
+            "transform_import_response".to_string(),
+            Vec::new(),
+        )),
+    };
+    let (response,) = http_request(request, IMPORT_CYCLES).await.map_err(|(_, msg)| {
+        Error::InvalidPayload {
+            msg: format!("could not fetch events from the source: {}", msg),
+        }
+    })?;
+    if response.status < 200u64.into() || response.status >= 300u64.into() {
+        return Err(Error::InvalidPayload {
+            msg: format!("the source answered with status {}", response.status),
+        });
+    }
+    serde_json::from_slice(&response.body).map_err(|_| Error::InvalidPayload {
+        msg: "the source did not return a list of events".to_string(),
+    })
+}
+
+// Reads events from a JSON array, or from an object holding one under `events`, `results`
+// or `data`, covering Eventbrite's and both of Meetup's response shapes
+fn map_events(body: &[u8]) -> Option<Vec<ImportedEvent>> {
+    let value: Value = serde_json::from_slice(body).ok()?;
+    let items = match &value {
+        Value::Array(items) => items,
+        Value::Object(object) => ["events", "results", "data"]
+            .iter()
+            .find_map(|key| object.get(*key)?.as_array())?,
+        _ => return None,
+    };
+    Some(items.iter().take(MAX_IMPORT_ROWS).map(map_event).collect())
+}
+
+fn map_event(item: &Value) -> ImportedEvent {
+    let starts_at = timestamp(
+        item,
+        &["/start/utc", "/dateTime", "/start_time", "/startsAt", "/start", "/time"],
+    );
+    let ends_at = timestamp(item, &["/end/utc", "/endTime", "/end_time", "/endsAt", "/end"])
+        .or_else(|| {
+            // Meetup gives a duration in milliseconds instead of an end time
+            let duration_ms = item.pointer("/duration")?.as_u64()?;
+            starts_at?.checked_add(duration_ms.checked_mul(1_000_000)?)
+        });
+    let mut location_parts: Vec<String> = Vec::new();
+    let parts = [
+        text(item, &["/venue/name", "/location/name", "/location"]),
+        text(
+            item,
+            &[
+                "/venue/address/localized_address_display",
+                "/venue/address",
+                "/venue/address_1",
+                "/location/address",
+            ],
+        ),
+        text(item, &["/venue/city"]),
+    ];
+    for part in parts.into_iter().flatten() {
+        if !part.is_empty() && !location_parts.contains(&part) {
+            location_parts.push(part);
+        }
+    }
+    let title = text(item, &["/name/text", "/name", "/title"]).unwrap_or_default();
+    let description =
+        text(item, &["/description/text", "/description", "/summary"]).unwrap_or_default();
+    let source_id = match item.pointer("/id") {
+        Some(Value::String(id)) => Some(id.clone()),
+        Some(Value::Number(id)) => Some(id.to_string()),
+        _ => None,
+    };
+    ImportedEvent {
+        source_id: source_id.map(|id| truncate(&id, MAX_SOURCE_ID_BYTES)),
+        title: truncate(&title, MAX_TITLE_BYTES),
+        description: truncate(&strip_html(&description), MAX_DESCRIPTION_BYTES),
+        location: truncate(&location_parts.join(", "), MAX_LOCATION_BYTES),
+        image_url: text(
+            item,
+            &[
+                "/logo/url",
+                "/featuredEventPhoto/highResUrl",
+                "/featured_photo/photo_link",
+                "/imageUrl",
+                "/image_url",
+                "/image",
+            ],
+        )
+        .unwrap_or_default(),
+        starts_at,
+        ends_at,
+        capacity: ["/capacity", "/maxTickets", "/rsvp_limit"]
+            .iter()
+            .find_map(|pointer| item.pointer(pointer)?.as_u64())
+            .and_then(|capacity| u32::try_from(capacity).ok())
+            .filter(|capacity| *capacity > 0),
+        ..Default::default()
+    }
+}
+
+// The first of the fields holding a string, trimmed
+fn text(item: &Value, pointers: &[&str]) -> Option<String> {
+    pointers
+        .iter()
+        .find_map(|pointer| item.pointer(pointer)?.as_str())
+        .map(|text| text.trim().to_string())
+}
+
+// The first of the fields holding an RFC 3339 timestamp or milliseconds since the epoch
+fn timestamp(item: &Value, pointers: &[&str]) -> Option<u64> {
+    pointers.iter().find_map(|pointer| match item.pointer(pointer)? {
+        Value::String(text) => parse_rfc3339(text),
+        Value::Number(millis) => millis.as_u64()?.checked_mul(1_000_000),
+        _ => None,
+    })
+}
+
+// Meetup descriptions are HTML; keep the text with collapsed whitespace
+fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => {
+                in_tag = true;
+                text.push(' ');
+            }
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+// Shortens text to at most `max_bytes`, on a character boundary and marked with an ellipsis
+pub(crate) fn truncate(text: &str, max_bytes: usize) -> String {
+    if text.len() <= max_bytes {
+        return text.to_string();
+    }
+    let mut end = max_bytes - '…'.len_utf8();
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}…", text[..end].trim_end())
+}
+
+fn import_csv(csv: &str) -> Result<Vec<ImportResult>, Error> {
+    let records = parse_csv(csv)?;
+    let Some(((_, header), rows)) = records.split_first() else {
+        return Err(Error::InvalidPayload {
+            msg: "the CSV is empty".to_string(),
+        });
+    };
+    let columns: Vec<String> = header.iter().map(|name| name.trim().to_lowercase()).collect();
+    let known = |name: &String| CSV_COLUMNS.split(", ").any(|column| column == name);
+    if let Some(unknown) = columns.iter().find(|name| !known(name)) {
+        return Err(Error::InvalidPayload {
+            msg: format!("unknown column `{}`; columns can be {}", unknown, CSV_COLUMNS),
+        });
+    }
+    if !columns.iter().any(|name| name == "title") {
+        return Err(Error::InvalidPayload {
+            msg: "the CSV needs a `title` column".to_string(),
+        });
+    }
+    if rows.len() > MAX_CSV_ROWS {
+        return Err(Error::InvalidPayload {
+            msg: format!("a CSV can hold at most {} events", MAX_CSV_ROWS),
+        });
+    }
+    Ok(rows
+        .iter()
+        .map(|(line, fields)| match event_of(&columns, fields) {
+            Ok(event) => create(*line, &event),
+            Err(error) => ImportResult {
+                row: *line,
+                event_id: None,
+                error: Some(error),
+            },
+        })
+        .collect())
+}
+
+// Reads an event from a CSV row; empty fields are left unset
+fn event_of(columns: &[String], fields: &[String]) -> Result<ImportedEvent, String> {
+    if fields.len() > columns.len() {
+        return Err("the row has more fields than the header".to_string());
+    }
+    let mut event = ImportedEvent::default();
+    for (column, value) in columns.iter().zip(fields) {
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        let invalid = |expected: &str| format!("`{}` must be {}", column, expected);
+        let time = || {
+            parse_rfc3339(value).ok_or_else(|| invalid("a date and time like 2025-05-01T18:00:00Z"))
+        };
+        match column.as_str() {
+            "title" => event.title = truncate(value, MAX_TITLE_BYTES),
+            "description" => event.description = truncate(value, MAX_DESCRIPTION_BYTES),
+            "location" => event.location = truncate(value, MAX_LOCATION_BYTES),
+            "image_url" => event.image_url = value.to_string(),
+            "starts_at" => event.starts_at = Some(time()?),
+            "ends_at" => event.ends_at = Some(time()?),
+            "capacity" => {
+                event.capacity = Some(value.parse().map_err(|_| invalid("a whole number"))?)
+            }
+            "venue_id" => event.venue_id = Some(value.parse().map_err(|_| invalid("a venue id"))?),
+            "tags" => {
+                // Tags are separated by commas or semicolons within the field
+                let tags = value.split([',', ';']).map(str::trim).filter(|tag| !tag.is_empty());
+                event.tags = Some(tags.map(str::to_string).collect());
+            }
+            _ => {}
+        }
+    }
+    Ok(event)
+}
+
+// Splits CSV text into records of fields, each with the line it starts on. Fields may be
+// quoted, with `""` for a quote and line breaks inside; blank lines are skipped.
+fn parse_csv(text: &str) -> Result<Vec<(u32, Vec<String>)>, Error> {
+    let mut records = Vec::new();
+    let mut record: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut push_record = |record: &mut Vec<String>, record_line: u32| {
+        let fields = std::mem::take(record);
+        if fields.iter().any(|field| !field.is_empty()) {
+            records.push((record_line, fields));
+        }
+    };
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                _ => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    field.push(c);
+                }
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' => {
+                record.push(std::mem::take(&mut field));
+                push_record(&mut record, record_line);
+                line += 1;
+                record_line = line;
+            }
+            _ => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(Error::InvalidPayload {
+            msg: format!("the quoted field on line {} is never closed", record_line),
+        });
+    }
+    record.push(field);
+    push_record(&mut record, record_line);
+    Ok(records)
+}
+
+// Builds the payload for an imported event and checks it the way `create_event` would,
+// returning why it can't be created
+fn payload_of(event: &ImportedEvent) -> Result<EventPayload, String> {
+    if event.title.is_empty() {
+        return Err("the event has no title".to_string());
+    }
+    let mut payload = EventPayload {
+        event_title: event.title.clone(),
+        event_description: event.description.clone(),
+        event_location: event.location.clone(),
+        event_card_imgurl: event.image_url.clone(),
+        venue_id: event.venue_id,
+        capacity: event.capacity,
+        starts_at: event.starts_at,
+        ends_at: event.ends_at,
+        tags: event.tags.clone(),
+        ..Default::default()
+    };
+    _check_new_event(&mut payload).map_err(|err| match err {
+        Error::InvalidPayload { msg }
+        | Error::NotFound { msg }
+        | Error::NotAuthorized { msg, .. }
+        | Error::DuplicateEvent { msg, .. }
+        | Error::VenueDoubleBooked { msg, .. } => msg,
+        _ => "the event can't be created".to_string(),
+    })?;
+    Ok(payload)
+}
+
+// Creates an imported event owned by the caller, or reports why it can't be created
+fn create(row: u32, event: &ImportedEvent) -> ImportResult {
+    match payload_of(event) {
+        Ok(payload) => {
+            let event = _create_event(payload);
+            google_calendar::enqueue_event(&event);
+            ImportResult {
+                row,
+                event_id: Some(event.id),
+                error: None,
+            }
+        }
+        Err(error) => ImportResult {
+            row,
+            event_id: None,
+            error: Some(error),
+        },
+    }
+}
+
+fn rows_of(owner: &str) -> Vec<ImportRow> {
+    let start = ImportRowKey {
+        owner: owner.to_string(),
+        row: 0,
+    };
+    IMPORT_ROWS.with(|r| {
+        r.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.owner == owner)
+            .map(|(_, row)| row)
+            .collect()
+    })
+}
+
+fn preview_of(owner: &str) -> Option<ImportPreview> {
+    let key = PrincipalKey {
+        principal: owner.to_string(),
+    };
+    let pending = PENDING_IMPORTS.with(|p| p.borrow().get(&key))?;
+    Some(ImportPreview {
+        url: pending.url,
+        fetched_at: pending.fetched_at,
+        rows: rows_of(owner),
+    })
+}
+
+// Splits a chunk into pieces small enough to store, on character boundaries
+fn split_pieces(chunk: &str) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut rest = chunk;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(CSV_PIECE_BYTES);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        pieces.push(rest[..end].to_string());
+        rest = &rest[end..];
+    }
+    pieces
+}
+
+fn csv_pieces_of(owner: &str) -> Vec<String> {
+    let start = CsvPieceKey {
+        owner: owner.to_string(),
+        index: 0,
+    };
+    CSV_UPLOADS.with(|u| {
+        u.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.owner == owner)
+            .map(|(_, piece)| piece.data)
+            .collect()
+    })
+}
+
+fn clear_csv(owner: &str) {
+    for index in 0..csv_pieces_of(owner).len() {
+        let key = CsvPieceKey {
+            owner: owner.to_string(),
+            index: index as u32,
+        };
+        CSV_UPLOADS.with(|u| u.borrow_mut().remove(&key));
+    }
+}
+
+fn clear_import(owner: &str) {
+    for row in rows_of(owner) {
+        let key = ImportRowKey {
+            owner: owner.to_string(),
+            row: row.row,
+        };
+        IMPORT_ROWS.with(|r| r.borrow_mut().remove(&key));
+    }
+    PENDING_IMPORTS.with(|p| p.borrow_mut().remove(&PrincipalKey { principal: owner.to_string() }));
+}
@@ -0,0 +1,214 @@
+// Ingress filter. `inspect_message` runs on one replica before an ingress update call is
+// accepted, so turning away calls that can only fail spares the canister the cycles of
+// executing them: calls to methods not served over ingress, anonymous calls to methods that
+// need a caller, calls from banned principals, arguments larger than the method accepts,
+// and any call while the write throttle is empty. Bucket canisters accept no ingress updates
+// at all, since their router serves the public interface. The filter is a cost guard, not a security
+// boundary, since a replica can skip it; endpoints still check their caller and arguments
+// themselves. Inter-canister calls, such as the router's `bucket_*` calls and cycles
+// deposits, never go through it. New update methods must be added to the list below.
+use crate::{bans, shard, throttle};
+use candid::Principal;
+use ic_cdk::api::call::{accept_message, arg_data_raw_size, method_name};
+use ic_cdk::caller;
+
+const MAX_ARG_BYTES: usize = 32 * 1024;
+
+// Update methods callable over ingress, sorted
+const INGRESS_METHODS: &[&str] = &[
+    "accept_dispute",
+    "accept_gift",
+    "add_accepted_token",
+    "add_session",
+    "add_sponsor",
+    "add_staff",
+    "add_to_agenda",
+    "add_to_beta_allowlist",
+    "answer_question",
+    "append_event_image",
+    "apply_as_organizer",
+    "approve_event",
+    "approve_organizer_application",
+    "ask_question",
+    "assign_group_ticket",
+    "attend_event",
+    "ban_principal",
+    "buy_resale_ticket",
+    "cancel_event",
+    "cancel_event_import",
+    "cancel_gift",
+    "cancel_purchase",
+    "cancel_resale_listing",
+    "cancel_session_rsvp",
+    "cancel_subscription",
+    "change_organization_role",
+    "check_in",
+    "claim_group_ticket",
+    "commit_event_import",
+    "confirm_purchase",
+    "connect_google_calendar",
+    "create_access_token",
+    "create_attendee_export_link",
+    "create_event",
+    "create_from_template",
+    "create_group_claim_link",
+    "create_invite_code",
+    "create_organization",
+    "create_promo_code",
+    "create_template",
+    "create_ticket_tier",
+    "create_venue",
+    "decline_gift",
+    "delete_event",
+    "delete_event_image",
+    "delete_events",
+    "delete_question",
+    "delete_session",
+    "delete_template",
+    "delete_venue",
+    "disconnect_google_calendar",
+    "export_badges",
+    "get_fiat_prices",
+    "get_my_ticket",
+    "get_referral_code",
+    "gift_ticket",
+    "grant_delegation",
+    "hold_seat",
+    "import_events_csv",
+    "invite_organization_member",
+    "list_ticket_for_resale",
+    "mark_read",
+    "merge_events",
+    "moderate_question",
+    "open_dispute",
+    "opt_in_sms",
+    "opt_out_sms",
+    "post_announcement",
+    "prepare_checkout",
+    "preview_event_import",
+    "purchase_group_tickets",
+    "purchase_ticket",
+    "purge_my_events",
+    "rebuild_indexes",
+    "record_view",
+    "redeem_invite_code",
+    "reissue_my_ticket",
+    "reject_event",
+    "reject_organizer_application",
+    "release_seat_hold",
+    "remove_accepted_token",
+    "remove_from_agenda",
+    "remove_from_beta_allowlist",
+    "remove_organization_member",
+    "remove_sponsor",
+    "reorder_sponsors",
+    "reply_to_dispute",
+    "report_event",
+    "resolve_dispute",
+    "respond_to_organization_invite",
+    "revoke_access_token",
+    "revoke_attendee_export_link",
+    "revoke_delegation",
+    "revoke_invite_code",
+    "revoke_staff",
+    "rsvp_session",
+    "seed_demo_data",
+    "set_beta_mode",
+    "set_check_in_window",
+    "set_commemorative_badge",
+    "set_credential_config",
+    "set_credential_requirement",
+    "set_cycles_monitor_config",
+    "set_demand_pricing",
+    "set_deposit_policy",
+    "set_deposit_treasury",
+    "set_event_review_required",
+    "set_event_tokens",
+    "set_event_unlisted",
+    "set_feature_flag",
+    "set_google_oauth_client",
+    "set_governance_canister",
+    "set_image_host_allowlist",
+    "set_log_level",
+    "set_my_profile",
+    "set_no_show_waitlist",
+    "set_organizer_invite_required",
+    "set_payment_ledger",
+    "set_plan",
+    "set_plan_terms",
+    "set_price_phases",
+    "set_privacy_settings",
+    "set_purchase_limits",
+    "set_registration_form",
+    "set_seating_chart",
+    "set_shard_config",
+    "set_sms_provider",
+    "set_system_banner",
+    "set_tier_token_prices",
+    "set_time_offset",
+    "set_unverified_price_limit",
+    "set_write_throttle",
+    "start_purchase",
+    "submit_review",
+    "subscribe",
+    "take_down_events",
+    "takedown_event",
+    "unban_principal",
+    "unverify_organizer",
+    "update_event",
+    "update_organization",
+    "update_session",
+    "update_sponsor",
+    "update_template",
+    "update_ticket_tier",
+    "update_venue",
+    "upload_bucket_wasm",
+    "upload_event_image",
+    "upvote_question",
+    "verify_integrity",
+    "verify_organizer",
+    "withdraw_cycles",
+];
+
+// Update methods open to anonymous callers, such as visitors browsing events
+const ANONYMOUS_METHODS: &[&str] = &["get_fiat_prices", "record_view"];
+
+// Methods taking more than the default argument size
+const LARGE_ARG_METHODS: &[(&str, usize)] = &[
+    // CSV chunks add up to at most 1 MiB
+    ("import_events_csv", 1024 * 1024 + 1024),
+    ("set_seating_chart", 256 * 1024),
+    // Event images are sent in parts of at most 1 MiB
+    ("append_event_image", 1024 * 1024 + 1024),
+    ("upload_event_image", 1024 * 1024 + 1024),
+    // Bucket wasm modules are only limited by the ingress message size
+    ("upload_bucket_wasm", usize::MAX),
+];
+
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    if shard::is_bucket() {
+        return;
+    }
+    let method = method_name();
+    if INGRESS_METHODS.binary_search(&method.as_str()).is_err() {
+        return;
+    }
+    if caller() == Principal::anonymous() && !ANONYMOUS_METHODS.contains(&method.as_str()) {
+        return;
+    }
+    if bans::is_banned(&caller()) {
+        return;
+    }
+    let max_bytes = LARGE_ARG_METHODS
+        .iter()
+        .find(|(name, _)| *name == method)
+        .map_or(MAX_ARG_BYTES, |(_, max_bytes)| *max_bytes);
+    if arg_data_raw_size() > max_bytes {
+        return;
+    }
+    if !throttle::has_token() {
+        return;
+    }
+    accept_message();
+}
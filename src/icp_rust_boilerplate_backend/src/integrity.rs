@@ -0,0 +1,188 @@
+// Consistency checks between the event store and what is derived from it: the event id
+// counter and the secondary indexes (duplicate fingerprints, venue bookings, slugs, attendee
+// schedules and the search index). Index entries pointing at events that no longer exist
+// are reported as orphaned, and events missing from an index as unindexed. Events spilled
+// to buckets or merged into another event still count as existing; deleted events are kept
+// in storage but expected in none of the indexes but search. With `repair` set, the indexes
+// are rebuilt and the counter moved past the highest id in use.
+use crate::admin::{self, _check_admin};
+use crate::{attendees, cache, duplicates, metrics, schedule, shard, slugs, status, venues};
+use crate::{Error, ID_COUNTER, STORAGE};
+use std::collections::{BTreeMap, BTreeSet};
+
+// Issues beyond this many are counted but not listed
+const MAX_LISTED_ISSUES: usize = 100;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum CheckedIndex {
+    EventCounter,
+    Fingerprints,
+    Bookings,
+    Slugs,
+    Schedules,
+    Search,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct IntegrityIssue {
+    index: CheckedIndex,
+    event_id: Option<u64>,
+    problem: String,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct IntegrityReport {
+    events_checked: u64,
+    issue_count: u64,
+    // The first issues found
+    issues: Vec<IntegrityIssue>,
+    repaired: bool,
+}
+
+// What the indexes should hold for an event stored on this canister
+struct Expected {
+    fingerprinted: bool,
+    booked: bool,
+    slug: Option<String>,
+    // Attendees whose schedule should have the event; empty for events without a start
+    scheduled: BTreeSet<String>,
+}
+
+#[derive(Default)]
+struct Issues {
+    count: u64,
+    listed: Vec<IntegrityIssue>,
+}
+
+impl Issues {
+    fn push(&mut self, index: CheckedIndex, event_id: Option<u64>, problem: String) {
+        self.count += 1;
+        if self.listed.len() < MAX_LISTED_ISSUES {
+            self.listed.push(IntegrityIssue {
+                index,
+                event_id,
+                problem,
+            });
+        }
+    }
+}
+
+// Update function for admins to check the secondary indexes and the id counter against the
+// event store, and optionally repair what is wrong
+#[ic_cdk::update]
+fn verify_integrity(repair: bool) -> Result<IntegrityReport, Error> {
+    let _metrics = metrics::track("verify_integrity");
+    _check_admin()?;
+    let local: BTreeMap<u64, Expected> = STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(id, event)| {
+                let deleted = status::is_deleted(id);
+                let scheduled = if event.starts_at.is_some() && !deleted {
+                    attendees::attendees_of(id).into_iter().collect()
+                } else {
+                    BTreeSet::new()
+                };
+                let expected = Expected {
+                    fingerprinted: !deleted,
+                    booked: !deleted && event.venue_id.is_some() && event.starts_at.is_some(),
+                    slug: if deleted { None } else { event.slug },
+                    scheduled,
+                };
+                (id, expected)
+            })
+            .collect()
+    });
+    let exists = |id: u64| {
+        local.contains_key(&id)
+            || shard::bucket_of(id).is_some()
+            || admin::_resolve_merged(id) != id
+    };
+    let mut issues = Issues::default();
+
+    let counter = ID_COUNTER.with(|c| *c.borrow().get());
+    let next_id = local.keys().next_back().map_or(0, |id| id + 1);
+    if counter < next_id {
+        issues.push(
+            CheckedIndex::EventCounter,
+            None,
+            format!("the counter is at {} but event {} exists", counter, next_id - 1),
+        );
+    }
+
+    let (fingerprinted, reverse) = duplicates::indexed_events();
+    for id in fingerprinted.iter().chain(&reverse).filter(|id| !exists(**id)) {
+        issues.push(CheckedIndex::Fingerprints, Some(*id), "orphaned entry".to_string());
+    }
+    let reverse: BTreeSet<u64> = reverse.into_iter().collect();
+    for (id, _) in local.iter().filter(|(id, e)| e.fingerprinted && !reverse.contains(id)) {
+        issues.push(CheckedIndex::Fingerprints, Some(*id), "unindexed event".to_string());
+    }
+
+    let booked: BTreeSet<u64> = venues::booked_events().into_iter().collect();
+    for id in booked.iter().filter(|id| !exists(**id)) {
+        issues.push(CheckedIndex::Bookings, Some(*id), "orphaned booking".to_string());
+    }
+    for (id, _) in local.iter().filter(|(id, e)| e.booked && !booked.contains(id)) {
+        issues.push(CheckedIndex::Bookings, Some(*id), "unbooked event at a venue".to_string());
+    }
+
+    let slugged: BTreeMap<String, u64> = slugs::slugged_events().into_iter().collect();
+    for (slug, id) in slugged.iter().filter(|(_, id)| !exists(**id)) {
+        issues.push(CheckedIndex::Slugs, Some(*id), format!("orphaned slug \"{}\"", slug));
+    }
+    for (id, expected) in &local {
+        let Some(slug) = &expected.slug else {
+            continue;
+        };
+        if slugged.get(slug) != Some(id) {
+            issues.push(CheckedIndex::Slugs, Some(*id), format!("unindexed slug \"{}\"", slug));
+        }
+    }
+
+    let mut scheduled: BTreeSet<(String, u64)> = BTreeSet::new();
+    for (principal, id) in schedule::scheduled_entries() {
+        let stale = match local.get(&id) {
+            Some(expected) => !expected.scheduled.contains(&principal),
+            None => !exists(id),
+        };
+        if stale {
+            let problem = format!("stale entry for {}", principal);
+            issues.push(CheckedIndex::Schedules, Some(id), problem);
+        }
+        scheduled.insert((principal, id));
+    }
+    for (id, expected) in &local {
+        for principal in &expected.scheduled {
+            if !scheduled.contains(&(principal.clone(), *id)) {
+                let problem = format!("missing from the schedule of {}", principal);
+                issues.push(CheckedIndex::Schedules, Some(*id), problem);
+            }
+        }
+    }
+
+    let indexed = cache::indexed_count();
+    if indexed != local.len() as u64 {
+        issues.push(
+            CheckedIndex::Search,
+            None,
+            format!("{} events are indexed out of {} stored", indexed, local.len()),
+        );
+    }
+
+    let repaired = repair && issues.count > 0;
+    if repaired {
+        if counter < next_id {
+            ID_COUNTER
+                .with(|c| c.borrow_mut().set(next_id))
+                .expect("cannot update id counter");
+        }
+        admin::_rebuild_indexes();
+    }
+    Ok(IntegrityReport {
+        events_checked: local.len() as u64,
+        issue_count: issues.count,
+        issues: issues.listed,
+        repaired,
+    })
+}
@@ -0,0 +1,141 @@
+// Community leaderboards. Every award from the points module adds to a principal's score on
+// the affected boards, for all time and for the current month (UTC). Each board keeps a
+// ranking index ordered by score, updated with the score, so reading the top of a board
+// never needs a recount.
+use crate::{points, profiles};
+use crate::{Memory, MEMORY_MANAGER};
+use crate::clock::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const LEADERBOARD_SIZE: usize = 20;
+// Period key of the all-time boards; monthly boards use the month number
+const ALL_TIME: u32 = u32::MAX;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Board {
+    // Attendees by engagement points
+    Points,
+    // Attendees by events they were checked in at
+    CheckIns,
+    // Organizers by attendees across the events they host
+    HostedAttendance,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+enum LeaderboardPeriod {
+    AllTime,
+    ThisMonth,
+}
+
+// A principal's score on one board for one period
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct ScoreKey {
+    board: Board,
+    period: u32,
+    principal: String,
+}
+
+impl_storable!(ScoreKey, 160);
+
+// Entry of a board's ranking index; `inverted_score` puts the highest scores first
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct RankKey {
+    board: Board,
+    period: u32,
+    inverted_score: u64,
+    principal: String,
+}
+
+impl_storable!(RankKey, 176);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct LeaderboardEntry {
+    rank: u32,
+    principal: String,
+    display_name: String,
+    score: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct Leaderboard {
+    attendees_by_points: Vec<LeaderboardEntry>,
+    attendees_by_check_ins: Vec<LeaderboardEntry>,
+    organizers_by_attendance: Vec<LeaderboardEntry>,
+}
+
+thread_local! {
+    static SCORES: RefCell<StableBTreeMap<ScoreKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+    ));
+
+    static RANKINGS: RefCell<StableBTreeMap<RankKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+    ));
+}
+
+// Query function returning the top of each leaderboard for a period
+#[ic_cdk::query]
+fn get_leaderboard(period: LeaderboardPeriod) -> Leaderboard {
+    let period = match period {
+        LeaderboardPeriod::AllTime => ALL_TIME,
+        LeaderboardPeriod::ThisMonth => points::month_of(time()),
+    };
+    Leaderboard {
+        attendees_by_points: top(Board::Points, period),
+        attendees_by_check_ins: top(Board::CheckIns, period),
+        organizers_by_attendance: top(Board::HostedAttendance, period),
+    }
+}
+
+// Adds to a principal's all-time and current-month score on a board
+pub(crate) fn add(board: Board, principal: &str, amount: u64) {
+    let month = points::month_of(time());
+    for period in [ALL_TIME, month] {
+        let key = ScoreKey {
+            board,
+            period,
+            principal: principal.to_string(),
+        };
+        let previous = SCORES.with(|s| s.borrow().get(&key)).unwrap_or(0);
+        let score = previous + amount;
+        SCORES.with(|s| s.borrow_mut().insert(key, score));
+        RANKINGS.with(|r| {
+            let mut rankings = r.borrow_mut();
+            if previous > 0 {
+                rankings.remove(&rank_key(board, period, previous, principal));
+            }
+            rankings.insert(rank_key(board, period, score, principal), score);
+        });
+    }
+}
+
+fn top(board: Board, period: u32) -> Vec<LeaderboardEntry> {
+    let start = rank_key(board, period, u64::MAX, "");
+    RANKINGS.with(|r| {
+        r.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.board == board && key.period == period)
+            .take(LEADERBOARD_SIZE)
+            .enumerate()
+            .map(|(index, (key, score))| LeaderboardEntry {
+                rank: index as u32 + 1,
+                display_name: profiles::display_name(&key.principal),
+                principal: key.principal,
+                score,
+            })
+            .collect()
+    })
+}
+
+fn rank_key(board: Board, period: u32, score: u64, principal: &str) -> RankKey {
+    RankKey {
+        board,
+        period,
+        inverted_score: u64::MAX - score,
+        principal: principal.to_string(),
+    }
+}
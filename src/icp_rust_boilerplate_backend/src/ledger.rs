@@ -0,0 +1,196 @@
+// Minimal ICRC-1 ledger client used to move tokens for paid events
+use candid::{Nat, Principal};
+use ic_cdk::api::{id, time};
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) struct Account {
+    pub(crate) owner: Principal,
+    pub(crate) subaccount: Option<Vec<u8>>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AllowanceArgs {
+    account: Account,
+    spender: Account,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct Allowance {
+    allowance: Nat,
+    expires_at: Option<u64>,
+}
+
+// A transfer out of one of this canister's accounts
+pub(crate) struct Transfer {
+    pub(crate) from_subaccount: Option<Vec<u8>>,
+    pub(crate) to: Account,
+    pub(crate) amount: u128,
+    // Memo and creation time make retries idempotent within the ledger's dedup window
+    pub(crate) memo: Vec<u8>,
+    pub(crate) created_at_time: u64,
+}
+
+// Sends tokens and returns the ledger block index. A transfer the ledger reports as a
+// duplicate of an earlier one counts as a success.
+pub(crate) async fn transfer(ledger: Principal, transfer: Transfer) -> Result<u64, String> {
+    let arg = TransferArg {
+        from_subaccount: transfer.from_subaccount,
+        to: transfer.to,
+        amount: Nat::from(transfer.amount),
+        fee: None,
+        memo: Some(transfer.memo),
+        created_at_time: Some(transfer.created_at_time),
+    };
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (arg,))
+        .await
+        .map_err(|(code, msg)| format!("icrc1_transfer failed with {:?}: {}", code, msg))?;
+    match result {
+        Ok(block_index) | Err(TransferError::Duplicate { duplicate_of: block_index }) => {
+            Ok(nat_to_u64(&block_index))
+        }
+        Err(err) => Err(format!("icrc1_transfer rejected: {:?}", err)),
+    }
+}
+
+// Pulls tokens the payer approved for this canister (ICRC-2) into the canister's default
+// account and returns the ledger block index
+pub(crate) async fn transfer_from(
+    ledger: Principal,
+    from: Account,
+    amount: u128,
+    memo: Vec<u8>,
+) -> Result<u64, String> {
+    let arg = TransferFromArgs {
+        spender_subaccount: None,
+        from,
+        to: Account {
+            owner: id(),
+            subaccount: None,
+        },
+        amount: Nat::from(amount),
+        fee: None,
+        memo: Some(memo),
+        created_at_time: Some(time()),
+    };
+    let (result,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(ledger, "icrc2_transfer_from", (arg,))
+            .await
+            .map_err(|(code, msg)| format!("icrc2_transfer_from failed with {:?}: {}", code, msg))?;
+    match result {
+        Ok(block_index) => Ok(nat_to_u64(&block_index)),
+        // A pull is never retried, so a duplicate is another charge with the same memo and
+        // pays nothing for this one
+        Err(TransferFromError::Duplicate { duplicate_of }) => Err(format!(
+            "icrc2_transfer_from duplicates the transfer in block {}",
+            duplicate_of
+        )),
+        // The ledger fee is taken from the allowance too, so the approval has to cover both
+        Err(TransferFromError::InsufficientAllowance { allowance }) => {
+            let required = match fee(ledger).await {
+                Ok(fee) => amount.saturating_add(fee).to_string(),
+                Err(_) => format!("{} plus the ledger fee", amount),
+            };
+            Err(format!(
+                "approve this canister for at least {} tokens first (current allowance: {})",
+                required, allowance
+            ))
+        }
+        Err(err) => Err(format!("icrc2_transfer_from rejected: {:?}", err)),
+    }
+}
+
+// Returns how much the owner's default account approved this canister to pull (ICRC-2), and
+// when the approval expires
+pub(crate) async fn allowance(
+    ledger: Principal,
+    owner: Principal,
+) -> Result<(u128, Option<u64>), String> {
+    let arg = AllowanceArgs {
+        account: Account {
+            owner,
+            subaccount: None,
+        },
+        spender: Account {
+            owner: id(),
+            subaccount: None,
+        },
+    };
+    let (allowance,): (Allowance,) = ic_cdk::call(ledger, "icrc2_allowance", (arg,))
+        .await
+        .map_err(|(code, msg)| format!("icrc2_allowance failed with {:?}: {}", code, msg))?;
+    let amount = u128::try_from(&allowance.allowance.0).unwrap_or(u128::MAX);
+    // An expired approval can no longer be used
+    match allowance.expires_at {
+        Some(expires_at) if expires_at <= time() => Ok((0, None)),
+        expires_at => Ok((amount, expires_at)),
+    }
+}
+
+// Returns the fee the ledger charges per transfer
+pub(crate) async fn fee(ledger: Principal) -> Result<u128, String> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, msg)| format!("icrc1_fee failed with {:?}: {}", code, msg))?;
+    u128::try_from(&fee.0).map_err(|_| "ledger fee does not fit in 128 bits".to_string())
+}
+
+// Returns the ledger's token symbol and the number of decimals its amounts use
+pub(crate) async fn token(ledger: Principal) -> Result<(String, u8), String> {
+    let (symbol,): (String,) = ic_cdk::call(ledger, "icrc1_symbol", ())
+        .await
+        .map_err(|(code, msg)| format!("icrc1_symbol failed with {:?}: {}", code, msg))?;
+    let (decimals,): (u8,) = ic_cdk::call(ledger, "icrc1_decimals", ())
+        .await
+        .map_err(|(code, msg)| format!("icrc1_decimals failed with {:?}: {}", code, msg))?;
+    Ok((symbol, decimals))
+}
+
+fn nat_to_u64(nat: &Nat) -> u64 {
+    u64::try_from(&nat.0).unwrap_or(u64::MAX)
+}
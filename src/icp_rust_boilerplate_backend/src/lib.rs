@@ -1,12 +1,129 @@
 #[macro_use]
     extern crate serde;
     use candid::{Decode, Encode};
-    use ic_cdk::api::time,
     use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
     use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-    use std::{borrow::Cow, cell::RefCell};
+    use std::{borrow::Cow, cell::RefCell, ops::Bound};
     use ic_cdk::caller;
+    use ic_cdk::api::call::RejectionCode;
     use candid::Principal;
+    use notifications::NotificationKind;
+    use activity::ActivityKind;
+    use delegations::DelegatedAction;
+    use clock::time;
+
+    // Largest page returned by `list_events_after`
+    const MAX_LIST_PAGE: u64 = 100;
+    // Most events `delete_events` takes in one call
+    const MAX_BULK_DELETE: usize = 100;
+    // Longest principal in text form, as event owners are stored
+    pub(crate) const MAX_PRINCIPAL_TEXT_BYTES: usize = 63;
+    // Room kept in an event record for the slug and image status, set after validation
+    const EVENT_HEADROOM_BYTES: usize = 128;
+
+    // Implements Storable and BoundedStorable for a candid-encoded type stored in a stable struct
+    macro_rules! impl_storable {
+        ($type:ty, $max_size:expr) => {
+            impl ic_stable_structures::Storable for $type {
+                fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+                    std::borrow::Cow::Owned(candid::Encode!(self).unwrap())
+                }
+
+                fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+                    candid::Decode!(bytes.as_ref(), Self).unwrap()
+                }
+            }
+
+            impl ic_stable_structures::BoundedStorable for $type {
+                const MAX_SIZE: u32 = $max_size;
+                const IS_FIXED_SIZE: bool = false;
+            }
+        };
+    }
+
+    mod access;
+    mod achievements;
+    mod activity;
+    mod admin;
+    mod analytics;
+    mod announcements;
+    mod assets;
+    mod attendance_proofs;
+    mod attendees;
+    mod badges;
+    mod banner;
+    mod bans;
+    mod beta;
+    mod billing;
+    mod budget;
+    mod cache;
+    mod calendar;
+    mod clock;
+    mod credentials;
+    mod config;
+    mod cycles;
+    mod delegations;
+    mod deposits;
+    mod diagnostics;
+    mod disputes;
+    mod duplicates;
+    mod feeds;
+    mod flags;
+    mod fiat;
+    mod gifts;
+    mod google_calendar;
+    mod groups;
+    mod history;
+    mod http;
+    mod images;
+    mod imports;
+    mod inspect;
+    mod integrity;
+    mod leaderboards;
+    mod ledger;
+    mod limits;
+    mod logs;
+    mod metrics;
+    mod moderation;
+    mod notifications;
+    mod noshows;
+    mod orders;
+    mod organizations;
+    mod organizers;
+    mod pages;
+    mod payments;
+    mod plans;
+    mod points;
+    mod profiles;
+    mod promos;
+    mod questions;
+    mod referrals;
+    mod registration;
+    mod reputation;
+    mod reservations;
+    mod resale;
+    mod reviews;
+    mod schedule;
+    mod seating;
+    mod secrets;
+    mod seed;
+    mod sessions;
+    mod shard;
+    mod slugs;
+    mod sms;
+    mod sponsors;
+    mod staff;
+    mod status;
+    mod tags;
+    mod throttle;
+    mod templates;
+    mod tickets;
+    mod tiers;
+    mod tokens;
+    mod venues;
+    mod verification;
+    mod views;
+    mod weather;
 
     type Memory = VirtualMemory<DefaultMemoryImpl>;
     type IdCell = Cell<u64, Memory>;
@@ -21,9 +138,28 @@
         event_title: String,
         event_location : String,
         event_card_imgurl : String,
+        // Attendees are kept in their own map (see attendees.rs) and listed by `get_attendees`;
+        // this is only read to move lists stored before that, and is otherwise always empty
         attendees : Vec<String>,
         created_at: u64,
         updated_at: Option<u64>,
+        // Registered venue the event takes place at, if any
+        venue_id: Option<u64>,
+        // Maximum number of attendees; unlimited when None
+        capacity: Option<u32>,
+        // Start and end of the event in nanoseconds since the epoch
+        starts_at: Option<u64>,
+        ends_at: Option<u64>,
+        // Lowercased topic labels used for filtering
+        tags: Option<Vec<String>>,
+        // Result of checking that the card image URL serves an image
+        image_status: Option<images::ImageStatus>,
+        // URL-safe name derived from the title at creation
+        slug: Option<String>,
+        // Whether the organizer is verified; filled in when the event is returned, never stored
+        organizer_verified: Option<bool>,
+        // Number of attendees; filled in when the event is read, never stored
+        attendee_count: Option<u64>,
     }
 
      // a trait that must be implemented for a struct that is stored in a stable struct
@@ -63,6 +199,93 @@
     }
 
 
+    // Composite key identifying a principal's record within a specific event
+    #[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+    struct EventPrincipalKey {
+        event_id: u64,
+        principal: String,
+    }
+
+    impl_storable!(EventPrincipalKey, 128);
+
+
+    // Key for records stored per principal
+    #[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+    struct PrincipalKey {
+        principal: String,
+    }
+
+    impl_storable!(PrincipalKey, 96);
+
+
+    // Optional arguments accepted when the canister is installed or upgraded
+    #[derive(candid::CandidType, Serialize, Deserialize, Default)]
+    struct InitArgs {
+        // Set when this canister is installed as a bucket by a router canister
+        router: Option<Principal>,
+        config: Option<config::Config>,
+    }
+
+
+    #[ic_cdk::init]
+    fn init(args: Option<InitArgs>) {
+        let args = args.unwrap_or_default();
+        if let Some(router) = args.router {
+            shard::set_router(router);
+        }
+        if let Some(config) = args.config {
+            config::apply(config);
+        }
+        cycles::start_monitor();
+        payments::start_refund_processor();
+        sms::start_reminder_sender();
+        google_calendar::start_calendar_sync();
+        weather::start_forecast_fetcher();
+        billing::start_billing();
+        deposits::start_deposit_settlement();
+        noshows::start_no_show_tracking();
+        reservations::start_reservation_expiry();
+        seating::start_hold_expiry();
+        assets::certify();
+    }
+
+
+    // Heap caches are saved to stable memory so they survive the upgrade
+    #[ic_cdk::pre_upgrade]
+    fn pre_upgrade() {
+        cache::save();
+    }
+
+
+    // Timers do not survive upgrades, so they are restarted here. A router given on upgrade
+    // is ignored: buckets keep the one they were installed with.
+    #[ic_cdk::post_upgrade]
+    fn post_upgrade(args: Option<InitArgs>) {
+        cache::restore();
+        attendees::migrate();
+        venues::migrate();
+        cycles::migrate();
+        payments::migrate();
+        gifts::migrate();
+        templates::migrate();
+        if let Some(config) = args.and_then(|args| args.config) {
+            config::apply(config);
+        }
+        cycles::start_monitor();
+        payments::start_refund_processor();
+        sms::start_reminder_sender();
+        google_calendar::start_calendar_sync();
+        weather::start_forecast_fetcher();
+        billing::start_billing();
+        deposits::start_deposit_settlement();
+        noshows::start_no_show_tracking();
+        reservations::start_reservation_expiry();
+        seating::start_hold_expiry();
+        assets::certify();
+        tiers::schedule_price_phases();
+    }
+
+
     // Event payload for creating or updating an Event
     #[derive(candid::CandidType, Serialize, Deserialize, Default)]
     struct EventPayload {
@@ -70,13 +293,88 @@
         event_title: String,
         event_location : String,
         event_card_imgurl : String,
+        // Ask for a dedicated child canister on creation (factory mode); ignored on update
+        dedicated_canister: Option<bool>,
+        // Registered venue to take the location and default capacity from
+        venue_id: Option<u64>,
+        capacity: Option<u32>,
+        starts_at: Option<u64>,
+        ends_at: Option<u64>,
+        tags: Option<Vec<String>>,
+        // Create the event even if the caller already has one with the same title, day and location
+        allow_duplicate: Option<bool>,
+        // Book the venue even if another event overlaps there, when the venue only warns
+        allow_double_booking: Option<bool>,
+        // Organization to create the event for; ignored on update
+        organization_id: Option<u64>,
     }
 
 
     // Query function to retrieve details of a specific event by its unique identifier
+    #[ic_cdk::query(composite = true)]
+    async fn get_event(id: u64) -> Result<Event, Error> {
+        let event = _fetch_event(id).await?;
+
+        // Events awaiting or refused admin review are only visible to their owner and admins
+        status::_check_visible(&event)?;
+
+        // Unlisted events are only shown in full to their members
+        Ok(verification::with_badge(access::redact(event)))
+    }
+
+    // A page of `list_events_after`
+    #[derive(candid::CandidType, Serialize, Deserialize)]
+    struct EventListPage {
+        events: Vec<Event>,
+        // Cursor for the next page: the id of the last event looked at, which may not be
+        // listed. None once every event has been looked at.
+        next: Option<u64>,
+    }
+
+    // Query function listing published, listed events in id order, starting after the given
+    // id or from the first event when None. The cursor is optional rather than a plain id as
+    // event ids start at 0, so no id lies before the first event. Paging with `next` never
+    // skips or repeats an event, whatever is created or deleted in between. A page stops at
+    // the scan budget, so it can be short, or even empty, while `next` is still set.
+    // Events spilled to bucket canisters are listed by their bucket.
     #[ic_cdk::query]
-    fn get_event(id: u64) -> Result<Event, Error> {
-        
+    fn list_events_after(id: Option<u64>, limit: u64) -> EventListPage {
+        let start = match id {
+            Some(id) => Bound::Excluded(id),
+            None => Bound::Unbounded,
+        };
+        let limit = limit.clamp(1, MAX_LIST_PAGE) as usize;
+        let mut page = EventListPage {
+            events: Vec::new(),
+            next: None,
+        };
+        let mut scanned = None;
+        STORAGE.with(|s| {
+            for (id, event) in s.borrow().range((start, Bound::Unbounded)) {
+                // At least one event is looked at, so paging always moves on
+                if page.events.len() == limit || (scanned.is_some() && budget::exhausted()) {
+                    page.next = scanned;
+                    return;
+                }
+                scanned = Some(id);
+                if status::is_published(id) && !access::is_unlisted(id) {
+                    page.events.push(verification::with_badge(event));
+                }
+            }
+        });
+        page
+    }
+
+    // Helper function to retrieve an event wherever it is stored
+    async fn _fetch_event(id: u64) -> Result<Event, Error> {
+        // Events merged into another one are redirected to it
+        let id = admin::_resolve_merged(id);
+
+        // Events that were spilled to a bucket canister are fetched from there
+        if let Some(bucket) = shard::bucket_of(id) {
+            return shard::forward(bucket, "bucket_get_event", (id,)).await;
+        }
+
         // Attempt to retrieve the event using the internal helper function
         match _get_event(&id) {
             // If the event is found, return it as a Result::Ok
@@ -90,9 +388,84 @@
     }
 
     
-    // Function to create a new event based on the provided payload
+    // Function to create a new event based on the provided payload. Submitting an event that
+    // duplicates one of the caller's fails with `DuplicateEvent` carrying the existing id, so
+    // clients can dedupe.
     #[ic_cdk::update]
-    fn create_event(payload: EventPayload) -> Option<Event> {
+    async fn create_event(payload: EventPayload) -> Result<Event, Error> {
+        let _metrics = metrics::track("create_event");
+        _prepare_and_create(payload).await
+    }
+
+
+    // Helper function running every check an event created by the caller goes through before
+    // it is stored, filling in the venue and normalizing the tags on the way. Imports, which
+    // create events without awaiting, call it directly.
+    pub(crate) fn _check_new_event(payload: &mut EventPayload) -> Result<(), Error> {
+        shard::_check_not_bucket()?;
+        organizers::_check_organizer()?;
+        organizations::_check_can_create(payload)?;
+        beta::_check_allowlisted()?;
+        venues::apply_venue(payload)?;
+        tags::normalize_tags(payload)?;
+        _check_event_size(payload)?;
+        images::_validate_image_url(&payload.event_card_imgurl)?;
+        _validate_event_times(payload)?;
+        duplicates::_check_duplicate(payload)?;
+        venues::_check_double_booking(payload, None)?;
+        plans::_check_event_quota(&caller().to_string())
+    }
+
+    // Helper function checking an event created by the caller with `_check_new_event`, then
+    // storing it here or on another canister. Endpoints creating events from other input build
+    // the payload and hand it over here, so they can't drift from `create_event`.
+    pub(crate) async fn _prepare_and_create(mut payload: EventPayload) -> Result<Event, Error> {
+        _check_new_event(&mut payload)?;
+        let dedicated_canister = payload.dedicated_canister.unwrap_or(false);
+        if dedicated_canister {
+            plans::_check_feature(&caller().to_string(), plans::Feature::DedicatedCanister)?;
+        }
+        let event = _build_event(payload);
+
+        // The event goes to a dedicated child canister if requested, or to a bucket canister
+        // once local stable memory nears its limit
+        if let Some(event) = shard::place_remotely(event.clone(), dedicated_canister).await? {
+            status::_hold_for_review(event.id);
+            duplicates::remember(&event);
+            venues::remember_booking(&event);
+            google_calendar::enqueue_event(&event);
+            return Ok(event);
+        }
+
+        // Insert the newly created event into the storage
+        do_insert(&event);
+        status::_hold_for_review(event.id);
+        duplicates::remember(&event);
+        venues::remember_booking(&event);
+        images::verify_image(&event);
+        google_calendar::enqueue_event(&event);
+
+        Ok(event)
+    }
+
+
+    // Helper function that builds, stores and returns a new event owned by the caller
+    fn _create_event(payload: EventPayload) -> Event {
+        let event = _build_event(payload);
+
+        // Insert the newly created event into the storage
+        do_insert(&event);
+        status::_hold_for_review(event.id);
+        duplicates::remember(&event);
+        venues::remember_booking(&event);
+        images::verify_image(&event);
+
+        event
+    }
+
+
+    // Helper function that allocates an id and builds a new event owned by the caller
+    fn _build_event(payload: EventPayload) -> Event {
         // Increment the unique identifier for the new event
         let id = ID_COUNTER
             .with(|counter| {
@@ -101,8 +474,17 @@
             })
             .expect("cannot increment id counter");
 
+        let image_status = images::initial_status(&payload.event_card_imgurl);
+        let slug = slugs::claim(&payload.event_title, id);
+        achievements::record_event_organized(&caller().to_string());
+        let title = &payload.event_title;
+        activity::record(&caller().to_string(), ActivityKind::CreatedEvent, id, title);
+        if let Some(org_id) = payload.organization_id {
+            organizations::remember_event(id, org_id);
+        }
+
         // Create a new Event instance with the provided payload and additional details        
-        let event = Event {
+        Event {
             id,
             event_description: payload.event_description,
             owner: caller().to_string(),
@@ -112,31 +494,52 @@
             attendees : Vec::new(),
             created_at: time(),
             updated_at: None,
-        };
-
-        // Insert the newly created event into the storage
-        do_insert(&event);
-
-        // Return the newly created event as an Option
-        Some(event)
+            venue_id: payload.venue_id,
+            capacity: payload.capacity,
+            starts_at: payload.starts_at,
+            ends_at: payload.ends_at,
+            tags: payload.tags,
+            image_status,
+            slug: Some(slug),
+            organizer_verified: None,
+            attendee_count: None,
+        }
     }
 
 
     // Update function to modify the details of an existing event
     #[ic_cdk::update]
-    fn update_event(id: u64, payload: EventPayload) -> Result<Event, Error> {
-    
-    // Check if the caller is the owner of the event; if not, return an authorization error
-    if !_check_if_owner(&_get_event(&id).unwrap().clone()){
-        return Err(Error::NotAuthorized {
-            msg: format!(
-                "You're not the owner of the event with id={}",
-                id
-            ),
-            caller: caller()
-        })
+    async fn update_event(id: u64, mut payload: EventPayload) -> Result<Event, Error> {
+        let _metrics = metrics::track("update_event");
+        venues::apply_venue(&mut payload)?;
+        tags::normalize_tags(&mut payload)?;
+        _check_event_size(&payload)?;
+        images::_validate_image_url(&payload.event_card_imgurl)?;
+        _validate_event_times(&payload)?;
+        // Only the owner learns whether the venue is booked; spilled events are checked by
+        // their bucket, which holds them
+        if shard::bucket_of(id).is_none() {
+            _get_updatable_event(caller(), id)?;
+        }
+        venues::_check_double_booking(&payload, Some(id))?;
+
+        // Events that were spilled to a bucket canister are updated there
+        let event = match shard::bucket_of(id) {
+            Some(bucket) => shard::forward(bucket, "bucket_update_event", (caller(), id, payload)).await?,
+            None => _update_event(caller(), id, payload)?,
+        };
+        duplicates::remember(&event);
+        venues::remember_booking(&event);
+        schedule::refresh(&event);
+        google_calendar::enqueue_event(&event);
+        Ok(event)
     }
 
+
+    // Helper function to modify an event on behalf of the given principal
+    fn _update_event(principal: Principal, id: u64, payload: EventPayload) -> Result<Event, Error> {
+    _get_updatable_event(principal, id)?;
+
         // Attempt to retrieve the event from storage based on its unique identifier
         match STORAGE.with(|service| service.borrow().get(&id)) {
            
@@ -146,11 +549,27 @@
                 event.event_description = payload.event_description;
                 event.event_title = payload.event_title;
                 event.event_location  = payload.event_location;
+                if event.event_card_imgurl != payload.event_card_imgurl {
+                    event.image_status = images::initial_status(&payload.event_card_imgurl);
+                }
                 event.event_card_imgurl  = payload.event_card_imgurl;
+                event.venue_id = payload.venue_id;
+                event.capacity = payload.capacity;
+                event.starts_at = payload.starts_at;
+                event.ends_at = payload.ends_at;
+                event.tags = payload.tags;
                 event.updated_at = Some(time());
                 
                 // Insert the modified event back into storage
                 do_insert(&event);
+                images::verify_image(&event);
+
+                // Let attendees know the details changed
+                notifications::notify_attendees(
+                    &event,
+                    NotificationKind::EventUpdated,
+                    format!("\"{}\" has been updated", event.event_title),
+                );
                 Ok(event)
             }
 
@@ -165,30 +584,123 @@
     }
 
 
-    // Update function to add an attendee to a specific event
+    // Helper function returning an event the principal may modify: it exists, wasn't deleted
+    // and the principal owns it
+    fn _get_updatable_event(principal: Principal, id: u64) -> Result<Event, Error> {
+        let Some(event) = _get_event(&id) else {
+            return Err(Error::NotFound {
+                msg: format!("Event with id={} not found", id),
+            });
+        };
+        if status::is_deleted(id) {
+            return Err(Error::InvalidPayload {
+                msg: format!("Event with id={} was deleted and can't be updated", id),
+            });
+        }
+
+        // Check if the principal is the owner of the event; if not, return an authorization error
+        if !_is_owner(&event, &principal) {
+            return Err(Error::NotAuthorized {
+                msg: format!("You're not the owner of the event with id={}", id),
+                caller: principal,
+            });
+        }
+        Ok(event)
+    }
+
+
+    // Update function to add an attendee to a specific event, optionally through a referral code,
+    // with answers to the event's registration form. An event overlapping others the caller
+    // attends is refused with the conflicts listed, unless `allow_conflicts` is set. A
+    // delegated service can RSVP for the principal it passes as `on_behalf_of`.
     #[ic_cdk::update]
-    fn attend_event(id: u64) -> Result<Event, Error> {
-    
+    async fn attend_event(
+        id: u64,
+        referral_code: Option<String>,
+        answers: Option<Vec<registration::Answer>>,
+        allow_conflicts: Option<bool>,
+        credential: Option<String>,
+        on_behalf_of: Option<Principal>,
+    ) -> Result<Event, Error> {
+        let _metrics = metrics::track("attend_event");
+        // Every check below applies to the event a merged id now redirects to
+        let id = admin::_resolve_merged(id);
+        beta::_check_allowlisted()?;
+        let attendee = delegations::_acting_for(on_behalf_of, DelegatedAction::Rsvp)?;
+        // Review holds are kept here even for events stored in a bucket
+        status::_check_open_for_registration(id)?;
+        credentials::_check_credential(id, credential.as_deref())?;
+        noshows::_check_no_show_waitlist(id, &attendee.to_string())?;
+        if let Some(code) = &referral_code {
+            referrals::_check_code(id, code)?;
+        }
+        let answers = registration::_check_answers(id, answers.unwrap_or_default())?;
+        let target = _fetch_event(id).await?;
+        let allow_conflicts = allow_conflicts.unwrap_or(false);
+        schedule::_check_conflicts(&target, &attendee.to_string(), allow_conflicts)?;
+
+        // Events that were spilled to a bucket canister are attended there
+        let event = match shard::bucket_of(id) {
+            Some(bucket) => shard::forward(bucket, "bucket_attend_event", (attendee, id)).await?,
+            None => {
+                // Free events may ask for a deposit, returned when the attendee checks in
+                deposits::_take_deposit(id, attendee).await?;
+                _attend_event(attendee, id)
+                    .inspect_err(|_| deposits::return_deposit(id, &attendee.to_string()))?
+            }
+        };
+        points::record_attendance(&event, &attendee.to_string());
+        schedule::record(&event, &attendee.to_string());
+        activity::record(&attendee.to_string(), ActivityKind::Rsvped, id, &event.event_title);
+        google_calendar::enqueue(id, &attendee.to_string());
+        if let Some(code) = &referral_code {
+            referrals::credit(id, code, &attendee.to_string());
+        }
+        registration::store_answers(id, &attendee.to_string(), answers);
+        Ok(event)
+    }
+
+
+    // Helper function to add the given principal to an event's attendees
+    fn _attend_event(principal: Principal, id: u64) -> Result<Event, Error> {
+
+    // Cancelled events no longer accept attendees
+    status::_check_open_for_registration(id)?;
+
+    // Events selling ticket tiers are joined by purchasing a ticket
+    tiers::_check_free_registration(id)?;
+
+    _add_attendee(principal, id)
+}
+
+
+    // Helper function to record the given principal as an attendee, respecting the event's capacity
+    fn _add_attendee(principal: Principal, id: u64) -> Result<Event, Error> {
+
     // Attempt to retrieve the event from storage based on its unique identifier
     match STORAGE.with(|service| service.borrow().get(&id)) {
         Some(mut event) => {
-            // Get the caller's identity as an attendee
-            let attendee = caller().to_string();
-            
-            // Retrieve the current list of attendees for the event
-            let mut attendees: Vec<String> = event.attendees;
-
-            // Check if that caller is already in the attendees list
-            if attendees.contains(&attendee) {
+            // Get the principal's identity as an attendee
+            let attendee = principal.to_string();
+
+            // Check if that caller is already an attendee
+            if attendees::is_attending(id, &attendee) {
                 // Return an error message
                 Err(Error::NotFound {
-                    msg: format!("You are already an attendee"),
+                    msg: "You are already an attendee".to_string(),
+                })
+            } else if event
+                .capacity
+                .is_some_and(|capacity| attendees::count(id) >= capacity.into())
+            {
+                // The event has no seats left
+                Err(Error::InvalidPayload {
+                    msg: format!("Event with id={} is full", id),
                 })
             } else {
-                attendees.push(attendee);
-                event.attendees = attendees;
-
-                do_insert(&event);
+                attendees::add(id, &attendee);
+                event.attendee_count = Some(attendees::count(id));
+                attendees::record_registration(id, &attendee);
                 // Return the modified event on success
                 Ok(event)
             }
@@ -205,24 +717,107 @@
 
     // Update function to delete a specific event by its unique identifier
     #[ic_cdk::update]
-    fn delete_event(id: u64) -> Result<Event, Error> {
+    async fn delete_event(id: u64) -> Result<Event, Error> {
+        let _metrics = metrics::track("delete_event");
+        _delete_and_clean_up(id).await.map(|(event, _)| event)
+    }
+
+
+    // Outcome of deleting one event of a bulk delete
+    #[derive(candid::CandidType, Serialize, Deserialize)]
+    struct EventDeletion {
+        event_id: u64,
+        // Refunds queued for the event's paid tickets
+        refunds_queued: u64,
+        error: Option<Error>,
+    }
+
+    // Update function to delete several of the caller's events at once. Each id is deleted
+    // on its own, so one the caller doesn't own or that doesn't exist doesn't stop the rest.
+    #[ic_cdk::update]
+    async fn delete_events(ids: Vec<u64>) -> Result<Vec<EventDeletion>, Error> {
+        let _metrics = metrics::track("delete_events");
+        if ids.len() > MAX_BULK_DELETE {
+            return Err(Error::InvalidPayload {
+                msg: format!("at most {} events can be deleted at once", MAX_BULK_DELETE),
+            });
+        }
+        let mut deletions: Vec<EventDeletion> = Vec::new();
+        for id in ids {
+            if deletions.iter().any(|deletion| deletion.event_id == id) {
+                continue;
+            }
+            let deletion = match _delete_and_clean_up(id).await {
+                Ok((_, refunds_queued)) => EventDeletion {
+                    event_id: id,
+                    refunds_queued,
+                    error: None,
+                },
+                Err(error) => EventDeletion {
+                    event_id: id,
+                    refunds_queued: 0,
+                    error: Some(error),
+                },
+            };
+            deletions.push(deletion);
+        }
+        Ok(deletions)
+    }
+
+    // Helper function deleting an event of the caller wherever it is stored, then dropping it
+    // from the indexes and refunding its paid tickets and held deposits. Returns the event
+    // and the number of refunds queued.
+    async fn _delete_and_clean_up(id: u64) -> Result<(Event, u64), Error> {
+        // Events that were spilled to a bucket canister are deleted there
+        let event = match shard::bucket_of(id) {
+            Some(bucket) => shard::forward_delete_event(bucket, id).await?,
+            None => _delete_event(caller(), id)?,
+        };
+        duplicates::forget(id);
+        venues::forget_booking(id);
+        slugs::release(&event);
+        schedule::forget(&event);
+        assets::remove_image(id);
+        let refunds_queued = payments::enqueue_refunds(id);
+        deposits::return_deposits(id);
+        google_calendar::enqueue_event(&event);
+        attendees::forget(id);
+        Ok((event, refunds_queued))
+    }
+
+
+    // Helper function to delete an event on behalf of the given principal
+    fn _delete_event(principal: Principal, id: u64) -> Result<Event, Error> {
     
-    // Check if the caller is the owner of the event; if not, return an authorization error
-    if !_check_if_owner(&_get_event(&id).unwrap().clone()){
+    let Some(event) = _get_event(&id) else {
+        return Err(Error::NotFound {
+            msg: format!("Event with id={} not found", id),
+        });
+    };
+
+    // Check if the principal is the owner of the event; if not, return an authorization error
+    if !_is_owner(&event, &principal){
         return Err(Error::NotAuthorized {
             msg: format!(
                 "You're not the owner of the event with id={}",
                 id
             ),
-            caller: caller()
+            caller: principal
         })
     }
 
     // Attempt to remove the event from storage based on its unique identifier
-    match STORAGE.with(|service| service.borrow_mut().remove(&id)) {
+    match cache::remove(id) {
         
-        // If the event is found and removed, return it as a Result::Ok
-        Some(event) => Ok(event),
+        // If the event is found and removed, let attendees know and return it as a Result::Ok
+        Some(event) => {
+            notifications::notify_attendees(
+                &event,
+                NotificationKind::EventCancelled,
+                format!("\"{}\" has been deleted by its organizer", event.event_title),
+            );
+            Ok(event)
+        }
 
         // If the event is not found, return a Result::Err with a NotFound error
         None => Err(Error::NotFound {
@@ -243,27 +838,158 @@
 
         // Indicates an authorization error when the caller is not the owner of the event
         NotAuthorized {msg: String , caller: Principal},
+
+        // Indicates that the submitted payload failed validation
+        InvalidPayload { msg: String },
+
+        // Indicates that a presented ticket token is malformed, forged or revoked
+        InvalidTicket { msg: String },
+
+        // Indicates that a required credential is missing or could not be verified
+        InvalidCredential { msg: String },
+
+        // Indicates that a call to another canister failed
+        CallFailed { msg: String },
+
+        // Indicates that a ticket tier is not on sale at the current time
+        SaleNotOpen { msg: String },
+
+        // Indicates that a ticket tier or the event itself has no seats left
+        TierSoldOut { msg: String },
+
+        // Indicates that charging the ticket price on the ledger failed
+        PaymentFailed { msg: String },
+
+        // Indicates that a promo code does not exist, has expired or is used up
+        InvalidPromoCode { msg: String },
+
+        // Indicates that a purchase would exceed the event's per-principal limits or cooldown
+        PurchaseLimitExceeded { msg: String },
+
+        // Indicates that the caller already created a near-identical event
+        DuplicateEvent { msg: String, existing_id: u64 },
+
+        // Indicates that the event overlaps others the caller attends
+        ScheduleConflict { msg: String, conflicts: Vec<schedule::ConflictingEvent> },
+
+        // Indicates that another event is booked at the same venue at the same time
+        VenueDoubleBooked { msg: String, conflicts: Vec<schedule::ConflictingEvent> },
+
+        // Indicates that admins switched the feature off, for everyone or for the caller
+        FeatureDisabled { msg: String },
     }
 
 
      // Helper method to insert an event.
      fn do_insert(event: &Event) {
-        STORAGE.with(|service| service.borrow_mut().insert(event.id, event.clone()));
+        cache::store(event);
     }
 
     // Helper method to retrieve an event by it's id 
     fn _get_event(id: &u64) -> Option<Event> {
-        STORAGE.with(|s| s.borrow().get(id))
+        cache::event(*id)
     }
     
+    // Helper method to retrieve an event and check that the caller organizes it
+    fn _get_organized_event(id: u64) -> Result<Event, Error> {
+        let event = _get_event(&id).ok_or(Error::NotFound {
+            msg: format!("Event with id={} not found", id),
+        })?;
+        if !_check_if_owner(&event) {
+            return Err(Error::NotAuthorized {
+                msg: format!("You're not the owner of the event with id={}", id),
+                caller: caller(),
+            });
+        }
+        Ok(event)
+    }
+
+    // Helper function to reject an event whose record wouldn't fit the storage bound. Text is
+    // counted in bytes, and room is left for the slug and image status filled in later.
+    fn _check_event_size(payload: &EventPayload) -> Result<(), Error> {
+        let probe = Event {
+            id: u64::MAX,
+            event_description: payload.event_description.clone(),
+            owner: "x".repeat(MAX_PRINCIPAL_TEXT_BYTES),
+            event_title: payload.event_title.clone(),
+            event_location: payload.event_location.clone(),
+            event_card_imgurl: payload.event_card_imgurl.clone(),
+            created_at: u64::MAX,
+            updated_at: Some(u64::MAX),
+            venue_id: payload.venue_id,
+            capacity: payload.capacity,
+            starts_at: payload.starts_at,
+            ends_at: payload.ends_at,
+            tags: payload.tags.clone(),
+            ..Default::default()
+        };
+        let size = Encode!(&probe).map_or(usize::MAX, |bytes| bytes.len());
+        let limit = Event::MAX_SIZE as usize - EVENT_HEADROOM_BYTES;
+        if size > limit {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "the event takes {} bytes but at most {} fit; shorten its description",
+                    size, limit
+                ),
+            });
+        }
+        Ok(())
+    }
+
+
+    // Helper function to reject a change made to a stored event after its payload was checked,
+    // such as a new image URL, when the record would no longer fit the storage bound
+    pub(crate) fn _check_event_fits(event: &Event) -> Result<(), Error> {
+        let size = Encode!(event).map_or(usize::MAX, |bytes| bytes.len());
+        if size > Event::MAX_SIZE as usize {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "the event would take {} bytes but at most {} fit; shorten its description",
+                    size,
+                    Event::MAX_SIZE
+                ),
+            });
+        }
+        Ok(())
+    }
+
+
+    // Helper function to reject an event that ends before it starts
+    fn _validate_event_times(payload: &EventPayload) -> Result<(), Error> {
+        match (payload.starts_at, payload.ends_at) {
+            (Some(starts_at), Some(ends_at)) if ends_at < starts_at => Err(Error::InvalidPayload {
+                msg: "an event cannot end before it starts".to_string(),
+            }),
+            (None, Some(_)) => Err(Error::InvalidPayload {
+                msg: "an event with an end time needs a start time".to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Helper function to turn a failed inter-canister call into an Error
+    fn _call_failed(method: &str, (code, msg): (RejectionCode, String)) -> Error {
+        Error::CallFailed {
+            msg: format!("{} failed with {:?}: {}", method, code, msg),
+        }
+    }
+
     // Helper function to check whether the caller is the owner of the event
     fn _check_if_owner(event: &Event) -> bool {
-    if event.owner.to_string() != caller().to_string(){
-        false  
-    }else{
-        true
+        _is_owner(event, &caller())
+    }
+
+    // Helper function to check whether the given principal is the owner of the event, or
+    // manages it for the organization owning it
+    fn _is_owner(event: &Event, principal: &Principal) -> bool {
+        event.owner == principal.to_string() || organizations::can_manage_event(event.id, principal)
+    }
+
+    // Helper function to check whether the given principal is the owner of the event or
+    // belongs to the organization owning it, with any role
+    fn _is_on_team(event: &Event, principal: &Principal) -> bool {
+        _is_owner(event, principal) || organizations::is_member_for_event(event.id, principal)
     }
-}
 
 
 
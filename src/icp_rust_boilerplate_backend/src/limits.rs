@@ -0,0 +1,149 @@
+// Anti-scalping purchase limits. Organizers can cap how many tickets a principal may buy
+// for an event and per tier, counting primary sales and resale purchases alike, and
+// enforce a cooldown between a principal's purchases.
+use crate::{metrics, Error, EventPrincipalKey, Memory, MEMORY_MANAGER, _get_organized_event};
+use crate::clock::time;
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PurchaseLimits {
+    max_per_event: Option<u32>,
+    max_per_tier: Option<u32>,
+    cooldown_secs: Option<u64>,
+}
+
+impl_storable!(PurchaseLimits, 64);
+
+// How many tickets a principal has bought for an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PurchaseHistory {
+    total: u32,
+    // (tier id, tickets bought in that tier)
+    per_tier: Vec<(u64, u32)>,
+    last_purchase_at: u64,
+}
+
+impl_storable!(PurchaseHistory, 512);
+
+thread_local! {
+    static PURCHASE_LIMITS: RefCell<StableBTreeMap<u64, PurchaseLimits, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+
+    static PURCHASE_HISTORY: RefCell<StableBTreeMap<EventPrincipalKey, PurchaseHistory, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+    ));
+}
+
+// Update function for an event's organizer to set its purchase limits
+#[ic_cdk::update]
+fn set_purchase_limits(event_id: u64, limits: PurchaseLimits) -> Result<PurchaseLimits, Error> {
+    let _metrics = metrics::track("set_purchase_limits");
+    _get_organized_event(event_id)?;
+    if limits.max_per_event == Some(0) || limits.max_per_tier == Some(0) {
+        return Err(Error::InvalidPayload {
+            msg: "purchase limits must allow at least one ticket".to_string(),
+        });
+    }
+    PURCHASE_LIMITS.with(|l| l.borrow_mut().insert(event_id, limits.clone()));
+    Ok(limits)
+}
+
+// Query function returning the purchase limits of an event
+#[ic_cdk::query]
+fn get_purchase_limits(event_id: u64) -> PurchaseLimits {
+    limits_of(event_id)
+}
+
+// Helper function to reject a purchase of `quantity` tickets that would exceed the event's
+// limits. The tier is None for resale purchases, which count only towards the per-event limit.
+pub(crate) fn _check_purchase_limits(
+    event_id: u64,
+    principal: Principal,
+    tier_id: Option<u64>,
+    quantity: u32,
+) -> Result<(), Error> {
+    let limits = limits_of(event_id);
+    let history = history_of(event_id, principal);
+    let exceeded = |msg: String| Err(Error::PurchaseLimitExceeded { msg });
+
+    if let Some(max) = limits.max_per_event
+        && history.total + quantity > max
+    {
+        return exceeded(format!("You can buy at most {} tickets for this event", max));
+    }
+    if let (Some(max), Some(tier_id)) = (limits.max_per_tier, tier_id)
+        && tier_count(&history, tier_id) + quantity > max
+    {
+        return exceeded(format!("You can buy at most {} tickets in this tier", max));
+    }
+    if let Some(cooldown) = limits.cooldown_secs
+        && history.total > 0
+    {
+        let ready_at = history
+            .last_purchase_at
+            .saturating_add(cooldown.saturating_mul(NANOS_PER_SEC));
+        if time() < ready_at {
+            return exceeded(format!(
+                "Please wait {} more seconds before buying another ticket",
+                (ready_at - time()).div_ceil(NANOS_PER_SEC)
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Counts a completed purchase of `quantity` tickets towards the principal's limits
+pub(crate) fn record_purchase(
+    event_id: u64,
+    principal: Principal,
+    tier_id: Option<u64>,
+    quantity: u32,
+) {
+    let mut history = history_of(event_id, principal);
+    history.total += quantity;
+    history.last_purchase_at = time();
+    if let Some(tier_id) = tier_id {
+        match history.per_tier.iter_mut().find(|(id, _)| *id == tier_id) {
+            Some((_, count)) => *count += quantity,
+            None => history.per_tier.push((tier_id, quantity)),
+        }
+    }
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    PURCHASE_HISTORY.with(|h| h.borrow_mut().insert(key, history));
+}
+
+fn limits_of(event_id: u64) -> PurchaseLimits {
+    PURCHASE_LIMITS
+        .with(|l| l.borrow().get(&event_id))
+        .unwrap_or_default()
+}
+
+fn history_of(event_id: u64, principal: Principal) -> PurchaseHistory {
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    PURCHASE_HISTORY
+        .with(|h| h.borrow().get(&key))
+        .unwrap_or_default()
+}
+
+fn tier_count(history: &PurchaseHistory, tier_id: u64) -> u32 {
+    history
+        .per_tier
+        .iter()
+        .find(|(id, _)| *id == tier_id)
+        .map(|(_, count)| *count)
+        .unwrap_or_default()
+}
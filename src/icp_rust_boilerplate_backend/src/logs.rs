@@ -0,0 +1,136 @@
+// Structured log. Background jobs and admin operations write entries with a level, the module
+// they come from and a message, and admins read them back with `get_logs`, so what happened
+// on a live canister can be looked into without redeploying it with extra output. The log
+// is a ring buffer in stable memory holding the latest entries, and survives upgrades.
+// Entries below the level set with `set_log_level` are dropped when written; every entry is
+// also printed to the replica's debug output.
+use crate::admin::_check_admin;
+use crate::{metrics, Error, IdCell, Memory, MEMORY_MANAGER};
+use crate::clock::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+
+const MAX_LOG_ENTRIES: u64 = 10_000;
+const MAX_LOGS_PAGE: usize = 500;
+const MAX_MESSAGE_BYTES: usize = 1024;
+
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+    Debug, Default,
+)]
+pub(crate) enum LogLevel {
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LogEntry {
+    seq: u64,
+    level: LogLevel,
+    timestamp: u64,
+    module: String,
+    message: String,
+}
+
+impl_storable!(LogEntry, 1280);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LogConfig {
+    level: LogLevel,
+}
+
+impl_storable!(LogConfig, 16);
+
+thread_local! {
+    static LOG_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(127))), 0)
+            .expect("Cannot create the log sequence counter")
+    );
+
+    static LOGS: RefCell<StableBTreeMap<u64, LogEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(128)))
+    ));
+
+    static LOG_CONFIG: RefCell<Cell<LogConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(129))), LogConfig::default())
+            .expect("Cannot create the log config cell")
+    );
+}
+
+// Query function for admins returning log entries at or above a level, written at or after a
+// time, oldest first; page through by passing the last timestamp seen as `since`
+#[ic_cdk::query]
+fn get_logs(level: Option<LogLevel>, since: Option<u64>) -> Result<Vec<LogEntry>, Error> {
+    _check_admin()?;
+    let level = level.unwrap_or(LogLevel::Debug);
+    let since = since.unwrap_or(0);
+    Ok(LOGS.with(|l| {
+        l.borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.level >= level && entry.timestamp >= since)
+            .take(MAX_LOGS_PAGE)
+            .collect()
+    }))
+}
+
+// Query function returning the level below which log entries are dropped
+#[ic_cdk::query]
+fn get_log_level() -> LogLevel {
+    LOG_CONFIG.with(|c| c.borrow().get().level)
+}
+
+// Update function for admins to change the level below which log entries are dropped
+#[ic_cdk::update]
+fn set_log_level(level: LogLevel) -> Result<LogLevel, Error> {
+    let _metrics = metrics::track("set_log_level");
+    _check_admin()?;
+    LOG_CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(LogConfig { level })
+            .expect("cannot update the log level")
+    });
+    Ok(level)
+}
+
+// Writes a log entry from a module, given as `module_path!()`, unless it is below the level
+pub(crate) fn write(level: LogLevel, module: &str, mut message: String) {
+    if level < get_log_level() {
+        return;
+    }
+    let module = module.rsplit("::").next().unwrap_or(module).to_string();
+    ic_cdk::println!("[{:?}] {}: {}", level, module, message);
+    if message.len() > MAX_MESSAGE_BYTES {
+        let mut end = MAX_MESSAGE_BYTES;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+    let seq = LOG_SEQ
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment log sequence counter");
+    let entry = LogEntry {
+        seq,
+        level,
+        timestamp: time(),
+        module,
+        message,
+    };
+    LOGS.with(|l| {
+        let mut logs = l.borrow_mut();
+        logs.insert(seq, entry);
+        while logs.len() > MAX_LOG_ENTRIES {
+            let Some((oldest, _)) = logs.iter().next() else { break };
+            logs.remove(&oldest);
+        }
+    });
+}
@@ -0,0 +1,110 @@
+// Per-method call statistics. Every update endpoint starts with `metrics::track`, whose guard
+// records the call when the endpoint returns: how often each method is called and how many
+// instructions it takes, as a total, a maximum and a histogram by order of magnitude. On the
+// IC instructions are what makes a call slow and costly, so they stand in for latency. Async
+// endpoints are measured over their whole call context, awaits included. Queries can't keep
+// state, and calls that trap are rolled back, so neither is counted. Being the first thing
+// every update call does, `track` is also where banned callers are refused and calls pass
+// the write throttle.
+use crate::admin::_check_admin;
+use crate::{bans, throttle, Error, Memory, MEMORY_MANAGER};
+use crate::clock::time;
+use ic_cdk::api::performance_counter;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Instructions used by the whole call context, rather than the current message only
+const CALL_CONTEXT_COUNTER: u32 = 1;
+// Upper bounds of the histogram buckets; a last bucket holds anything above
+const BUCKET_BOUNDS: [u64; 5] = [
+    1_000_000,
+    10_000_000,
+    100_000_000,
+    1_000_000_000,
+    10_000_000_000,
+];
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct MethodKey {
+    method: String,
+}
+
+impl_storable!(MethodKey, 96);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct MethodStats {
+    method: String,
+    calls: u64,
+    total_instructions: u128,
+    max_instructions: u64,
+    // Calls per bucket: under 1M, 10M, 100M, 1B and 10B instructions, then above
+    histogram: Vec<u64>,
+    last_called_at: u64,
+}
+
+impl_storable!(MethodStats, 256);
+
+// Records the call it was created for when dropped
+pub(crate) struct CallTracker {
+    method: &'static str,
+}
+
+impl Drop for CallTracker {
+    fn drop(&mut self) {
+        record(self.method, performance_counter(CALL_CONTEXT_COUNTER));
+    }
+}
+
+thread_local! {
+    static METHOD_STATS: RefCell<StableBTreeMap<MethodKey, MethodStats, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(126)))
+    ));
+}
+
+// Query function for admins listing call statistics of the update methods, the most
+// instructions in total first
+#[ic_cdk::query]
+fn get_method_stats() -> Result<Vec<MethodStats>, Error> {
+    _check_admin()?;
+    let mut stats: Vec<MethodStats> =
+        METHOD_STATS.with(|s| s.borrow().iter().map(|(_, stats)| stats).collect());
+    stats.sort_by(|a, b| b.total_instructions.cmp(&a.total_instructions));
+    Ok(stats)
+}
+
+// Starts measuring a call to the named method, once it got past the ban list and the write
+// throttle; keep the guard until the method returns
+pub(crate) fn track(method: &'static str) -> CallTracker {
+    bans::_check_not_banned();
+    throttle::take_token();
+    CallTracker { method }
+}
+
+fn record(method: &str, instructions: u64) {
+    let key = MethodKey {
+        method: method.to_string(),
+    };
+    METHOD_STATS.with(|s| {
+        let mut map = s.borrow_mut();
+        let mut stats = map.get(&key).unwrap_or_else(|| MethodStats {
+            method: method.to_string(),
+            calls: 0,
+            total_instructions: 0,
+            max_instructions: 0,
+            histogram: vec![0; BUCKET_BOUNDS.len() + 1],
+            last_called_at: 0,
+        });
+        stats.calls += 1;
+        stats.total_instructions += instructions as u128;
+        stats.max_instructions = stats.max_instructions.max(instructions);
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|bound| instructions < *bound)
+            .unwrap_or(BUCKET_BOUNDS.len());
+        stats.histogram[bucket] += 1;
+        stats.last_called_at = time();
+        map.insert(key, stats);
+    });
+}
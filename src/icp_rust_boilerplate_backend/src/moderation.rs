@@ -0,0 +1,232 @@
+// Spam moderation. Users report events they find abusive with `report_event`, once per
+// event. Admins select events by owner, creation time range and number of reports and take
+// them down in bulk with `take_down_events`; a dry run returns the selection without
+// touching anything. Taken-down events are soft-deleted like purged ones: attendees are told,
+// paid tickets refunded and the events hidden, but nothing is removed from storage.
+use crate::admin::{self, _check_admin};
+use crate::logs::{self, LogLevel};
+use crate::notifications::{self, NotificationKind};
+use crate::{metrics, status, Error, Event, EventPrincipalKey, Memory, MEMORY_MANAGER, STORAGE};
+use crate::{_get_event, cache};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const MAX_REASON_BYTES: usize = 500;
+// Most events taken down in one call; the rest are left for the next call
+const MAX_TAKEDOWN_EVENTS: usize = 200;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EventReport {
+    event_id: u64,
+    reporter: Principal,
+    reason: String,
+    reported_at: u64,
+}
+
+impl_storable!(EventReport, 768);
+
+// Events to take down; every criterion given must match
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TakedownFilter {
+    owner: Option<Principal>,
+    // Creation time range in nanoseconds since the epoch, both ends included
+    created_after: Option<u64>,
+    created_before: Option<u64>,
+    min_reports: Option<u32>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TakedownCandidate {
+    event_id: u64,
+    event_title: String,
+    owner: String,
+    created_at: u64,
+    reports: u32,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct BulkTakedown {
+    dry_run: bool,
+    // Events taken down, or that would be on a dry run
+    events: Vec<TakedownCandidate>,
+    refunds_queued: u64,
+    // Set when more events matched than one call takes down
+    more: bool,
+}
+
+thread_local! {
+    static EVENT_REPORTS: RefCell<StableBTreeMap<EventPrincipalKey, EventReport, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(166)))
+    ));
+}
+
+// Update function reporting an event as spam or abuse; reporting it again replaces the reason
+#[ic_cdk::update]
+fn report_event(event_id: u64, reason: String) -> Result<(), Error> {
+    let _metrics = metrics::track("report_event");
+    let reporter = caller();
+    let event = _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    if event.owner == reporter.to_string() {
+        return Err(Error::InvalidPayload {
+            msg: "You can't report your own event".to_string(),
+        });
+    }
+    let reason = reason.trim().to_string();
+    if reason.is_empty() || reason.len() > MAX_REASON_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("a reason of at most {} bytes is required", MAX_REASON_BYTES),
+        });
+    }
+    let key = EventPrincipalKey {
+        event_id,
+        principal: reporter.to_string(),
+    };
+    let report = EventReport {
+        event_id,
+        reporter,
+        reason,
+        reported_at: time(),
+    };
+    EVENT_REPORTS.with(|r| r.borrow_mut().insert(key, report));
+    Ok(())
+}
+
+// Query function for admins listing the reports filed against an event
+#[ic_cdk::query]
+fn get_event_reports(event_id: u64) -> Result<Vec<EventReport>, Error> {
+    _check_admin()?;
+    let start = EventPrincipalKey {
+        event_id,
+        principal: String::new(),
+    };
+    Ok(EVENT_REPORTS.with(|r| {
+        r.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(_, report)| report)
+            .collect()
+    }))
+}
+
+// Update function for admins to take down the events matching a filter, oldest first, or
+// with `dry_run` to only list them. Events already deleted are left out; each one taken down
+// gets an audit record listed by `get_takedowns`.
+#[ic_cdk::update]
+fn take_down_events(
+    filter: TakedownFilter,
+    reason: String,
+    dry_run: bool,
+) -> Result<BulkTakedown, Error> {
+    let _metrics = metrics::track("take_down_events");
+    _check_admin()?;
+    if filter.owner.is_none()
+        && filter.created_after.is_none()
+        && filter.created_before.is_none()
+        && filter.min_reports.is_none()
+    {
+        return Err(Error::InvalidPayload {
+            msg: "at least one criterion is required".to_string(),
+        });
+    }
+    admin::_check_takedown_reason(&reason).map_err(|msg| Error::InvalidPayload { msg })?;
+
+    let reports = report_counts();
+    // An owner's events come from their index rather than a scan of every event
+    let candidates: Vec<Event> = match filter.owner {
+        Some(owner) => cache::events_owned_by(&owner.to_string())
+            .iter()
+            .filter_map(_get_event)
+            .collect(),
+        None => STORAGE.with(|s| s.borrow().iter().map(|(_, event)| event).collect()),
+    };
+    let mut matched: Vec<(Event, u32)> = candidates
+        .into_iter()
+        .map(|event| {
+            let count = reports.get(&event.id).copied().unwrap_or_default();
+            (event, count)
+        })
+        .filter(|(event, reports)| {
+            filter.created_after.is_none_or(|after| event.created_at >= after)
+                && filter.created_before.is_none_or(|before| event.created_at <= before)
+                && filter.min_reports.is_none_or(|min| *reports >= min)
+                && !status::is_deleted(event.id)
+        })
+        .collect();
+    matched.sort_by_key(|(event, _)| event.created_at);
+    let more = matched.len() > MAX_TAKEDOWN_EVENTS;
+    matched.truncate(MAX_TAKEDOWN_EVENTS);
+
+    // Every audit record is built before anything is taken down, so one that wouldn't fit
+    // refuses the whole call instead of trapping halfway through it
+    let records = if dry_run {
+        Vec::new()
+    } else {
+        matched
+            .iter()
+            .map(|(event, _)| admin::_takedown_record(event, &reason))
+            .collect::<Result<Vec<_>, String>>()
+            .map_err(|msg| Error::InvalidPayload { msg })?
+    };
+    let mut records = records.into_iter();
+
+    let mut takedown = BulkTakedown {
+        dry_run,
+        events: Vec::new(),
+        refunds_queued: 0,
+        more,
+    };
+    for (event, reports) in matched {
+        if !dry_run {
+            takedown.refunds_queued += status::soft_delete(
+                &event,
+                format!("\"{}\" has been removed by the moderators", event.event_title),
+            );
+            notifications::notify(
+                &event.owner,
+                NotificationKind::ReviewDecision,
+                Some(event.id),
+                format!(
+                    "\"{}\" was taken down by the moderators: {}",
+                    event.event_title, reason
+                ),
+            );
+            if let Some(record) = records.next() {
+                admin::record_takedown(record);
+            }
+        }
+        takedown.events.push(TakedownCandidate {
+            event_id: event.id,
+            event_title: event.event_title,
+            owner: event.owner,
+            created_at: event.created_at,
+            reports,
+        });
+    }
+    if !dry_run {
+        logs::write(
+            LogLevel::Info,
+            module_path!(),
+            format!("{} took down {} event(s)", caller(), takedown.events.len()),
+        );
+    }
+    Ok(takedown)
+}
+
+// Number of reports filed against each reported event
+fn report_counts() -> BTreeMap<u64, u32> {
+    let mut counts: BTreeMap<u64, u32> = BTreeMap::new();
+    EVENT_REPORTS.with(|r| {
+        for (key, _) in r.borrow().iter() {
+            *counts.entry(key.event_id).or_default() += 1;
+        }
+    });
+    counts
+}
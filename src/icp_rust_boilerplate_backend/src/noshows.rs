@@ -0,0 +1,487 @@
+// No-show tracking. A while after an event ends, everyone who registered but was never
+// checked in is recorded as a no-show. Events where nobody was checked in at all are
+// skipped, since their organizer evidently didn't scan tickets at the door. Attendees see
+// their own history; organizers only get counts for their events.
+//
+// For high-demand events, organizers can have chronic no-shows waitlisted: registrations
+// from principals with at least a set number of no-shows are held back and only admitted a
+// day before the event if seats are still free.
+use crate::notifications::{self, NotificationKind};
+use crate::{attendance_proofs, attendees, deposits, google_calendar, metrics, points, reputation};
+use crate::{schedule, status, tickets};
+use crate::{Error, EventPrincipalKey, Memory, MEMORY_MANAGER, STORAGE};
+use crate::{_attend_event, _get_event, _get_organized_event};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::time::Duration;
+
+const NO_SHOW_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+// Late check-ins are still accepted for a while after the event ends
+const RECORD_AFTER_NANOS: u64 = 6 * NANOS_PER_HOUR;
+// Events that ended longer ago than this are left alone
+const LOOKBACK_NANOS: u64 = 30 * 24 * NANOS_PER_HOUR;
+// Waitlisted registrants are admitted this long before the event starts
+const WAITLIST_RELEASE_NANOS: u64 = 24 * NANOS_PER_HOUR;
+const DEFAULT_THRESHOLD: u32 = 3;
+const MAX_TITLE_CHARS: usize = 100;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct NoShowKey {
+    principal: String,
+    event_id: u64,
+}
+
+impl_storable!(NoShowKey, 96);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NoShow {
+    event_id: u64,
+    event_title: String,
+    starts_at: u64,
+    recorded_at: u64,
+}
+
+impl_storable!(NoShow, 512);
+
+// Turnout of an ended event, once its no-shows have been recorded
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EventTurnout {
+    registered: u32,
+    checked_in: u32,
+    no_shows: u32,
+    // Set when nobody was checked in, so no no-shows were recorded
+    skipped: bool,
+    recorded_at: u64,
+}
+
+impl_storable!(EventTurnout, 64);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct WaitlistPolicy {
+    // No-shows from which a registrant is waitlisted
+    threshold: u32,
+}
+
+impl_storable!(WaitlistPolicy, 32);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct WaitlistEntry {
+    no_shows: u32,
+    joined_at: u64,
+}
+
+impl_storable!(WaitlistEntry, 32);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct NoShowStats {
+    event_id: u64,
+    // None until the event has ended and its no-shows were recorded
+    turnout: Option<EventTurnout>,
+    // Current registrants with at least one past no-show
+    registrants_with_no_shows: u32,
+    waitlisted: u32,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct OrganizerNoShowStats {
+    events: u32,
+    registered: u32,
+    checked_in: u32,
+    no_shows: u32,
+}
+
+thread_local! {
+    static NO_SHOWS: RefCell<StableBTreeMap<NoShowKey, NoShow, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(107)))
+    ));
+
+    static TURNOUTS: RefCell<StableBTreeMap<u64, EventTurnout, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(108)))
+    ));
+
+    static WAITLIST_POLICIES: RefCell<StableBTreeMap<u64, WaitlistPolicy, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(109)))
+    ));
+
+    static WAITLIST: RefCell<StableBTreeMap<EventPrincipalKey, WaitlistEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(110)))
+    ));
+
+    // Guards against two timer runs admitting the same waitlisted registrants
+    static RELEASING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Starts the periodic recording of no-shows and release of waitlists; called from init and
+// post_upgrade
+pub(crate) fn start_no_show_tracking() {
+    ic_cdk_timers::set_timer_interval(NO_SHOW_INTERVAL, || {
+        record_ended_events();
+        ic_cdk::spawn(release_waitlists());
+    });
+}
+
+// Query function listing the events the caller registered for but never showed up to,
+// most recent first
+#[ic_cdk::query]
+fn my_no_shows() -> Vec<NoShow> {
+    let mut no_shows = no_shows_of(&caller().to_string());
+    no_shows.sort_by(|a, b| b.starts_at.cmp(&a.starts_at));
+    no_shows
+}
+
+// Query function returning no-show counts for an event; restricted to its organizer
+#[ic_cdk::query]
+fn get_no_show_stats(event_id: u64) -> Result<NoShowStats, Error> {
+    _get_organized_event(event_id)?;
+    let registrants_with_no_shows = attendees::attendees_of(event_id)
+        .iter()
+        .filter(|attendee| no_show_count(attendee) > 0)
+        .count() as u32;
+    Ok(NoShowStats {
+        event_id,
+        turnout: TURNOUTS.with(|t| t.borrow().get(&event_id)),
+        registrants_with_no_shows,
+        waitlisted: waitlist_of(event_id).len() as u32,
+    })
+}
+
+// Query function summing up the turnout of all the caller's ended events
+#[ic_cdk::query]
+fn get_organizer_no_show_stats() -> OrganizerNoShowStats {
+    let organizer = caller().to_string();
+    let event_ids: Vec<u64> = STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, event)| event.owner == organizer)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    let mut stats = OrganizerNoShowStats {
+        events: 0,
+        registered: 0,
+        checked_in: 0,
+        no_shows: 0,
+    };
+    for turnout in event_ids
+        .into_iter()
+        .filter_map(|id| TURNOUTS.with(|t| t.borrow().get(&id)))
+        .filter(|turnout| !turnout.skipped)
+    {
+        stats.events += 1;
+        stats.registered += turnout.registered;
+        stats.checked_in += turnout.checked_in;
+        stats.no_shows += turnout.no_shows;
+    }
+    stats
+}
+
+// Update function for an event's organizer to waitlist registrants with at least
+// `threshold` no-shows (3 if not given), or to stop with `enabled` false. Only applies to
+// events with a capacity and a start time.
+#[ic_cdk::update]
+fn set_no_show_waitlist(
+    event_id: u64,
+    enabled: bool,
+    threshold: Option<u32>,
+) -> Result<Option<WaitlistPolicy>, Error> {
+    let _metrics = metrics::track("set_no_show_waitlist");
+    let event = _get_organized_event(event_id)?;
+    if !enabled {
+        WAITLIST_POLICIES.with(|p| p.borrow_mut().remove(&event_id));
+        for (key, _) in waitlist_of(event_id) {
+            WAITLIST.with(|w| w.borrow_mut().remove(&key));
+            notifications::notify(
+                &key.principal,
+                NotificationKind::PromotedFromWaitlist,
+                Some(event_id),
+                format!(
+                    "The waitlist for \"{}\" was lifted; you can register now",
+                    event.event_title
+                ),
+            );
+        }
+        return Ok(None);
+    }
+    if event.capacity.is_none() || event.starts_at.is_none() {
+        return Err(Error::InvalidPayload {
+            msg: "only events with a capacity and a start time can waitlist no-shows".to_string(),
+        });
+    }
+    let policy = WaitlistPolicy {
+        threshold: threshold.unwrap_or(DEFAULT_THRESHOLD).max(1),
+    };
+    WAITLIST_POLICIES.with(|p| p.borrow_mut().insert(event_id, policy.clone()));
+    Ok(Some(policy))
+}
+
+// Helper function waitlisting a chronic no-show registering for an event that asks for it.
+// The registration is refused for now with an error saying when it may go through.
+pub(crate) fn _check_no_show_waitlist(event_id: u64, principal: &str) -> Result<(), Error> {
+    let Some(policy) = WAITLIST_POLICIES.with(|p| p.borrow().get(&event_id)) else {
+        return Ok(());
+    };
+    if release_time(event_id).is_none_or(|release_at| release_at <= time()) {
+        return Ok(());
+    }
+    let no_shows = no_show_count(principal);
+    if no_shows < policy.threshold {
+        return Ok(());
+    }
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    // Registering again keeps the registrant's place
+    if !WAITLIST.with(|w| w.borrow().contains_key(&key)) {
+        let entry = WaitlistEntry {
+            no_shows,
+            joined_at: time(),
+        };
+        WAITLIST.with(|w| w.borrow_mut().insert(key, entry));
+    }
+    Err(Error::InvalidPayload {
+        msg: format!(
+            "You missed {} events you registered for, so you're on the waitlist; you'll be admitted a day before the event if seats are left",
+            no_shows
+        ),
+    })
+}
+
+// Number of events the principal registered for and never showed up to
+pub(crate) fn no_show_count(principal: &str) -> u32 {
+    no_shows_of(principal).len() as u32
+}
+
+// Records the turnout of events that ended a while ago, and their no-shows
+fn record_ended_events() {
+    let now = time();
+    let ended: Vec<u64> = STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, event)| {
+                event_end(event.starts_at, event.ends_at).is_some_and(|ends_at| {
+                    ends_at.saturating_add(RECORD_AFTER_NANOS) <= now
+                        && ends_at.saturating_add(LOOKBACK_NANOS) > now
+                })
+            })
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for id in ended {
+        if TURNOUTS.with(|t| t.borrow().contains_key(&id)) || !status::is_going_ahead(id) {
+            continue;
+        }
+        record_turnout(id, now);
+    }
+}
+
+fn record_turnout(id: u64, now: u64) {
+    let Some(event) = _get_event(&id) else {
+        return;
+    };
+    let attendees = attendees::attendees_of(id);
+    let missing: Vec<&String> = attendees
+        .iter()
+        .filter(|attendee| {
+            !tickets::is_checked_in(&EventPrincipalKey {
+                event_id: id,
+                principal: attendee.to_string(),
+            })
+        })
+        .collect();
+    let registered = attendees.len() as u32;
+    let checked_in = registered - missing.len() as u32;
+    let skipped = checked_in == 0;
+    if !skipped {
+        for attendee in &missing {
+            let key = NoShowKey {
+                principal: attendee.to_string(),
+                event_id: id,
+            };
+            let no_show = NoShow {
+                event_id: id,
+                event_title: event.event_title.chars().take(MAX_TITLE_CHARS).collect(),
+                starts_at: event.starts_at.unwrap_or_default(),
+                recorded_at: now,
+            };
+            NO_SHOWS.with(|n| n.borrow_mut().insert(key, no_show));
+        }
+        for attendee in &attendees {
+            reputation::record_attendance(attendee, !missing.contains(&attendee));
+        }
+        attendance_proofs::record_root(&event, now);
+    }
+    reputation::record_event_held(&event.owner);
+    let turnout = EventTurnout {
+        registered,
+        checked_in,
+        no_shows: if skipped { 0 } else { missing.len() as u32 },
+        skipped,
+        recorded_at: now,
+    };
+    TURNOUTS.with(|t| t.borrow_mut().insert(id, turnout));
+}
+
+// Marks a waitlist release in progress until dropped. A run that traps after an await has
+// its future dropped when the call context is cleaned up, so the flag is cleared then too.
+struct ReleaseRun;
+
+impl ReleaseRun {
+    fn start() -> Option<Self> {
+        (!RELEASING.with(|r| r.replace(true))).then_some(ReleaseRun)
+    }
+}
+
+impl Drop for ReleaseRun {
+    fn drop(&mut self) {
+        RELEASING.with(|r| r.set(false));
+    }
+}
+
+// Admits waitlisted registrants to events whose release time has come, as long as seats are
+// left; the rest are told they couldn't get in. Those with the best reputation go first,
+// then those who joined earliest. Waitlists of events that were called off, merged away or
+// lost their start time are lifted, and their registrants told.
+async fn release_waitlists() {
+    let Some(_run) = ReleaseRun::start() else {
+        return;
+    };
+    let now = time();
+    let policies: Vec<(u64, Option<u64>)> = WAITLIST_POLICIES.with(|p| {
+        p.borrow()
+            .iter()
+            .map(|(event_id, _)| {
+                let release_at = release_time(event_id).filter(|_| status::is_going_ahead(event_id));
+                (event_id, release_at)
+            })
+            .collect()
+    });
+    for (event_id, release_at) in policies {
+        match release_at {
+            None => lift_waitlist(event_id),
+            Some(release_at) if release_at <= now => {
+                let mut waitlist = waitlist_of(event_id);
+                waitlist.sort_by_cached_key(|(key, entry)| {
+                    (Reverse(reputation::attendee_score(&key.principal)), entry.joined_at)
+                });
+                for (key, _) in waitlist {
+                    WAITLIST.with(|w| w.borrow_mut().remove(&key));
+                    admit(event_id, &key.principal).await;
+                }
+                WAITLIST_POLICIES.with(|p| p.borrow_mut().remove(&event_id));
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+// Drops the waitlist of an event that can no longer be released, telling whoever was on it
+fn lift_waitlist(event_id: u64) {
+    WAITLIST_POLICIES.with(|p| p.borrow_mut().remove(&event_id));
+    for (key, _) in waitlist_of(event_id) {
+        WAITLIST.with(|w| w.borrow_mut().remove(&key));
+        notifications::notify(
+            &key.principal,
+            NotificationKind::PromotedFromWaitlist,
+            Some(event_id),
+            "The event you were waitlisted for is no longer taking registrations".to_string(),
+        );
+    }
+}
+
+async fn admit(event_id: u64, principal: &str) {
+    let Ok(attendee) = Principal::from_text(principal) else {
+        return;
+    };
+    let admitted = match deposits::_take_deposit(event_id, attendee).await {
+        Ok(()) => _attend_event(attendee, event_id)
+            .inspect_err(|_| deposits::return_deposit(event_id, principal)),
+        Err(err) => Err(err),
+    };
+    let message = match admitted {
+        Ok(event) => {
+            points::record_attendance(&event, principal);
+            schedule::record(&event, principal);
+            google_calendar::enqueue(event_id, principal);
+            format!("You're off the waitlist: you're registered for \"{}\"", event.event_title)
+        }
+        Err(_) => "Sorry, you couldn't be admitted from the waitlist".to_string(),
+    };
+    notifications::notify(
+        principal,
+        NotificationKind::PromotedFromWaitlist,
+        Some(event_id),
+        message,
+    );
+}
+
+// Moves the waitlist of a merged event to the event it was merged into, along with its
+// policy unless the target has its own. Registrants waitlisted on both keep their earlier place.
+pub(crate) fn move_waitlist(source_id: u64, target_id: u64) {
+    if let Some(policy) = WAITLIST_POLICIES.with(|p| p.borrow_mut().remove(&source_id))
+        && !WAITLIST_POLICIES.with(|p| p.borrow().contains_key(&target_id))
+    {
+        WAITLIST_POLICIES.with(|p| p.borrow_mut().insert(target_id, policy));
+    }
+    for (key, entry) in waitlist_of(source_id) {
+        WAITLIST.with(|w| w.borrow_mut().remove(&key));
+        let target = EventPrincipalKey {
+            event_id: target_id,
+            principal: key.principal,
+        };
+        let earlier = WAITLIST
+            .with(|w| w.borrow().get(&target))
+            .is_some_and(|existing| existing.joined_at <= entry.joined_at);
+        if !earlier {
+            WAITLIST.with(|w| w.borrow_mut().insert(target, entry));
+        }
+    }
+}
+
+fn release_time(event_id: u64) -> Option<u64> {
+    _get_event(&event_id)?
+        .starts_at
+        .map(|starts_at| starts_at.saturating_sub(WAITLIST_RELEASE_NANOS))
+}
+
+fn event_end(starts_at: Option<u64>, ends_at: Option<u64>) -> Option<u64> {
+    let starts_at = starts_at?;
+    Some(ends_at.unwrap_or_else(|| starts_at.saturating_add(schedule::DEFAULT_DURATION_NANOS)))
+}
+
+fn no_shows_of(principal: &str) -> Vec<NoShow> {
+    let start = NoShowKey {
+        principal: principal.to_string(),
+        event_id: 0,
+    };
+    NO_SHOWS.with(|n| {
+        n.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.principal == principal)
+            .map(|(_, no_show)| no_show)
+            .collect()
+    })
+}
+
+fn waitlist_of(event_id: u64) -> Vec<(EventPrincipalKey, WaitlistEntry)> {
+    let start = EventPrincipalKey {
+        event_id,
+        principal: String::new(),
+    };
+    WAITLIST.with(|w| {
+        w.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .collect()
+    })
+}
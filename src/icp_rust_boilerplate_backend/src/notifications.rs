@@ -0,0 +1,188 @@
+// Per-principal notification inbox. Other modules push entries through `notify` and
+// `notify_attendees`; principals read them with `my_notifications` and `mark_read`.
+use crate::{attendees, metrics, Event, IdCell, Memory, PrincipalKey, MEMORY_MANAGER};
+use crate::clock::time;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+
+// Oldest entries are dropped once an inbox grows past this size
+const MAX_INBOX_SIZE: usize = 500;
+const MAX_MESSAGE_BYTES: usize = 400;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum NotificationKind {
+    EventUpdated,
+    EventCancelled,
+    PromotedFromWaitlist,
+    Reminder,
+    Announcement,
+    ReviewDecision,
+    QuestionAnswered,
+    Billing,
+    Dispute,
+    OrganizerApplication,
+    OrganizationInvite,
+    TicketGift,
+    LowCycles,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Notification {
+    id: u64,
+    kind: NotificationKind,
+    event_id: Option<u64>,
+    message: String,
+    created_at: u64,
+    read: bool,
+}
+
+impl_storable!(Notification, 1024);
+
+// Inbox entries are keyed by recipient first so one principal's inbox is a range scan
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct NotificationKey {
+    recipient: String,
+    id: u64,
+}
+
+impl_storable!(NotificationKey, 128);
+
+thread_local! {
+    static NOTIFICATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))), 0)
+            .expect("Cannot create a notification counter")
+    );
+
+    static NOTIFICATIONS: RefCell<StableBTreeMap<NotificationKey, Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    // Recipient -> number of entries in their inbox
+    static INBOX_SIZES: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+}
+
+// Query function returning the caller's notifications created after `since` (nanoseconds), newest first
+#[ic_cdk::query]
+fn my_notifications(since: u64) -> Vec<Notification> {
+    let mut notifications: Vec<Notification> = inbox_of(&caller().to_string())
+        .into_iter()
+        .map(|(_, notification)| notification)
+        .filter(|notification| notification.created_at > since)
+        .collect();
+    notifications.reverse();
+    notifications
+}
+
+// Query function returning how many of the caller's notifications are unread
+#[ic_cdk::query]
+fn unread_notification_count() -> u64 {
+    inbox_of(&caller().to_string())
+        .into_iter()
+        .filter(|(_, notification)| !notification.read)
+        .count() as u64
+}
+
+// Update function marking the given notifications of the caller as read; returns how many changed
+#[ic_cdk::update]
+fn mark_read(ids: Vec<u64>) -> u64 {
+    let _metrics = metrics::track("mark_read");
+    let recipient = caller().to_string();
+    NOTIFICATIONS.with(|n| {
+        let mut notifications = n.borrow_mut();
+        let mut marked = 0;
+        for id in ids {
+            let key = NotificationKey {
+                recipient: recipient.clone(),
+                id,
+            };
+            if let Some(mut notification) = notifications.get(&key).filter(|n| !n.read) {
+                notification.read = true;
+                notifications.insert(key, notification);
+                marked += 1;
+            }
+        }
+        marked
+    })
+}
+
+// Pushes a notification into a principal's inbox
+pub(crate) fn notify(
+    recipient: &str,
+    kind: NotificationKind,
+    event_id: Option<u64>,
+    mut message: String,
+) {
+    let id = NOTIFICATION_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment notification id counter");
+
+    // Cut on a byte budget, at a character boundary
+    if message.len() > MAX_MESSAGE_BYTES {
+        let mut end = MAX_MESSAGE_BYTES;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+    let notification = Notification {
+        id,
+        kind,
+        event_id,
+        message,
+        created_at: time(),
+        read: false,
+    };
+    let key = NotificationKey {
+        recipient: recipient.to_string(),
+        id,
+    };
+    NOTIFICATIONS.with(|n| n.borrow_mut().insert(key, notification));
+
+    // Keep the inbox bounded by dropping its oldest entry
+    let size_key = PrincipalKey {
+        principal: recipient.to_string(),
+    };
+    let size = INBOX_SIZES.with(|s| s.borrow().get(&size_key)).unwrap_or_default() + 1;
+    if size as usize > MAX_INBOX_SIZE {
+        let oldest = NOTIFICATIONS.with(|n| n.borrow().range(inbox_range(recipient)).next());
+        if let Some((oldest, _)) = oldest {
+            NOTIFICATIONS.with(|n| n.borrow_mut().remove(&oldest));
+        }
+        INBOX_SIZES.with(|s| s.borrow_mut().insert(size_key, size - 1));
+    } else {
+        INBOX_SIZES.with(|s| s.borrow_mut().insert(size_key, size));
+    }
+}
+
+// Pushes the same notification to every attendee of an event
+pub(crate) fn notify_attendees(event: &Event, kind: NotificationKind, message: String) {
+    for attendee in attendees::attendees_of(event.id) {
+        notify(&attendee, kind.clone(), Some(event.id), message.clone());
+    }
+}
+
+fn inbox_of(recipient: &str) -> Vec<(NotificationKey, Notification)> {
+    NOTIFICATIONS.with(|n| n.borrow().range(inbox_range(recipient)).collect())
+}
+
+fn inbox_range(recipient: &str) -> RangeInclusive<NotificationKey> {
+    let start = NotificationKey {
+        recipient: recipient.to_string(),
+        id: 0,
+    };
+    let end = NotificationKey {
+        recipient: recipient.to_string(),
+        id: u64::MAX,
+    };
+    start..=end
+}
@@ -0,0 +1,609 @@
+// The orders ledger: one record for every ticket sold on this canister, at its price and on
+// the ledger it was paid on. Unlike payments, which only follow the current ticket holder
+// for refunds, orders are kept for good, so organizers can reconcile their sales from them:
+// gross sales, refunds, the platform's fees and what they are owed in the end. Buyers find
+// their orders with `my_orders` and get a receipt for each of them.
+use crate::admin::_check_admin;
+use crate::history::{HistoryEntry, HistoryItem, HistoryStatus};
+use crate::tiers::HeldTicket;
+use crate::{groups, payments, tiers, tokens};
+use crate::{Error, EventPrincipalKey, IdCell, Memory, MEMORY_MANAGER};
+use crate::{_get_event, _get_organized_event, _is_owner};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_TITLE_CHARS: usize = 100;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum OrderKind {
+    // A ticket bought from the organizer
+    Ticket,
+    // A ticket bought from another attendee; the organizer gets a share of its price
+    Resale { seller: Principal, organizer_fee: u128 },
+    // Several tickets bought in one payment, handed out by the buyer afterwards
+    Group { quantity: u32, unassigned: u32 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum OrderStatus {
+    Paid,
+    // A refund of the whole amount has been queued
+    Refunded { refund_id: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Order {
+    id: u64,
+    pub(crate) event_id: u64,
+    // Title at the time of the order, kept for receipts
+    pub(crate) event_title: String,
+    pub(crate) organizer: String,
+    pub(crate) buyer: Principal,
+    // None when the ticket isn't from a tier
+    tier_id: Option<u64>,
+    kind: OrderKind,
+    // None for free tickets
+    ledger: Option<Principal>,
+    amount: u128,
+    platform_fee: u128,
+    block_index: Option<u64>,
+    status: OrderStatus,
+    created_at: u64,
+    // Markup of the tier's demand pricing when the ticket was bought, in basis points
+    demand_markup_bps: Option<u32>,
+}
+
+impl_storable!(Order, 1024);
+
+impl Order {
+    pub(crate) fn is_group(&self) -> bool {
+        matches!(self.kind, OrderKind::Group { .. })
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct EventOrderKey {
+    event_id: u64,
+    order_id: u64,
+}
+
+impl_storable!(EventOrderKey, 32);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct BuyerOrderKey {
+    principal: String,
+    order_id: u64,
+}
+
+impl_storable!(BuyerOrderKey, 96);
+
+// What a buyer paid for an order, for their records
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct Receipt {
+    order_id: u64,
+    buyer: Principal,
+    event_id: u64,
+    event_title: String,
+    // Start of the event, when it is still on this canister
+    event_starts_at: Option<u64>,
+    organizer: String,
+    tier_id: Option<u64>,
+    tier_name: Option<String>,
+    kind: OrderKind,
+    // None for free tickets
+    ledger: Option<Principal>,
+    // Symbol of the token paid in, when it is an accepted token
+    token_symbol: Option<String>,
+    amount: u128,
+    block_index: Option<u64>,
+    status: OrderStatus,
+    paid_at: u64,
+}
+
+// Start and end (exclusive) of a reporting period, in nanoseconds since the epoch
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct RevenuePeriod {
+    start: u64,
+    end: u64,
+}
+
+// Sales on one ledger, in its smallest unit
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct LedgerRevenue {
+    ledger: Principal,
+    tickets_sold: u64,
+    tickets_refunded: u64,
+    // Everything paid for tickets bought from the organizer, refunded or not
+    gross_sales: u128,
+    refunds: u128,
+    // Taken by the platform from the sales that weren't refunded
+    platform_fees: u128,
+    // The organizer's share of resales
+    resale_fees: u128,
+    // What the organizer is owed: gross sales less refunds and platform fees, plus resale fees
+    net_payout: u128,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct RevenueReport {
+    event_id: u64,
+    ledgers: Vec<LedgerRevenue>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct OrganizerRevenue {
+    period: RevenuePeriod,
+    // Events with at least one order in the period
+    events: u64,
+    ledgers: Vec<LedgerRevenue>,
+}
+
+thread_local! {
+    static ORDER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(95))), 0)
+            .expect("Cannot create an order counter")
+    );
+
+    static ORDERS: RefCell<StableBTreeMap<u64, Order, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(96)))
+    ));
+
+    static EVENT_ORDERS: RefCell<StableBTreeMap<EventOrderKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(97)))
+    ));
+
+    static BUYER_ORDERS: RefCell<StableBTreeMap<BuyerOrderKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(99)))
+    ));
+
+    // The order behind each ticket holder's current payment, so refunds find it
+    static HOLDER_ORDERS: RefCell<StableBTreeMap<EventPrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(98)))
+    ));
+}
+
+// Query function listing the caller's orders, newest first
+#[ic_cdk::query]
+fn my_orders() -> Vec<Order> {
+    buyer_orders(&caller().to_string())
+}
+
+// Orders of a buyer, newest first
+fn buyer_orders(principal: &str) -> Vec<Order> {
+    let principal = principal.to_string();
+    let start = BuyerOrderKey {
+        principal: principal.clone(),
+        order_id: 0,
+    };
+    let ids: Vec<u64> = BUYER_ORDERS.with(|b| {
+        b.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.principal == principal)
+            .map(|(_, id)| id)
+            .collect()
+    });
+    ORDERS.with(|o| {
+        let o = o.borrow();
+        ids.into_iter().rev().filter_map(|id| o.get(&id)).collect()
+    })
+}
+
+// Query function returning the receipt of an order; restricted to its buyer, the event's
+// organizer and admins
+#[ic_cdk::query]
+fn get_receipt(order_id: u64) -> Result<Receipt, Error> {
+    let order = ORDERS.with(|o| o.borrow().get(&order_id)).ok_or(Error::NotFound {
+        msg: format!("Order with id={} not found", order_id),
+    })?;
+    let event = _get_event(&order.event_id);
+    let organizes = match &event {
+        Some(event) => _is_owner(event, &caller()),
+        None => order.organizer == caller().to_string(),
+    };
+    if order.buyer != caller() && !organizes {
+        _check_admin()?;
+    }
+    Ok(Receipt {
+        order_id,
+        buyer: order.buyer,
+        event_id: order.event_id,
+        event_title: order.event_title,
+        event_starts_at: event.and_then(|event| event.starts_at),
+        organizer: order.organizer,
+        tier_id: order.tier_id,
+        tier_name: order
+            .tier_id
+            .and_then(|tier_id| tiers::tier_name(order.event_id, tier_id)),
+        kind: order.kind,
+        token_symbol: order.ledger.and_then(tokens::symbol_of),
+        ledger: order.ledger,
+        amount: order.amount,
+        block_index: order.block_index,
+        status: order.status,
+        paid_at: order.created_at,
+    })
+}
+
+// Query function summarizing an event's sales per ledger; restricted to its organizer
+#[ic_cdk::query]
+fn get_revenue_report(event_id: u64) -> Result<RevenueReport, Error> {
+    _get_organized_event(event_id)?;
+    Ok(RevenueReport {
+        event_id,
+        ledgers: summarize(&orders_of(event_id)),
+    })
+}
+
+// Query function summarizing the sales of all the caller's events made within the period
+#[ic_cdk::query]
+fn get_organizer_revenue(period: RevenuePeriod) -> Result<OrganizerRevenue, Error> {
+    if period.start >= period.end {
+        return Err(Error::InvalidPayload {
+            msg: "the period must start before it ends".to_string(),
+        });
+    }
+    let organizer = caller().to_string();
+    let orders: Vec<Order> = ORDERS.with(|o| {
+        o.borrow()
+            .iter()
+            .map(|(_, order)| order)
+            .filter(|order| order.organizer == organizer)
+            .filter(|order| order.created_at >= period.start && order.created_at < period.end)
+            .collect()
+    });
+    let mut events: Vec<u64> = orders.iter().map(|order| order.event_id).collect();
+    events.sort_unstable();
+    events.dedup();
+    Ok(OrganizerRevenue {
+        period,
+        events: events.len() as u64,
+        ledgers: summarize(&orders),
+    })
+}
+
+// A buyer's orders as purchase history entries
+pub(crate) fn history_of(buyer: Principal) -> Vec<HistoryEntry> {
+    buyer_orders(&buyer.to_string())
+        .into_iter()
+        .map(|order| HistoryEntry {
+            item: match order.kind {
+                OrderKind::Ticket => HistoryItem::Ticket {
+                    order_id: order.id,
+                    tier_id: order.tier_id,
+                },
+                OrderKind::Resale { seller, .. } => HistoryItem::ResaleTicket {
+                    order_id: order.id,
+                    seller,
+                },
+                OrderKind::Group { quantity, .. } => HistoryItem::GroupTickets {
+                    order_id: order.id,
+                    tier_id: order.tier_id,
+                    quantity,
+                },
+            },
+            event_id: order.event_id,
+            ledger: order.ledger,
+            amount: order.amount,
+            status: match order.status {
+                OrderStatus::Paid => HistoryStatus::Paid,
+                OrderStatus::Refunded { refund_id } => HistoryStatus::Refunded { refund_id },
+            },
+            block_index: order.block_index,
+            at: order.created_at,
+        })
+        .collect()
+}
+
+// Records a ticket bought from the organizer, once its payment has been recorded
+pub(crate) fn record_ticket(key: &EventPrincipalKey, held: &HeldTicket, block_index: Option<u64>) {
+    let Some(order) = new_order(key, Some(held.tier_id), OrderKind::Ticket, block_index) else {
+        return;
+    };
+    insert_order(
+        key,
+        Order {
+            ledger: block_index.map(|_| held.ledger),
+            amount: held.price,
+            demand_markup_bps: held.demand_markup_bps,
+            ..order
+        },
+    );
+}
+
+// Records a group of tickets bought in a single payment; returns the order's id
+pub(crate) fn record_group(
+    key: &EventPrincipalKey,
+    held: &HeldTicket,
+    quantity: u32,
+    block_index: Option<u64>,
+) -> Option<u64> {
+    let kind = OrderKind::Group {
+        quantity,
+        unassigned: quantity,
+    };
+    let order = new_order(key, Some(held.tier_id), kind, block_index)?;
+    let id = order.id;
+    insert_order(
+        key,
+        Order {
+            ledger: block_index.map(|_| held.ledger),
+            amount: held.price.saturating_mul(u128::from(quantity)),
+            demand_markup_bps: held.demand_markup_bps,
+            ..order
+        },
+    );
+    Some(id)
+}
+
+// Updates how many tickets of a group order are still to be handed out
+pub(crate) fn set_unassigned(order_id: u64, unassigned: u32) {
+    let Some(mut order) = ORDERS.with(|o| o.borrow().get(&order_id)) else {
+        return;
+    };
+    if let OrderKind::Group { quantity, .. } = order.kind {
+        order.kind = OrderKind::Group {
+            quantity,
+            unassigned,
+        };
+        ORDERS.with(|o| o.borrow_mut().insert(order_id, order));
+    }
+}
+
+// Helper function returning a paid order whose ticket the principal holds, or a paid group
+// order they bought
+pub(crate) fn _get_held_order(order_id: u64, holder: Principal) -> Result<Order, Error> {
+    let order = ORDERS
+        .with(|o| o.borrow().get(&order_id))
+        .filter(|order| order.status == OrderStatus::Paid)
+        .ok_or(Error::NotFound {
+            msg: format!("Order with id={} not found", order_id),
+        })?;
+    let key = EventPrincipalKey {
+        event_id: order.event_id,
+        principal: holder.to_string(),
+    };
+    let held = if order.is_group() {
+        order.buyer == holder
+    } else {
+        HOLDER_ORDERS.with(|h| h.borrow().get(&key)) == Some(order_id)
+    };
+    if !held {
+        return Err(Error::NotFound {
+            msg: format!("Order with id={} not found", order_id),
+        });
+    }
+    Ok(order)
+}
+
+// Detaches a ticket's order from its former holder once the ticket changed hands
+pub(crate) fn release_holder(key: &EventPrincipalKey) {
+    HOLDER_ORDERS.with(|h| h.borrow_mut().remove(key));
+}
+
+// Whether an order is paid and not refunded
+pub(crate) fn is_paid(order_id: u64) -> bool {
+    ORDERS
+        .with(|o| o.borrow().get(&order_id))
+        .is_some_and(|order| order.status == OrderStatus::Paid)
+}
+
+// Records a ticket bought from another attendee, who no longer holds it
+pub(crate) fn record_resale(
+    key: &EventPrincipalKey,
+    seller: &EventPrincipalKey,
+    ledger: Principal,
+    amount: u128,
+    organizer_fee: u128,
+    block_index: u64,
+) {
+    release_holder(seller);
+    let Ok(seller) = Principal::from_text(&seller.principal) else {
+        return;
+    };
+    let kind = OrderKind::Resale {
+        seller,
+        organizer_fee,
+    };
+    let Some(order) = new_order(key, tiers::tier_id_of(key), kind, Some(block_index)) else {
+        return;
+    };
+    insert_order(
+        key,
+        Order {
+            ledger: Some(ledger),
+            amount,
+            ..order
+        },
+    );
+}
+
+// Helper function returning an order that can still be refunded: a paid ticket its buyer
+// holds, or a group of tickets none of which has been handed out
+pub(crate) fn _get_refundable_order(order_id: u64) -> Result<Order, Error> {
+    let order = ORDERS.with(|o| o.borrow().get(&order_id)).ok_or(Error::NotFound {
+        msg: format!("Order with id={} not found", order_id),
+    })?;
+    let held = HOLDER_ORDERS.with(|h| h.borrow().get(&holder_key(&order))) == Some(order.id);
+    let handed_out = matches!(
+        order.kind,
+        OrderKind::Group { quantity, unassigned } if unassigned < quantity
+    );
+    if order.ledger.is_none() || order.status != OrderStatus::Paid || !held || handed_out {
+        return Err(Error::InvalidPayload {
+            msg: format!("Order with id={} can't be refunded", order_id),
+        });
+    }
+    Ok(order)
+}
+
+// Queues a refund of an order's whole amount to its buyer; returns the refund's id
+pub(crate) fn refund(order_id: u64) -> Result<u64, Error> {
+    let order = _get_refundable_order(order_id)?;
+    if let Some(block_index) = order.block_index {
+        payments::enqueue_refund(&holder_key(&order), block_index);
+    }
+    match ORDERS.with(|o| o.borrow().get(&order_id)).map(|order| order.status) {
+        Some(OrderStatus::Refunded { refund_id }) => Ok(refund_id),
+        _ => Err(Error::PaymentFailed {
+            msg: format!("the payment for order with id={} could not be refunded", order_id),
+        }),
+    }
+}
+
+// Marks the order paid in the given ledger block as refunded
+pub(crate) fn mark_refunded(key: &EventPrincipalKey, block_index: u64, refund_id: u64) {
+    let Some(mut order) = HOLDER_ORDERS
+        .with(|h| h.borrow().get(key))
+        .and_then(|id| ORDERS.with(|o| o.borrow().get(&id)))
+        .filter(|order| order.status == OrderStatus::Paid && order.block_index == Some(block_index))
+    else {
+        return;
+    };
+    order.status = OrderStatus::Refunded { refund_id };
+    // Tickets of a group not handed out yet go with the refund
+    if let OrderKind::Group { quantity, .. } = order.kind {
+        groups::void_unassigned(order.id);
+        order.kind = OrderKind::Group {
+            quantity,
+            unassigned: 0,
+        };
+    }
+    ORDERS.with(|o| o.borrow_mut().insert(order.id, order));
+}
+
+// An order for the holder's payment in the given ledger block, with the event's organizer and
+// the platform's fee
+fn new_order(
+    key: &EventPrincipalKey,
+    tier_id: Option<u64>,
+    kind: OrderKind,
+    block_index: Option<u64>,
+) -> Option<Order> {
+    let buyer = Principal::from_text(&key.principal).ok()?;
+    let event = _get_event(&key.event_id)?;
+    let id = ORDER_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment order id counter");
+    Some(Order {
+        id,
+        event_id: key.event_id,
+        event_title: event.event_title.chars().take(MAX_TITLE_CHARS).collect(),
+        organizer: event.owner,
+        buyer,
+        tier_id,
+        kind,
+        ledger: None,
+        amount: 0,
+        platform_fee: block_index.map_or(0, |index| payments::platform_fee_of(key, index)),
+        block_index,
+        status: OrderStatus::Paid,
+        created_at: time(),
+        demand_markup_bps: None,
+    })
+}
+
+fn insert_order(key: &EventPrincipalKey, order: Order) {
+    let event_key = EventOrderKey {
+        event_id: order.event_id,
+        order_id: order.id,
+    };
+    EVENT_ORDERS.with(|e| e.borrow_mut().insert(event_key, order.id));
+    let buyer_key = BuyerOrderKey {
+        principal: order.buyer.to_string(),
+        order_id: order.id,
+    };
+    BUYER_ORDERS.with(|b| b.borrow_mut().insert(buyer_key, order.id));
+    HOLDER_ORDERS.with(|h| h.borrow_mut().insert(key.clone(), order.id));
+    ORDERS.with(|o| o.borrow_mut().insert(order.id, order));
+}
+
+fn holder_key(order: &Order) -> EventPrincipalKey {
+    EventPrincipalKey {
+        event_id: order.event_id,
+        principal: order.buyer.to_string(),
+    }
+}
+
+fn orders_of(event_id: u64) -> Vec<Order> {
+    let start = EventOrderKey {
+        event_id,
+        order_id: 0,
+    };
+    let ids: Vec<u64> = EVENT_ORDERS.with(|e| {
+        e.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(_, id)| id)
+            .collect()
+    });
+    ORDERS.with(|o| {
+        let o = o.borrow();
+        ids.into_iter().filter_map(|id| o.get(&id)).collect()
+    })
+}
+
+// Totals per ledger; free tickets are left out
+fn summarize(orders: &[Order]) -> Vec<LedgerRevenue> {
+    let mut ledgers: Vec<LedgerRevenue> = Vec::new();
+    for order in orders {
+        let Some(ledger) = order.ledger else {
+            continue;
+        };
+        let position = match ledgers.iter().position(|line| line.ledger == ledger) {
+            Some(position) => position,
+            None => {
+                ledgers.push(LedgerRevenue {
+                    ledger,
+                    tickets_sold: 0,
+                    tickets_refunded: 0,
+                    gross_sales: 0,
+                    refunds: 0,
+                    platform_fees: 0,
+                    resale_fees: 0,
+                    net_payout: 0,
+                });
+                ledgers.len() - 1
+            }
+        };
+        let line = &mut ledgers[position];
+        let refunded = order.status != OrderStatus::Paid;
+        match order.kind {
+            OrderKind::Ticket | OrderKind::Group { .. } => {
+                let tickets = match order.kind {
+                    OrderKind::Group { quantity, .. } => u64::from(quantity),
+                    _ => 1,
+                };
+                line.tickets_sold += tickets;
+                line.gross_sales += order.amount;
+                if refunded {
+                    line.tickets_refunded += tickets;
+                    line.refunds += order.amount;
+                } else {
+                    line.platform_fees += order.platform_fee;
+                }
+            }
+            // The rest of a resale's price goes to the seller, and refunding it takes back
+            // the organizer's share
+            OrderKind::Resale { organizer_fee, .. } if !refunded => {
+                line.resale_fees += organizer_fee;
+            }
+            OrderKind::Resale { .. } => {}
+        }
+    }
+    for line in &mut ledgers {
+        line.net_payout = (line.gross_sales + line.resale_fees)
+            .saturating_sub(line.refunds + line.platform_fees);
+    }
+    ledgers
+}
@@ -0,0 +1,466 @@
+// Organizations. A club, company or community can own events together: its editors create
+// events for it with `organization_id` in the payload and can manage every event of the
+// organization as if they had created it, and all of its members see those events before
+// they are published. Members are invited with a role (owner, admin, editor or viewer) and
+// join by accepting; see `OrgRole` for what each role allows. An organization's page lists
+// its members and events. Events stored in a bucket canister can only be managed there by
+// the member who created them.
+use crate::{metrics, Error, Event, EventPayload, IdCell, Memory, MEMORY_MANAGER};
+use crate::{access, status, verification, _fetch_event};
+use crate::notifications::{self, NotificationKind};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_NAME_BYTES: usize = 80;
+const MAX_DESCRIPTION_BYTES: usize = 1000;
+const MAX_MEMBERS: usize = 200;
+const MAX_PAGE_EVENTS: usize = 100;
+
+// From the least to the most privileged
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+    Debug,
+)]
+enum OrgRole {
+    // Sees the organization's unpublished events
+    Viewer,
+    // Also creates events for the organization and edits all of its events
+    Editor,
+    // Also invites and removes editors and viewers, and edits the organization
+    Admin,
+    // Also manages admins and owners
+    Owner,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Organization {
+    id: u64,
+    name: String,
+    description: String,
+    created_by: Principal,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl_storable!(Organization, 1536);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct OrgMemberKey {
+    org_id: u64,
+    principal: String,
+}
+
+impl_storable!(OrgMemberKey, 128);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrgMember {
+    principal: Principal,
+    role: OrgRole,
+    added_at: u64,
+    added_by: Principal,
+}
+
+impl_storable!(OrgMember, 192);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrgInvite {
+    org_id: u64,
+    principal: Principal,
+    role: OrgRole,
+    invited_by: Principal,
+    invited_at: u64,
+}
+
+impl_storable!(OrgInvite, 192);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct OrgEventKey {
+    org_id: u64,
+    event_id: u64,
+}
+
+impl_storable!(OrgEventKey, 32);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct OrganizationPage {
+    organization: Organization,
+    members: Vec<OrgMember>,
+    // Newest first; members also see events that aren't published
+    events: Vec<Event>,
+}
+
+thread_local! {
+    static ORG_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(141))), 0)
+            .expect("Cannot create an organization counter")
+    );
+
+    static ORGANIZATIONS: RefCell<StableBTreeMap<u64, Organization, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(142)))
+    ));
+
+    static ORG_MEMBERS: RefCell<StableBTreeMap<OrgMemberKey, OrgMember, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(143)))
+    ));
+
+    static ORG_EVENTS: RefCell<StableBTreeMap<OrgEventKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(144)))
+    ));
+
+    // Organization owning each event
+    static EVENT_ORGS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(145)))
+    ));
+
+    static ORG_INVITES: RefCell<StableBTreeMap<OrgMemberKey, OrgInvite, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(146)))
+    ));
+}
+
+// Query function returning an organization's page with its members and events
+#[ic_cdk::query(composite = true)]
+async fn get_organization(id: u64) -> Result<OrganizationPage, Error> {
+    let organization = _get_organization(id)?;
+    let members = members_of(id);
+    let is_member = role_of(id, &caller()).is_some();
+    let event_ids: Vec<u64> = ORG_EVENTS.with(|e| {
+        e.borrow()
+            .range(OrgEventKey { org_id: id, event_id: 0 }..)
+            .take_while(|(key, _)| key.org_id == id)
+            .map(|(_, event_id)| event_id)
+            .collect()
+    });
+    let mut events = Vec::new();
+    for event_id in event_ids.into_iter().rev() {
+        if events.len() == MAX_PAGE_EVENTS {
+            break;
+        }
+        if !is_member && (!status::is_published(event_id) || access::is_unlisted(event_id)) {
+            continue;
+        }
+        if let Ok(event) = _fetch_event(event_id).await {
+            events.push(verification::with_badge(access::redact(event)));
+        }
+    }
+    Ok(OrganizationPage {
+        organization,
+        members,
+        events,
+    })
+}
+
+// Query function listing the organizations the caller belongs to
+#[ic_cdk::query]
+fn get_my_organizations() -> Vec<Organization> {
+    let caller = caller().to_string();
+    let ids: Vec<u64> = ORG_MEMBERS.with(|m| {
+        m.borrow()
+            .iter()
+            .filter(|(key, _)| key.principal == caller)
+            .map(|(key, _)| key.org_id)
+            .collect()
+    });
+    ORGANIZATIONS.with(|o| {
+        let organizations = o.borrow();
+        ids.iter().filter_map(|id| organizations.get(id)).collect()
+    })
+}
+
+// Query function listing the caller's pending invites to organizations
+#[ic_cdk::query]
+fn get_my_organization_invites() -> Vec<OrgInvite> {
+    let caller = caller().to_string();
+    ORG_INVITES.with(|i| {
+        i.borrow()
+            .iter()
+            .filter(|(key, _)| key.principal == caller)
+            .map(|(_, invite)| invite)
+            .collect()
+    })
+}
+
+// Update function creating an organization with the caller as its owner
+#[ic_cdk::update]
+fn create_organization(name: String, description: String) -> Result<Organization, Error> {
+    let _metrics = metrics::track("create_organization");
+    if caller() == Principal::anonymous() {
+        return Err(Error::NotAuthorized {
+            msg: "Sign in to create an organization".to_string(),
+            caller: caller(),
+        });
+    }
+    let (name, description) = _validate_details(name, description)?;
+    let id = ORG_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment organization id counter");
+    let organization = Organization {
+        id,
+        name,
+        description,
+        created_by: caller(),
+        created_at: time(),
+        updated_at: None,
+    };
+    ORGANIZATIONS.with(|o| o.borrow_mut().insert(id, organization.clone()));
+    let owner = OrgMember {
+        principal: caller(),
+        role: OrgRole::Owner,
+        added_at: time(),
+        added_by: caller(),
+    };
+    ORG_MEMBERS.with(|m| m.borrow_mut().insert(member_key(id, &caller()), owner));
+    Ok(organization)
+}
+
+// Update function for an organization's owners and admins to change its name and description
+#[ic_cdk::update]
+fn update_organization(id: u64, name: String, description: String) -> Result<Organization, Error> {
+    let _metrics = metrics::track("update_organization");
+    let mut organization = _get_organization(id)?;
+    _check_role(id, OrgRole::Admin)?;
+    let (name, description) = _validate_details(name, description)?;
+    organization.name = name;
+    organization.description = description;
+    organization.updated_at = Some(time());
+    ORGANIZATIONS.with(|o| o.borrow_mut().insert(id, organization.clone()));
+    Ok(organization)
+}
+
+// Update function inviting a principal to an organization with a role; the invite stands
+// until they accept or decline it, or it is replaced. Owners can invite with any role, admins
+// only editors and viewers.
+#[ic_cdk::update]
+fn invite_organization_member(
+    id: u64,
+    principal: Principal,
+    role: OrgRole,
+) -> Result<OrgInvite, Error> {
+    let _metrics = metrics::track("invite_organization_member");
+    let organization = _get_organization(id)?;
+    _check_can_assign(id, None, role)?;
+    if principal == Principal::anonymous() {
+        return Err(Error::InvalidPayload {
+            msg: "the anonymous principal can't be a member".to_string(),
+        });
+    }
+    if role_of(id, &principal).is_some() {
+        return Err(Error::InvalidPayload {
+            msg: format!("{} is already a member of organization {}", principal, id),
+        });
+    }
+    if members_of(id).len() >= MAX_MEMBERS {
+        return Err(Error::InvalidPayload {
+            msg: format!("an organization can have at most {} members", MAX_MEMBERS),
+        });
+    }
+    let invite = OrgInvite {
+        org_id: id,
+        principal,
+        role,
+        invited_by: caller(),
+        invited_at: time(),
+    };
+    ORG_INVITES.with(|i| i.borrow_mut().insert(member_key(id, &principal), invite.clone()));
+    notifications::notify(
+        &principal.to_string(),
+        NotificationKind::OrganizationInvite,
+        None,
+        format!("You were invited to join {} as {:?}", organization.name, role),
+    );
+    Ok(invite)
+}
+
+// Update function accepting or declining the caller's invite to an organization
+#[ic_cdk::update]
+fn respond_to_organization_invite(id: u64, accept: bool) -> Result<Option<OrgMember>, Error> {
+    let _metrics = metrics::track("respond_to_organization_invite");
+    let invite = ORG_INVITES
+        .with(|i| i.borrow_mut().remove(&member_key(id, &caller())))
+        .ok_or(Error::NotFound {
+            msg: format!("You have no invite to organization {}", id),
+        })?;
+    if !accept {
+        return Ok(None);
+    }
+    _get_organization(id)?;
+    if members_of(id).len() >= MAX_MEMBERS {
+        return Err(Error::InvalidPayload {
+            msg: format!("an organization can have at most {} members", MAX_MEMBERS),
+        });
+    }
+    let member = OrgMember {
+        principal: caller(),
+        role: invite.role,
+        added_at: time(),
+        added_by: invite.invited_by,
+    };
+    ORG_MEMBERS.with(|m| m.borrow_mut().insert(member_key(id, &caller()), member.clone()));
+    Ok(Some(member))
+}
+
+// Update function changing a member's role. Owners can change any role; admins can only
+// switch members between editor and viewer. The last owner can't be demoted.
+#[ic_cdk::update]
+fn change_organization_role(
+    id: u64,
+    principal: Principal,
+    role: OrgRole,
+) -> Result<OrgMember, Error> {
+    let _metrics = metrics::track("change_organization_role");
+    _get_organization(id)?;
+    let mut member = _get_member(id, &principal)?;
+    _check_can_assign(id, Some(member.role), role)?;
+    if member.role == OrgRole::Owner && role != OrgRole::Owner {
+        _check_other_owner(id, &principal)?;
+    }
+    member.role = role;
+    ORG_MEMBERS.with(|m| m.borrow_mut().insert(member_key(id, &principal), member.clone()));
+    Ok(member)
+}
+
+// Update function removing a member from an organization. Members can leave on their own;
+// admins can remove editors and viewers, and owners anyone. The last owner can't leave.
+#[ic_cdk::update]
+fn remove_organization_member(id: u64, principal: Principal) -> Result<OrgMember, Error> {
+    let _metrics = metrics::track("remove_organization_member");
+    _get_organization(id)?;
+    let member = _get_member(id, &principal)?;
+    if principal != caller() {
+        _check_can_assign(id, Some(member.role), member.role)?;
+    }
+    if member.role == OrgRole::Owner {
+        _check_other_owner(id, &principal)?;
+    }
+    ORG_MEMBERS.with(|m| m.borrow_mut().remove(&member_key(id, &principal)));
+    Ok(member)
+}
+
+// Helper function refusing to create an event for an organization the caller can't edit
+pub(crate) fn _check_can_create(payload: &EventPayload) -> Result<(), Error> {
+    let Some(id) = payload.organization_id else {
+        return Ok(());
+    };
+    _get_organization(id)?;
+    _check_role(id, OrgRole::Editor)
+}
+
+// Records that an event was created for an organization
+pub(crate) fn remember_event(event_id: u64, org_id: u64) {
+    ORG_EVENTS.with(|e| e.borrow_mut().insert(OrgEventKey { org_id, event_id }, event_id));
+    EVENT_ORGS.with(|e| e.borrow_mut().insert(event_id, org_id));
+}
+
+// Whether the principal can edit events of the organization owning the event
+pub(crate) fn can_manage_event(event_id: u64, principal: &Principal) -> bool {
+    role_for_event(event_id, principal).is_some_and(|role| role >= OrgRole::Editor)
+}
+
+// Whether the principal belongs to the organization owning the event, with any role
+pub(crate) fn is_member_for_event(event_id: u64, principal: &Principal) -> bool {
+    role_for_event(event_id, principal).is_some()
+}
+
+fn role_for_event(event_id: u64, principal: &Principal) -> Option<OrgRole> {
+    EVENT_ORGS
+        .with(|e| e.borrow().get(&event_id))
+        .and_then(|org_id| role_of(org_id, principal))
+}
+
+fn _get_organization(id: u64) -> Result<Organization, Error> {
+    ORGANIZATIONS.with(|o| o.borrow().get(&id)).ok_or(Error::NotFound {
+        msg: format!("Organization with id={} not found", id),
+    })
+}
+
+// Refuses the call unless the caller holds at least the given role in the organization
+fn _check_role(id: u64, required: OrgRole) -> Result<(), Error> {
+    if role_of(id, &caller()).is_none_or(|role| role < required) {
+        return Err(Error::NotAuthorized {
+            msg: format!("You don't have the {:?} role in organization {}", required, id),
+            caller: caller(),
+        });
+    }
+    Ok(())
+}
+
+// Refuses to give or take away a role the caller can't manage: admins and owners are managed
+// by owners, editors and viewers by admins too
+fn _check_can_assign(id: u64, current: Option<OrgRole>, role: OrgRole) -> Result<(), Error> {
+    if role >= OrgRole::Admin || current.is_some_and(|current| current >= OrgRole::Admin) {
+        _check_role(id, OrgRole::Owner)
+    } else {
+        _check_role(id, OrgRole::Admin)
+    }
+}
+
+// Refuses to demote or remove the principal when they are the organization's only owner
+fn _check_other_owner(id: u64, principal: &Principal) -> Result<(), Error> {
+    let other_owner = members_of(id)
+        .iter()
+        .any(|member| member.role == OrgRole::Owner && member.principal != *principal);
+    if !other_owner {
+        return Err(Error::InvalidPayload {
+            msg: "an organization needs at least one owner".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn _validate_details(name: String, description: String) -> Result<(String, String), Error> {
+    let name = name.trim().to_string();
+    if name.is_empty() || name.len() > MAX_NAME_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("a name must be between 1 and {} bytes", MAX_NAME_BYTES),
+        });
+    }
+    let description = description.trim().to_string();
+    if description.len() > MAX_DESCRIPTION_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("a description can have at most {} bytes", MAX_DESCRIPTION_BYTES),
+        });
+    }
+    Ok((name, description))
+}
+
+fn _get_member(id: u64, principal: &Principal) -> Result<OrgMember, Error> {
+    ORG_MEMBERS
+        .with(|m| m.borrow().get(&member_key(id, principal)))
+        .ok_or(Error::NotFound {
+            msg: format!("{} is not a member of organization {}", principal, id),
+        })
+}
+
+fn role_of(id: u64, principal: &Principal) -> Option<OrgRole> {
+    ORG_MEMBERS
+        .with(|m| m.borrow().get(&member_key(id, principal)))
+        .map(|member| member.role)
+}
+
+fn members_of(id: u64) -> Vec<OrgMember> {
+    ORG_MEMBERS.with(|m| {
+        m.borrow()
+            .range(OrgMemberKey { org_id: id, principal: String::new() }..)
+            .take_while(|(key, _)| key.org_id == id)
+            .map(|(_, member)| member)
+            .collect()
+    })
+}
+
+fn member_key(org_id: u64, principal: &Principal) -> OrgMemberKey {
+    OrgMemberKey {
+        org_id,
+        principal: principal.to_string(),
+    }
+}
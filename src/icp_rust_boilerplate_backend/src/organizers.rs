@@ -0,0 +1,452 @@
+// Organizer onboarding by invite. Once admins require invites, only principals holding the
+// Organizer role can create events, whether directly, from a template or by import; a new
+// principal gets the role by redeeming an invite code an admin generated. Codes are single
+// use or shared by a set number of people, and can expire or be revoked. Principals that
+// already organize an event when the requirement is switched on are given the role, and
+// admins never need it.
+//
+// Without a code, a principal can apply with a short motivation instead; admins approve or
+// reject the application, approval grants the role, and the applicant hears the decision in
+// their inbox.
+use crate::admin::_check_admin;
+use crate::clock::time;
+use crate::notifications::{self, NotificationKind};
+use crate::{metrics, Error, IdCell, Memory, PrincipalKey, MEMORY_MANAGER, STORAGE, _call_failed};
+use candid::Principal;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+
+const CODE_BYTES: usize = 8;
+const MAX_CODE_USES: u32 = 10_000;
+const MAX_MOTIVATION_BYTES: usize = 1000;
+const MAX_ORGANIZATION_BYTES: usize = 100;
+const MAX_REJECTION_BYTES: usize = 500;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct InviteConfig {
+    required: bool,
+}
+
+impl_storable!(InviteConfig, 16);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct InviteCodeKey {
+    code: String,
+}
+
+impl_storable!(InviteCodeKey, 48);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct InviteCode {
+    code: String,
+    // 1 for a single-use code
+    max_uses: u32,
+    uses: u32,
+    expires_at: Option<u64>,
+    revoked: bool,
+    created_by: Principal,
+    created_at: u64,
+}
+
+impl_storable!(InviteCode, 192);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Organizer {
+    principal: Principal,
+    granted_at: u64,
+    // How the role was obtained; neither for organizers given the role when invites became
+    // required
+    invite_code: Option<String>,
+    application_id: Option<u64>,
+}
+
+impl_storable!(Organizer, 192);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum ApplicationStatus {
+    Pending,
+    Approved {
+        decided_by: Principal,
+        decided_at: u64,
+    },
+    Rejected {
+        reason: String,
+        decided_by: Principal,
+        decided_at: u64,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrganizerApplication {
+    id: u64,
+    applicant: Principal,
+    motivation: String,
+    // Club, company or community the applicant organizes for, if any
+    organization: Option<String>,
+    status: ApplicationStatus,
+    submitted_at: u64,
+}
+
+impl_storable!(OrganizerApplication, 2048);
+
+thread_local! {
+    static INVITE_CONFIG: RefCell<Cell<InviteConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(135))), InviteConfig::default())
+            .expect("Cannot create the invite config cell")
+    );
+
+    static INVITE_CODES: RefCell<StableBTreeMap<InviteCodeKey, InviteCode, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(136)))
+    ));
+
+    static ORGANIZERS: RefCell<StableBTreeMap<PrincipalKey, Organizer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(137)))
+    ));
+
+    static APPLICATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(138))), 0)
+            .expect("Cannot create an application counter")
+    );
+
+    static APPLICATIONS: RefCell<StableBTreeMap<u64, OrganizerApplication, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(139)))
+    ));
+
+    // Latest application of each applicant
+    static APPLICANTS: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(140)))
+    ));
+}
+
+// Query function telling whether creating events requires the Organizer role
+#[ic_cdk::query]
+fn is_organizer_invite_required() -> bool {
+    INVITE_CONFIG.with(|c| c.borrow().get().required)
+}
+
+// Query function returning the caller's Organizer role, if they hold it
+#[ic_cdk::query]
+fn get_my_organizer_role() -> Option<Organizer> {
+    organizer_of(&caller())
+}
+
+// Query function for admins listing invite codes, newest first
+#[ic_cdk::query]
+fn get_invite_codes() -> Result<Vec<InviteCode>, Error> {
+    _check_admin()?;
+    let mut codes: Vec<InviteCode> =
+        INVITE_CODES.with(|c| c.borrow().iter().map(|(_, code)| code).collect());
+    codes.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    Ok(codes)
+}
+
+// Update function for admins to require the Organizer role for creating events, or lift
+// the requirement. Switching it on gives the role to everyone already organizing an event.
+#[ic_cdk::update]
+fn set_organizer_invite_required(required: bool) -> Result<bool, Error> {
+    let _metrics = metrics::track("set_organizer_invite_required");
+    _check_admin()?;
+    if required && !is_organizer_invite_required() {
+        let owners: Vec<String> =
+            STORAGE.with(|s| s.borrow().iter().map(|(_, event)| event.owner).collect());
+        for owner in owners {
+            let Ok(principal) = Principal::from_text(&owner) else {
+                continue;
+            };
+            if organizer_of(&principal).is_none() {
+                grant(principal, None, None);
+            }
+        }
+    }
+    INVITE_CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(InviteConfig { required })
+            .expect("cannot update the invite config")
+    });
+    Ok(required)
+}
+
+// Update function for admins to generate an invite code usable `max_uses` times
+#[ic_cdk::update]
+async fn create_invite_code(max_uses: u32, expires_at: Option<u64>) -> Result<InviteCode, Error> {
+    let _metrics = metrics::track("create_invite_code");
+    _check_admin()?;
+    if max_uses == 0 || max_uses > MAX_CODE_USES {
+        return Err(Error::InvalidPayload {
+            msg: format!("an invite code can be used between 1 and {} times", MAX_CODE_USES),
+        });
+    }
+    if expires_at.is_some_and(|expires_at| expires_at <= time()) {
+        return Err(Error::InvalidPayload {
+            msg: "an invite code must expire in the future".to_string(),
+        });
+    }
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|err| _call_failed("raw_rand", err))?;
+    let code: String = bytes
+        .iter()
+        .take(CODE_BYTES)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let invite = InviteCode {
+        code: code.clone(),
+        max_uses,
+        uses: 0,
+        expires_at,
+        revoked: false,
+        created_by: caller(),
+        created_at: time(),
+    };
+    INVITE_CODES.with(|c| c.borrow_mut().insert(InviteCodeKey { code }, invite.clone()));
+    Ok(invite)
+}
+
+// Update function for admins to revoke an invite code; roles already granted with it stay
+#[ic_cdk::update]
+fn revoke_invite_code(code: String) -> Result<InviteCode, Error> {
+    let _metrics = metrics::track("revoke_invite_code");
+    _check_admin()?;
+    let key = InviteCodeKey { code };
+    let mut invite = INVITE_CODES.with(|c| c.borrow().get(&key)).ok_or(Error::NotFound {
+        msg: format!("Invite code {} not found", key.code),
+    })?;
+    invite.revoked = true;
+    INVITE_CODES.with(|c| c.borrow_mut().insert(key, invite.clone()));
+    Ok(invite)
+}
+
+// Update function for a principal to redeem an invite code and become an organizer
+#[ic_cdk::update]
+fn redeem_invite_code(code: String) -> Result<Organizer, Error> {
+    let _metrics = metrics::track("redeem_invite_code");
+    if caller() == Principal::anonymous() {
+        return Err(Error::NotAuthorized {
+            msg: "Sign in to redeem an invite code".to_string(),
+            caller: caller(),
+        });
+    }
+    if organizer_of(&caller()).is_some() {
+        return Err(Error::InvalidPayload {
+            msg: "You are already an organizer".to_string(),
+        });
+    }
+    let key = InviteCodeKey {
+        code: code.trim().to_lowercase(),
+    };
+    let invite = INVITE_CODES
+        .with(|c| c.borrow().get(&key))
+        .filter(|invite| {
+            !invite.revoked
+                && invite.uses < invite.max_uses
+                && invite.expires_at.is_none_or(|expires_at| expires_at > time())
+        })
+        .ok_or(Error::NotFound {
+            msg: "This invite code is invalid, used up or expired".to_string(),
+        })?;
+    INVITE_CODES.with(|c| {
+        let mut used = invite.clone();
+        used.uses += 1;
+        c.borrow_mut().insert(key, used)
+    });
+    Ok(grant(caller(), Some(invite.code), None))
+}
+
+// Query function returning the caller's latest organizer application
+#[ic_cdk::query]
+fn get_my_organizer_application() -> Option<OrganizerApplication> {
+    let key = PrincipalKey {
+        principal: caller().to_string(),
+    };
+    let id = APPLICANTS.with(|a| a.borrow().get(&key))?;
+    APPLICATIONS.with(|a| a.borrow().get(&id))
+}
+
+// Query function for admins listing organizer applications, oldest first, optionally only
+// those waiting for a decision
+#[ic_cdk::query]
+fn get_organizer_applications(pending_only: bool) -> Result<Vec<OrganizerApplication>, Error> {
+    _check_admin()?;
+    Ok(APPLICATIONS.with(|a| {
+        a.borrow()
+            .iter()
+            .map(|(_, application)| application)
+            .filter(|application| {
+                !pending_only || matches!(application.status, ApplicationStatus::Pending)
+            })
+            .collect()
+    }))
+}
+
+// Update function for a principal to apply for the Organizer role
+#[ic_cdk::update]
+fn apply_as_organizer(
+    motivation: String,
+    organization: Option<String>,
+) -> Result<OrganizerApplication, Error> {
+    let _metrics = metrics::track("apply_as_organizer");
+    if caller() == Principal::anonymous() {
+        return Err(Error::NotAuthorized {
+            msg: "Sign in to apply as an organizer".to_string(),
+            caller: caller(),
+        });
+    }
+    if organizer_of(&caller()).is_some() {
+        return Err(Error::InvalidPayload {
+            msg: "You are already an organizer".to_string(),
+        });
+    }
+    if get_my_organizer_application()
+        .is_some_and(|application| matches!(application.status, ApplicationStatus::Pending))
+    {
+        return Err(Error::InvalidPayload {
+            msg: "Your previous application is still being reviewed".to_string(),
+        });
+    }
+    let motivation = motivation.trim().to_string();
+    if motivation.is_empty() || motivation.len() > MAX_MOTIVATION_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("a motivation must be between 1 and {} bytes", MAX_MOTIVATION_BYTES),
+        });
+    }
+    let organization = organization
+        .map(|organization| organization.trim().to_string())
+        .filter(|organization| !organization.is_empty());
+    if organization
+        .as_ref()
+        .is_some_and(|organization| organization.len() > MAX_ORGANIZATION_BYTES)
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!("an organization can have at most {} bytes", MAX_ORGANIZATION_BYTES),
+        });
+    }
+    let id = APPLICATION_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment application id counter");
+    let application = OrganizerApplication {
+        id,
+        applicant: caller(),
+        motivation,
+        organization,
+        status: ApplicationStatus::Pending,
+        submitted_at: time(),
+    };
+    APPLICATIONS.with(|a| a.borrow_mut().insert(id, application.clone()));
+    let key = PrincipalKey {
+        principal: caller().to_string(),
+    };
+    APPLICANTS.with(|a| a.borrow_mut().insert(key, id));
+    Ok(application)
+}
+
+// Update function for admins to approve an organizer application, granting the role
+#[ic_cdk::update]
+fn approve_organizer_application(id: u64) -> Result<OrganizerApplication, Error> {
+    let _metrics = metrics::track("approve_organizer_application");
+    _check_admin()?;
+    let mut application = _get_pending_application(id)?;
+    application.status = ApplicationStatus::Approved {
+        decided_by: caller(),
+        decided_at: time(),
+    };
+    APPLICATIONS.with(|a| a.borrow_mut().insert(id, application.clone()));
+    if organizer_of(&application.applicant).is_none() {
+        grant(application.applicant, None, Some(id));
+    }
+    notifications::notify(
+        &application.applicant.to_string(),
+        NotificationKind::OrganizerApplication,
+        None,
+        "Your organizer application was approved; you can now create events".to_string(),
+    );
+    Ok(application)
+}
+
+// Update function for admins to reject an organizer application with a reason
+#[ic_cdk::update]
+fn reject_organizer_application(id: u64, reason: String) -> Result<OrganizerApplication, Error> {
+    let _metrics = metrics::track("reject_organizer_application");
+    _check_admin()?;
+    let reason = reason.trim().to_string();
+    if reason.is_empty() || reason.len() > MAX_REJECTION_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("a reason must be between 1 and {} bytes", MAX_REJECTION_BYTES),
+        });
+    }
+    let mut application = _get_pending_application(id)?;
+    application.status = ApplicationStatus::Rejected {
+        reason: reason.clone(),
+        decided_by: caller(),
+        decided_at: time(),
+    };
+    APPLICATIONS.with(|a| a.borrow_mut().insert(id, application.clone()));
+    notifications::notify(
+        &application.applicant.to_string(),
+        NotificationKind::OrganizerApplication,
+        None,
+        format!("Your organizer application was not approved: {}", reason),
+    );
+    Ok(application)
+}
+
+// Helper function refusing event creation to principals without the Organizer role while
+// invites are required
+pub(crate) fn _check_organizer() -> Result<(), Error> {
+    if !is_organizer_invite_required() || _check_admin().is_ok() {
+        return Ok(());
+    }
+    if organizer_of(&caller()).is_none() {
+        return Err(Error::NotAuthorized {
+            msg: "Redeem an organizer invite code to create events".to_string(),
+            caller: caller(),
+        });
+    }
+    Ok(())
+}
+
+fn organizer_of(principal: &Principal) -> Option<Organizer> {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    ORGANIZERS.with(|o| o.borrow().get(&key))
+}
+
+fn _get_pending_application(id: u64) -> Result<OrganizerApplication, Error> {
+    let application = APPLICATIONS.with(|a| a.borrow().get(&id)).ok_or(Error::NotFound {
+        msg: format!("Application with id={} not found", id),
+    })?;
+    if !matches!(application.status, ApplicationStatus::Pending) {
+        return Err(Error::InvalidPayload {
+            msg: format!("Application with id={} was already decided", id),
+        });
+    }
+    Ok(application)
+}
+
+fn grant(
+    principal: Principal,
+    invite_code: Option<String>,
+    application_id: Option<u64>,
+) -> Organizer {
+    let organizer = Organizer {
+        principal,
+        granted_at: time(),
+        invite_code,
+        application_id,
+    };
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    ORGANIZERS.with(|o| o.borrow_mut().insert(key, organizer.clone()));
+    organizer
+}
@@ -0,0 +1,153 @@
+// Shareable HTML event pages at /event/{slug} (or /event/{id}). Each page carries
+// OpenGraph and Twitter card meta tags so links shared in chat apps and social media
+// unfurl with the event's title, image, date and location. Unlisted events only have a
+// page for links carrying one of their access tokens.
+use crate::http::{escape_xml, HttpResponse, UtcDateTime};
+use crate::{access, slugs, sponsors, status};
+use crate::{Event, _get_event};
+use ic_cdk::api::id;
+
+// Public URL of an event's page, by slug when it has one
+pub(crate) fn event_url(event: &Event) -> String {
+    let path = event.slug.clone().unwrap_or_else(|| event.id.to_string());
+    format!("https://{}.raw.icp0.io/event/{}", id(), path)
+}
+
+// Renders the page of a published event stored on this canister, given its id or slug and
+// the access token from the link, if any
+pub(crate) fn event_page(id_or_slug: &str, token: Option<&str>) -> HttpResponse {
+    let event_id = id_or_slug.parse().ok().or_else(|| slugs::event_id_of(id_or_slug));
+    let event = event_id
+        .and_then(|event_id| _get_event(&event_id))
+        .filter(|event| status::is_published(event.id))
+        .filter(|event| {
+            !access::is_unlisted(event.id)
+                || token.is_some_and(|token| access::is_valid_token(event.id, token))
+        });
+    let Some(event) = event else {
+        return HttpResponse::error(404, "Event not found");
+    };
+    HttpResponse::ok("text/html; charset=utf-8", render(&event))
+}
+
+fn render(event: &Event) -> String {
+    let title = escape_xml(&event.event_title);
+    let summary = escape_xml(&summary(event));
+    let url = event_url(event);
+
+    let mut meta = vec![
+        format!("<meta name=\"description\" content=\"{}\">", summary),
+        "<meta property=\"og:type\" content=\"website\">".to_string(),
+        format!("<meta property=\"og:title\" content=\"{}\">", title),
+        format!("<meta property=\"og:description\" content=\"{}\">", summary),
+        format!("<meta property=\"og:url\" content=\"{}\">", url),
+        format!("<meta name=\"twitter:title\" content=\"{}\">", title),
+        format!("<meta name=\"twitter:description\" content=\"{}\">", summary),
+    ];
+    // Private links should not end up in search results
+    if access::is_unlisted(event.id) {
+        meta.push("<meta name=\"robots\" content=\"noindex\">".to_string());
+    }
+    if event.event_card_imgurl.is_empty() {
+        meta.push("<meta name=\"twitter:card\" content=\"summary\">".to_string());
+    } else {
+        let image = escape_xml(&event.event_card_imgurl);
+        meta.push(format!("<meta property=\"og:image\" content=\"{}\">", image));
+        meta.push(format!("<meta name=\"twitter:image\" content=\"{}\">", image));
+        meta.push("<meta name=\"twitter:card\" content=\"summary_large_image\">".to_string());
+    }
+    if let Some(starts_at) = event.starts_at {
+        meta.push(format!(
+            "<meta property=\"event:start_time\" content=\"{}\">",
+            iso8601(starts_at)
+        ));
+    }
+    if let Some(ends_at) = event.ends_at {
+        meta.push(format!(
+            "<meta property=\"event:end_time\" content=\"{}\">",
+            iso8601(ends_at)
+        ));
+    }
+    if !event.event_location.is_empty() {
+        meta.push(format!(
+            "<meta property=\"event:location\" content=\"{}\">",
+            escape_xml(&event.event_location)
+        ));
+    }
+
+    let mut body = format!("<h1>{}</h1>\n", title);
+    if let Some(when) = when(event) {
+        body.push_str(&format!("<p>{}</p>\n", escape_xml(&when)));
+    }
+    if !event.event_location.is_empty() {
+        body.push_str(&format!("<p>{}</p>\n", escape_xml(&event.event_location)));
+    }
+    if !event.event_card_imgurl.is_empty() {
+        body.push_str(&format!(
+            "<img src=\"{}\" alt=\"\">\n",
+            escape_xml(&event.event_card_imgurl)
+        ));
+    }
+    body.push_str(&format!("<p>{}</p>\n", escape_xml(&event.event_description)));
+    let sponsors = sponsors::sponsors_of(event.id);
+    if !sponsors.is_empty() {
+        body.push_str("<h2>Sponsors</h2>\n<ul>\n");
+        for sponsor in &sponsors {
+            let mut item = if sponsor.logo_url.is_empty() {
+                escape_xml(&sponsor.name)
+            } else {
+                format!(
+                    "<img src=\"{}\" alt=\"{}\">",
+                    escape_xml(&sponsor.logo_url),
+                    escape_xml(&sponsor.name)
+                )
+            };
+            if let Some(link) = &sponsor.link {
+                item = format!("<a href=\"{}\" rel=\"sponsored\">{}</a>", escape_xml(link), item);
+            }
+            if let Some(tier) = &sponsor.tier {
+                item.push_str(&format!(" {}", escape_xml(tier)));
+            }
+            body.push_str(&format!("<li>{}</li>\n", item));
+        }
+        body.push_str("</ul>\n");
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{}</title>\n{}\n</head>\n<body>\n{}</body>\n</html>\n",
+        title,
+        meta.join("\n"),
+        body
+    )
+}
+
+// One-line description for link previews: date, location, then the description
+fn summary(event: &Event) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    parts.extend(when(event));
+    if !event.event_location.is_empty() {
+        parts.push(event.event_location.clone());
+    }
+    if !event.event_description.is_empty() {
+        parts.push(event.event_description.clone());
+    }
+    parts.join(" · ")
+}
+
+fn when(event: &Event) -> Option<String> {
+    let t = UtcDateTime::from_nanos(event.starts_at?);
+    Some(format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        t.year, t.month, t.day, t.hour, t.minute
+    ))
+}
+
+fn iso8601(nanos: u64) -> String {
+    let t = UtcDateTime::from_nanos(nanos);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        t.year, t.month, t.day, t.hour, t.minute, t.second
+    )
+}
@@ -0,0 +1,625 @@
+// Ledger payments received for events, and the queue of refunds owed to attendees when
+// an event is cancelled. A timer works through pending refunds, retrying failed
+// transfers with exponential backoff until they succeed or run out of attempts.
+//
+// The same queue pays out resale proceeds to sellers and the organizer's share of them.
+//
+// Buyers pay through ICRC-2: they approve this canister on the configured ledger and the
+// canister pulls the ticket price with `icrc2_transfer_from`.
+use crate::admin::_check_admin;
+use crate::history::{HistoryEntry, HistoryItem, HistoryStatus};
+use crate::ledger::{self, Account, Transfer};
+use crate::logs::{self, LogLevel};
+use crate::{metrics, orders, plans, tokens};
+use crate::{Error, EventPrincipalKey, IdCell, Memory, MEMORY_MANAGER, _get_event, _is_owner};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell as StableCell, StableBTreeMap};
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+const REFUND_INTERVAL: Duration = Duration::from_secs(60);
+const REFUND_BATCH_SIZE: usize = 20;
+// Attempts back off 1, 2, 4, ... minutes, so every retry stays inside the ledger's
+// 24 hour deduplication window and cannot pay out twice
+const MAX_REFUND_ATTEMPTS: u32 = 8;
+const RETRY_BASE_NANOS: u64 = 60 * 1_000_000_000;
+// Ledger errors are kept with the refund record, in bytes
+const MAX_ERROR_BYTES: usize = 400;
+
+// A settled payment for an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Payment {
+    ledger: Principal,
+    payer: Account,
+    amount: u128,
+    block_index: u64,
+    paid_at: u64,
+    // Set once a refund has been queued for this payment
+    refund_id: Option<u64>,
+    // The platform's share under the organizer's plan; payments from before plans had none
+    platform_fee: Option<u128>,
+}
+
+impl_storable!(Payment, 256);
+
+// Payments are keyed by event and payer first, so an event's or a payer's payments are a
+// range scan; a payer charged twice for an event has one entry per ledger block
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct PaymentKey {
+    event_id: u64,
+    principal: String,
+    block_index: u64,
+}
+
+impl_storable!(PaymentKey, 128);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PaymentConfig {
+    // ICRC-1/ICRC-2 ledger ticket prices are charged on
+    ledger: Option<Principal>,
+}
+
+impl_storable!(PaymentConfig, 64);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+enum RefundStatus {
+    Pending,
+    Completed { block_index: u64, refunded_at: u64 },
+    Failed,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum PayoutKind {
+    Refund,
+    ResaleProceeds,
+    ResaleFee,
+    DepositReturn,
+    DepositForfeit,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Refund {
+    id: u64,
+    event_id: u64,
+    principal: String,
+    ledger: Principal,
+    to: Account,
+    amount: u128,
+    created_at: u64,
+    attempts: u32,
+    next_attempt_at: u64,
+    last_error: Option<String>,
+    status: RefundStatus,
+    // What the transfer pays out; refunds queued before resale existed have none
+    kind: Option<PayoutKind>,
+    // System time of the first attempt, sent as the transfer's `created_at_time` on every
+    // attempt so the ledger deduplicates retries
+    sent_at: Option<u64>,
+}
+
+impl_storable!(Refund, 1024);
+
+// Pending refunds by when they're next due, so a run reads only the ones that are
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct DueRefundKey {
+    next_attempt_at: u64,
+    id: u64,
+}
+
+impl_storable!(DueRefundKey, 48);
+
+impl DueRefundKey {
+    fn of(refund: &Refund) -> Self {
+        DueRefundKey {
+            next_attempt_at: refund.next_attempt_at,
+            id: refund.id,
+        }
+    }
+}
+
+// Refunds of an event, in the order they were queued
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct EventRefundKey {
+    event_id: u64,
+    id: u64,
+}
+
+impl_storable!(EventRefundKey, 48);
+
+// Refunds and payouts owed to a principal, in the order they were queued
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct PrincipalRefundKey {
+    principal: String,
+    id: u64,
+}
+
+impl_storable!(PrincipalRefundKey, 128);
+
+thread_local! {
+    static PAYMENTS: RefCell<StableBTreeMap<PaymentKey, Payment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    static REFUND_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))), 0)
+            .expect("Cannot create a refund counter")
+    );
+
+    // Numbers every charge so two charges of one payer for one event never share a memo
+    static CHARGE_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(172))), 0)
+            .expect("Cannot create a charge counter")
+    );
+
+    static REFUNDS: RefCell<StableBTreeMap<u64, Refund, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    static DUE_REFUNDS: RefCell<StableBTreeMap<DueRefundKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(177)))
+    ));
+
+    static EVENT_REFUNDS: RefCell<StableBTreeMap<EventRefundKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(178)))
+    ));
+
+    static PRINCIPAL_REFUNDS: RefCell<StableBTreeMap<PrincipalRefundKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(179)))
+    ));
+
+    static PAYMENT_CONFIG: RefCell<StableCell<PaymentConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))), PaymentConfig::default())
+            .expect("Cannot create the payment config cell")
+    );
+
+    // Guards against two timer runs processing the same refunds concurrently
+    static PROCESSING_REFUNDS: Cell<bool> = const { Cell::new(false) };
+}
+
+// Starts the periodic refund processing; called from init and post_upgrade
+pub(crate) fn start_refund_processor() {
+    ic_cdk_timers::set_timer_interval(REFUND_INTERVAL, || ic_cdk::spawn(process_refunds()));
+}
+
+// Query function returning the ledger ticket prices are charged on, if configured
+#[ic_cdk::query]
+fn get_payment_ledger() -> Option<Principal> {
+    payment_ledger()
+}
+
+// Update function for admins to choose the ledger ticket prices are charged on
+#[ic_cdk::update]
+fn set_payment_ledger(ledger: Option<Principal>) -> Result<(), Error> {
+    let _metrics = metrics::track("set_payment_ledger");
+    _check_admin()?;
+    configure_ledger(ledger);
+    Ok(())
+}
+
+// Sets the ledger ticket prices are charged on, from an admin call or the install config
+pub(crate) fn configure_ledger(ledger: Option<Principal>) {
+    PAYMENT_CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(PaymentConfig { ledger })
+            .expect("cannot update the payment config")
+    });
+}
+
+// Query function listing the refunds queued for an event; restricted to its organizer and admins
+#[ic_cdk::query]
+fn get_refunds(event_id: u64) -> Result<Vec<Refund>, Error> {
+    let event = _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    if !_is_owner(&event, &caller()) {
+        _check_admin()?;
+    }
+    let start = EventRefundKey { event_id, id: 0 };
+    let ids: Vec<u64> = EVENT_REFUNDS.with(|e| {
+        e.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(key, _)| key.id)
+            .collect()
+    });
+    Ok(ids.into_iter().filter_map(get_refund).collect())
+}
+
+// Query function listing the refunds and payouts owed or paid to the caller
+#[ic_cdk::query]
+fn my_refunds() -> Vec<Refund> {
+    refunds_of(&caller().to_string())
+}
+
+// The refunds and payouts sent to a principal as purchase history entries
+pub(crate) fn history_of(principal: &str) -> Vec<HistoryEntry> {
+    refunds_of(principal)
+        .into_iter()
+        .map(|refund| {
+            let (status, block_index) = match refund.status {
+                RefundStatus::Pending => (HistoryStatus::Pending, None),
+                RefundStatus::Completed { block_index, .. } => {
+                    (HistoryStatus::Completed, Some(block_index))
+                }
+                RefundStatus::Failed => (HistoryStatus::Failed, None),
+            };
+            HistoryEntry {
+                item: HistoryItem::Payout {
+                    payout_id: refund.id,
+                    kind: refund.kind.unwrap_or(PayoutKind::Refund),
+                },
+                event_id: refund.event_id,
+                ledger: Some(refund.ledger),
+                amount: refund.amount,
+                status,
+                block_index,
+                at: refund.created_at,
+            }
+        })
+        .collect()
+}
+
+// The default ledger ticket prices are charged on, if configured
+pub(crate) fn payment_ledger() -> Option<Principal> {
+    PAYMENT_CONFIG.with(|c| c.borrow().get().ledger)
+}
+
+// Charges a principal for an event on the given ledger through their ICRC-2 allowance and
+// records the payment; returns the ledger block index
+pub(crate) async fn charge(
+    event_id: u64,
+    principal: Principal,
+    ledger: Principal,
+    amount: u128,
+) -> Result<u64, Error> {
+    tokens::_check_min_amount(ledger, amount)?;
+    let platform_fee =
+        _get_event(&event_id).map_or(0, |event| plans::platform_fee(&event.owner, amount));
+    let payer = Account {
+        owner: principal,
+        subaccount: None,
+    };
+    let nonce = CHARGE_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment charge counter");
+    let mut memo = event_id.to_be_bytes().to_vec();
+    memo.extend_from_slice(&nonce.to_be_bytes());
+    let block_index = ledger::transfer_from(ledger, payer.clone(), amount, memo)
+        .await
+        .map_err(|msg| Error::PaymentFailed { msg })?;
+
+    let key = PaymentKey {
+        event_id,
+        principal: principal.to_string(),
+        block_index,
+    };
+    let payment = Payment {
+        ledger,
+        payer,
+        amount,
+        block_index,
+        paid_at: time(),
+        refund_id: None,
+        platform_fee: Some(platform_fee),
+    };
+    PAYMENTS.with(|p| p.borrow_mut().insert(key, payment));
+    Ok(block_index)
+}
+
+// Queues a refund for every payment received for the event; returns how many were queued
+pub(crate) fn enqueue_refunds(event_id: u64) -> u64 {
+    let start = PaymentKey {
+        event_id,
+        principal: String::new(),
+        block_index: 0,
+    };
+    let keys: Vec<PaymentKey> = PAYMENTS.with(|p| {
+        p.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    keys.into_iter().filter(|key| refund_payment(key.clone())).count() as u64
+}
+
+// Queues a refund for the holder's payment in the given ledger block unless one is already
+// queued; returns whether it was
+pub(crate) fn enqueue_refund(key: &EventPrincipalKey, block_index: u64) -> bool {
+    refund_payment(payment_key(key, block_index))
+}
+
+fn refund_payment(key: PaymentKey) -> bool {
+    let Some(mut payment) = PAYMENTS
+        .with(|p| p.borrow().get(&key))
+        .filter(|payment| payment.refund_id.is_none())
+    else {
+        return false;
+    };
+
+    let id = queue_transfer(
+        key.event_id,
+        key.principal.clone(),
+        payment.ledger,
+        payment.payer.clone(),
+        payment.amount,
+        PayoutKind::Refund,
+    );
+    payment.refund_id = Some(id);
+    let holder = EventPrincipalKey {
+        event_id: key.event_id,
+        principal: key.principal.clone(),
+    };
+    orders::mark_refunded(&holder, key.block_index, id);
+    PAYMENTS.with(|p| p.borrow_mut().insert(key, payment));
+    true
+}
+
+// Queues a payout from this canister to a principal's default account
+pub(crate) fn enqueue_payout(
+    event_id: u64,
+    recipient: Principal,
+    ledger: Principal,
+    amount: u128,
+    kind: PayoutKind,
+) -> u64 {
+    let to = Account {
+        owner: recipient,
+        subaccount: None,
+    };
+    queue_transfer(event_id, recipient.to_string(), ledger, to, amount, kind)
+}
+
+// Whether the holder has a payment for the event that hasn't been refunded
+pub(crate) fn has_paid(key: &EventPrincipalKey) -> bool {
+    payments_of(key)
+        .iter()
+        .any(|(_, payment)| payment.refund_id.is_none())
+}
+
+// Returns the platform's share of the holder's payment in the given ledger block
+pub(crate) fn platform_fee_of(key: &EventPrincipalKey, block_index: u64) -> u128 {
+    PAYMENTS
+        .with(|p| p.borrow().get(&payment_key(key, block_index)))
+        .and_then(|payment| payment.platform_fee)
+        .unwrap_or(0)
+}
+
+// Forgets the holder's payments that weren't refunded once the holder has been paid out
+// through a resale, so cancelling the event later refunds only the current ticket holder
+pub(crate) fn remove_payments(key: &EventPrincipalKey) {
+    for (payment_key, payment) in payments_of(key) {
+        if payment.refund_id.is_none() {
+            PAYMENTS.with(|p| p.borrow_mut().remove(&payment_key));
+        }
+    }
+}
+
+fn payments_of(key: &EventPrincipalKey) -> Vec<(PaymentKey, Payment)> {
+    let start = payment_key(key, 0);
+    PAYMENTS.with(|p| {
+        p.borrow()
+            .range(start..)
+            .take_while(|(payment_key, _)| {
+                payment_key.event_id == key.event_id && payment_key.principal == key.principal
+            })
+            .collect()
+    })
+}
+
+fn payment_key(key: &EventPrincipalKey, block_index: u64) -> PaymentKey {
+    PaymentKey {
+        event_id: key.event_id,
+        principal: key.principal.clone(),
+        block_index,
+    }
+}
+
+fn queue_transfer(
+    event_id: u64,
+    principal: String,
+    ledger: Principal,
+    to: Account,
+    amount: u128,
+    kind: PayoutKind,
+) -> u64 {
+    let id = REFUND_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment refund id counter");
+    let now = time();
+    let refund = Refund {
+        id,
+        event_id,
+        principal,
+        ledger,
+        to,
+        amount,
+        created_at: now,
+        attempts: 0,
+        next_attempt_at: now,
+        last_error: None,
+        status: RefundStatus::Pending,
+        kind: Some(kind),
+        sent_at: None,
+    };
+    store_refund(refund);
+    id
+}
+
+fn get_refund(id: u64) -> Option<Refund> {
+    REFUNDS.with(|r| r.borrow().get(&id))
+}
+
+fn refunds_of(principal: &str) -> Vec<Refund> {
+    let start = PrincipalRefundKey {
+        principal: principal.to_string(),
+        id: 0,
+    };
+    let ids: Vec<u64> = PRINCIPAL_REFUNDS.with(|p| {
+        p.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.principal == principal)
+            .map(|(key, _)| key.id)
+            .collect()
+    });
+    ids.into_iter().filter_map(get_refund).collect()
+}
+
+// Writes a refund along with its index entries, dropping the due entry it had before
+fn store_refund(refund: Refund) {
+    let previous = REFUNDS.with(|r| r.borrow_mut().insert(refund.id, refund.clone()));
+    if let Some(previous) = previous.filter(|previous| previous.status == RefundStatus::Pending) {
+        DUE_REFUNDS.with(|d| d.borrow_mut().remove(&DueRefundKey::of(&previous)));
+    }
+    index_refund(&refund);
+}
+
+fn index_refund(refund: &Refund) {
+    if refund.status == RefundStatus::Pending {
+        DUE_REFUNDS.with(|d| d.borrow_mut().insert(DueRefundKey::of(refund), ()));
+    }
+    let key = EventRefundKey {
+        event_id: refund.event_id,
+        id: refund.id,
+    };
+    EVENT_REFUNDS.with(|e| e.borrow_mut().insert(key, ()));
+    let key = PrincipalRefundKey {
+        principal: refund.principal.clone(),
+        id: refund.id,
+    };
+    PRINCIPAL_REFUNDS.with(|p| p.borrow_mut().insert(key, ()));
+}
+
+// Indexes refunds queued before the indexes existed; called from post_upgrade
+pub(crate) fn migrate() {
+    let unindexed = EVENT_REFUNDS.with(|e| e.borrow().is_empty())
+        && REFUNDS.with(|r| !r.borrow().is_empty());
+    if !unindexed {
+        return;
+    }
+    REFUNDS.with(|r| {
+        for (_, refund) in r.borrow().iter() {
+            index_refund(&refund);
+        }
+    });
+}
+
+// Marks a refund run in progress until dropped. A run that traps after an await has its
+// future dropped when the call context is cleaned up, so the flag is cleared then too.
+struct RefundRun;
+
+impl RefundRun {
+    fn start() -> Option<Self> {
+        (!PROCESSING_REFUNDS.with(|p| p.replace(true))).then_some(RefundRun)
+    }
+}
+
+impl Drop for RefundRun {
+    fn drop(&mut self) {
+        PROCESSING_REFUNDS.with(|p| p.set(false));
+    }
+}
+
+// Sends the pending refunds that are due, rescheduling the ones that fail
+async fn process_refunds() {
+    let Some(_run) = RefundRun::start() else {
+        return;
+    };
+    let now = time();
+    let due = DueRefundKey {
+        next_attempt_at: now,
+        id: u64::MAX,
+    };
+    let ids: Vec<u64> = DUE_REFUNDS.with(|d| {
+        d.borrow()
+            .range(..=due)
+            .take(REFUND_BATCH_SIZE)
+            .map(|(key, _)| key.id)
+            .collect()
+    });
+    for refund in ids.into_iter().filter_map(get_refund) {
+        send_refund(refund).await;
+    }
+}
+
+async fn send_refund(mut refund: Refund) {
+    // Stamped on the first attempt rather than when queued, so a refund that waited in the
+    // queue is still inside the ledger's window. Refunds attempted before the stamp was kept
+    // were sent with their queue time.
+    let created_at_time = match refund.sent_at {
+        Some(sent_at) => sent_at,
+        None if refund.attempts > 0 => refund.created_at,
+        None => ic_cdk::api::time(),
+    };
+    refund.sent_at = Some(created_at_time);
+    refund.attempts += 1;
+    // Kept before awaiting the ledger, so a run that traps retries with the same stamp
+    store_refund(refund.clone());
+    // The ledger fee is taken out of the refunded amount. An amount that doesn't cover it never
+    // will, so it fails right away instead of being retried.
+    let result = match ledger::fee(refund.ledger).await {
+        Ok(fee) if fee >= refund.amount => {
+            refund.attempts = MAX_REFUND_ATTEMPTS;
+            Err(format!(
+                "the amount of {} does not cover the ledger fee of {}",
+                refund.amount, fee
+            ))
+        }
+        Ok(fee) => {
+            let transfer = Transfer {
+                from_subaccount: None,
+                to: refund.to.clone(),
+                amount: refund.amount - fee,
+                memo: refund.id.to_be_bytes().to_vec(),
+                created_at_time,
+            };
+            ledger::transfer(refund.ledger, transfer).await
+        }
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Ok(block_index) => {
+            refund.status = RefundStatus::Completed {
+                block_index,
+                refunded_at: time(),
+            };
+            refund.last_error = None;
+        }
+        Err(err) => {
+            let level = if refund.attempts >= MAX_REFUND_ATTEMPTS {
+                LogLevel::Error
+            } else {
+                LogLevel::Warn
+            };
+            let message =
+                format!("refund {} attempt {} failed: {}", refund.id, refund.attempts, err);
+            logs::write(level, module_path!(), message);
+            let mut end = err.len().min(MAX_ERROR_BYTES);
+            while !err.is_char_boundary(end) {
+                end -= 1;
+            }
+            refund.last_error = Some(err[..end].to_string());
+            if refund.attempts >= MAX_REFUND_ATTEMPTS {
+                refund.status = RefundStatus::Failed;
+            } else {
+                refund.next_attempt_at = time() + RETRY_BASE_NANOS * (1 << (refund.attempts - 1));
+            }
+        }
+    }
+    store_refund(refund);
+}
@@ -0,0 +1,203 @@
+// What each organizer plan includes. Admins set the terms of the free plan and of every paid
+// plan from the billing module: the platform fee taken from ticket sales, how many events an
+// organizer may have, and which features their events may use. Endpoints call the checks
+// here rather than reading plans themselves, so the rules live in one place. Until admins
+// set terms, the free plan has no fee, no event limit and every feature but dedicated
+// canisters, which spend this canister's cycles and are only granted by an admin.
+use crate::admin::_check_admin;
+use crate::{billing, cache, metrics, Error, Memory, MEMORY_MANAGER};
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+
+const MAX_FEE_BPS: u32 = 5_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum Feature {
+    // Registration forms with custom questions
+    CustomForms,
+    // Attendees reselling their tickets
+    ResaleMarket,
+    // Events in a child canister of their own, funded from this canister's cycles
+    DedicatedCanister,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PlanTerms {
+    // Share of each ticket payment kept by the platform, in basis points
+    platform_fee_bps: u32,
+    // Events stored on this canister an organizer may have; None for no limit
+    max_events: Option<u32>,
+    features: Vec<Feature>,
+}
+
+impl Default for PlanTerms {
+    fn default() -> Self {
+        PlanTerms {
+            platform_fee_bps: 0,
+            max_events: None,
+            features: vec![Feature::CustomForms, Feature::ResaleMarket],
+        }
+    }
+}
+
+impl_storable!(PlanTerms, 256);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct PlanName {
+    name: String,
+}
+
+impl_storable!(PlanName, 64);
+
+// The terms an organizer is currently on
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct OrganizerPlan {
+    // None for the free plan
+    plan: Option<String>,
+    terms: PlanTerms,
+    events: u32,
+}
+
+thread_local! {
+    static PLAN_TERMS: RefCell<StableBTreeMap<PlanName, PlanTerms, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(93)))
+    ));
+
+    static FREE_TERMS: RefCell<Cell<PlanTerms, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(94))), PlanTerms::default())
+            .expect("Cannot create the free plan terms cell")
+    );
+}
+
+// Query function returning the terms of a plan, or of the free plan when none is given
+#[ic_cdk::query]
+fn get_plan_terms(plan: Option<String>) -> PlanTerms {
+    terms_of(plan.as_deref())
+}
+
+// Update function for admins to set the terms of a plan, or of the free plan when none is
+// given. Paid plans without terms of their own get the free plan's.
+#[ic_cdk::update]
+fn set_plan_terms(plan: Option<String>, mut terms: PlanTerms) -> Result<PlanTerms, Error> {
+    let _metrics = metrics::track("set_plan_terms");
+    _check_admin()?;
+    if terms.platform_fee_bps > MAX_FEE_BPS {
+        return Err(Error::InvalidPayload {
+            msg: format!("the platform fee can be at most {} basis points", MAX_FEE_BPS),
+        });
+    }
+    let mut features: Vec<Feature> = Vec::new();
+    for feature in terms.features {
+        if !features.contains(&feature) {
+            features.push(feature);
+        }
+    }
+    terms.features = features;
+    match plan {
+        Some(name) => {
+            let key = plan_name(&name).ok_or(Error::InvalidPayload {
+                msg: "plan names are at most 32 characters".to_string(),
+            })?;
+            PLAN_TERMS.with(|t| t.borrow_mut().insert(key, terms.clone()));
+        }
+        None => {
+            FREE_TERMS.with(|t| {
+                t.borrow_mut()
+                    .set(terms.clone())
+                    .expect("cannot update the free plan terms")
+            });
+        }
+    }
+    Ok(terms)
+}
+
+// Query function returning the caller's plan, its terms and how many events they have
+#[ic_cdk::query]
+fn my_plan() -> OrganizerPlan {
+    let organizer = caller().to_string();
+    let plan = billing::plan_of(&organizer);
+    OrganizerPlan {
+        terms: terms_of(plan.as_deref()),
+        plan,
+        events: events_of(&organizer),
+    }
+}
+
+// Helper function to refuse the use of a feature the organizer's plan doesn't include
+pub(crate) fn _check_feature(organizer: &str, feature: Feature) -> Result<(), Error> {
+    if organizer_terms(organizer).features.contains(&feature) {
+        return Ok(());
+    }
+    let name = match feature {
+        Feature::CustomForms => "custom registration forms",
+        Feature::ResaleMarket => "ticket resale",
+        Feature::DedicatedCanister => "dedicated event canisters",
+    };
+    Err(Error::NotAuthorized {
+        msg: format!("The organizer's plan doesn't include {}", name),
+        caller: caller(),
+    })
+}
+
+// Helper function to refuse a new event once the organizer has as many as their plan allows
+pub(crate) fn _check_event_quota(organizer: &str) -> Result<(), Error> {
+    let Some(max_events) = organizer_terms(organizer).max_events else {
+        return Ok(());
+    };
+    if events_of(organizer) >= max_events {
+        return Err(Error::NotAuthorized {
+            msg: format!("Your plan allows up to {} events; upgrade to create more", max_events),
+            caller: caller(),
+        });
+    }
+    Ok(())
+}
+
+// The platform's share of a payment to the organizer, rounded down
+pub(crate) fn platform_fee(organizer: &str, amount: u128) -> u128 {
+    let bps = organizer_terms(organizer).platform_fee_bps as u128;
+    amount / 10_000 * bps + amount % 10_000 * bps / 10_000
+}
+
+// Sets the free plan's platform fee and event limit from the install config, leaving what
+// isn't given as it is
+pub(crate) fn configure_free_plan(
+    platform_fee_bps: Option<u32>,
+    max_events: Option<u32>,
+) -> Result<(), String> {
+    if platform_fee_bps.is_some_and(|bps| bps > MAX_FEE_BPS) {
+        return Err(format!("the platform fee can be at most {} basis points", MAX_FEE_BPS));
+    }
+    let mut terms = terms_of(None);
+    terms.platform_fee_bps = platform_fee_bps.unwrap_or(terms.platform_fee_bps);
+    terms.max_events = max_events.or(terms.max_events);
+    FREE_TERMS.with(|t| {
+        t.borrow_mut()
+            .set(terms)
+            .expect("cannot update the free plan terms")
+    });
+    Ok(())
+}
+
+fn organizer_terms(organizer: &str) -> PlanTerms {
+    terms_of(billing::plan_of(organizer).as_deref())
+}
+
+fn terms_of(plan: Option<&str>) -> PlanTerms {
+    plan.and_then(plan_name)
+        .and_then(|key| PLAN_TERMS.with(|t| t.borrow().get(&key)))
+        .unwrap_or_else(|| FREE_TERMS.with(|t| t.borrow().get().clone()))
+}
+
+fn plan_name(name: &str) -> Option<PlanName> {
+    let name = name.trim().to_lowercase();
+    (name.chars().count() <= 32).then_some(PlanName { name })
+}
+
+// Events the organizer stores on this canister, counted from the owner index
+fn events_of(organizer: &str) -> u32 {
+    cache::events_owned_by(organizer).len() as u32
+}
@@ -0,0 +1,143 @@
+// Engagement points. Principals earn points for joining events and more for actually
+// turning up at the door, and keep a streak of consecutive calendar months (UTC) in which
+// they were active. Each event awards its points once per principal, so leaving and
+// rejoining an event earns nothing extra. Awards also feed the leaderboards.
+use crate::http::UtcDateTime;
+use crate::achievements;
+use crate::leaderboards::{self, Board};
+use crate::{Event, EventPrincipalKey, Memory, PrincipalKey, MEMORY_MANAGER};
+use crate::clock::time;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const ATTENDANCE_POINTS: u64 = 10;
+const CHECK_IN_POINTS: u64 = 20;
+
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+struct Points {
+    points: u64,
+    events_attended: u64,
+    check_ins: u64,
+    // Consecutive months with activity, up to and including `last_active_month`
+    current_streak: u32,
+    longest_streak: u32,
+    // Months since the epoch (year * 12 + month - 1) of the principal's latest activity
+    last_active_month: Option<u32>,
+}
+
+impl_storable!(Points, 128);
+
+// Which of an event's awards a principal has already received
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+struct Awarded {
+    attendance: bool,
+    check_in: bool,
+}
+
+impl_storable!(Awarded, 32);
+
+#[derive(Clone, Copy)]
+enum Activity {
+    Attendance,
+    CheckIn,
+}
+
+thread_local! {
+    static POINTS: RefCell<StableBTreeMap<PrincipalKey, Points, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+
+    static AWARDED: RefCell<StableBTreeMap<EventPrincipalKey, Awarded, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49)))
+    ));
+}
+
+// Query function returning the caller's points and attendance streak. A streak that was
+// not extended last month or this month is reported as zero.
+#[ic_cdk::query]
+fn my_points() -> Points {
+    let key = PrincipalKey {
+        principal: caller().to_string(),
+    };
+    let mut points = POINTS.with(|p| p.borrow().get(&key)).unwrap_or_default();
+    let current = month_of(time());
+    if points
+        .last_active_month
+        .is_none_or(|month| month + 1 < current)
+    {
+        points.current_streak = 0;
+    }
+    points
+}
+
+// Awards the points for joining an event and credits its organizer with the attendee
+pub(crate) fn record_attendance(event: &Event, principal: &str) {
+    if award(event.id, principal, Activity::Attendance) {
+        leaderboards::add(Board::HostedAttendance, &event.owner, 1);
+    }
+}
+
+// Awards the points for being checked in at an event's door
+pub(crate) fn record_check_in(event_id: u64, principal: &str) {
+    if award(event_id, principal, Activity::CheckIn) {
+        leaderboards::add(Board::CheckIns, principal, 1);
+    }
+}
+
+// Records an activity's points unless the event already awarded them; returns whether it did
+fn award(event_id: u64, principal: &str, activity: Activity) -> bool {
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    let mut awarded = AWARDED.with(|a| a.borrow().get(&key)).unwrap_or_default();
+    let already = match activity {
+        Activity::Attendance => std::mem::replace(&mut awarded.attendance, true),
+        Activity::CheckIn => std::mem::replace(&mut awarded.check_in, true),
+    };
+    if already {
+        return false;
+    }
+    AWARDED.with(|a| a.borrow_mut().insert(key, awarded));
+
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    let mut points = POINTS.with(|p| p.borrow().get(&key)).unwrap_or_default();
+    let earned = match activity {
+        Activity::Attendance => {
+            points.events_attended += 1;
+            achievements::record_attendance(principal, points.events_attended);
+            ATTENDANCE_POINTS
+        }
+        Activity::CheckIn => {
+            points.check_ins += 1;
+            CHECK_IN_POINTS
+        }
+    };
+    points.points += earned;
+    extend_streak(&mut points, month_of(time()));
+    POINTS.with(|p| p.borrow_mut().insert(key, points));
+    leaderboards::add(Board::Points, principal, earned);
+    true
+}
+
+fn extend_streak(points: &mut Points, month: u32) {
+    points.current_streak = match points.last_active_month {
+        Some(last) if last == month => points.current_streak,
+        Some(last) if last + 1 == month => points.current_streak + 1,
+        _ => 1,
+    };
+    points.longest_streak = points.longest_streak.max(points.current_streak);
+    points.last_active_month = Some(month);
+}
+
+// Months since the epoch of a timestamp, counting calendar months in UTC
+pub(crate) fn month_of(nanos: u64) -> u32 {
+    let t = UtcDateTime::from_nanos(nanos);
+    (t.year * 12) as u32 + t.month - 1
+}
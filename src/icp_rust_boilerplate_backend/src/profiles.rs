@@ -0,0 +1,82 @@
+// Public user profiles. A principal can pick a display name that is shown in place of the
+// raw principal on badges and other attendee-facing listings.
+use crate::verification;
+use crate::{metrics, Error, Memory, PrincipalKey, MEMORY_MANAGER};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_DISPLAY_NAME_CHARS: usize = 64;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Profile {
+    display_name: String,
+    updated_at: u64,
+    // Whether they are a verified organizer; filled in when the profile is returned
+    verified: Option<bool>,
+}
+
+impl_storable!(Profile, 320);
+
+thread_local! {
+    static PROFILES: RefCell<StableBTreeMap<PrincipalKey, Profile, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+    ));
+}
+
+// Update function to set the caller's display name
+#[ic_cdk::update]
+fn set_my_profile(display_name: String) -> Result<Profile, Error> {
+    let _metrics = metrics::track("set_my_profile");
+    let display_name = display_name.trim().to_string();
+    if display_name.is_empty() || display_name.chars().count() > MAX_DISPLAY_NAME_CHARS {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "display name must be between 1 and {} characters",
+                MAX_DISPLAY_NAME_CHARS
+            ),
+        });
+    }
+    let profile = Profile {
+        display_name,
+        updated_at: time(),
+        verified: None,
+    };
+    let key = PrincipalKey {
+        principal: caller().to_string(),
+    };
+    PROFILES.with(|p| p.borrow_mut().insert(key.clone(), profile.clone()));
+    Ok(with_badge(&key.principal, profile))
+}
+
+// Query function returning a principal's profile, if they set one
+#[ic_cdk::query]
+fn get_profile(principal: Principal) -> Option<Profile> {
+    let principal = principal.to_string();
+    profile_of(&principal).map(|profile| with_badge(&principal, profile))
+}
+
+// Name to show for a principal: their display name, or the principal itself
+pub(crate) fn display_name(principal: &str) -> String {
+    profile_of(principal)
+        .map(|profile| profile.display_name)
+        .unwrap_or_else(|| principal.to_string())
+}
+
+fn with_badge(principal: &str, profile: Profile) -> Profile {
+    Profile {
+        verified: Some(verification::is_verified(principal)),
+        ..profile
+    }
+}
+
+fn profile_of(principal: &str) -> Option<Profile> {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    PROFILES.with(|p| p.borrow().get(&key))
+}
@@ -0,0 +1,273 @@
+// Promo codes. Organizers create limited-use discount codes for their events; buyers pass
+// a code to `purchase_ticket` and every redemption is recorded against the code.
+use crate::{metrics, Error, Memory, MEMORY_MANAGER, _get_organized_event};
+use crate::clock::time;
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+pub(crate) const MAX_CODE_CHARS: usize = 32;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum Discount {
+    // Percentage off the tier price, 1 to 100
+    Percentage(u8),
+    // Amount off the tier price in the ledger's smallest unit
+    Fixed(u128),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PromoCode {
+    code: String,
+    event_id: u64,
+    discount: Discount,
+    expires_at: Option<u64>,
+    max_uses: u32,
+    uses: u32,
+    created_at: u64,
+}
+
+impl_storable!(PromoCode, 256);
+
+// Promo code payload for creating a code
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PromoCodePayload {
+    code: String,
+    discount: Discount,
+    expires_at: Option<u64>,
+    max_uses: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PromoRedemption {
+    code: String,
+    principal: Principal,
+    original_price: u128,
+    discounted_price: u128,
+    redeemed_at: u64,
+}
+
+impl_storable!(PromoRedemption, 256);
+
+// Codes are unique per event and stored upper-cased so they match case-insensitively
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct PromoKey {
+    event_id: u64,
+    code: String,
+}
+
+impl_storable!(PromoKey, 96);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct RedemptionKey {
+    event_id: u64,
+    code: String,
+    principal: String,
+}
+
+impl_storable!(RedemptionKey, 192);
+
+thread_local! {
+    static PROMO_CODES: RefCell<StableBTreeMap<PromoKey, PromoCode, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    static REDEMPTIONS: RefCell<StableBTreeMap<RedemptionKey, PromoRedemption, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+}
+
+// Update function for an event's organizer to create a promo code
+#[ic_cdk::update]
+fn create_promo_code(event_id: u64, payload: PromoCodePayload) -> Result<PromoCode, Error> {
+    let _metrics = metrics::track("create_promo_code");
+    _get_organized_event(event_id)?;
+    validate_promo_payload(&payload)?;
+
+    let key = PromoKey {
+        event_id,
+        code: normalize_code(&payload.code),
+    };
+    if PROMO_CODES.with(|p| p.borrow().contains_key(&key)) {
+        return Err(Error::InvalidPayload {
+            msg: format!("promo code {} already exists for this event", key.code),
+        });
+    }
+    let promo = PromoCode {
+        code: key.code.clone(),
+        event_id,
+        discount: payload.discount,
+        expires_at: payload.expires_at,
+        max_uses: payload.max_uses,
+        uses: 0,
+        created_at: time(),
+    };
+    PROMO_CODES.with(|p| p.borrow_mut().insert(key, promo.clone()));
+    Ok(promo)
+}
+
+// Query function listing an event's promo codes; restricted to its organizer
+#[ic_cdk::query]
+fn get_promo_codes(event_id: u64) -> Result<Vec<PromoCode>, Error> {
+    _get_organized_event(event_id)?;
+    let start = PromoKey {
+        event_id,
+        code: String::new(),
+    };
+    Ok(PROMO_CODES.with(|p| {
+        p.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(_, promo)| promo)
+            .collect()
+    }))
+}
+
+// Query function listing who redeemed a promo code; restricted to the event's organizer
+#[ic_cdk::query]
+fn get_promo_redemptions(event_id: u64, code: String) -> Result<Vec<PromoRedemption>, Error> {
+    _get_organized_event(event_id)?;
+    let code = normalize_code(&code);
+    let start = RedemptionKey {
+        event_id,
+        code: code.clone(),
+        principal: String::new(),
+    };
+    Ok(REDEMPTIONS.with(|r| {
+        r.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id && key.code == code)
+            .map(|(_, redemption)| redemption)
+            .collect()
+    }))
+}
+
+// Claims one use of a promo code and returns the discounted price. Uses are claimed
+// before payment so concurrent buyers can't exceed max_uses; `release` gives one back.
+pub(crate) fn redeem(event_id: u64, code: &str, price: u128) -> Result<u128, Error> {
+    let key = PromoKey {
+        event_id,
+        code: normalize_code(code),
+    };
+    let mut promo = _get_usable_promo(&key)?;
+    promo.uses += 1;
+    let discounted = discounted(&promo, price);
+    PROMO_CODES.with(|p| p.borrow_mut().insert(key, promo));
+    Ok(discounted)
+}
+
+// Returns the price a promo code would bring the given price down to, without claiming a use
+pub(crate) fn _preview(event_id: u64, code: &str, price: u128) -> Result<u128, Error> {
+    let key = PromoKey {
+        event_id,
+        code: normalize_code(code),
+    };
+    Ok(discounted(&_get_usable_promo(&key)?, price))
+}
+
+// Whether the code takes a fixed amount off, which is only meaningful in the event's main token
+pub(crate) fn is_fixed_amount(event_id: u64, code: &str) -> bool {
+    let key = PromoKey {
+        event_id,
+        code: normalize_code(code),
+    };
+    PROMO_CODES
+        .with(|p| p.borrow().get(&key))
+        .is_some_and(|promo| matches!(promo.discount, Discount::Fixed(_)))
+}
+
+// Gives back a use claimed by `redeem` when the purchase did not go through
+pub(crate) fn release(event_id: u64, code: &str) {
+    let key = PromoKey {
+        event_id,
+        code: normalize_code(code),
+    };
+    if let Some(mut promo) = PROMO_CODES.with(|p| p.borrow().get(&key)) {
+        promo.uses = promo.uses.saturating_sub(1);
+        PROMO_CODES.with(|p| p.borrow_mut().insert(key, promo));
+    }
+}
+
+// Records a completed purchase made with a promo code
+pub(crate) fn record_redemption(
+    event_id: u64,
+    code: &str,
+    principal: Principal,
+    original_price: u128,
+    discounted_price: u128,
+) {
+    let code = normalize_code(code);
+    let key = RedemptionKey {
+        event_id,
+        code: code.clone(),
+        principal: principal.to_string(),
+    };
+    let redemption = PromoRedemption {
+        code,
+        principal,
+        original_price,
+        discounted_price,
+        redeemed_at: time(),
+    };
+    REDEMPTIONS.with(|r| r.borrow_mut().insert(key, redemption));
+}
+
+pub(crate) fn normalize_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+// Helper function to reject malformed codes, discounts and use limits
+fn validate_promo_payload(payload: &PromoCodePayload) -> Result<(), Error> {
+    let code = payload.code.trim();
+    if code.is_empty()
+        || code.chars().count() > MAX_CODE_CHARS
+        || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "promo codes must be 1 to {} letters, digits, '-' or '_'",
+                MAX_CODE_CHARS
+            ),
+        });
+    }
+    if let Discount::Percentage(percent) = payload.discount
+        && !(1..=100).contains(&percent)
+    {
+        return Err(Error::InvalidPayload {
+            msg: "percentage discounts must be between 1 and 100".to_string(),
+        });
+    }
+    if payload.max_uses == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "promo code max_uses must be at least 1".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn _get_usable_promo(key: &PromoKey) -> Result<PromoCode, Error> {
+    let invalid = |msg: &str| Error::InvalidPromoCode { msg: msg.to_string() };
+    let promo = PROMO_CODES
+        .with(|p| p.borrow().get(key))
+        .ok_or_else(|| invalid("promo code does not exist"))?;
+    if promo.expires_at.is_some_and(|expires_at| time() >= expires_at) {
+        return Err(invalid("promo code has expired"));
+    }
+    if promo.uses >= promo.max_uses {
+        return Err(invalid("promo code has been fully redeemed"));
+    }
+    Ok(promo)
+}
+
+fn discounted(promo: &PromoCode, price: u128) -> u128 {
+    match promo.discount {
+        Discount::Percentage(percent) => {
+            let percent = percent as u128;
+            price - (price / 100 * percent + price % 100 * percent / 100)
+        }
+        Discount::Fixed(amount) => price.saturating_sub(amount),
+    }
+}
@@ -0,0 +1,300 @@
+// Public Q&A. Attendees post questions on an event, upvote each other's, and the organizer
+// answers and moderates them; hidden questions are only shown to the organizer. Questions
+// are kept for events stored on this canister.
+use crate::activity::{self, ActivityKind};
+use crate::delegations::{self, DelegatedAction};
+use crate::flags::{self, FlaggedFeature};
+use crate::notifications::{self, NotificationKind};
+use crate::{access, attendees, bans, metrics, status};
+use crate::{Error, Event, IdCell, Memory, MEMORY_MANAGER, _get_event, _get_organized_event, _is_owner};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+
+const MAX_QUESTION_CHARS: usize = 280;
+const MAX_ANSWER_CHARS: usize = 1000;
+const MAX_QUESTIONS_PAGE: u64 = 50;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Question {
+    id: u64,
+    event_id: u64,
+    author: String,
+    text: String,
+    upvotes: u32,
+    answer: Option<String>,
+    answered_at: Option<u64>,
+    // Hidden by the organizer; left out of listings for everyone else
+    hidden: bool,
+    asked_at: u64,
+}
+
+impl_storable!(Question, 6144);
+
+// Questions are keyed by event first so an event's Q&A is a range scan
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct QuestionKey {
+    event_id: u64,
+    id: u64,
+}
+
+impl_storable!(QuestionKey, 32);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct VoteKey {
+    question_id: u64,
+    principal: String,
+}
+
+impl_storable!(VoteKey, 112);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct QuestionPage {
+    questions: Vec<Question>,
+    // Number of questions the caller can see, across all pages
+    total: u64,
+}
+
+thread_local! {
+    static QUESTION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61))), 0)
+            .expect("Cannot create a question counter")
+    );
+
+    static QUESTIONS: RefCell<StableBTreeMap<QuestionKey, Question, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62)))
+    ));
+
+    // Upvotes by question and voter, with the time of the vote
+    static VOTES: RefCell<StableBTreeMap<VoteKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63)))
+    ));
+}
+
+// Update function for an attendee, or a service delegated by one, to ask a question on an event
+#[ic_cdk::update]
+fn ask_question(
+    event_id: u64,
+    text: String,
+    on_behalf_of: Option<Principal>,
+) -> Result<Question, Error> {
+    let _metrics = metrics::track("ask_question");
+    flags::_check_enabled(FlaggedFeature::Comments)?;
+    let author = delegations::_acting_for(on_behalf_of, DelegatedAction::AskQuestion)?;
+    let event = _get_attended_event(event_id, &author)?;
+    let text = text.trim().to_string();
+    if text.is_empty() || text.chars().count() > MAX_QUESTION_CHARS {
+        return Err(Error::InvalidPayload {
+            msg: format!("a question must be between 1 and {} characters", MAX_QUESTION_CHARS),
+        });
+    }
+    Ok(post_question(&event, &author.to_string(), text))
+}
+
+// Update function for an attendee to upvote someone else's question, once
+#[ic_cdk::update]
+fn upvote_question(event_id: u64, question_id: u64) -> Result<Question, Error> {
+    let _metrics = metrics::track("upvote_question");
+    _get_attended_event(event_id, &caller())?;
+    let mut question = _get_question(event_id, question_id)?;
+    let voter = caller().to_string();
+    if question.author == voter || question.hidden {
+        return Err(Error::InvalidPayload {
+            msg: "You can't upvote this question".to_string(),
+        });
+    }
+    let key = VoteKey {
+        question_id,
+        principal: voter,
+    };
+    if VOTES.with(|v| v.borrow().contains_key(&key)) {
+        return Err(Error::InvalidPayload {
+            msg: "You already upvoted this question".to_string(),
+        });
+    }
+    VOTES.with(|v| v.borrow_mut().insert(key, time()));
+    question.upvotes += 1;
+    do_insert_question(&question);
+    Ok(question)
+}
+
+// Update function for an event's organizer to answer a question, or change the answer.
+// The author is notified.
+#[ic_cdk::update]
+fn answer_question(event_id: u64, question_id: u64, answer: String) -> Result<Question, Error> {
+    let _metrics = metrics::track("answer_question");
+    let event = _get_organized_event(event_id)?;
+    let mut question = _get_question(event_id, question_id)?;
+    let answer = answer.trim().to_string();
+    if answer.is_empty() || answer.chars().count() > MAX_ANSWER_CHARS {
+        return Err(Error::InvalidPayload {
+            msg: format!("an answer must be between 1 and {} characters", MAX_ANSWER_CHARS),
+        });
+    }
+    question.answer = Some(answer);
+    question.answered_at = Some(time());
+    do_insert_question(&question);
+    notifications::notify(
+        &question.author,
+        NotificationKind::QuestionAnswered,
+        Some(event_id),
+        format!("{}: your question was answered", event.event_title),
+    );
+    Ok(question)
+}
+
+// Update function for an event's organizer to hide a question from the public Q&A or show
+// it again
+#[ic_cdk::update]
+fn moderate_question(event_id: u64, question_id: u64, hidden: bool) -> Result<Question, Error> {
+    let _metrics = metrics::track("moderate_question");
+    _get_organized_event(event_id)?;
+    let mut question = _get_question(event_id, question_id)?;
+    question.hidden = hidden;
+    do_insert_question(&question);
+    Ok(question)
+}
+
+// Update function for an event's organizer to delete a question and its upvotes
+#[ic_cdk::update]
+fn delete_question(event_id: u64, question_id: u64) -> Result<Question, Error> {
+    let _metrics = metrics::track("delete_question");
+    _get_organized_event(event_id)?;
+    let question = _get_question(event_id, question_id)?;
+    QUESTIONS.with(|q| {
+        q.borrow_mut().remove(&QuestionKey {
+            event_id,
+            id: question_id,
+        })
+    });
+    let start = VoteKey {
+        question_id,
+        principal: String::new(),
+    };
+    let voters: Vec<VoteKey> = VOTES.with(|v| {
+        v.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.question_id == question_id)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    VOTES.with(|v| {
+        let mut votes = v.borrow_mut();
+        for key in voters {
+            votes.remove(&key);
+        }
+    });
+    Ok(question)
+}
+
+// Query function returning a page of an event's questions, most upvoted first
+#[ic_cdk::query]
+fn get_questions(event_id: u64, offset: u64, limit: u64) -> Result<QuestionPage, Error> {
+    let event = _get_event(&event_id)
+        .filter(|event| status::is_published(event.id) && access::can_view(event))
+        .ok_or(Error::NotFound {
+            msg: format!("Event with id={} not found", event_id),
+        })?;
+    let is_organizer = _is_owner(&event, &caller());
+    let start = QuestionKey { event_id, id: 0 };
+    let mut questions: Vec<Question> = QUESTIONS.with(|q| {
+        q.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(_, question)| question)
+            .filter(|question| is_organizer || !question.hidden)
+            .filter(|question| !bans::is_content_hidden(&question.author))
+            .collect()
+    });
+    questions.sort_by_key(|question| (Reverse(question.upvotes), question.id));
+    let total = questions.len() as u64;
+    let questions = questions
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit.min(MAX_QUESTIONS_PAGE) as usize)
+        .collect();
+    Ok(QuestionPage { questions, total })
+}
+
+// Helper function returning a published event the principal attends
+fn _get_attended_event(event_id: u64, principal: &Principal) -> Result<Event, Error> {
+    let event = _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    status::_check_open_for_registration(event_id)?;
+    if !attendees::is_attending(event_id, &principal.to_string()) {
+        return Err(Error::NotAuthorized {
+            msg: format!("Only attendees can take part in the Q&A of the event with id={}", event_id),
+            caller: caller(),
+        });
+    }
+    Ok(event)
+}
+
+fn _get_question(event_id: u64, question_id: u64) -> Result<Question, Error> {
+    let key = QuestionKey {
+        event_id,
+        id: question_id,
+    };
+    QUESTIONS.with(|q| q.borrow().get(&key)).ok_or(Error::NotFound {
+        msg: format!("Question with id={} not found", question_id),
+    })
+}
+
+// Stores a new question by the given author on an event
+pub(crate) fn post_question(event: &Event, author: &str, text: String) -> Question {
+    let id = QUESTION_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment question id counter");
+    let question = Question {
+        id,
+        event_id: event.id,
+        author: author.to_string(),
+        text,
+        upvotes: 0,
+        answer: None,
+        answered_at: None,
+        hidden: false,
+        asked_at: time(),
+    };
+    do_insert_question(&question);
+    activity::record(author, ActivityKind::Commented, event.id, &event.event_title);
+    question
+}
+
+// Moves the questions of a merged event to the event it was merged into; upvotes are kept
+// by question and follow them
+pub(crate) fn move_questions(source_id: u64, target_id: u64) {
+    let start = QuestionKey {
+        event_id: source_id,
+        id: 0,
+    };
+    let moved: Vec<(QuestionKey, Question)> = QUESTIONS.with(|q| {
+        q.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == source_id)
+            .collect()
+    });
+    for (key, mut question) in moved {
+        QUESTIONS.with(|q| q.borrow_mut().remove(&key));
+        question.event_id = target_id;
+        do_insert_question(&question);
+    }
+}
+
+fn do_insert_question(question: &Question) {
+    let key = QuestionKey {
+        event_id: question.event_id,
+        id: question.id,
+    };
+    QUESTIONS.with(|q| q.borrow_mut().insert(key, question.clone()));
+}
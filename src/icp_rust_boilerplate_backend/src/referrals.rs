@@ -0,0 +1,199 @@
+// Referral tracking. Anyone can get a personal referral code for an event and share it;
+// an RSVP through `attend_event` that carries the code credits its owner, and the
+// organizer sees which promoters drove sign-ups. Referrals are kept on the router, so
+// they work for events stored in buckets too.
+use crate::{metrics, profiles, status};
+use crate::{Error, EventPrincipalKey, Memory, PrincipalKey, MEMORY_MANAGER, _fetch_event, _is_owner};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::cmp::Reverse;
+
+const CODE_CHARS: usize = 10;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct ReferralCodeKey {
+    event_id: u64,
+    code: String,
+}
+
+impl_storable!(ReferralCodeKey, 160);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Referrer {
+    code: String,
+    sign_ups: u32,
+    created_at: u64,
+    last_sign_up_at: Option<u64>,
+}
+
+impl_storable!(Referrer, 192);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ReferralStats {
+    referrer: String,
+    display_name: String,
+    code: String,
+    sign_ups: u32,
+    last_sign_up_at: Option<u64>,
+}
+
+thread_local! {
+    // Owner of each referral code
+    static REFERRAL_CODES: RefCell<StableBTreeMap<ReferralCodeKey, PrincipalKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55)))
+    ));
+
+    static REFERRERS: RefCell<StableBTreeMap<EventPrincipalKey, Referrer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))
+    ));
+
+    // Who referred each attendee that signed up with a code
+    static REFERRED_BY: RefCell<StableBTreeMap<EventPrincipalKey, PrincipalKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+    ));
+}
+
+// Update function returning the caller's referral code for an event, creating it on first use
+#[ic_cdk::update]
+async fn get_referral_code(event_id: u64) -> Result<String, Error> {
+    let _metrics = metrics::track("get_referral_code");
+    let referrer = caller();
+    if referrer == Principal::anonymous() {
+        return Err(Error::NotAuthorized {
+            msg: "sign in to get a referral code".to_string(),
+            caller: referrer,
+        });
+    }
+    _fetch_event(event_id).await?;
+    status::_check_open_for_registration(event_id)?;
+
+    let key = EventPrincipalKey {
+        event_id,
+        principal: referrer.to_string(),
+    };
+    if let Some(existing) = REFERRERS.with(|r| r.borrow().get(&key)) {
+        return Ok(existing.code);
+    }
+    let code = new_code(event_id, &key.principal);
+    REFERRAL_CODES.with(|c| {
+        c.borrow_mut().insert(
+            ReferralCodeKey {
+                event_id,
+                code: code.clone(),
+            },
+            PrincipalKey {
+                principal: key.principal.clone(),
+            },
+        )
+    });
+    let entry = Referrer {
+        code: code.clone(),
+        sign_ups: 0,
+        created_at: time(),
+        last_sign_up_at: None,
+    };
+    REFERRERS.with(|r| r.borrow_mut().insert(key, entry));
+    Ok(code)
+}
+
+// Query function for an event's organizer to see sign-ups per referrer, most first
+#[ic_cdk::query(composite = true)]
+async fn get_referral_stats(event_id: u64) -> Result<Vec<ReferralStats>, Error> {
+    let event = _fetch_event(event_id).await?;
+    if !_is_owner(&event, &caller()) {
+        return Err(Error::NotAuthorized {
+            msg: format!("You're not the organizer of the event with id={}", event_id),
+            caller: caller(),
+        });
+    }
+    let start = EventPrincipalKey {
+        event_id,
+        principal: String::new(),
+    };
+    let mut stats: Vec<ReferralStats> = REFERRERS.with(|r| {
+        r.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(key, referrer)| ReferralStats {
+                display_name: profiles::display_name(&key.principal),
+                referrer: key.principal,
+                code: referrer.code,
+                sign_ups: referrer.sign_ups,
+                last_sign_up_at: referrer.last_sign_up_at,
+            })
+            .collect()
+    });
+    stats.sort_by_key(|s| Reverse(s.sign_ups));
+    Ok(stats)
+}
+
+// Helper function to reject an RSVP carrying a code that is not valid for the event
+pub(crate) fn _check_code(event_id: u64, code: &str) -> Result<(), Error> {
+    if referrer_of(event_id, code).is_none() {
+        return Err(Error::InvalidPayload {
+            msg: format!("\"{}\" is not a referral code for the event with id={}", code, event_id),
+        });
+    }
+    Ok(())
+}
+
+// Credits a code's owner with an attendee's sign-up. Self-referrals and repeat sign-ups by
+// the same attendee earn nothing.
+pub(crate) fn credit(event_id: u64, code: &str, attendee: &str) {
+    let Some(referrer) = referrer_of(event_id, code) else {
+        return;
+    };
+    let referee = EventPrincipalKey {
+        event_id,
+        principal: attendee.to_string(),
+    };
+    if referrer == attendee || REFERRED_BY.with(|r| r.borrow().contains_key(&referee)) {
+        return;
+    }
+    REFERRED_BY.with(|r| {
+        r.borrow_mut().insert(
+            referee,
+            PrincipalKey {
+                principal: referrer.clone(),
+            },
+        )
+    });
+
+    let key = EventPrincipalKey {
+        event_id,
+        principal: referrer,
+    };
+    if let Some(mut entry) = REFERRERS.with(|r| r.borrow().get(&key)) {
+        entry.sign_ups += 1;
+        entry.last_sign_up_at = Some(time());
+        REFERRERS.with(|r| r.borrow_mut().insert(key, entry));
+    }
+}
+
+fn referrer_of(event_id: u64, code: &str) -> Option<String> {
+    let code = code.trim().to_lowercase();
+    // Longer input can't be a code and would not fit in a key
+    if code.len() > 64 {
+        return None;
+    }
+    let key = ReferralCodeKey { event_id, code };
+    REFERRAL_CODES.with(|c| c.borrow().get(&key)).map(|owner| owner.principal)
+}
+
+// Short code derived from the event and the referrer, lengthened on the rare collision
+fn new_code(event_id: u64, principal: &str) -> String {
+    let digest = Sha256::digest(format!("{}|{}", event_id, principal));
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    (CODE_CHARS..=hex.len())
+        .map(|len| hex[..len].to_string())
+        .find(|code| referrer_of(event_id, code).is_none())
+        .unwrap_or(hex)
+}
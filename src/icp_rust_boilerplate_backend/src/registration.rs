@@ -0,0 +1,381 @@
+// Custom registration questions. An organizer can add a form to an event (free text,
+// single choice and checkbox fields, each optionally required); an RSVP through
+// `attend_event` then carries answers, which are checked against the form and kept per
+// attendee. Organizers page through the answers with a summary per question, such as how
+// many attendees picked each t-shirt size. Forms and answers are kept on the router, so
+// they cover events in buckets too.
+use crate::plans::{self, Feature};
+use crate::profiles;
+use crate::{Error, Event, EventPrincipalKey, Memory, MEMORY_MANAGER, _fetch_event, _is_owner};
+use crate::clock::time;
+use crate::metrics;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Limits are in bytes so that a full form, and a full set of answers to it, fit their
+// storage bounds
+const MAX_FIELDS: usize = 15;
+const MAX_KEY_CHARS: usize = 32;
+const MAX_LABEL_BYTES: usize = 100;
+const MAX_OPTIONS: usize = 10;
+const MAX_OPTION_BYTES: usize = 64;
+const MAX_TEXT_ANSWER_BYTES: usize = 500;
+const MAX_ANSWERS_PAGE: u64 = 100;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum FieldKind {
+    Text,
+    SingleChoice { options: Vec<String> },
+    // A required checkbox has to be ticked, e.g. to accept a code of conduct
+    Checkbox,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RegistrationField {
+    // Short identifier answers refer to, e.g. "tshirt_size"; keep it when editing the form
+    key: String,
+    label: String,
+    kind: FieldKind,
+    required: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RegistrationForm {
+    fields: Vec<RegistrationField>,
+    updated_at: u64,
+}
+
+impl_storable!(RegistrationForm, 16384);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum AnswerValue {
+    Text(String),
+    Choice(String),
+    Checked(bool),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Answer {
+    key: String,
+    value: AnswerValue,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RegistrationAnswers {
+    answers: Vec<Answer>,
+    submitted_at: u64,
+}
+
+impl_storable!(RegistrationAnswers, 16384);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AttendeeAnswers {
+    principal: String,
+    display_name: String,
+    answers: Vec<Answer>,
+    submitted_at: u64,
+}
+
+// Totals for one question across every attendee who answered the form
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct FieldSummary {
+    key: String,
+    label: String,
+    answered: u64,
+    // Attendees per option, for single choice questions
+    choice_counts: Vec<(String, u64)>,
+    // Attendees who ticked the box, for checkbox questions
+    checked: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct RegistrationAnswersPage {
+    answers: Vec<AttendeeAnswers>,
+    // Number of attendees who answered, across all pages
+    total: u64,
+    summary: Vec<FieldSummary>,
+}
+
+thread_local! {
+    static FORMS: RefCell<StableBTreeMap<u64, RegistrationForm, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74)))
+    ));
+
+    static ANSWERS: RefCell<StableBTreeMap<EventPrincipalKey, RegistrationAnswers, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75)))
+    ));
+}
+
+// Update function for an event's organizer to set its registration form; an empty list
+// removes it. Answers already given are kept.
+#[ic_cdk::update]
+async fn set_registration_form(
+    event_id: u64,
+    fields: Vec<RegistrationField>,
+) -> Result<RegistrationForm, Error> {
+    let _metrics = metrics::track("set_registration_form");
+    let event = _get_own_event(event_id).await?;
+    plans::_check_feature(&event.owner, Feature::CustomForms)?;
+    if fields.len() > MAX_FIELDS {
+        return Err(Error::InvalidPayload {
+            msg: format!("a registration form can have at most {} fields", MAX_FIELDS),
+        });
+    }
+    let mut normalized: Vec<RegistrationField> = Vec::new();
+    for field in fields {
+        let field = normalize_field(field)?;
+        if normalized.iter().any(|other| other.key == field.key) {
+            return Err(Error::InvalidPayload {
+                msg: format!("the form has more than one field with key `{}`", field.key),
+            });
+        }
+        normalized.push(field);
+    }
+    let form = RegistrationForm {
+        fields: normalized,
+        updated_at: time(),
+    };
+    FORMS.with(|f| {
+        let mut forms = f.borrow_mut();
+        if form.fields.is_empty() {
+            forms.remove(&event_id);
+        } else {
+            forms.insert(event_id, form.clone());
+        }
+    });
+    Ok(form)
+}
+
+// Query function returning an event's registration form, for clients to render
+#[ic_cdk::query]
+fn get_registration_form(event_id: u64) -> Option<RegistrationForm> {
+    form_of(event_id)
+}
+
+// Query function returning the answers the caller gave when registering for an event
+#[ic_cdk::query]
+fn my_registration_answers(event_id: u64) -> Option<Vec<Answer>> {
+    let key = EventPrincipalKey {
+        event_id,
+        principal: caller().to_string(),
+    };
+    ANSWERS.with(|a| a.borrow().get(&key)).map(|answers| answers.answers)
+}
+
+// Query function for an event's organizer to page through the registration answers, with
+// totals per question over all of them
+#[ic_cdk::query(composite = true)]
+async fn get_registration_answers(
+    event_id: u64,
+    offset: u64,
+    limit: u64,
+) -> Result<RegistrationAnswersPage, Error> {
+    _get_own_event(event_id).await?;
+    let start = EventPrincipalKey {
+        event_id,
+        principal: String::new(),
+    };
+    let all: Vec<(EventPrincipalKey, RegistrationAnswers)> = ANSWERS.with(|a| {
+        a.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .collect()
+    });
+    let fields = form_of(event_id).map(|form| form.fields).unwrap_or_default();
+    let summary = fields.iter().map(|field| summarize(field, &all)).collect();
+    let total = all.len() as u64;
+    let answers = all
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit.min(MAX_ANSWERS_PAGE) as usize)
+        .map(|(key, entry)| AttendeeAnswers {
+            display_name: profiles::display_name(&key.principal),
+            principal: key.principal,
+            answers: entry.answers,
+            submitted_at: entry.submitted_at,
+        })
+        .collect();
+    Ok(RegistrationAnswersPage {
+        answers,
+        total,
+        summary,
+    })
+}
+
+// Helper function to check an RSVP's answers against the event's form, returning them
+// trimmed and in the form's order
+pub(crate) fn _check_answers(event_id: u64, answers: Vec<Answer>) -> Result<Vec<Answer>, Error> {
+    let fields = form_of(event_id).map(|form| form.fields).unwrap_or_default();
+    let invalid = |msg: String| Err(Error::InvalidPayload { msg });
+    let unknown = |answer: &&Answer| !fields.iter().any(|field| field.key == answer.key);
+    if let Some(answer) = answers.iter().find(unknown) {
+        return invalid(format!("the registration form has no field `{}`", answer.key));
+    }
+    let mut checked = Vec::new();
+    for field in &fields {
+        let mut given = answers.iter().filter(|answer| answer.key == field.key);
+        let answer = given.next();
+        if given.next().is_some() {
+            return invalid(format!("`{}` is answered more than once", field.label));
+        }
+        let value = match (&field.kind, answer.map(|answer| &answer.value)) {
+            (FieldKind::Text, Some(AnswerValue::Text(text))) => {
+                let text = text.trim();
+                if text.len() > MAX_TEXT_ANSWER_BYTES {
+                    return invalid(format!(
+                        "the answer to `{}` can have at most {} bytes",
+                        field.label, MAX_TEXT_ANSWER_BYTES
+                    ));
+                }
+                (!text.is_empty()).then(|| AnswerValue::Text(text.to_string()))
+            }
+            (FieldKind::SingleChoice { options }, Some(AnswerValue::Choice(choice))) => {
+                if !options.contains(choice) {
+                    return invalid(format!("`{}` is not an option of `{}`", choice, field.label));
+                }
+                Some(AnswerValue::Choice(choice.clone()))
+            }
+            (FieldKind::Checkbox, Some(AnswerValue::Checked(ticked))) => {
+                Some(AnswerValue::Checked(*ticked)).filter(|_| *ticked || !field.required)
+            }
+            (_, Some(_)) => {
+                return invalid(format!("the answer to `{}` has the wrong type", field.label))
+            }
+            (_, None) => None,
+        };
+        match value {
+            Some(value) => checked.push(Answer {
+                key: field.key.clone(),
+                value,
+            }),
+            None if field.required => return invalid(format!("`{}` is required", field.label)),
+            None => {}
+        }
+    }
+    Ok(checked)
+}
+
+// Stores the answers an attendee registered with
+pub(crate) fn store_answers(event_id: u64, principal: &str, answers: Vec<Answer>) {
+    if answers.is_empty() {
+        return;
+    }
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    let entry = RegistrationAnswers {
+        answers,
+        submitted_at: time(),
+    };
+    ANSWERS.with(|a| a.borrow_mut().insert(key, entry));
+}
+
+fn summarize(
+    field: &RegistrationField,
+    all: &[(EventPrincipalKey, RegistrationAnswers)],
+) -> FieldSummary {
+    // Every current option is listed, even when nobody picked it
+    let mut choice_counts: Vec<(String, u64)> = match &field.kind {
+        FieldKind::SingleChoice { options } => {
+            options.iter().map(|option| (option.clone(), 0)).collect()
+        }
+        _ => Vec::new(),
+    };
+    let mut answered = 0;
+    let mut checked = 0;
+    let values = all
+        .iter()
+        .flat_map(|(_, entry)| &entry.answers)
+        .filter(|answer| answer.key == field.key)
+        .map(|answer| &answer.value);
+    for value in values {
+        answered += 1;
+        match value {
+            AnswerValue::Choice(choice) => {
+                match choice_counts.iter_mut().find(|(option, _)| option == choice) {
+                    Some((_, count)) => *count += 1,
+                    // The option was removed from the form after this answer was given
+                    None => choice_counts.push((choice.clone(), 1)),
+                }
+            }
+            AnswerValue::Checked(true) => checked += 1,
+            _ => {}
+        }
+    }
+    FieldSummary {
+        key: field.key.clone(),
+        label: field.label.clone(),
+        answered,
+        choice_counts,
+        checked,
+    }
+}
+
+fn normalize_field(field: RegistrationField) -> Result<RegistrationField, Error> {
+    let invalid = |msg: String| Err(Error::InvalidPayload { msg });
+    let key = field.key.trim().to_lowercase();
+    if key.is_empty()
+        || key.chars().count() > MAX_KEY_CHARS
+        || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return invalid(format!(
+            "field keys must be 1 to {} letters, digits or underscores",
+            MAX_KEY_CHARS
+        ));
+    }
+    let label = field.label.trim().to_string();
+    if label.is_empty() || label.len() > MAX_LABEL_BYTES {
+        return invalid(format!(
+            "field labels must be between 1 and {} bytes",
+            MAX_LABEL_BYTES
+        ));
+    }
+    let kind = match field.kind {
+        FieldKind::SingleChoice { options } => {
+            let options: Vec<String> =
+                options.iter().map(|option| option.trim().to_string()).collect();
+            if options.is_empty() || options.len() > MAX_OPTIONS {
+                return invalid(format!("`{}` needs between 1 and {} options", label, MAX_OPTIONS));
+            }
+            let unusable =
+                |option: &String| option.is_empty() || option.len() > MAX_OPTION_BYTES;
+            if options.iter().any(unusable) {
+                return invalid(format!(
+                    "options must be between 1 and {} bytes",
+                    MAX_OPTION_BYTES
+                ));
+            }
+            if options.iter().enumerate().any(|(i, option)| options[..i].contains(option)) {
+                return invalid(format!("`{}` lists an option twice", label));
+            }
+            FieldKind::SingleChoice { options }
+        }
+        kind => kind,
+    };
+    Ok(RegistrationField {
+        key,
+        label,
+        kind,
+        required: field.required,
+    })
+}
+
+async fn _get_own_event(event_id: u64) -> Result<Event, Error> {
+    let event = _fetch_event(event_id).await?;
+    if !_is_owner(&event, &caller()) {
+        return Err(Error::NotAuthorized {
+            msg: format!("You're not the organizer of the event with id={}", event_id),
+            caller: caller(),
+        });
+    }
+    Ok(event)
+}
+
+fn form_of(event_id: u64) -> Option<RegistrationForm> {
+    FORMS.with(|f| f.borrow().get(&event_id))
+}
@@ -0,0 +1,190 @@
+// Reputation of attendees and organizers, kept as running totals that are updated as things
+// happen rather than recomputed: attendees by how often they show up to events they
+// registered for and how many reviews they write, organizers by their ratings and how
+// often they cancel. Each gets a score from 0 to 100 that other features can rank by, such
+// as the no-show waitlist.
+use crate::{Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Score of someone with no history yet
+const NEUTRAL_SCORE: u32 = 50;
+// Reviews beyond this many don't raise an attendee's score further
+const MAX_REVIEWS_COUNTED: u32 = 10;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AttendeeStats {
+    // Ended events they were registered for where tickets were scanned at the door
+    events_registered: u32,
+    events_checked_in: u32,
+    reviews_written: u32,
+}
+
+impl_storable!(AttendeeStats, 64);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct OrganizerStats {
+    events_held: u32,
+    events_cancelled: u32,
+    ratings_count: u32,
+    ratings_sum: u64,
+}
+
+impl_storable!(OrganizerStats, 64);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AttendeeReputation {
+    principal: Principal,
+    stats: AttendeeStats,
+    // In basis points; None before their first ended event
+    check_in_rate_bps: Option<u32>,
+    score: u32,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct OrganizerReputation {
+    principal: Principal,
+    stats: OrganizerStats,
+    // Hundredths of a star, e.g. 450 for 4.5; None before their first rating
+    average_rating: Option<u32>,
+    // In basis points; None before their first ended or cancelled event
+    cancellation_rate_bps: Option<u32>,
+    score: u32,
+}
+
+thread_local! {
+    static ATTENDEE_STATS: RefCell<StableBTreeMap<PrincipalKey, AttendeeStats, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(112)))
+    ));
+
+    static ORGANIZER_STATS: RefCell<StableBTreeMap<PrincipalKey, OrganizerStats, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(113)))
+    ));
+}
+
+// Query function returning an attendee's reputation
+#[ic_cdk::query]
+fn get_attendee_reputation(principal: Principal) -> AttendeeReputation {
+    let stats = attendee_stats(&principal.to_string());
+    AttendeeReputation {
+        principal,
+        check_in_rate_bps: check_in_rate_bps(&stats),
+        score: attendee_score_of(&stats),
+        stats,
+    }
+}
+
+// Query function returning an organizer's reputation
+#[ic_cdk::query]
+fn get_organizer_reputation(principal: Principal) -> OrganizerReputation {
+    let stats = organizer_stats(&principal.to_string());
+    OrganizerReputation {
+        principal,
+        average_rating: average_rating(&stats),
+        cancellation_rate_bps: cancellation_rate_bps(&stats),
+        score: organizer_score_of(&stats),
+        stats,
+    }
+}
+
+// An attendee's score from 0 to 100: mostly their check-in rate, plus a little for reviews
+pub(crate) fn attendee_score(principal: &str) -> u32 {
+    attendee_score_of(&attendee_stats(principal))
+}
+
+// Counts an ended event towards an attendee's check-in rate
+pub(crate) fn record_attendance(principal: &str, checked_in: bool) {
+    update_attendee(principal, |stats| {
+        stats.events_registered += 1;
+        if checked_in {
+            stats.events_checked_in += 1;
+        }
+    });
+}
+
+// Counts a review towards its author's and the organizer's reputation
+pub(crate) fn record_review(author: &str, organizer: &str, rating: u8) {
+    update_attendee(author, |stats| stats.reviews_written += 1);
+    update_organizer(organizer, |stats| {
+        stats.ratings_count += 1;
+        stats.ratings_sum += rating as u64;
+    });
+}
+
+// Counts an event that went ahead and ended towards its organizer's cancellation rate
+pub(crate) fn record_event_held(organizer: &str) {
+    update_organizer(organizer, |stats| stats.events_held += 1);
+}
+
+// Counts a cancelled event towards its organizer's cancellation rate
+pub(crate) fn record_cancellation(organizer: &str) {
+    update_organizer(organizer, |stats| stats.events_cancelled += 1);
+}
+
+fn attendee_score_of(stats: &AttendeeStats) -> u32 {
+    let reviews = stats.reviews_written.min(MAX_REVIEWS_COUNTED) * 2;
+    match check_in_rate_bps(stats) {
+        Some(rate) => rate * 80 / 10_000 + reviews,
+        None => (NEUTRAL_SCORE + reviews).min(100),
+    }
+}
+
+// Ratings make up to 70 points, not cancelling up to 30
+fn organizer_score_of(stats: &OrganizerStats) -> u32 {
+    let ratings = average_rating(stats).map(|rating| (rating - 100) * 70 / 400);
+    let reliability = cancellation_rate_bps(stats).map(|rate| (10_000 - rate) * 30 / 10_000);
+    match (ratings, reliability) {
+        (None, None) => NEUTRAL_SCORE,
+        (ratings, reliability) => ratings.unwrap_or(35) + reliability.unwrap_or(15),
+    }
+}
+
+fn check_in_rate_bps(stats: &AttendeeStats) -> Option<u32> {
+    (stats.events_registered > 0)
+        .then(|| (stats.events_checked_in as u64 * 10_000 / stats.events_registered as u64) as u32)
+}
+
+fn average_rating(stats: &OrganizerStats) -> Option<u32> {
+    (stats.ratings_count > 0).then(|| (stats.ratings_sum * 100 / stats.ratings_count as u64) as u32)
+}
+
+fn cancellation_rate_bps(stats: &OrganizerStats) -> Option<u32> {
+    let events = stats.events_held as u64 + stats.events_cancelled as u64;
+    (events > 0).then(|| (stats.events_cancelled as u64 * 10_000 / events) as u32)
+}
+
+fn attendee_stats(principal: &str) -> AttendeeStats {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    ATTENDEE_STATS.with(|s| s.borrow().get(&key)).unwrap_or_default()
+}
+
+fn organizer_stats(principal: &str) -> OrganizerStats {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    ORGANIZER_STATS.with(|s| s.borrow().get(&key)).unwrap_or_default()
+}
+
+fn update_attendee(principal: &str, update: impl FnOnce(&mut AttendeeStats)) {
+    let mut stats = attendee_stats(principal);
+    update(&mut stats);
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    ATTENDEE_STATS.with(|s| s.borrow_mut().insert(key, stats));
+}
+
+fn update_organizer(principal: &str, update: impl FnOnce(&mut OrganizerStats)) {
+    let mut stats = organizer_stats(principal);
+    update(&mut stats);
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    ORGANIZER_STATS.with(|s| s.borrow_mut().insert(key, stats));
+}
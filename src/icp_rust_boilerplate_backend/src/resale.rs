@@ -0,0 +1,234 @@
+// Ticket resale marketplace. Holders of a purchased ticket can list it at or below face
+// value; a buyer pays the canister, which swaps the attendee record in one step and
+// queues the payouts: the seller's proceeds and the organizer's share.
+use crate::payments::{self, PayoutKind};
+use crate::flags::{self, FlaggedFeature};
+use crate::plans::{self, Feature};
+use crate::status::_check_open_for_registration;
+use crate::{attendees, beta, limits, metrics, orders, schedule, tiers, tokens};
+use crate::{Error, EventPrincipalKey, Memory, MEMORY_MANAGER, _get_event};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Share of every resale paid to the organizer, in basis points
+const ORGANIZER_FEE_BPS: u128 = 500;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ResaleListing {
+    event_id: u64,
+    seller: Principal,
+    price: u128,
+    face_value: u128,
+    listed_at: u64,
+}
+
+impl_storable!(ResaleListing, 192);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ResaleReceipt {
+    event_id: u64,
+    seller: Principal,
+    buyer: Principal,
+    price: u128,
+    organizer_fee: u128,
+    block_index: u64,
+}
+
+thread_local! {
+    // Keyed by the seller's ticket
+    static LISTINGS: RefCell<StableBTreeMap<EventPrincipalKey, ResaleListing, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+    ));
+}
+
+// Update function for a ticket holder to list their ticket for resale, or change the price
+// of an existing listing
+#[ic_cdk::update]
+fn list_ticket_for_resale(event_id: u64, price: u128) -> Result<ResaleListing, Error> {
+    let _metrics = metrics::track("list_ticket_for_resale");
+    flags::_check_enabled(FlaggedFeature::Resale)?;
+    let seller = caller();
+    let key = EventPrincipalKey {
+        event_id,
+        principal: seller.to_string(),
+    };
+    _check_open_for_registration(event_id)?;
+    let event = _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    plans::_check_feature(&event.owner, Feature::ResaleMarket)?;
+    let face_value = tiers::face_value(&key).ok_or(Error::NotFound {
+        msg: format!("You don't hold a purchased ticket for the event with id={}", event_id),
+    })?;
+    if price == 0 || price > face_value {
+        return Err(Error::InvalidPayload {
+            msg: format!("resale price must be between 1 and the face value of {}", face_value),
+        });
+    }
+
+    let listing = ResaleListing {
+        event_id,
+        seller,
+        price,
+        face_value,
+        listed_at: time(),
+    };
+    LISTINGS.with(|l| l.borrow_mut().insert(key, listing.clone()));
+    Ok(listing)
+}
+
+// Update function for a seller to withdraw their resale listing
+#[ic_cdk::update]
+fn cancel_resale_listing(event_id: u64) -> Result<ResaleListing, Error> {
+    let _metrics = metrics::track("cancel_resale_listing");
+    let key = EventPrincipalKey {
+        event_id,
+        principal: caller().to_string(),
+    };
+    LISTINGS
+        .with(|l| l.borrow_mut().remove(&key))
+        .ok_or(Error::NotFound {
+            msg: format!("You have no resale listing for the event with id={}", event_id),
+        })
+}
+
+// Query function listing the tickets offered for resale for an event, cheapest first
+#[ic_cdk::query]
+fn get_resale_listings(event_id: u64) -> Vec<ResaleListing> {
+    let start = EventPrincipalKey {
+        event_id,
+        principal: String::new(),
+    };
+    let mut listings: Vec<ResaleListing> = LISTINGS.with(|l| {
+        l.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(_, listing)| listing)
+            .collect()
+    });
+    listings.sort_by_key(|listing| listing.price);
+    listings
+}
+
+// Update function to buy a listed ticket. The buyer pays through an ICRC-2 allowance like
+// for `purchase_ticket`; the seller is then replaced by the buyer as an attendee.
+#[ic_cdk::update]
+async fn buy_resale_ticket(event_id: u64, seller: Principal) -> Result<ResaleReceipt, Error> {
+    let _metrics = metrics::track("buy_resale_ticket");
+    beta::_check_allowlisted()?;
+    flags::_check_enabled(FlaggedFeature::Resale)?;
+    flags::_check_enabled(FlaggedFeature::Payments)?;
+    let buyer = caller();
+    let seller_key = EventPrincipalKey {
+        event_id,
+        principal: seller.to_string(),
+    };
+    let buyer_key = EventPrincipalKey {
+        event_id,
+        principal: buyer.to_string(),
+    };
+    _check_open_for_registration(event_id)?;
+    let event = _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    if attendees::is_attending(event_id, &buyer_key.principal) {
+        return Err(Error::InvalidPayload {
+            msg: "You are already an attendee".to_string(),
+        });
+    }
+    limits::_check_purchase_limits(event_id, buyer, None, 1)?;
+
+    // Take the listing off the market while the payment is in flight
+    let listing = LISTINGS
+        .with(|l| l.borrow_mut().remove(&seller_key))
+        .ok_or(Error::NotFound {
+            msg: "this ticket is not listed for resale".to_string(),
+        })?;
+    // Resale prices are in the event's main token
+    let charged = match tokens::_payment_ledger(event_id, None) {
+        Ok(ledger) => payments::charge(event_id, buyer, ledger, listing.price)
+            .await
+            .map(|block_index| (ledger, block_index)),
+        Err(err) => Err(err),
+    };
+    let (ledger, block_index) = match charged {
+        Ok(charged) => charged,
+        Err(err) => {
+            LISTINGS.with(|l| l.borrow_mut().insert(seller_key, listing));
+            return Err(err);
+        }
+    };
+
+    // The event may have been cancelled, or the seller removed, while the payment was in flight
+    let swapped = _check_open_for_registration(event_id)
+        .and_then(|_| swap_attendee(event_id, &seller_key, &buyer_key));
+    if let Err(err) = swapped {
+        payments::enqueue_refund(&buyer_key, block_index);
+        return Err(err);
+    }
+    tiers::transfer_purchase(&seller_key, buyer_key.clone(), listing.price, block_index);
+    limits::record_purchase(event_id, buyer, None, 1);
+
+    let organizer_fee = listing.price * ORGANIZER_FEE_BPS / 10_000;
+    orders::record_resale(
+        &buyer_key,
+        &seller_key,
+        ledger,
+        listing.price,
+        organizer_fee,
+        block_index,
+    );
+    payments::remove_payments(&seller_key);
+    payments::enqueue_payout(
+        event_id,
+        seller,
+        ledger,
+        listing.price - organizer_fee,
+        PayoutKind::ResaleProceeds,
+    );
+    if let Some(organizer) = Principal::from_text(&event.owner).ok().filter(|_| organizer_fee > 0) {
+        payments::enqueue_payout(event_id, organizer, ledger, organizer_fee, PayoutKind::ResaleFee);
+    }
+
+    Ok(ResaleReceipt {
+        event_id,
+        seller,
+        buyer,
+        price: listing.price,
+        organizer_fee,
+        block_index,
+    })
+}
+
+// Whether the holder has their ticket up for resale
+pub(crate) fn is_listed(key: &EventPrincipalKey) -> bool {
+    LISTINGS.with(|l| l.borrow().contains_key(key))
+}
+
+// Replaces the seller with the buyer in the event's attendees
+pub(crate) fn swap_attendee(event_id: u64, seller: &EventPrincipalKey, buyer: &EventPrincipalKey) -> Result<(), Error> {
+    let event = _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    if !attendees::is_attending(event_id, &seller.principal) {
+        return Err(Error::NotFound {
+            msg: "the seller no longer holds this ticket".to_string(),
+        });
+    }
+    if attendees::is_attending(event_id, &buyer.principal) {
+        return Err(Error::InvalidPayload {
+            msg: "You are already an attendee".to_string(),
+        });
+    }
+    attendees::remove(event_id, &seller.principal);
+    attendees::add(event_id, &buyer.principal);
+    attendees::record_registration(event_id, &buyer.principal);
+    schedule::record(&event, &buyer.principal);
+    schedule::forget_attendee(event_id, &seller.principal);
+    Ok(())
+}
@@ -0,0 +1,241 @@
+// Two-phase ticket purchases. `start_purchase` holds a seat in a tier for the caller, like
+// `purchase_ticket` does, and returns what to approve on the ledger; approving can take a
+// while in a wallet, so the seat stays held for a few minutes. `confirm_purchase` then pulls
+// the payment through the ICRC-2 allowance and registers the caller. A timer gives back the
+// seats of reservations that weren't confirmed in time, so abandoned checkouts don't eat
+// into an event's capacity.
+use crate::flags::{self, FlaggedFeature};
+use crate::logs::{self, LogLevel};
+use crate::seating::{self, SeatRef};
+use crate::tiers::{self, HeldTicket, TicketPurchase};
+use crate::{beta, ledger, metrics, payments};
+use crate::{Error, EventPrincipalKey, IdCell, Memory, MEMORY_MANAGER};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::time::Duration;
+
+const RESERVATION_NANOS: u64 = 15 * 60 * 1_000_000_000;
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Reservation {
+    id: u64,
+    ticket: HeldTicket,
+    // Account to approve: this canister
+    spender: Principal,
+    // Price plus the ledger fee; 0 for free tickets
+    approve_amount: u128,
+    created_at: u64,
+    // The seat is given back when the purchase isn't confirmed by then
+    expires_at: u64,
+}
+
+impl_storable!(Reservation, 1024);
+
+thread_local! {
+    static RESERVATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(148))), 0)
+            .expect("Cannot create a reservation counter")
+    );
+
+    static RESERVATIONS: RefCell<StableBTreeMap<u64, Reservation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(149)))
+    ));
+
+    // Reservation of each buyer for an event
+    static HOLDER_RESERVATIONS: RefCell<StableBTreeMap<EventPrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(150)))
+    ));
+}
+
+// Starts the periodic expiry of unconfirmed reservations; called from init and post_upgrade
+pub(crate) fn start_reservation_expiry() {
+    ic_cdk_timers::set_timer_interval(EXPIRY_INTERVAL, expire_reservations);
+}
+
+// Query function listing the caller's reservations waiting for confirmation
+#[ic_cdk::query]
+fn get_my_reservations() -> Vec<Reservation> {
+    let buyer = caller();
+    RESERVATIONS.with(|r| {
+        r.borrow()
+            .iter()
+            .map(|(_, reservation)| reservation)
+            .filter(|reservation| reservation.ticket.buyer == buyer)
+            .collect()
+    })
+}
+
+// Update function holding a seat in a tier for the caller for a few minutes, optionally with
+// a promo code and a specific seat, and returning the payment instructions: approve
+// `approve_amount` on `ticket.ledger` for `spender`, then call `confirm_purchase`
+#[ic_cdk::update]
+async fn start_purchase(
+    event_id: u64,
+    tier_id: u64,
+    promo_code: Option<String>,
+    seat: Option<SeatRef>,
+    ledger: Option<Principal>,
+) -> Result<Reservation, Error> {
+    let _metrics = metrics::track("start_purchase");
+    beta::_check_allowlisted()?;
+    flags::_check_enabled(FlaggedFeature::Payments)?;
+    let buyer = caller();
+    let holder_key = EventPrincipalKey {
+        event_id,
+        principal: buyer.to_string(),
+    };
+    if let Some(id) = HOLDER_RESERVATIONS.with(|h| h.borrow().get(&holder_key)) {
+        return Err(Error::InvalidPayload {
+            msg: format!("You already have reservation {} for this event", id),
+        });
+    }
+    let expires_at = time() + RESERVATION_NANOS;
+    let ticket =
+        tiers::_hold_ticket(event_id, tier_id, promo_code, seat, ledger, buyer, expires_at)?;
+    let id = RESERVATION_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment reservation id counter");
+    let mut reservation = Reservation {
+        id,
+        ticket,
+        spender: ic_cdk::api::id(),
+        approve_amount: 0,
+        created_at: time(),
+        expires_at,
+    };
+    // Stored before awaiting the ledger, so the hold expires even if the call below fails
+    do_insert(&reservation);
+    if reservation.ticket.price > 0 {
+        let fee = match ledger::fee(reservation.ticket.ledger).await {
+            Ok(fee) => fee,
+            Err(msg) => {
+                cancel(id);
+                return Err(Error::CallFailed { msg });
+            }
+        };
+        reservation.approve_amount = reservation.ticket.price.saturating_add(fee);
+        // The reservation may have been confirmed or cancelled in the meantime
+        if RESERVATIONS.with(|r| r.borrow().contains_key(&id)) {
+            do_insert(&reservation);
+        }
+    }
+    Ok(reservation)
+}
+
+// Update function completing a reservation: pulls the payment through the caller's ICRC-2
+// allowance and registers them. A failed payment can be retried until the reservation expires.
+#[ic_cdk::update]
+async fn confirm_purchase(id: u64) -> Result<TicketPurchase, Error> {
+    let _metrics = metrics::track("confirm_purchase");
+    flags::_check_enabled(FlaggedFeature::Payments)?;
+    let reservation = _get_own_reservation(id)?;
+    let ticket = reservation.ticket.clone();
+    // The seat stays held while the payment is in flight, even past the reservation's expiry
+    if let Some(seat) = &ticket.seat {
+        let until = time() + seating::HOLD_DURATION_NANOS;
+        seating::reserve_for_purchase(ticket.event_id, seat, ticket.buyer, until)?;
+    }
+    // Taken out while the payment is in flight, so the timer can't expire it meanwhile
+    remove(&reservation);
+    let block_index = if ticket.price > 0 {
+        match payments::charge(ticket.event_id, ticket.buyer, ticket.ledger, ticket.price).await {
+            Ok(block_index) => Some(block_index),
+            Err(err) => {
+                if reservation.expires_at > time() {
+                    do_insert(&reservation);
+                } else {
+                    tiers::release_ticket(&ticket);
+                }
+                return Err(err);
+            }
+        }
+    } else {
+        None
+    };
+    tiers::_complete_purchase(ticket, block_index)
+}
+
+// Update function giving up a reservation before it expires
+#[ic_cdk::update]
+fn cancel_purchase(id: u64) -> Result<Reservation, Error> {
+    let _metrics = metrics::track("cancel_purchase");
+    let reservation = _get_own_reservation(id)?;
+    cancel(id);
+    Ok(reservation)
+}
+
+fn _get_own_reservation(id: u64) -> Result<Reservation, Error> {
+    let reservation = RESERVATIONS
+        .with(|r| r.borrow().get(&id))
+        .filter(|reservation| reservation.ticket.buyer == caller())
+        .ok_or(Error::NotFound {
+            msg: format!("Reservation with id={} not found", id),
+        })?;
+    if reservation.expires_at <= time() {
+        cancel(id);
+        return Err(Error::InvalidPayload {
+            msg: format!("Reservation with id={} has expired", id),
+        });
+    }
+    Ok(reservation)
+}
+
+// Gives back the seats of reservations past their expiry
+fn expire_reservations() {
+    let now = time();
+    let expired: Vec<u64> = RESERVATIONS.with(|r| {
+        r.borrow()
+            .iter()
+            .filter(|(_, reservation)| reservation.expires_at <= now)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    if expired.is_empty() {
+        return;
+    }
+    for id in &expired {
+        cancel(*id);
+    }
+    logs::write(
+        LogLevel::Info,
+        module_path!(),
+        format!("expired {} unconfirmed reservation(s)", expired.len()),
+    );
+}
+
+// Drops a reservation and gives back what it held
+fn cancel(id: u64) {
+    if let Some(reservation) = RESERVATIONS.with(|r| r.borrow().get(&id)) {
+        remove(&reservation);
+        tiers::release_ticket(&reservation.ticket);
+    }
+}
+
+fn remove(reservation: &Reservation) {
+    RESERVATIONS.with(|r| r.borrow_mut().remove(&reservation.id));
+    HOLDER_RESERVATIONS.with(|h| h.borrow_mut().remove(&holder_key(&reservation.ticket)));
+}
+
+fn do_insert(reservation: &Reservation) {
+    RESERVATIONS.with(|r| r.borrow_mut().insert(reservation.id, reservation.clone()));
+    HOLDER_RESERVATIONS.with(|h| {
+        h.borrow_mut().insert(holder_key(&reservation.ticket), reservation.id)
+    });
+}
+
+fn holder_key(ticket: &HeldTicket) -> EventPrincipalKey {
+    EventPrincipalKey {
+        event_id: ticket.event_id,
+        principal: ticket.buyer.to_string(),
+    }
+}
@@ -0,0 +1,107 @@
+// Event reviews. Once an event has started, its attendees can rate it from 1 to 5 stars
+// with an optional comment, once each. Ratings count towards the organizer's reputation and
+// writing reviews towards the attendee's.
+use crate::activity::{self, ActivityKind};
+use crate::flags::{self, FlaggedFeature};
+use crate::{attendees, bans, reputation};
+use crate::{metrics, Error, EventPrincipalKey, Memory, MEMORY_MANAGER, _get_event};
+use crate::clock::time;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_COMMENT_CHARS: usize = 1000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Review {
+    event_id: u64,
+    author: String,
+    // From 1 to 5
+    rating: u8,
+    comment: Option<String>,
+    created_at: u64,
+}
+
+impl_storable!(Review, 4608);
+
+thread_local! {
+    static REVIEWS: RefCell<StableBTreeMap<EventPrincipalKey, Review, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(111)))
+    ));
+}
+
+// Update function for an attendee to review an event that has started
+#[ic_cdk::update]
+fn submit_review(event_id: u64, rating: u8, comment: Option<String>) -> Result<Review, Error> {
+    let _metrics = metrics::track("submit_review");
+    flags::_check_enabled(FlaggedFeature::Comments)?;
+    let event = _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    let author = caller().to_string();
+    if !attendees::is_attending(event_id, &author) || event.owner == author {
+        return Err(Error::NotAuthorized {
+            msg: "Only the event's attendees can review it".to_string(),
+            caller: caller(),
+        });
+    }
+    if event.starts_at.is_none_or(|starts_at| starts_at > time()) {
+        return Err(Error::InvalidPayload {
+            msg: "An event can be reviewed once it has started".to_string(),
+        });
+    }
+    if !(1..=5).contains(&rating) {
+        return Err(Error::InvalidPayload {
+            msg: "a rating must be between 1 and 5".to_string(),
+        });
+    }
+    let comment = comment
+        .map(|comment| comment.trim().to_string())
+        .filter(|comment| !comment.is_empty());
+    if comment.as_ref().is_some_and(|comment| comment.chars().count() > MAX_COMMENT_CHARS) {
+        return Err(Error::InvalidPayload {
+            msg: format!("a comment can have at most {} characters", MAX_COMMENT_CHARS),
+        });
+    }
+    let key = EventPrincipalKey {
+        event_id,
+        principal: author.clone(),
+    };
+    if REVIEWS.with(|r| r.borrow().contains_key(&key)) {
+        return Err(Error::InvalidPayload {
+            msg: "You already reviewed this event".to_string(),
+        });
+    }
+    let review = Review {
+        event_id,
+        author,
+        rating,
+        comment,
+        created_at: time(),
+    };
+    REVIEWS.with(|r| r.borrow_mut().insert(key, review.clone()));
+    reputation::record_review(&review.author, &event.owner, rating);
+    activity::record(&review.author, ActivityKind::Commented, event_id, &event.event_title);
+    Ok(review)
+}
+
+// Query function listing an event's reviews, newest first
+#[ic_cdk::query]
+fn get_event_reviews(event_id: u64) -> Vec<Review> {
+    let start = EventPrincipalKey {
+        event_id,
+        principal: String::new(),
+    };
+    let mut reviews: Vec<Review> = REVIEWS.with(|r| {
+        r.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(_, review)| review)
+            .filter(|review| !bans::is_content_hidden(&review.author))
+            .collect()
+    });
+    reviews.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    reviews
+}
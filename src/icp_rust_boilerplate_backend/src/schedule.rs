@@ -0,0 +1,180 @@
+// Schedule conflict detection. The router keeps, per principal, the times of the events they
+// attend; an RSVP to an event that overlaps one of them is refused with the list of
+// conflicts, unless the attendee confirms they want both. Events without a start time never
+// conflict, and those without an end time count as lasting an hour.
+use crate::status;
+use crate::{attendees, Error, Event, Memory, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+pub(crate) const DEFAULT_DURATION_NANOS: u64 = 60 * 60 * 1_000_000_000;
+const MAX_TITLE_CHARS: usize = 100;
+
+// Keyed by principal first so someone's schedule is a range scan
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct ScheduleKey {
+    principal: String,
+    event_id: u64,
+}
+
+impl_storable!(ScheduleKey, 112);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ScheduledEvent {
+    title: String,
+    starts_at: u64,
+    ends_at: u64,
+}
+
+impl_storable!(ScheduledEvent, 512);
+
+// An event overlapping the one being joined or scheduled
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ConflictingEvent {
+    pub(crate) event_id: u64,
+    pub(crate) event_title: String,
+    pub(crate) starts_at: u64,
+    pub(crate) ends_at: u64,
+}
+
+thread_local! {
+    static SCHEDULES: RefCell<StableBTreeMap<ScheduleKey, ScheduledEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79)))
+    ));
+}
+
+// Helper function to refuse an RSVP to an event overlapping others the principal attends,
+// unless they allowed conflicts
+pub(crate) fn _check_conflicts(
+    event: &Event,
+    principal: &str,
+    allow_conflicts: bool,
+) -> Result<(), Error> {
+    if allow_conflicts {
+        return Ok(());
+    }
+    let Some(candidate) = scheduled(event) else {
+        return Ok(());
+    };
+    let conflicts: Vec<ConflictingEvent> = schedule_of(principal)
+        .into_iter()
+        .filter(|(event_id, other)| {
+            *event_id != event.id
+                && other.starts_at < candidate.ends_at
+                && candidate.starts_at < other.ends_at
+                && status::is_published(*event_id)
+        })
+        .map(|(event_id, other)| ConflictingEvent {
+            event_id,
+            event_title: other.title,
+            starts_at: other.starts_at,
+            ends_at: other.ends_at,
+        })
+        .collect();
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    Err(Error::ScheduleConflict {
+        msg: format!(
+            "This event overlaps {} other event(s) you're attending; set allow_conflicts to join anyway",
+            conflicts.len()
+        ),
+        conflicts,
+    })
+}
+
+// Adds the event to the principal's schedule
+pub(crate) fn record(event: &Event, principal: &str) {
+    let key = ScheduleKey {
+        principal: principal.to_string(),
+        event_id: event.id,
+    };
+    SCHEDULES.with(|s| {
+        let mut s = s.borrow_mut();
+        match scheduled(event) {
+            Some(entry) => s.insert(key, entry),
+            None => s.remove(&key),
+        }
+    });
+}
+
+// Brings the schedules of the event's attendees up to date after it changed
+pub(crate) fn refresh(event: &Event) {
+    for attendee in attendees::attendees_of(event.id) {
+        record(event, &attendee);
+    }
+}
+
+// Removes the event from the schedule of each of its attendees
+pub(crate) fn forget(event: &Event) {
+    SCHEDULES.with(|s| {
+        let mut s = s.borrow_mut();
+        for attendee in attendees::attendees_of(event.id) {
+            s.remove(&ScheduleKey {
+                principal: attendee,
+                event_id: event.id,
+            });
+        }
+    });
+}
+
+// Removes the event from one principal's schedule, e.g. after they resold their ticket
+pub(crate) fn forget_attendee(event_id: u64, principal: &str) {
+    let key = ScheduleKey {
+        principal: principal.to_string(),
+        event_id,
+    };
+    SCHEDULES.with(|s| s.borrow_mut().remove(&key));
+}
+
+// Every (principal, event id) schedule entry
+pub(crate) fn scheduled_entries() -> Vec<(String, u64)> {
+    SCHEDULES.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(key, _)| (key.principal, key.event_id))
+            .collect()
+    })
+}
+
+// Drops the schedule entries of every event but those kept, ahead of a rebuild
+pub(crate) fn clear_schedules(keep: impl Fn(u64) -> bool) {
+    SCHEDULES.with(|s| {
+        let mut s = s.borrow_mut();
+        let dropped: Vec<ScheduleKey> = s
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| !keep(key.event_id))
+            .collect();
+        for key in dropped {
+            s.remove(&key);
+        }
+    });
+}
+
+fn scheduled(event: &Event) -> Option<ScheduledEvent> {
+    let starts_at = event.starts_at?;
+    Some(ScheduledEvent {
+        title: event.event_title.chars().take(MAX_TITLE_CHARS).collect(),
+        starts_at,
+        ends_at: event
+            .ends_at
+            .unwrap_or_else(|| starts_at.saturating_add(DEFAULT_DURATION_NANOS)),
+    })
+}
+
+fn schedule_of(principal: &str) -> Vec<(u64, ScheduledEvent)> {
+    let start = ScheduleKey {
+        principal: principal.to_string(),
+        event_id: 0,
+    };
+    SCHEDULES.with(|s| {
+        s.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.principal == principal)
+            .map(|(key, entry)| (key.event_id, entry))
+            .collect()
+    })
+}
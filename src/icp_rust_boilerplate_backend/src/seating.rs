@@ -0,0 +1,438 @@
+// Reserved seating. An organizer can give an event a seating chart of sections, rows and
+// numbered seats, optionally tying each section to a ticket tier. Buyers pick a seat when
+// calling `purchase_ticket`; `hold_seat` keeps a seat for them for a few minutes while
+// they go through checkout. A purchase holds its seat while the payment is in flight, so two
+// buyers can't pay for the same seat across ledger calls. Holds stop counting once expired and
+// a timer clears them out.
+use crate::status::_check_open_for_registration;
+use crate::tiers;
+use crate::{metrics, Error, Memory, MEMORY_MANAGER, _get_event, _get_organized_event};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::time::Duration;
+
+pub(crate) const HOLD_DURATION_NANOS: u64 = 10 * 60 * 1_000_000_000;
+const MAX_SEATS_PER_ROW: u32 = 500;
+const MAX_SEATS: u32 = 20_000;
+// Keep the encoded chart within its stable storage bound
+const MAX_SECTIONS: usize = 50;
+const MAX_ROWS: usize = 500;
+const MAX_LABEL_BYTES: usize = 16;
+const HOLD_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SeatRow {
+    label: String,
+    // Seats are numbered 1 to seats
+    seats: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SeatSection {
+    name: String,
+    // Tier whose tickets are sold for this section; any tier when unset
+    tier_id: Option<u64>,
+    rows: Vec<SeatRow>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SeatingChart {
+    sections: Vec<SeatSection>,
+}
+
+impl_storable!(SeatingChart, 16384);
+
+// A single seat within an event's chart
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct SeatRef {
+    section: String,
+    row: String,
+    number: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct SeatKey {
+    event_id: u64,
+    section: String,
+    row: String,
+    number: u32,
+}
+
+impl_storable!(SeatKey, 96);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct HoldExpiryKey {
+    expires_at: u64,
+    seat: SeatKey,
+}
+
+impl_storable!(HoldExpiryKey, 128);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum SeatState {
+    Held { expires_at: u64 },
+    Sold,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SeatAssignment {
+    holder: Principal,
+    state: SeatState,
+}
+
+impl_storable!(SeatAssignment, 96);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SeatHold {
+    seat: SeatRef,
+    expires_at: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SeatAvailability {
+    seat: SeatRef,
+    available: bool,
+}
+
+thread_local! {
+    static SEATING_CHARTS: RefCell<StableBTreeMap<u64, SeatingChart, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+    ));
+
+    // Only seats that are held or sold have an entry
+    static SEATS: RefCell<StableBTreeMap<SeatKey, SeatAssignment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+
+    // Held seats by expiry, for the timer clearing them out
+    static HOLD_EXPIRIES: RefCell<StableBTreeMap<HoldExpiryKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(153)))
+    ));
+}
+
+// Starts the periodic clearing of expired seat holds; called from init and post_upgrade
+pub(crate) fn start_hold_expiry() {
+    ic_cdk_timers::set_timer_interval(HOLD_EXPIRY_INTERVAL, expire_holds);
+}
+
+// Update function for an event's organizer to set its seating chart. The chart can no
+// longer be replaced once a seat has been sold.
+#[ic_cdk::update]
+fn set_seating_chart(event_id: u64, chart: SeatingChart) -> Result<SeatingChart, Error> {
+    let _metrics = metrics::track("set_seating_chart");
+    _get_organized_event(event_id)?;
+    validate_chart(event_id, &chart)?;
+    let sold = assignments_of(event_id)
+        .iter()
+        .any(|(_, assignment)| matches!(assignment.state, SeatState::Sold));
+    if sold {
+        return Err(Error::InvalidPayload {
+            msg: "the seating chart cannot change once seats have been sold".to_string(),
+        });
+    }
+
+    // Outstanding holds refer to the old layout
+    for (key, _) in assignments_of(event_id) {
+        remove_assignment(&key);
+    }
+    SEATING_CHARTS.with(|c| c.borrow_mut().insert(event_id, chart.clone()));
+    Ok(chart)
+}
+
+// Query function returning an event's seating chart, if it has one
+#[ic_cdk::query]
+fn get_seating_chart(event_id: u64) -> Option<SeatingChart> {
+    chart_of(event_id)
+}
+
+// Query function listing the seats of an event, or of one of its sections, and whether
+// each can still be bought
+#[ic_cdk::query]
+fn get_seat_availability(event_id: u64, section: Option<String>) -> Result<Vec<SeatAvailability>, Error> {
+    let chart = _get_chart(event_id)?;
+    let now = time();
+    let mut seats = Vec::new();
+    for s in chart.sections.iter().filter(|s| section.as_ref().is_none_or(|name| s.name == *name)) {
+        for row in &s.rows {
+            for number in 1..=row.seats {
+                let seat = SeatRef {
+                    section: s.name.clone(),
+                    row: row.label.clone(),
+                    number,
+                };
+                let available = assignment_of(event_id, &seat).is_none_or(|a| is_expired(&a, now));
+                seats.push(SeatAvailability { seat, available });
+            }
+        }
+    }
+    Ok(seats)
+}
+
+// Update function to hold a seat for the caller during checkout. A caller holds at most
+// one seat per event; holding another releases the previous one.
+#[ic_cdk::update]
+fn hold_seat(event_id: u64, seat: SeatRef) -> Result<SeatHold, Error> {
+    let _metrics = metrics::track("hold_seat");
+    let holder = caller();
+    _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    _check_open_for_registration(event_id)?;
+    _check_seat(event_id, &seat, None)?;
+    release_holds_of(event_id, holder);
+    _check_seat_free(event_id, &seat, holder)?;
+
+    let expires_at = time() + HOLD_DURATION_NANOS;
+    insert_assignment(
+        event_id,
+        &seat,
+        SeatAssignment {
+            holder,
+            state: SeatState::Held { expires_at },
+        },
+    );
+    Ok(SeatHold { seat, expires_at })
+}
+
+// Update function to give up the caller's seat hold for an event
+#[ic_cdk::update]
+fn release_seat_hold(event_id: u64) {
+    let _metrics = metrics::track("release_seat_hold");
+    release_holds_of(event_id, caller());
+}
+
+// Helper function to check that a seat exists in the event's chart and, when a tier is
+// given, that its section sells that tier
+pub(crate) fn _check_seat(event_id: u64, seat: &SeatRef, tier_id: Option<u64>) -> Result<(), Error> {
+    let chart = _get_chart(event_id)?;
+    let section = chart
+        .sections
+        .iter()
+        .find(|s| s.name == seat.section)
+        .ok_or(Error::NotFound {
+            msg: format!("Section \"{}\" not found", seat.section),
+        })?;
+    let exists = section
+        .rows
+        .iter()
+        .any(|row| row.label == seat.row && (1..=row.seats).contains(&seat.number));
+    if !exists {
+        return Err(Error::NotFound {
+            msg: format!("Seat {} in row {} of section \"{}\" not found", seat.number, seat.row, seat.section),
+        });
+    }
+    if let (Some(required), Some(tier_id)) = (section.tier_id, tier_id)
+        && required != tier_id
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!("Section \"{}\" is sold through tier id={}", seat.section, required),
+        });
+    }
+    Ok(())
+}
+
+// Holds a seat for a buyer until `expires_at` while they pay for it; the seat must be free or
+// already held by the buyer. Calling it again moves the expiry of the buyer's hold.
+pub(crate) fn reserve_for_purchase(
+    event_id: u64,
+    seat: &SeatRef,
+    buyer: Principal,
+    expires_at: u64,
+) -> Result<(), Error> {
+    _check_seat_free(event_id, seat, buyer)?;
+    insert_assignment(
+        event_id,
+        seat,
+        SeatAssignment {
+            holder: buyer,
+            state: SeatState::Held { expires_at },
+        },
+    );
+    Ok(())
+}
+
+// Marks a reserved seat as sold to the buyer
+pub(crate) fn confirm_sale(event_id: u64, seat: &SeatRef, buyer: Principal) {
+    insert_assignment(
+        event_id,
+        seat,
+        SeatAssignment {
+            holder: buyer,
+            state: SeatState::Sold,
+        },
+    );
+}
+
+// Frees a seat reserved by the buyer when their purchase does not go through
+pub(crate) fn release(event_id: u64, seat: &SeatRef, buyer: Principal) {
+    if assignment_of(event_id, seat).is_some_and(|a| a.holder == buyer) {
+        remove_assignment(&seat_key(event_id, seat));
+    }
+}
+
+// Hands a sold seat to the new ticket holder after a resale
+pub(crate) fn reassign(event_id: u64, seat: &SeatRef, holder: Principal) {
+    confirm_sale(event_id, seat, holder);
+}
+
+fn _check_seat_free(event_id: u64, seat: &SeatRef, buyer: Principal) -> Result<(), Error> {
+    match assignment_of(event_id, seat) {
+        Some(assignment) if assignment.holder != buyer && !is_expired(&assignment, time()) => {
+            Err(Error::TierSoldOut {
+                msg: format!("Seat {} in row {} is taken", seat.number, seat.row),
+            })
+        }
+        Some(SeatAssignment {
+            state: SeatState::Sold,
+            ..
+        }) => Err(Error::InvalidPayload {
+            msg: "You already bought this seat".to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn _get_chart(event_id: u64) -> Result<SeatingChart, Error> {
+    chart_of(event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} has no seating chart", event_id),
+    })
+}
+
+fn chart_of(event_id: u64) -> Option<SeatingChart> {
+    SEATING_CHARTS.with(|c| c.borrow().get(&event_id))
+}
+
+fn is_expired(assignment: &SeatAssignment, now: u64) -> bool {
+    matches!(assignment.state, SeatState::Held { expires_at } if expires_at <= now)
+}
+
+fn release_holds_of(event_id: u64, holder: Principal) {
+    for (key, assignment) in assignments_of(event_id) {
+        if assignment.holder == holder && matches!(assignment.state, SeatState::Held { .. }) {
+            remove_assignment(&key);
+        }
+    }
+}
+
+fn assignments_of(event_id: u64) -> Vec<(SeatKey, SeatAssignment)> {
+    let start = SeatKey {
+        event_id,
+        section: String::new(),
+        row: String::new(),
+        number: 0,
+    };
+    SEATS.with(|s| {
+        s.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .collect()
+    })
+}
+
+fn assignment_of(event_id: u64, seat: &SeatRef) -> Option<SeatAssignment> {
+    SEATS.with(|s| s.borrow().get(&seat_key(event_id, seat)))
+}
+
+// Clears the holds that expired; seats sold since keep their sale
+fn expire_holds() {
+    let now = time();
+    let expired: Vec<HoldExpiryKey> = HOLD_EXPIRIES.with(|h| {
+        h.borrow()
+            .iter()
+            .take_while(|(key, _)| key.expires_at <= now)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for key in expired {
+        HOLD_EXPIRIES.with(|h| h.borrow_mut().remove(&key));
+        let assignment = SEATS.with(|s| s.borrow().get(&key.seat));
+        if assignment.is_some_and(|a| is_expired(&a, now)) {
+            SEATS.with(|s| s.borrow_mut().remove(&key.seat));
+        }
+    }
+}
+
+fn insert_assignment(event_id: u64, seat: &SeatRef, assignment: SeatAssignment) {
+    let key = seat_key(event_id, seat);
+    remove_assignment(&key);
+    if let SeatState::Held { expires_at } = assignment.state {
+        let expiry = HoldExpiryKey {
+            expires_at,
+            seat: key.clone(),
+        };
+        HOLD_EXPIRIES.with(|h| h.borrow_mut().insert(expiry, 0));
+    }
+    SEATS.with(|s| s.borrow_mut().insert(key, assignment));
+}
+
+fn remove_assignment(key: &SeatKey) {
+    let removed = SEATS.with(|s| s.borrow_mut().remove(key));
+    if let Some(SeatAssignment {
+        state: SeatState::Held { expires_at },
+        ..
+    }) = removed
+    {
+        let expiry = HoldExpiryKey {
+            expires_at,
+            seat: key.clone(),
+        };
+        HOLD_EXPIRIES.with(|h| h.borrow_mut().remove(&expiry));
+    }
+}
+
+fn seat_key(event_id: u64, seat: &SeatRef) -> SeatKey {
+    SeatKey {
+        event_id,
+        section: seat.section.clone(),
+        row: seat.row.clone(),
+        number: seat.number,
+    }
+}
+
+// Helper function to reject charts with unnamed or repeated sections and rows, empty rows,
+// unknown tiers or too many seats
+fn validate_chart(event_id: u64, chart: &SeatingChart) -> Result<(), Error> {
+    let invalid = |msg: String| Err(Error::InvalidPayload { msg });
+    let valid_label = |label: &str| !label.trim().is_empty() && label.len() <= MAX_LABEL_BYTES;
+    if chart.sections.is_empty() || chart.sections.len() > MAX_SECTIONS {
+        return invalid(format!("a seating chart needs between 1 and {} sections", MAX_SECTIONS));
+    }
+    if chart.sections.iter().map(|s| s.rows.len()).sum::<usize>() > MAX_ROWS {
+        return invalid(format!("a seating chart can have at most {} rows", MAX_ROWS));
+    }
+
+    let mut total: u32 = 0;
+    for (i, section) in chart.sections.iter().enumerate() {
+        if !valid_label(&section.name) || chart.sections[..i].iter().any(|s| s.name == section.name) {
+            return invalid(format!(
+                "section names must be unique and between 1 and {} bytes",
+                MAX_LABEL_BYTES
+            ));
+        }
+        if let Some(tier_id) = section.tier_id
+            && !tiers::has_tier(event_id, tier_id)
+        {
+            return invalid(format!("Tier with id={} not found", tier_id));
+        }
+        for (j, row) in section.rows.iter().enumerate() {
+            if !valid_label(&row.label) || section.rows[..j].iter().any(|r| r.label == row.label) {
+                return invalid(format!("row labels in section \"{}\" must be unique", section.name));
+            }
+            if row.seats == 0 || row.seats > MAX_SEATS_PER_ROW {
+                return invalid(format!("a row must have between 1 and {} seats", MAX_SEATS_PER_ROW));
+            }
+            total += row.seats;
+        }
+    }
+    if total > MAX_SEATS {
+        return invalid(format!("a seating chart can have at most {} seats", MAX_SEATS));
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+// Encryption at rest for secrets users hand to the canister (phone numbers, OAuth tokens).
+// Values are encrypted with ChaCha20-Poly1305 under a random key the canister generates and
+// keeps in stable memory, so they don't appear in plain text in state or snapshots. This does
+// not hide them from the canister itself. Values sealed by earlier versions, with an
+// HMAC-SHA256 keystream and a 16-byte nonce, can still be opened.
+use crate::{Error, IdCell, Memory, MEMORY_MANAGER, _call_failed};
+use chacha20poly1305::aead::AeadInPlace;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce, Tag};
+use hmac::{Hmac, Mac};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Cell;
+use sha2::Sha256;
+use std::cell::RefCell;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_BYTES: usize = 12;
+const TAG_BYTES: usize = 16;
+const LEGACY_NONCE_BYTES: usize = 16;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Sealed {
+    pub(crate) nonce: Vec<u8>,
+    pub(crate) ciphertext: Vec<u8>,
+    pub(crate) tag: Vec<u8>,
+}
+
+thread_local! {
+    // First created for SMS phone numbers, hence its place in stable memory
+    static SECRET_KEY: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65))), Vec::new())
+            .expect("Cannot create the secret key cell")
+    );
+
+    // Values sealed so far; each one's count is its nonce, so no nonce is used twice
+    static NONCE_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(170))), 0)
+            .expect("Cannot create the nonce counter")
+    );
+}
+
+// Helper function to lazily generate the encryption key; call before `seal`
+pub(crate) async fn ensure_key() -> Result<(), Error> {
+    if SECRET_KEY.with(|k| !k.borrow().get().is_empty()) {
+        return Ok(());
+    }
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|err| _call_failed("raw_rand", err))?;
+    // Another call may have set the key while this one was awaiting
+    SECRET_KEY.with(|k| {
+        if k.borrow().get().is_empty() {
+            k.borrow_mut().set(bytes).expect("cannot store the secret key");
+        }
+    });
+    Ok(())
+}
+
+// Encrypts a value under a fresh nonce
+pub(crate) fn seal(plaintext: &[u8]) -> Sealed {
+    let key = SECRET_KEY.with(|k| k.borrow().get().clone());
+    let count = NONCE_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment nonce counter");
+    let mut nonce = [0u8; NONCE_BYTES];
+    nonce[NONCE_BYTES - 8..].copy_from_slice(&count.to_be_bytes());
+    let mut ciphertext = plaintext.to_vec();
+    let tag = ChaCha20Poly1305::new(Key::from_slice(&key))
+        .encrypt_in_place_detached(Nonce::from_slice(&nonce), b"", &mut ciphertext)
+        .expect("cannot encrypt the value");
+    Sealed {
+        nonce: nonce.to_vec(),
+        ciphertext,
+        tag: tag.to_vec(),
+    }
+}
+
+// Decrypts a value, or returns None if it was tampered with
+pub(crate) fn open(sealed: &Sealed) -> Option<Vec<u8>> {
+    let key = SECRET_KEY.with(|k| k.borrow().get().clone());
+    if sealed.nonce.len() == LEGACY_NONCE_BYTES {
+        return open_legacy(&key, sealed);
+    }
+    if sealed.nonce.len() != NONCE_BYTES || sealed.tag.len() != TAG_BYTES {
+        return None;
+    }
+    let mut plaintext = sealed.ciphertext.clone();
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+        .decrypt_in_place_detached(
+            Nonce::from_slice(&sealed.nonce),
+            b"",
+            &mut plaintext,
+            Tag::from_slice(&sealed.tag),
+        )
+        .ok()?;
+    Some(plaintext)
+}
+
+// Opens a value sealed by an earlier version, checking its tag in constant time
+fn open_legacy(key: &[u8], sealed: &Sealed) -> Option<Vec<u8>> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(b"tag");
+    mac.update(&sealed.nonce);
+    mac.update(&sealed.ciphertext);
+    mac.verify_slice(&sealed.tag).ok()?;
+    Some(apply_keystream(key, &sealed.nonce, &sealed.ciphertext))
+}
+
+// XORs the data with HMAC-SHA256(key, "enc" | nonce | block index) blocks
+fn apply_keystream(key: &[u8], nonce: &[u8], data: &[u8]) -> Vec<u8> {
+    data.chunks(32)
+        .enumerate()
+        .flat_map(|(index, chunk)| {
+            let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+            mac.update(b"enc");
+            mac.update(nonce);
+            mac.update(&(index as u32).to_be_bytes());
+            let block = mac.finalize().into_bytes();
+            chunk.iter().zip(block).map(|(byte, k)| byte ^ k).collect::<Vec<u8>>()
+        })
+        .collect()
+}
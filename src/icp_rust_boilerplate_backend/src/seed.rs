@@ -0,0 +1,150 @@
+// Demo data for local and staging deployments. On a canister installed or upgraded with
+// `dev_mode` in its config, admins can fill it with realistic events organized by the caller,
+// attended by generated users who ask questions on them, so every screen has something to
+// show without clicking through the flows by hand. Events are built from fixed lists of
+// titles, places and tags, varied by a hash of the call time and their position, and have
+// no card image so no outcalls are made. Generated users are opaque principals nobody holds
+// keys for.
+use crate::admin::_check_admin;
+use crate::{attendees, config, metrics, questions, schedule, shard, tags};
+use crate::{Error, EventPayload, _add_attendee, _create_event};
+use crate::clock::time;
+use candid::Principal;
+use sha2::{Digest, Sha256};
+
+const MAX_SEED_EVENTS: u32 = 100;
+const MAX_SEED_USERS: u32 = 500;
+// Event records are bounded in size, so each event only gets a handful of attendees
+const MAX_ATTENDEES_PER_EVENT: usize = 8;
+const MAX_QUESTIONS_PER_EVENT: usize = 3;
+const HOUR_NANOS: u64 = 60 * 60 * 1_000_000_000;
+const DAY_NANOS: u64 = 24 * HOUR_NANOS;
+
+const TOPICS: &[(&str, &str, &[&str])] = &[
+    ("Rust Meetup", "Talks and pairing on Rust and WebAssembly.", &["tech", "rust"]),
+    ("Sunset Yoga", "An easy flow for all levels; bring a mat.", &["wellness", "outdoors"]),
+    ("Startup Pitch Night", "Founders pitch to a panel of local investors.", &["business"]),
+    ("Jazz in the Park", "A local quartet plays standards until dusk.", &["music", "outdoors"]),
+    ("Board Game Evening", "Dozens of games to borrow, beginners welcome.", &["games", "social"]),
+    ("Photography Walk", "A guided walk through the old town at golden hour.", &["art"]),
+    ("Web3 Builders Hackathon", "Build on the Internet Computer over a weekend.", &["tech"]),
+    ("Farmers Market Tour", "Meet the growers and taste what is in season.", &["food"]),
+    ("Charity 5K Run", "A flat course along the river; all proceeds donated.", &["sports"]),
+    ("Book Club", "This month we discuss a short novel; new readers welcome.", &["books"]),
+];
+
+const PLACES: &[&str] = &[
+    "Zurich",
+    "Lisbon",
+    "Berlin",
+    "Nairobi",
+    "Singapore",
+    "Austin",
+    "Buenos Aires",
+    "Tokyo",
+];
+
+const QUESTIONS: &[&str] = &[
+    "Is there parking nearby?",
+    "Can I bring a friend who isn't registered?",
+    "Will there be food and drinks?",
+    "Is the venue wheelchair accessible?",
+    "Will the talks be recorded?",
+    "What time do doors open?",
+];
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SeedSummary {
+    event_ids: Vec<u64>,
+    // Generated users, the same for a given count on every call
+    users: Vec<Principal>,
+    attendances: u64,
+    questions: u64,
+}
+
+// Update function for admins to fill a dev mode canister with demo events, attendees and
+// questions
+#[ic_cdk::update]
+fn seed_demo_data(n_events: u32, n_users: u32) -> Result<SeedSummary, Error> {
+    let _metrics = metrics::track("seed_demo_data");
+    _check_admin()?;
+    shard::_check_not_bucket()?;
+    if !config::is_dev_mode() {
+        return Err(Error::FeatureDisabled {
+            msg: "demo data can only be seeded on a canister installed in dev mode".to_string(),
+        });
+    }
+    if n_events == 0 || n_events > MAX_SEED_EVENTS || n_users > MAX_SEED_USERS {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "seed between 1 and {} events and at most {} users at a time",
+                MAX_SEED_EVENTS, MAX_SEED_USERS
+            ),
+        });
+    }
+    let users: Vec<Principal> = (0..n_users).map(demo_user).collect();
+    let mut summary = SeedSummary {
+        event_ids: Vec::new(),
+        users: users.clone(),
+        attendances: 0,
+        questions: 0,
+    };
+    for n in 0..n_events {
+        // Every call varies its events, so seeding twice doesn't look like one batch repeated
+        let seed = hash_of(&format!("{}|{}", time(), n));
+        let (title, description, topic_tags) = TOPICS[pick(&seed, 0, TOPICS.len())];
+        let place = PLACES[pick(&seed, 1, PLACES.len())];
+        let starts_at = time()
+            + (1 + pick(&seed, 2, 60) as u64) * DAY_NANOS
+            + (9 + pick(&seed, 3, 11) as u64) * HOUR_NANOS;
+        let mut payload = EventPayload {
+            event_title: format!("{} {}", place, title),
+            event_description: description.to_string(),
+            event_location: place.to_string(),
+            capacity: Some(10 + pick(&seed, 4, 90) as u32),
+            starts_at: Some(starts_at),
+            ends_at: Some(starts_at + (1 + pick(&seed, 5, 3) as u64) * HOUR_NANOS),
+            tags: Some(topic_tags.iter().map(|tag| tag.to_string()).collect()),
+            ..Default::default()
+        };
+        tags::normalize_tags(&mut payload)?;
+        let mut event = _create_event(payload);
+        summary.event_ids.push(event.id);
+
+        let attendees = pick(&seed, 6, MAX_ATTENDEES_PER_EVENT + 1).min(users.len());
+        let first = pick(&seed, 7, users.len().max(1));
+        for user in users.iter().cycle().skip(first).take(attendees) {
+            event = _add_attendee(*user, event.id)?;
+            schedule::record(&event, &user.to_string());
+            summary.attendances += 1;
+        }
+        let attendees = attendees::attendees_of(event.id);
+        let asked = pick(&seed, 8, MAX_QUESTIONS_PER_EVENT + 1).min(attendees.len());
+        for (i, author) in attendees.iter().take(asked).enumerate() {
+            let text = QUESTIONS[pick(&seed, 9 + i, QUESTIONS.len())].to_string();
+            questions::post_question(&event, author, text);
+            summary.questions += 1;
+        }
+    }
+    Ok(summary)
+}
+
+// The n-th demo user, the same on every call
+fn demo_user(n: u32) -> Principal {
+    let digest = hash_of(&format!("demo-user|{}", n));
+    // Ten bytes ending in the opaque id class keeps attendee lists short
+    let mut bytes = [0u8; 10];
+    bytes[..9].copy_from_slice(&digest[..9]);
+    bytes[9] = 0x01;
+    Principal::from_slice(&bytes)
+}
+
+fn hash_of(text: &str) -> [u8; 32] {
+    Sha256::digest(text).into()
+}
+
+// A number below `n` drawn from byte pair `slot` of the seed
+fn pick(seed: &[u8; 32], slot: usize, n: usize) -> usize {
+    let offset = (slot * 2) % 32;
+    u16::from_be_bytes([seed[offset], seed[offset + 1]]) as usize % n
+}
@@ -0,0 +1,464 @@
+// Sessions within an event, such as talks and workshops at a conference. The organizer adds
+// sessions with their own time, room and capacity; attendees of the event RSVP to the
+// sessions they want to join, and the organizer plans rooms from the headcounts. Sessions
+// are kept for events stored on this canister.
+//
+// Attendees also build a personal agenda: the sessions they RSVPed to plus any others they
+// add to it, such as talks that need no RSVP, in time order with overlaps flagged.
+use crate::{access, attendees, metrics, status};
+use crate::{Error, Event, IdCell, Memory, MEMORY_MANAGER, _get_event, _get_organized_event};
+use crate::clock::time;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_SESSIONS_PER_EVENT: usize = 100;
+const MAX_TITLE_CHARS: usize = 100;
+const MAX_ROOM_CHARS: usize = 64;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Session {
+    id: u64,
+    event_id: u64,
+    title: String,
+    room: Option<String>,
+    starts_at: u64,
+    ends_at: u64,
+    capacity: Option<u32>,
+    headcount: u32,
+    created_at: u64,
+}
+
+impl_storable!(Session, 512);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SessionPayload {
+    title: String,
+    room: Option<String>,
+    starts_at: u64,
+    ends_at: u64,
+    capacity: Option<u32>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct SessionKey {
+    event_id: u64,
+    id: u64,
+}
+
+impl_storable!(SessionKey, 32);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct SessionRsvpKey {
+    session_id: u64,
+    principal: String,
+}
+
+impl_storable!(SessionRsvpKey, 112);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SessionHeadcount {
+    session_id: u64,
+    title: String,
+    room: Option<String>,
+    starts_at: u64,
+    headcount: u32,
+    capacity: Option<u32>,
+    // Attendees of the session, for room assignments
+    attendees: Vec<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct AgendaKey {
+    event_id: u64,
+    principal: String,
+    session_id: u64,
+}
+
+impl_storable!(AgendaKey, 128);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AgendaItem {
+    session: Session,
+    rsvped: bool,
+    // Other sessions on the agenda that overlap this one
+    conflicts_with: Vec<u64>,
+}
+
+thread_local! {
+    static SESSION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76))), 0)
+            .expect("Cannot create a session counter")
+    );
+
+    static SESSIONS: RefCell<StableBTreeMap<SessionKey, Session, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77)))
+    ));
+
+    // Session RSVPs, with the time they were made
+    static SESSION_RSVPS: RefCell<StableBTreeMap<SessionRsvpKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78)))
+    ));
+
+    // Sessions added to personal agendas, with the time they were added
+    static AGENDAS: RefCell<StableBTreeMap<AgendaKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80)))
+    ));
+}
+
+// Update function for an event's organizer to add a session
+#[ic_cdk::update]
+fn add_session(event_id: u64, payload: SessionPayload) -> Result<Session, Error> {
+    let _metrics = metrics::track("add_session");
+    let event = _get_organized_event(event_id)?;
+    if sessions_of(event_id).len() >= MAX_SESSIONS_PER_EVENT {
+        return Err(Error::InvalidPayload {
+            msg: format!("an event can have at most {} sessions", MAX_SESSIONS_PER_EVENT),
+        });
+    }
+    let payload = validate_session_payload(&event, payload)?;
+    let id = SESSION_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment session id counter");
+    let session = Session {
+        id,
+        event_id,
+        title: payload.title,
+        room: payload.room,
+        starts_at: payload.starts_at,
+        ends_at: payload.ends_at,
+        capacity: payload.capacity,
+        headcount: 0,
+        created_at: time(),
+    };
+    do_insert_session(&session);
+    Ok(session)
+}
+
+// Update function for an event's organizer to change a session. The capacity can't drop
+// below the number of attendees who already RSVPed.
+#[ic_cdk::update]
+fn update_session(
+    event_id: u64,
+    session_id: u64,
+    payload: SessionPayload,
+) -> Result<Session, Error> {
+    let _metrics = metrics::track("update_session");
+    let event = _get_organized_event(event_id)?;
+    let mut session = _get_session(event_id, session_id)?;
+    let payload = validate_session_payload(&event, payload)?;
+    if payload.capacity.is_some_and(|capacity| capacity < session.headcount) {
+        return Err(Error::InvalidPayload {
+            msg: format!("{} attendees already RSVPed to this session", session.headcount),
+        });
+    }
+    session.title = payload.title;
+    session.room = payload.room;
+    session.starts_at = payload.starts_at;
+    session.ends_at = payload.ends_at;
+    session.capacity = payload.capacity;
+    do_insert_session(&session);
+    Ok(session)
+}
+
+// Update function for an event's organizer to remove a session and its RSVPs
+#[ic_cdk::update]
+fn delete_session(event_id: u64, session_id: u64) -> Result<Session, Error> {
+    let _metrics = metrics::track("delete_session");
+    _get_organized_event(event_id)?;
+    let session = _get_session(event_id, session_id)?;
+    SESSIONS.with(|s| {
+        s.borrow_mut().remove(&SessionKey {
+            event_id,
+            id: session_id,
+        })
+    });
+    let rsvps: Vec<SessionRsvpKey> = rsvps_of(session_id)
+        .into_iter()
+        .map(|principal| SessionRsvpKey {
+            session_id,
+            principal,
+        })
+        .collect();
+    SESSION_RSVPS.with(|r| {
+        let mut r = r.borrow_mut();
+        for key in rsvps {
+            r.remove(&key);
+        }
+    });
+    let start = AgendaKey {
+        event_id,
+        principal: String::new(),
+        session_id: 0,
+    };
+    let agenda_entries: Vec<AgendaKey> = AGENDAS.with(|a| {
+        a.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .filter(|(key, _)| key.session_id == session_id)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    AGENDAS.with(|a| {
+        let mut a = a.borrow_mut();
+        for key in agenda_entries {
+            a.remove(&key);
+        }
+    });
+    Ok(session)
+}
+
+// Query function listing an event's sessions by start time
+#[ic_cdk::query]
+fn get_sessions(event_id: u64) -> Result<Vec<Session>, Error> {
+    _get_event(&event_id)
+        .filter(|event| status::is_published(event.id) && access::can_view(event))
+        .ok_or(Error::NotFound {
+            msg: format!("Event with id={} not found", event_id),
+        })?;
+    Ok(sessions_of(event_id))
+}
+
+// Update function for an attendee of the event to RSVP to one of its sessions
+#[ic_cdk::update]
+fn rsvp_session(event_id: u64, session_id: u64) -> Result<Session, Error> {
+    let _metrics = metrics::track("rsvp_session");
+    _get_attended_event(event_id)?;
+    let mut session = _get_session(event_id, session_id)?;
+    let key = SessionRsvpKey {
+        session_id,
+        principal: caller().to_string(),
+    };
+    if SESSION_RSVPS.with(|r| r.borrow().contains_key(&key)) {
+        return Err(Error::InvalidPayload {
+            msg: "You already RSVPed to this session".to_string(),
+        });
+    }
+    if session.capacity.is_some_and(|capacity| session.headcount >= capacity) {
+        return Err(Error::InvalidPayload {
+            msg: format!("Session with id={} is full", session_id),
+        });
+    }
+    SESSION_RSVPS.with(|r| r.borrow_mut().insert(key, time()));
+    session.headcount += 1;
+    do_insert_session(&session);
+    Ok(session)
+}
+
+// Update function withdrawing the caller's RSVP to a session, freeing their place
+#[ic_cdk::update]
+fn cancel_session_rsvp(event_id: u64, session_id: u64) -> Result<Session, Error> {
+    let _metrics = metrics::track("cancel_session_rsvp");
+    let mut session = _get_session(event_id, session_id)?;
+    let key = SessionRsvpKey {
+        session_id,
+        principal: caller().to_string(),
+    };
+    if SESSION_RSVPS.with(|r| r.borrow_mut().remove(&key)).is_none() {
+        return Err(Error::NotFound {
+            msg: "You haven't RSVPed to this session".to_string(),
+        });
+    }
+    session.headcount = session.headcount.saturating_sub(1);
+    do_insert_session(&session);
+    Ok(session)
+}
+
+// Query function for an event's organizer to see who is coming to each session
+#[ic_cdk::query]
+fn get_session_headcounts(event_id: u64) -> Result<Vec<SessionHeadcount>, Error> {
+    _get_organized_event(event_id)?;
+    Ok(sessions_of(event_id)
+        .into_iter()
+        .map(|session| SessionHeadcount {
+            attendees: rsvps_of(session.id),
+            session_id: session.id,
+            title: session.title,
+            room: session.room,
+            starts_at: session.starts_at,
+            headcount: session.headcount,
+            capacity: session.capacity,
+        })
+        .collect())
+}
+
+// Update function for an attendee to add a session to their agenda without taking a place
+// in it
+#[ic_cdk::update]
+fn add_to_agenda(event_id: u64, session_id: u64) -> Result<Session, Error> {
+    let _metrics = metrics::track("add_to_agenda");
+    _get_attended_event(event_id)?;
+    let session = _get_session(event_id, session_id)?;
+    let key = AgendaKey {
+        event_id,
+        principal: caller().to_string(),
+        session_id,
+    };
+    AGENDAS.with(|a| {
+        let mut a = a.borrow_mut();
+        if !a.contains_key(&key) {
+            a.insert(key, time());
+        }
+    });
+    Ok(session)
+}
+
+// Update function for an attendee to take a session off their agenda. Sessions they RSVPed
+// to stay on it until the RSVP is cancelled.
+#[ic_cdk::update]
+fn remove_from_agenda(event_id: u64, session_id: u64) -> Result<Session, Error> {
+    let _metrics = metrics::track("remove_from_agenda");
+    let session = _get_session(event_id, session_id)?;
+    let key = AgendaKey {
+        event_id,
+        principal: caller().to_string(),
+        session_id,
+    };
+    if AGENDAS.with(|a| a.borrow_mut().remove(&key)).is_none() {
+        return Err(Error::NotFound {
+            msg: "This session is not on your agenda".to_string(),
+        });
+    }
+    Ok(session)
+}
+
+// Query function returning the caller's agenda for an event in time order, with
+// overlapping sessions flagged
+#[ic_cdk::query]
+fn my_agenda(event_id: u64) -> Vec<AgendaItem> {
+    let principal = caller().to_string();
+    let chosen: Vec<(Session, bool)> = sessions_of(event_id)
+        .into_iter()
+        .filter_map(|session| {
+            let rsvped = has_rsvp(session.id, &principal);
+            let key = AgendaKey {
+                event_id,
+                principal: principal.clone(),
+                session_id: session.id,
+            };
+            let added = AGENDAS.with(|a| a.borrow().contains_key(&key));
+            (rsvped || added).then_some((session, rsvped))
+        })
+        .collect();
+    chosen
+        .iter()
+        .map(|(session, rsvped)| AgendaItem {
+            conflicts_with: chosen
+                .iter()
+                .filter(|(other, _)| {
+                    other.id != session.id
+                        && other.starts_at < session.ends_at
+                        && session.starts_at < other.ends_at
+                })
+                .map(|(other, _)| other.id)
+                .collect(),
+            session: session.clone(),
+            rsvped: *rsvped,
+        })
+        .collect()
+}
+
+fn sessions_of(event_id: u64) -> Vec<Session> {
+    let start = SessionKey { event_id, id: 0 };
+    let mut sessions: Vec<Session> = SESSIONS.with(|s| {
+        s.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(_, session)| session)
+            .collect()
+    });
+    sessions.sort_by_key(|session| (session.starts_at, session.id));
+    sessions
+}
+
+fn _get_session(event_id: u64, session_id: u64) -> Result<Session, Error> {
+    let key = SessionKey {
+        event_id,
+        id: session_id,
+    };
+    SESSIONS.with(|s| s.borrow().get(&key)).ok_or(Error::NotFound {
+        msg: format!("Session with id={} not found", session_id),
+    })
+}
+
+// Helper function returning an event open for registration that the caller attends
+fn _get_attended_event(event_id: u64) -> Result<Event, Error> {
+    let event = _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    status::_check_open_for_registration(event_id)?;
+    if !attendees::is_attending(event_id, &caller().to_string()) {
+        return Err(Error::NotAuthorized {
+            msg: format!("Only attendees can join sessions of the event with id={}", event_id),
+            caller: caller(),
+        });
+    }
+    Ok(event)
+}
+
+fn validate_session_payload(
+    event: &Event,
+    mut payload: SessionPayload,
+) -> Result<SessionPayload, Error> {
+    let invalid = |msg: String| Err(Error::InvalidPayload { msg });
+    payload.title = payload.title.trim().to_string();
+    if payload.title.is_empty() || payload.title.chars().count() > MAX_TITLE_CHARS {
+        return invalid(format!(
+            "a session title must be between 1 and {} characters",
+            MAX_TITLE_CHARS
+        ));
+    }
+    payload.room = payload
+        .room
+        .map(|room| room.trim().to_string())
+        .filter(|room| !room.is_empty());
+    if payload.room.as_ref().is_some_and(|room| room.chars().count() > MAX_ROOM_CHARS) {
+        return invalid(format!("a room name can have at most {} characters", MAX_ROOM_CHARS));
+    }
+    if payload.ends_at <= payload.starts_at {
+        return invalid("a session must end after it starts".to_string());
+    }
+    let before_event = event.starts_at.is_some_and(|starts_at| payload.starts_at < starts_at);
+    let after_event = event.ends_at.is_some_and(|ends_at| payload.ends_at > ends_at);
+    if before_event || after_event {
+        return invalid("a session must take place during its event".to_string());
+    }
+    Ok(payload)
+}
+
+fn has_rsvp(session_id: u64, principal: &str) -> bool {
+    let key = SessionRsvpKey {
+        session_id,
+        principal: principal.to_string(),
+    };
+    SESSION_RSVPS.with(|r| r.borrow().contains_key(&key))
+}
+
+fn rsvps_of(session_id: u64) -> Vec<String> {
+    let start = SessionRsvpKey {
+        session_id,
+        principal: String::new(),
+    };
+    SESSION_RSVPS.with(|r| {
+        r.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.session_id == session_id)
+            .map(|(key, _)| key.principal)
+            .collect()
+    })
+}
+
+fn do_insert_session(session: &Session) {
+    let key = SessionKey {
+        event_id: session.event_id,
+        id: session.id,
+    };
+    SESSIONS.with(|s| s.borrow_mut().insert(key, session.clone()));
+}
@@ -0,0 +1,510 @@
+// Sharding layer: once this canister's stable memory nears its limit, new events are
+// written to bucket canisters spawned from an uploaded wasm module. This canister keeps
+// allocating ids and acts as the router, remembering which bucket holds each spilled
+// event so that get/update/attend/delete keep their public signatures.
+//
+// Buckets run this same wasm, installed with `InitArgs { router }`, and only accept the
+// `bucket_*` methods below from their router: they turn away ingress update calls and never
+// allocate event ids themselves. Side tables kept by other modules
+// (templates, ...) only cover events stored locally; ticket calls are forwarded too.
+//
+// In factory mode an organizer whose plan includes it can ask `create_event` for a dedicated
+// child canister: it is spawned the same way as a bucket but holds only that one event, so a
+// very large conference gets its own attendee and ticket storage. Canisters are spawned one
+// at a time, and one whose install failed is kept and reused by the next spawn.
+use crate::admin::{self, _check_admin};
+use crate::{attendees, images, metrics};
+use crate::status::{EventStatus, _cancel_event, _get_event_status};
+use crate::attendees::AttendeePage;
+use crate::tickets::{CheckIn, Ticket, TicketValidation, _check_in, _get_ticket, _reissue_ticket};
+use crate::tickets::_validate_ticket;
+use crate::{
+    Error, Event, EventPayload, InitArgs, Memory, MEMORY_MANAGER, _attend_event, _call_failed,
+    _delete_event, _get_event, _update_event, do_insert,
+};
+use crate::clock::time;
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, Encode, Principal};
+use serde::de::DeserializeOwned;
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+use ic_cdk::api::stable::stable64_size;
+use ic_cdk::api::id;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+
+// Stable memory is capped at 400 GiB; spill well before that
+const DEFAULT_SOFT_LIMIT_BYTES: u64 = 350 * 1024 * 1024 * 1024;
+const DEFAULT_CYCLES_PER_BUCKET: u128 = 2_000_000_000_000;
+const DEFAULT_MAX_EVENTS_PER_BUCKET: u64 = 1_000_000;
+const WASM_PAGE_SIZE_BYTES: u64 = 64 * 1024;
+
+// Tunables controlling when and how buckets are spawned
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ShardConfig {
+    soft_limit_bytes: u64,
+    cycles_per_bucket: u128,
+    max_events_per_bucket: u64,
+    // Whether organizers may request a dedicated child canister per event
+    dedicated_canisters_enabled: bool,
+}
+
+impl Default for ShardConfig {
+    fn default() -> Self {
+        ShardConfig {
+            soft_limit_bytes: DEFAULT_SOFT_LIMIT_BYTES,
+            cycles_per_bucket: DEFAULT_CYCLES_PER_BUCKET,
+            max_events_per_bucket: DEFAULT_MAX_EVENTS_PER_BUCKET,
+            dedicated_canisters_enabled: false,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ShardState {
+    // Only set on bucket canisters: the router allowed to call the bucket_* methods
+    router: Option<Principal>,
+    config: ShardConfig,
+    // A funded canister whose code install failed, reused by the next spawn
+    spare_canister: Option<Principal>,
+}
+
+impl_storable!(ShardState, 256);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BucketInfo {
+    canister_id: Principal,
+    created_at: u64,
+    event_count: u64,
+    // Set for child canisters spawned in factory mode for a single event
+    dedicated_event: Option<u64>,
+}
+
+impl_storable!(BucketInfo, 160);
+
+// Overview of the sharding layer for operators
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ShardStatus {
+    stable_memory_bytes: u64,
+    config: ShardConfig,
+    bucket_wasm_size: u64,
+    buckets: Vec<BucketInfo>,
+    spilled_events: u64,
+}
+
+thread_local! {
+    static SHARD_STATE: RefCell<Cell<ShardState, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))), ShardState::default())
+            .expect("Cannot create the shard state cell")
+    );
+
+    static BUCKET_WASM: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))), Vec::new())
+            .expect("Cannot create the bucket wasm cell")
+    );
+
+    // Bucket index -> bucket canister
+    static BUCKETS: RefCell<StableBTreeMap<u64, BucketInfo, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    // Event id -> bucket index, only for events stored outside this canister
+    static EVENT_BUCKETS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    // Prevents concurrent create_event calls from spawning several buckets at once
+    static SPAWNING_BUCKET: RefCell<bool> = const { RefCell::new(false) };
+}
+
+// Update function for admins to upload the wasm module new buckets are installed with
+#[ic_cdk::update]
+fn upload_bucket_wasm(wasm_module: Vec<u8>) -> Result<(), Error> {
+    let _metrics = metrics::track("upload_bucket_wasm");
+    _check_admin()?;
+    BUCKET_WASM.with(|w| w.borrow_mut().set(wasm_module))
+        .expect("cannot store the bucket wasm");
+    Ok(())
+}
+
+// Update function for admins to tune the sharding thresholds
+#[ic_cdk::update]
+fn set_shard_config(config: ShardConfig) -> Result<ShardConfig, Error> {
+    let _metrics = metrics::track("set_shard_config");
+    _check_admin()?;
+    if config.max_events_per_bucket == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "max_events_per_bucket must be greater than zero".to_string(),
+        });
+    }
+    update_state(|state| state.config = config.clone());
+    Ok(config)
+}
+
+// Query function describing memory usage, buckets and the number of spilled events
+#[ic_cdk::query]
+fn get_shard_status() -> ShardStatus {
+    ShardStatus {
+        stable_memory_bytes: stable_memory_bytes(),
+        config: SHARD_STATE.with(|s| s.borrow().get().config.clone()),
+        bucket_wasm_size: BUCKET_WASM.with(|w| w.borrow().get().len() as u64),
+        buckets: BUCKETS.with(|b| b.borrow().iter().map(|(_, bucket)| bucket).collect()),
+        spilled_events: EVENT_BUCKETS.with(|e| e.borrow().len()),
+    }
+}
+
+// Query function returning the canister holding an event's data when it is not stored here
+#[ic_cdk::query]
+fn get_event_canister(event_id: u64) -> Option<Principal> {
+    bucket_of(event_id)
+}
+
+// Bucket-side: store an event whose id was allocated by the router
+#[ic_cdk::update]
+fn bucket_put_event(event: Event) -> Result<(), Error> {
+    let _metrics = metrics::track("bucket_put_event");
+    _check_router()?;
+    do_insert(&event);
+    images::verify_image(&event);
+    Ok(())
+}
+
+// Bucket-side: read an event on behalf of the router
+#[ic_cdk::query]
+fn bucket_get_event(id: u64) -> Result<Event, Error> {
+    _check_router()?;
+    _get_event(&id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", id),
+    })
+}
+
+// Bucket-side: list an event's attendees on behalf of the router
+#[ic_cdk::query]
+fn bucket_get_attendees(event_id: u64, offset: Option<u64>) -> Result<AttendeePage, Error> {
+    _check_router()?;
+    Ok(attendees::_get_attendees(event_id, offset))
+}
+
+// Bucket-side: update an event on behalf of the principal that called the router
+#[ic_cdk::update]
+fn bucket_update_event(principal: Principal, id: u64, payload: EventPayload) -> Result<Event, Error> {
+    let _metrics = metrics::track("bucket_update_event");
+    _check_router()?;
+    _update_event(principal, id, payload)
+}
+
+// Bucket-side: register the principal that called the router as an attendee
+#[ic_cdk::update]
+fn bucket_attend_event(principal: Principal, id: u64) -> Result<Event, Error> {
+    let _metrics = metrics::track("bucket_attend_event");
+    _check_router()?;
+    _attend_event(principal, id)
+}
+
+// Bucket-side: delete an event on behalf of the principal that called the router
+#[ic_cdk::update]
+fn bucket_delete_event(principal: Principal, id: u64) -> Result<Event, Error> {
+    let _metrics = metrics::track("bucket_delete_event");
+    _check_router()?;
+    let event = _delete_event(principal, id)?;
+    attendees::forget(id);
+    Ok(event)
+}
+
+// Bucket-side: cancel an event on behalf of the principal that called the router
+#[ic_cdk::update]
+fn bucket_cancel_event(principal: Principal, id: u64, reason: String) -> Result<Event, Error> {
+    let _metrics = metrics::track("bucket_cancel_event");
+    _check_router()?;
+    _cancel_event(principal, id, reason)
+}
+
+// Bucket-side: take an event down for an admin of the router, which keeps the audit record
+#[ic_cdk::update]
+fn bucket_takedown(id: u64, reason: String) -> Result<Event, Error> {
+    let _metrics = metrics::track("bucket_takedown");
+    _check_router()?;
+    let event =
+        admin::_get_takedown_target(id, &reason).map_err(|msg| Error::InvalidPayload { msg })?;
+    admin::take_down(&event, &reason);
+    Ok(event)
+}
+
+// Bucket-side: read an event's lifecycle status on behalf of the router
+#[ic_cdk::query]
+fn bucket_get_event_status(id: u64) -> Result<EventStatus, Error> {
+    _check_router()?;
+    _get_event_status(id)
+}
+
+// Bucket-side: return the ticket of the principal that called the router
+#[ic_cdk::update]
+async fn bucket_get_my_ticket(principal: Principal, event_id: u64) -> Result<Ticket, Error> {
+    let _metrics = metrics::track("bucket_get_my_ticket");
+    _check_router()?;
+    _get_ticket(principal, event_id).await
+}
+
+// Bucket-side: reissue the ticket of the principal that called the router
+#[ic_cdk::update]
+async fn bucket_reissue_my_ticket(principal: Principal, event_id: u64) -> Result<Ticket, Error> {
+    let _metrics = metrics::track("bucket_reissue_my_ticket");
+    _check_router()?;
+    _reissue_ticket(principal, event_id).await
+}
+
+// Bucket-side: check in a ticket scanned by the principal that called the router
+#[ic_cdk::update]
+fn bucket_check_in(principal: Principal, event_id: u64, token: String) -> Result<CheckIn, Error> {
+    let _metrics = metrics::track("bucket_check_in");
+    _check_router()?;
+    _check_in(principal, event_id, token)
+}
+
+// Bucket-side: validate a ticket scanned by the principal that called the router
+#[ic_cdk::query]
+fn bucket_validate_ticket(
+    principal: Principal,
+    event_id: u64,
+    token: String,
+) -> Result<TicketValidation, Error> {
+    _check_router()?;
+    _validate_ticket(principal, event_id, token)
+}
+
+// Records the router principal when this canister is installed as a bucket
+pub(crate) fn set_router(router: Principal) {
+    update_state(|state| state.router = Some(router));
+}
+
+// Returns the bucket canister holding the event, if it was spilled
+pub(crate) fn bucket_of(event_id: u64) -> Option<Principal> {
+    let index = EVENT_BUCKETS.with(|e| e.borrow().get(&event_id))?;
+    BUCKETS.with(|b| b.borrow().get(&index)).map(|bucket| bucket.canister_id)
+}
+
+// Whether new events should be written to a bucket instead of local storage
+fn should_spill() -> bool {
+    let has_wasm = BUCKET_WASM.with(|w| !w.borrow().get().is_empty());
+    let soft_limit = SHARD_STATE.with(|s| s.borrow().get().config.soft_limit_bytes);
+    has_wasm && stable_memory_bytes() >= soft_limit
+}
+
+// Helper function refusing to store events in bulk on this canister once new events are
+// written to buckets, so imports can't keep filling it
+pub(crate) fn _check_room_for_local_events() -> Result<(), Error> {
+    if should_spill() {
+        return Err(Error::InvalidPayload {
+            msg: "This canister is near its storage limit; create events one at a time instead"
+                .to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Writes a freshly built event to a dedicated child canister when requested, or to a
+// bucket when local stable memory nears its limit. Returns None when the event should
+// be stored locally, and an error when it should go elsewhere but couldn't, rather than
+// filling this canister regardless.
+pub(crate) async fn place_remotely(
+    event: Event,
+    dedicated_canister: bool,
+) -> Result<Option<Event>, Error> {
+    if dedicated_canister {
+        return create_in_dedicated_canister(event).await.map(Some);
+    }
+    if should_spill() {
+        return create_in_bucket(event).await.map(Some);
+    }
+    Ok(None)
+}
+
+// Writes an event to the active bucket, spawning one when needed
+async fn create_in_bucket(event: Event) -> Result<Event, Error> {
+    let index = match active_bucket() {
+        Some(index) => index,
+        None => spawn_bucket(None).await?,
+    };
+    place_event(index, event).await
+}
+
+// Spawns a child canister dedicated to a single event and writes the event to it
+async fn create_in_dedicated_canister(event: Event) -> Result<Event, Error> {
+    if !SHARD_STATE.with(|s| s.borrow().get().config.dedicated_canisters_enabled) {
+        return Err(Error::InvalidPayload {
+            msg: "Dedicated canisters are not enabled".to_string(),
+        });
+    }
+    let index = spawn_bucket(Some(event.id)).await?;
+    place_event(index, event).await
+}
+
+// Calls a bucket_* method on the canister holding an event and unwraps its result
+pub(crate) async fn forward<A, R>(bucket: Principal, method: &str, args: A) -> Result<R, Error>
+where
+    A: ArgumentEncoder,
+    R: CandidType + DeserializeOwned,
+{
+    let (result,): (Result<R, Error>,) = ic_cdk::call(bucket, method, args)
+        .await
+        .map_err(|err| _call_failed(method, err))?;
+    result
+}
+
+pub(crate) async fn forward_delete_event(bucket: Principal, id: u64) -> Result<Event, Error> {
+    let result = forward(bucket, "bucket_delete_event", (caller(), id)).await;
+    if result.is_ok()
+        && let Some(index) = EVENT_BUCKETS.with(|e| e.borrow_mut().remove(&id))
+    {
+        adjust_event_count(index, -1);
+    }
+    result
+}
+
+// Helper function to store an event on a registered bucket and remember where it lives
+async fn place_event(index: u64, event: Event) -> Result<Event, Error> {
+    let bucket = BUCKETS
+        .with(|b| b.borrow().get(&index))
+        .ok_or(Error::NotFound {
+            msg: format!("Bucket {} not found", index),
+        })?;
+    forward::<_, ()>(bucket.canister_id, "bucket_put_event", (event.clone(),)).await?;
+    EVENT_BUCKETS.with(|e| e.borrow_mut().insert(event.id, index));
+    adjust_event_count(index, 1);
+    Ok(event)
+}
+
+// Helper function to find the newest bucket that still has room
+fn active_bucket() -> Option<u64> {
+    let max_events = SHARD_STATE.with(|s| s.borrow().get().config.max_events_per_bucket);
+    BUCKETS.with(|b| {
+        b.borrow()
+            .iter()
+            .filter(|(_, bucket)| bucket.dedicated_event.is_none() && bucket.event_count < max_events)
+            .map(|(index, _)| index)
+            .last()
+    })
+}
+
+// Helper function to create and install a new bucket canister controlled by this router
+async fn spawn_bucket(dedicated_event: Option<u64>) -> Result<u64, Error> {
+    // Buckets and dedicated canisters alike are spawned one at a time
+    if SPAWNING_BUCKET.with(|s| s.replace(true)) {
+        return Err(Error::CallFailed {
+            msg: "Another canister is being created; try again shortly".to_string(),
+        });
+    }
+    let canister_id = install_bucket().await;
+    SPAWNING_BUCKET.with(|s| *s.borrow_mut() = false);
+
+    let index = BUCKETS.with(|b| b.borrow().len());
+    let bucket = BucketInfo {
+        canister_id: canister_id?,
+        created_at: time(),
+        event_count: 0,
+        dedicated_event,
+    };
+    BUCKETS.with(|b| b.borrow_mut().insert(index, bucket));
+    Ok(index)
+}
+
+async fn install_bucket() -> Result<Principal, Error> {
+    let wasm_module = BUCKET_WASM.with(|w| w.borrow().get().clone());
+    let cycles = SHARD_STATE.with(|s| s.borrow().get().config.cycles_per_bucket);
+
+    let spare = SHARD_STATE.with(|s| s.borrow().get().spare_canister);
+    let canister_id = match spare {
+        Some(canister_id) => {
+            update_state(|state| state.spare_canister = None);
+            canister_id
+        }
+        None => {
+            let settings = CanisterSettings {
+                controllers: Some(vec![id()]),
+                ..Default::default()
+            };
+            let (record,) =
+                create_canister(CreateCanisterArgument { settings: Some(settings) }, cycles)
+                    .await
+                    .map_err(|err| _call_failed("create_canister", err))?;
+            record.canister_id
+        }
+    };
+
+    let args = InitArgs {
+        router: Some(id()),
+        config: None,
+    };
+    let arg = Encode!(&Some(args)).expect("cannot encode bucket init args");
+    let installed = install_code(InstallCodeArgument {
+        mode: CanisterInstallMode::Install,
+        canister_id,
+        wasm_module,
+        arg,
+    })
+    .await;
+    if let Err(err) = installed {
+        // Keep the funded canister for the next spawn rather than leaking it
+        update_state(|state| state.spare_canister = Some(canister_id));
+        return Err(_call_failed("install_code", err));
+    }
+
+    Ok(canister_id)
+}
+
+fn adjust_event_count(index: u64, delta: i64) {
+    BUCKETS.with(|b| {
+        let mut buckets = b.borrow_mut();
+        if let Some(mut bucket) = buckets.get(&index) {
+            bucket.event_count = bucket.event_count.saturating_add_signed(delta);
+            buckets.insert(index, bucket);
+        }
+    });
+}
+
+fn update_state(f: impl FnOnce(&mut ShardState)) {
+    SHARD_STATE.with(|s| {
+        let mut state = s.borrow().get().clone();
+        f(&mut state);
+        s.borrow_mut().set(state).expect("cannot update the shard state");
+    });
+}
+
+fn stable_memory_bytes() -> u64 {
+    stable64_size() * WASM_PAGE_SIZE_BYTES
+}
+
+// Whether this canister was installed as a bucket of a router
+pub(crate) fn is_bucket() -> bool {
+    SHARD_STATE.with(|s| s.borrow().get().router.is_some())
+}
+
+// Helper function keeping a bucket from allocating event ids of its own, which would collide
+// with the ids its router allocates
+pub(crate) fn _check_not_bucket() -> Result<(), Error> {
+    if is_bucket() {
+        return Err(Error::NotAuthorized {
+            msg: "This canister is a bucket; create events through its router".to_string(),
+            caller: caller(),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to restrict bucket-side endpoints to the router that spawned this canister
+pub(crate) fn _check_router() -> Result<(), Error> {
+    let router = SHARD_STATE.with(|s| s.borrow().get().router);
+    if router != Some(caller()) {
+        return Err(Error::NotAuthorized {
+            msg: "Only the router canister can call bucket methods".to_string(),
+            caller: caller(),
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+// Human-readable event slugs. Each new event gets a URL-safe slug derived from its title
+// ("Rust Meetup #4" becomes "rust-meetup-4", then "rust-meetup-4-2" for the next one) that
+// stays the same when the title changes, so shared links keep working.
+use crate::{access, status, verification};
+use crate::{Error, Event, Memory, MEMORY_MANAGER, _fetch_event};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_SLUG_CHARS: usize = 60;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct SlugKey {
+    slug: String,
+}
+
+impl_storable!(SlugKey, 96);
+
+thread_local! {
+    static SLUGS: RefCell<StableBTreeMap<SlugKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+}
+
+// Query function to retrieve an event by its slug
+#[ic_cdk::query(composite = true)]
+async fn get_event_by_slug(slug: String) -> Result<Event, Error> {
+    let id = event_id_of(&slug).ok_or(Error::NotFound {
+        msg: format!("Event with slug \"{}\" not found", slug),
+    })?;
+    let event = _fetch_event(id).await?;
+    status::_check_visible(&event)?;
+    Ok(verification::with_badge(access::redact(event)))
+}
+
+// Reserves a unique slug for a new event, adding a numeric suffix on collisions
+pub(crate) fn claim(title: &str, event_id: u64) -> String {
+    let base = slugify(title);
+    let mut slug = base.clone();
+    let mut suffix = 2;
+    while event_id_of(&slug).is_some() {
+        slug = format!("{}-{}", base, suffix);
+        suffix += 1;
+    }
+    SLUGS.with(|s| s.borrow_mut().insert(SlugKey { slug: slug.clone() }, event_id));
+    slug
+}
+
+// Frees the slug of a removed event
+pub(crate) fn release(event: &Event) {
+    if let Some(slug) = &event.slug {
+        SLUGS.with(|s| s.borrow_mut().remove(&SlugKey { slug: slug.clone() }));
+    }
+}
+
+// Indexes an event under the slug it already has, unless another event took it since
+pub(crate) fn restore(event: &Event) {
+    if let Some(slug) = &event.slug {
+        let key = SlugKey { slug: slug.clone() };
+        SLUGS.with(|s| {
+            let mut s = s.borrow_mut();
+            if !s.contains_key(&key) {
+                s.insert(key, event.id);
+            }
+        });
+    }
+}
+
+// Every claimed slug with the event it points to
+pub(crate) fn slugged_events() -> Vec<(String, u64)> {
+    SLUGS.with(|s| s.borrow().iter().map(|(key, id)| (key.slug, id)).collect())
+}
+
+// Drops the slugs of every event but those kept, ahead of a rebuild
+pub(crate) fn clear_index(keep: impl Fn(u64) -> bool) {
+    SLUGS.with(|s| {
+        let mut s = s.borrow_mut();
+        let dropped: Vec<SlugKey> = s
+            .iter()
+            .filter(|(_, id)| !keep(*id))
+            .map(|(key, _)| key)
+            .collect();
+        for key in dropped {
+            s.remove(&key);
+        }
+    });
+}
+
+pub(crate) fn event_id_of(slug: &str) -> Option<u64> {
+    SLUGS.with(|s| {
+        s.borrow().get(&SlugKey {
+            slug: slug.to_lowercase(),
+        })
+    })
+}
+
+// Lowercase ASCII letters and digits separated by single hyphens. Purely numeric slugs are
+// prefixed so they never look like an event id.
+fn slugify(title: &str) -> String {
+    let words: Vec<String> = title
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect();
+    let mut slug = String::new();
+    for word in words {
+        if slug.is_empty() {
+            slug.push_str(&word[..word.len().min(MAX_SLUG_CHARS)]);
+            continue;
+        }
+        if slug.len() + word.len() + 1 > MAX_SLUG_CHARS {
+            break;
+        }
+        slug.push('-');
+        slug.push_str(&word);
+    }
+    if slug.is_empty() {
+        return "event".to_string();
+    }
+    if slug.chars().all(|c| c.is_ascii_digit()) {
+        return format!("event-{}", slug);
+    }
+    slug
+}
@@ -0,0 +1,343 @@
+// SMS reminders. Principals opt in with a phone number, and a timer texts them shortly before
+// the events they attend start, through an admin-configured HTTP provider that accepts
+// Twilio-style form posts (`To`, `From`, `Body`).
+//
+// Phone numbers are stored encrypted (see the secrets module). Every replica sends the same
+// outcall, so each message carries an idempotency key for the provider to drop the copies.
+// A reminder is marked before it is sent so the next timer run never repeats one still in
+// flight; failed reminders are retried a few times with the same key. Reminders cover
+// events stored on this canister.
+use crate::admin::_check_admin;
+use crate::secrets::{self, Sealed};
+use crate::status;
+use crate::{attendees, metrics, Error, EventPrincipalKey, Memory, PrincipalKey, MEMORY_MANAGER};
+use crate::STORAGE;
+use crate::clock::time;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell as StableCell, StableBTreeMap};
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+const REMINDER_INTERVAL: Duration = Duration::from_secs(300);
+const REMINDER_BATCH_SIZE: usize = 20;
+const SMS_CYCLES: u128 = 2_000_000_000;
+const SMS_MAX_RESPONSE_BYTES: u64 = 8192;
+const MAX_ATTEMPTS: u32 = 3;
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+const DEFAULT_LEAD_TIME_MINS: u32 = 24 * 60;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SmsProviderConfig {
+    // Endpoint messages are posted to, e.g. a Twilio Messages URL; empty disables SMS
+    url: String,
+    // Value of the Authorization header, e.g. "Basic <base64 of sid:token>"
+    authorization: String,
+    // Sender number or id
+    from: String,
+    // How long before an event starts its reminder goes out
+    lead_time_mins: u32,
+}
+
+impl_storable!(SmsProviderConfig, 2048);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EncryptedPhone {
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+    tag: Vec<u8>,
+    opted_in_at: u64,
+}
+
+impl_storable!(EncryptedPhone, 256);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum DeliveryStatus {
+    // Marked before the outcall is made
+    Sending,
+    Sent,
+    Failed { reason: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SmsDelivery {
+    status: DeliveryStatus,
+    attempts: u32,
+    updated_at: u64,
+}
+
+impl_storable!(SmsDelivery, 512);
+
+thread_local! {
+    static SMS_CONFIG: RefCell<StableCell<SmsProviderConfig, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64))), SmsProviderConfig::default())
+            .expect("Cannot create the SMS config cell")
+    );
+
+    static PHONE_NUMBERS: RefCell<StableBTreeMap<PrincipalKey, EncryptedPhone, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66)))
+    ));
+
+    // Reminders sent or attempted, by event and attendee
+    static DELIVERIES: RefCell<StableBTreeMap<EventPrincipalKey, SmsDelivery, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67)))
+    ));
+
+    // Guards against two timer runs sending the same reminders concurrently
+    static SENDING_REMINDERS: Cell<bool> = const { Cell::new(false) };
+}
+
+// Starts the periodic reminder run; called from init and post_upgrade
+pub(crate) fn start_reminder_sender() {
+    ic_cdk_timers::set_timer_interval(REMINDER_INTERVAL, || ic_cdk::spawn(send_due_reminders()));
+}
+
+// Query function for admins returning the SMS provider settings, without the credentials
+#[ic_cdk::query]
+fn get_sms_provider() -> Result<SmsProviderConfig, Error> {
+    _check_admin()?;
+    let mut config = SMS_CONFIG.with(|c| c.borrow().get().clone());
+    if !config.authorization.is_empty() {
+        config.authorization = "(set)".to_string();
+    }
+    Ok(config)
+}
+
+// Update function for admins to configure the SMS provider; an empty URL turns SMS off
+#[ic_cdk::update]
+fn set_sms_provider(mut config: SmsProviderConfig) -> Result<(), Error> {
+    let _metrics = metrics::track("set_sms_provider");
+    _check_admin()?;
+    if !config.url.is_empty() && !config.url.starts_with("https://") {
+        return Err(Error::InvalidPayload {
+            msg: "the SMS provider URL must use https".to_string(),
+        });
+    }
+    if config.lead_time_mins == 0 {
+        config.lead_time_mins = DEFAULT_LEAD_TIME_MINS;
+    }
+    SMS_CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(config)
+            .expect("cannot update the SMS config")
+    });
+    Ok(())
+}
+
+// Update function for the caller to receive SMS reminders at a phone number in E.164
+// format (+ followed by the country code and number)
+#[ic_cdk::update]
+async fn opt_in_sms(phone: String) -> Result<String, Error> {
+    let _metrics = metrics::track("opt_in_sms");
+    let phone: String = phone.chars().filter(|c| !c.is_whitespace() && *c != '-').collect();
+    let digits = phone.strip_prefix('+').unwrap_or_default();
+    if !(8..=15).contains(&digits.len()) || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(Error::InvalidPayload {
+            msg: "phone numbers must be in E.164 format, e.g. +14155550100".to_string(),
+        });
+    }
+    secrets::ensure_key().await?;
+    let principal = caller().to_string();
+    let encrypted = encrypt(&phone);
+    PHONE_NUMBERS.with(|p| p.borrow_mut().insert(PrincipalKey { principal }, encrypted));
+    Ok(mask(&phone))
+}
+
+// Update function for the caller to stop SMS reminders and delete their phone number
+#[ic_cdk::update]
+fn opt_out_sms() -> bool {
+    let _metrics = metrics::track("opt_out_sms");
+    let key = PrincipalKey {
+        principal: caller().to_string(),
+    };
+    PHONE_NUMBERS.with(|p| p.borrow_mut().remove(&key)).is_some()
+}
+
+// Query function returning the caller's phone number with all but the last digits masked,
+// if they opted in
+#[ic_cdk::query]
+fn get_my_sms_number() -> Option<String> {
+    phone_of(&caller().to_string()).map(|phone| mask(&phone))
+}
+
+// Keeps only the status, so replicas agree on the outcall result
+#[ic_cdk::query]
+fn transform_sms_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+async fn send_due_reminders() {
+    let config = SMS_CONFIG.with(|c| c.borrow().get().clone());
+    if config.url.is_empty() || SENDING_REMINDERS.with(|s| s.replace(true)) {
+        return;
+    }
+    let now = time();
+    let horizon = now + config.lead_time_mins as u64 * NANOS_PER_MINUTE;
+    let mut due: Vec<(EventPrincipalKey, String, String)> = Vec::new();
+    STORAGE.with(|s| {
+        for (_, event) in s.borrow().iter() {
+            if !event.starts_at.is_some_and(|starts_at| starts_at > now && starts_at <= horizon)
+                || !status::is_published(event.id)
+            {
+                continue;
+            }
+            for attendee in attendees::attendees_of(event.id) {
+                let key = EventPrincipalKey {
+                    event_id: event.id,
+                    principal: attendee.clone(),
+                };
+                let Some(phone) = phone_of(&attendee) else {
+                    continue;
+                };
+                if !needs_reminder(&key) {
+                    continue;
+                }
+                due.push((key, phone, reminder_text(&event.event_title, event.starts_at)));
+                if due.len() >= REMINDER_BATCH_SIZE {
+                    return;
+                }
+            }
+        }
+    });
+    for (key, phone, body) in due {
+        send_reminder(&config, key, phone, body).await;
+    }
+    SENDING_REMINDERS.with(|s| s.set(false));
+}
+
+async fn send_reminder(config: &SmsProviderConfig, key: EventPrincipalKey, phone: String, body: String) {
+    let attempts = DELIVERIES
+        .with(|d| d.borrow().get(&key))
+        .map_or(0, |delivery| delivery.attempts)
+        + 1;
+    record_delivery(&key, DeliveryStatus::Sending, attempts);
+
+    let form = format!(
+        "To={}&From={}&Body={}",
+        form_encode(&phone),
+        form_encode(&config.from),
+        form_encode(&body)
+    );
+    let mut headers = vec![
+        header("Content-Type", "application/x-www-form-urlencoded"),
+        header("Idempotency-Key", &idempotency_key(&key)),
+        header("I-Twilio-Idempotency-Token", &idempotency_key(&key)),
+    ];
+    if !config.authorization.is_empty() {
+        headers.push(header("Authorization", &config.authorization));
+    }
+    let request = CanisterHttpRequestArgument {
+        url: config.url.clone(),
+        max_response_bytes: Some(SMS_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers,
+        body: Some(form.into_bytes()),
+        transform: Some(TransformContext::from_name(
+            "transform_sms_response".to_string(),
+            Vec::new(),
+        )),
+    };
+    let status = match http_request(request, SMS_CYCLES).await {
+        Ok((response,)) if response.status >= 200u64.into() && response.status < 300u64.into() => {
+            DeliveryStatus::Sent
+        }
+        Ok((response,)) => DeliveryStatus::Failed {
+            reason: format!("the provider answered with status {}", response.status),
+        },
+        Err((_, msg)) => DeliveryStatus::Failed { reason: msg },
+    };
+    record_delivery(&key, status, attempts);
+}
+
+// A reminder is due unless it was sent, is being sent, or failed too often
+fn needs_reminder(key: &EventPrincipalKey) -> bool {
+    DELIVERIES
+        .with(|d| d.borrow().get(key))
+        .is_none_or(|delivery| {
+            matches!(delivery.status, DeliveryStatus::Failed { .. }) && delivery.attempts < MAX_ATTEMPTS
+        })
+}
+
+fn record_delivery(key: &EventPrincipalKey, status: DeliveryStatus, attempts: u32) {
+    let delivery = SmsDelivery {
+        status,
+        attempts,
+        updated_at: time(),
+    };
+    DELIVERIES.with(|d| d.borrow_mut().insert(key.clone(), delivery));
+}
+
+fn reminder_text(title: &str, starts_at: Option<u64>) -> String {
+    let title: String = title.chars().take(100).collect();
+    let minutes = starts_at.unwrap_or_default().saturating_sub(time()) / NANOS_PER_MINUTE;
+    if minutes < 120 {
+        format!("Reminder: {} starts in {} minutes.", title, minutes)
+    } else {
+        format!("Reminder: {} starts in {} hours.", title, minutes / 60)
+    }
+}
+
+// Same for every attempt at the same reminder, so providers can drop duplicates
+fn idempotency_key(key: &EventPrincipalKey) -> String {
+    let digest = Sha256::digest(format!("sms-reminder|{}|{}", key.event_id, key.principal));
+    digest.iter().take(16).map(|b| format!("{:02x}", b)).collect()
+}
+
+fn header(name: &str, value: &str) -> HttpHeader {
+    HttpHeader {
+        name: name.to_string(),
+        value: value.to_string(),
+    }
+}
+
+pub(crate) fn form_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b' ' => "+".to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn mask(phone: &str) -> String {
+    let visible = phone.len().saturating_sub(4);
+    format!("{}{}", "*".repeat(visible), &phone[visible..])
+}
+
+fn encrypt(phone: &str) -> EncryptedPhone {
+    let sealed = secrets::seal(phone.as_bytes());
+    EncryptedPhone {
+        nonce: sealed.nonce,
+        ciphertext: sealed.ciphertext,
+        tag: sealed.tag,
+        opted_in_at: time(),
+    }
+}
+
+fn phone_of(principal: &str) -> Option<String> {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    let encrypted = PHONE_NUMBERS.with(|p| p.borrow().get(&key))?;
+    let sealed = Sealed {
+        nonce: encrypted.nonce,
+        ciphertext: encrypted.ciphertext,
+        tag: encrypted.tag,
+    };
+    String::from_utf8(secrets::open(&sealed)?).ok()
+}
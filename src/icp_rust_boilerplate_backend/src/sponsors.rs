@@ -0,0 +1,277 @@
+// Event sponsors for conference-style pages. Organizers add sponsors with a name, logo,
+// tier label (e.g. "Gold") and link, and put them in the order they should appear; clients
+// get them together with the event from `get_event_with_sponsors`, and the event's web page
+// lists them. Sponsors are kept on the router, so they cover events in buckets too.
+use crate::{access, images, metrics, status, verification};
+use crate::{Error, Event, IdCell, Memory, MEMORY_MANAGER, _fetch_event, _is_owner};
+use crate::clock::time;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_SPONSORS_PER_EVENT: usize = 50;
+const MAX_NAME_BYTES: usize = 100;
+const MAX_TIER_BYTES: usize = 32;
+const MAX_LOGO_URL_BYTES: usize = 512;
+const MAX_LINK_BYTES: usize = 512;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Sponsor {
+    id: u64,
+    event_id: u64,
+    pub(crate) name: String,
+    pub(crate) logo_url: String,
+    pub(crate) tier: Option<String>,
+    pub(crate) link: Option<String>,
+    // Place in the event's sponsor list, from 0
+    position: u32,
+    created_at: u64,
+}
+
+impl_storable!(Sponsor, 1536);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SponsorPayload {
+    name: String,
+    logo_url: String,
+    tier: Option<String>,
+    link: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct SponsorKey {
+    event_id: u64,
+    id: u64,
+}
+
+impl_storable!(SponsorKey, 32);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct EventWithSponsors {
+    event: Event,
+    sponsors: Vec<Sponsor>,
+}
+
+thread_local! {
+    static SPONSOR_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81))), 0)
+            .expect("Cannot create a sponsor counter")
+    );
+
+    static SPONSORS: RefCell<StableBTreeMap<SponsorKey, Sponsor, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82)))
+    ));
+}
+
+// Update function for an event's organizer to add a sponsor at the end of its list
+#[ic_cdk::update]
+async fn add_sponsor(event_id: u64, payload: SponsorPayload) -> Result<Sponsor, Error> {
+    let _metrics = metrics::track("add_sponsor");
+    _get_own_event(event_id).await?;
+    let payload = validate_sponsor_payload(payload)?;
+    let existing = sponsors_of(event_id);
+    if existing.len() >= MAX_SPONSORS_PER_EVENT {
+        return Err(Error::InvalidPayload {
+            msg: format!("an event can have at most {} sponsors", MAX_SPONSORS_PER_EVENT),
+        });
+    }
+    let id = SPONSOR_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment sponsor id counter");
+    let sponsor = Sponsor {
+        id,
+        event_id,
+        name: payload.name,
+        logo_url: payload.logo_url,
+        tier: payload.tier,
+        link: payload.link,
+        position: existing.len() as u32,
+        created_at: time(),
+    };
+    do_insert_sponsor(&sponsor);
+    Ok(sponsor)
+}
+
+// Update function for an event's organizer to change a sponsor's details
+#[ic_cdk::update]
+async fn update_sponsor(
+    event_id: u64,
+    sponsor_id: u64,
+    payload: SponsorPayload,
+) -> Result<Sponsor, Error> {
+    let _metrics = metrics::track("update_sponsor");
+    _get_own_event(event_id).await?;
+    let mut sponsor = _get_sponsor(event_id, sponsor_id)?;
+    let payload = validate_sponsor_payload(payload)?;
+    sponsor.name = payload.name;
+    sponsor.logo_url = payload.logo_url;
+    sponsor.tier = payload.tier;
+    sponsor.link = payload.link;
+    do_insert_sponsor(&sponsor);
+    Ok(sponsor)
+}
+
+// Update function for an event's organizer to remove a sponsor; the others keep their order
+#[ic_cdk::update]
+async fn remove_sponsor(event_id: u64, sponsor_id: u64) -> Result<Sponsor, Error> {
+    let _metrics = metrics::track("remove_sponsor");
+    _get_own_event(event_id).await?;
+    let sponsor = _get_sponsor(event_id, sponsor_id)?;
+    SPONSORS.with(|s| {
+        s.borrow_mut().remove(&SponsorKey {
+            event_id,
+            id: sponsor_id,
+        })
+    });
+    renumber(sponsors_of(event_id));
+    Ok(sponsor)
+}
+
+// Update function for an event's organizer to set the order of its sponsors; the list must
+// name each of them once
+#[ic_cdk::update]
+async fn reorder_sponsors(event_id: u64, sponsor_ids: Vec<u64>) -> Result<Vec<Sponsor>, Error> {
+    let _metrics = metrics::track("reorder_sponsors");
+    _get_own_event(event_id).await?;
+    let mut sponsors = sponsors_of(event_id);
+    let mut ids: Vec<u64> = sponsors.iter().map(|sponsor| sponsor.id).collect();
+    let mut requested = sponsor_ids.clone();
+    ids.sort_unstable();
+    requested.sort_unstable();
+    if ids != requested {
+        return Err(Error::InvalidPayload {
+            msg: "the new order must list each of the event's sponsors once".to_string(),
+        });
+    }
+    sponsors.sort_by_key(|sponsor| sponsor_ids.iter().position(|id| *id == sponsor.id));
+    Ok(renumber(sponsors))
+}
+
+// Query function listing an event's sponsors in order
+#[ic_cdk::query(composite = true)]
+async fn get_sponsors(event_id: u64) -> Result<Vec<Sponsor>, Error> {
+    Ok(_fetch_with_sponsors(event_id).await?.sponsors)
+}
+
+// Query function returning an event together with its sponsors, with the same visibility
+// rules as `get_event`
+#[ic_cdk::query(composite = true)]
+async fn get_event_with_sponsors(id: u64) -> Result<EventWithSponsors, Error> {
+    _fetch_with_sponsors(id).await
+}
+
+// An event's sponsors, in the order the organizer set
+pub(crate) fn sponsors_of(event_id: u64) -> Vec<Sponsor> {
+    let start = SponsorKey { event_id, id: 0 };
+    let mut sponsors: Vec<Sponsor> = SPONSORS.with(|s| {
+        s.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(_, sponsor)| sponsor)
+            .collect()
+    });
+    sponsors.sort_by_key(|sponsor| (sponsor.position, sponsor.id));
+    sponsors
+}
+
+fn validate_sponsor_payload(mut payload: SponsorPayload) -> Result<SponsorPayload, Error> {
+    let invalid = |msg: String| Err(Error::InvalidPayload { msg });
+    payload.name = payload.name.trim().to_string();
+    if payload.name.is_empty() || payload.name.len() > MAX_NAME_BYTES {
+        return invalid(format!(
+            "a sponsor name must be between 1 and {} bytes",
+            MAX_NAME_BYTES
+        ));
+    }
+    payload.logo_url = payload.logo_url.trim().to_string();
+    if payload.logo_url.len() > MAX_LOGO_URL_BYTES {
+        return invalid(format!("a sponsor logo URL can have at most {} bytes", MAX_LOGO_URL_BYTES));
+    }
+    images::_validate_image_url(&payload.logo_url)?;
+    payload.tier = payload
+        .tier
+        .map(|tier| tier.trim().to_string())
+        .filter(|tier| !tier.is_empty());
+    if payload.tier.as_ref().is_some_and(|tier| tier.len() > MAX_TIER_BYTES) {
+        return invalid(format!("a sponsor tier can have at most {} bytes", MAX_TIER_BYTES));
+    }
+    payload.link = payload
+        .link
+        .map(|link| link.trim().to_string())
+        .filter(|link| !link.is_empty());
+    if let Some(link) = &payload.link {
+        // Links end up in pages and clients, so only plain https URLs are accepted
+        let is_https = link.get(..8).is_some_and(|scheme| scheme.eq_ignore_ascii_case("https://"));
+        if !is_https
+            || link.len() > MAX_LINK_BYTES
+            || link.chars().any(|c| c.is_whitespace() || c.is_control())
+        {
+            return invalid(format!(
+                "a sponsor link must be an https URL of at most {} bytes",
+                MAX_LINK_BYTES
+            ));
+        }
+    }
+    Ok(payload)
+}
+
+// Stores the sponsors with positions following their order in the list
+fn renumber(mut sponsors: Vec<Sponsor>) -> Vec<Sponsor> {
+    for (position, sponsor) in sponsors.iter_mut().enumerate() {
+        if sponsor.position != position as u32 {
+            sponsor.position = position as u32;
+            do_insert_sponsor(sponsor);
+        }
+    }
+    sponsors
+}
+
+// Helper function returning a visible event with its sponsors; those of an unlisted event
+// are only shown to callers who can view it in full
+async fn _fetch_with_sponsors(id: u64) -> Result<EventWithSponsors, Error> {
+    let event = _fetch_event(id).await?;
+    status::_check_visible(&event)?;
+    let sponsors = if access::can_view(&event) {
+        sponsors_of(event.id)
+    } else {
+        Vec::new()
+    };
+    Ok(EventWithSponsors {
+        event: verification::with_badge(access::redact(event)),
+        sponsors,
+    })
+}
+
+async fn _get_own_event(event_id: u64) -> Result<Event, Error> {
+    let event = _fetch_event(event_id).await?;
+    if !_is_owner(&event, &caller()) {
+        return Err(Error::NotAuthorized {
+            msg: format!("You're not the organizer of the event with id={}", event_id),
+            caller: caller(),
+        });
+    }
+    Ok(event)
+}
+
+fn _get_sponsor(event_id: u64, sponsor_id: u64) -> Result<Sponsor, Error> {
+    let key = SponsorKey {
+        event_id,
+        id: sponsor_id,
+    };
+    SPONSORS.with(|s| s.borrow().get(&key)).ok_or(Error::NotFound {
+        msg: format!("Sponsor with id={} not found", sponsor_id),
+    })
+}
+
+fn do_insert_sponsor(sponsor: &Sponsor) {
+    let key = SponsorKey {
+        event_id: sponsor.event_id,
+        id: sponsor.id,
+    };
+    SPONSORS.with(|s| s.borrow_mut().insert(key, sponsor.clone()));
+}
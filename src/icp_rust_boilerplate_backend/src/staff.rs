@@ -0,0 +1,106 @@
+// Door staff. An organizer can let other principals scan tickets and check attendees in
+// for an event without giving them any way to edit it. Staff are kept for events stored
+// on this canister.
+use crate::{Error, Event, EventPrincipalKey, Memory, MEMORY_MANAGER, _get_organized_event, _is_owner};
+use crate::clock::time;
+use crate::metrics;
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_STAFF_PER_EVENT: usize = 50;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StaffMember {
+    principal: Principal,
+    added_at: u64,
+}
+
+impl_storable!(StaffMember, 64);
+
+thread_local! {
+    static STAFF: RefCell<StableBTreeMap<EventPrincipalKey, StaffMember, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+    ));
+}
+
+// Update function for an event's organizer to designate a door staff member
+#[ic_cdk::update]
+fn add_staff(event_id: u64, principal: Principal) -> Result<StaffMember, Error> {
+    let _metrics = metrics::track("add_staff");
+    let event = _get_organized_event(event_id)?;
+    if _is_owner(&event, &principal) || principal == Principal::anonymous() {
+        return Err(Error::InvalidPayload {
+            msg: "staff must be a signed-in principal other than the organizer".to_string(),
+        });
+    }
+    if staff_of(event_id).len() >= MAX_STAFF_PER_EVENT {
+        return Err(Error::InvalidPayload {
+            msg: format!("an event can have at most {} staff members", MAX_STAFF_PER_EVENT),
+        });
+    }
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    if let Some(member) = STAFF.with(|s| s.borrow().get(&key)) {
+        return Ok(member);
+    }
+    let member = StaffMember {
+        principal,
+        added_at: time(),
+    };
+    STAFF.with(|s| s.borrow_mut().insert(key, member.clone()));
+    Ok(member)
+}
+
+// Update function for an event's organizer to revoke a staff member's access
+#[ic_cdk::update]
+fn revoke_staff(event_id: u64, principal: Principal) -> Result<StaffMember, Error> {
+    let _metrics = metrics::track("revoke_staff");
+    _get_organized_event(event_id)?;
+    let key = EventPrincipalKey {
+        event_id,
+        principal: principal.to_string(),
+    };
+    STAFF
+        .with(|s| s.borrow_mut().remove(&key))
+        .ok_or(Error::NotFound {
+            msg: format!("{} is not on the staff of the event with id={}", principal, event_id),
+        })
+}
+
+// Query function for an event's organizer to list its staff
+#[ic_cdk::query]
+fn list_staff(event_id: u64) -> Result<Vec<StaffMember>, Error> {
+    _get_organized_event(event_id)?;
+    Ok(staff_of(event_id))
+}
+
+// Whether the principal may work the door for the event: its organizer or a staff member
+pub(crate) fn can_scan(event: &Event, principal: &Principal) -> bool {
+    if _is_owner(event, principal) {
+        return true;
+    }
+    let key = EventPrincipalKey {
+        event_id: event.id,
+        principal: principal.to_string(),
+    };
+    STAFF.with(|s| s.borrow().contains_key(&key))
+}
+
+fn staff_of(event_id: u64) -> Vec<StaffMember> {
+    let start = EventPrincipalKey {
+        event_id,
+        principal: String::new(),
+    };
+    STAFF.with(|s| {
+        s.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(_, member)| member)
+            .collect()
+    })
+}
@@ -0,0 +1,383 @@
+// Event lifecycle. Events are published when created; an organizer can cancel one, which
+// closes registration, tells every attendee and queues refunds for paid tickets. Organizers
+// leaving the platform can purge all their events the same way, and admins can take down
+// spam in bulk: such events are marked Deleted and hidden, but kept in storage.
+//
+// Admins can require new events to pass review first: they stay PendingReview, visible
+// only to their owner and admins, until approved or rejected. Review holds are kept by
+// the canister that created the event, even when its data lives in a bucket.
+use crate::admin::_check_admin;
+use crate::notifications::{self, NotificationKind};
+use crate::logs::{self, LogLevel};
+use crate::{bans, cache, deposits, duplicates, google_calendar, metrics, payments, reputation};
+use crate::{schedule, shard, slugs, venues};
+use crate::{Error, Event, Memory, MEMORY_MANAGER, _fetch_event, _get_event};
+use crate::{_is_on_team, _is_owner};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+
+// Phrase `purge_my_events` must be called with, so it isn't triggered by accident
+const PURGE_PHRASE: &str = "delete all my events";
+// Most events purged in one call; the rest are left for the next call
+const MAX_PURGE_EVENTS: usize = 200;
+// Reasons are stored in the event's status, whose bound leaves room for its encoding
+const MAX_REASON_BYTES: usize = 400;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) enum EventStatus {
+    #[default]
+    Published,
+    Cancelled { reason: String, cancelled_at: u64 },
+    PendingReview,
+    Rejected { reason: String, rejected_at: u64 },
+    Deleted { deleted_at: u64 },
+}
+
+impl_storable!(EventStatus, 512);
+
+// Outcome of purging an organizer's events
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct EventPurge {
+    event_ids: Vec<u64>,
+    refunds_queued: u64,
+    // Set when the organizer has more events than one call purges
+    more: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ReviewConfig {
+    required: bool,
+}
+
+impl_storable!(ReviewConfig, 16);
+
+thread_local! {
+    // Only events that left the default Published state have an entry
+    static EVENT_STATUS: RefCell<StableBTreeMap<u64, EventStatus, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    static REVIEW_CONFIG: RefCell<Cell<ReviewConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))), ReviewConfig::default())
+            .expect("Cannot create the review config cell")
+    );
+}
+
+// Query function returning the lifecycle status of an event
+#[ic_cdk::query(composite = true)]
+async fn get_event_status(id: u64) -> Result<EventStatus, Error> {
+    if let Some(status) = EVENT_STATUS.with(|s| s.borrow().get(&id)) {
+        return Ok(status);
+    }
+    if let Some(bucket) = shard::bucket_of(id) {
+        return shard::forward(bucket, "bucket_get_event_status", (id,)).await;
+    }
+    _get_event_status(id)
+}
+
+// Update function for an event's organizer to cancel it
+#[ic_cdk::update]
+async fn cancel_event(id: u64, reason: String) -> Result<Event, Error> {
+    let _metrics = metrics::track("cancel_event");
+    if let Some(bucket) = shard::bucket_of(id) {
+        return shard::forward(bucket, "bucket_cancel_event", (caller(), id, reason)).await;
+    }
+    _cancel_event(caller(), id, reason)
+}
+
+// Update function for organizers leaving the platform to delete every event they own on this
+// canister. Attendees are told, paid tickets refunded and held deposits returned, and the
+// events are then hidden as Deleted. Takes PURGE_PHRASE as confirmation; organizers with more
+// than MAX_PURGE_EVENTS events call it again until `more` is unset.
+#[ic_cdk::update]
+fn purge_my_events(confirm_phrase: String) -> Result<EventPurge, Error> {
+    let _metrics = metrics::track("purge_my_events");
+    if confirm_phrase.trim() != PURGE_PHRASE {
+        return Err(Error::InvalidPayload {
+            msg: format!("type \"{}\" to confirm purging your events", PURGE_PHRASE),
+        });
+    }
+    let owner = caller().to_string();
+    let mut events: Vec<Event> = cache::events_owned_by(&owner)
+        .into_iter()
+        .filter(|id| !is_deleted(*id))
+        .take(MAX_PURGE_EVENTS + 1)
+        .filter_map(|id| _get_event(&id))
+        .collect();
+    let more = events.len() > MAX_PURGE_EVENTS;
+    events.truncate(MAX_PURGE_EVENTS);
+
+    let mut purge = EventPurge {
+        event_ids: Vec::new(),
+        refunds_queued: 0,
+        more,
+    };
+    for event in events {
+        let message = format!("\"{}\" has been deleted by its organizer", event.event_title);
+        purge.refunds_queued += soft_delete(&event, message);
+        purge.event_ids.push(event.id);
+    }
+    logs::write(
+        LogLevel::Info,
+        module_path!(),
+        format!("{} purged {} event(s)", owner, purge.event_ids.len()),
+    );
+    Ok(purge)
+}
+
+// Query function telling whether new events must be approved by an admin before publishing
+#[ic_cdk::query]
+fn is_event_review_required() -> bool {
+    REVIEW_CONFIG.with(|c| c.borrow().get().required)
+}
+
+// Update function for admins to turn the review requirement on or off. Events already
+// waiting for review stay pending until decided.
+#[ic_cdk::update]
+fn set_event_review_required(required: bool) -> Result<(), Error> {
+    let _metrics = metrics::track("set_event_review_required");
+    _check_admin()?;
+    REVIEW_CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(ReviewConfig { required })
+            .expect("cannot update the review config")
+    });
+    Ok(())
+}
+
+// Query function listing the events waiting for admin review
+#[ic_cdk::query(composite = true)]
+async fn get_pending_events() -> Result<Vec<Event>, Error> {
+    _check_admin()?;
+    let ids: Vec<u64> = EVENT_STATUS.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, status)| matches!(status, EventStatus::PendingReview))
+            .map(|(id, _)| id)
+            .collect()
+    });
+    let mut events = Vec::new();
+    for id in ids {
+        if let Ok(event) = _fetch_event(id).await {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
+
+// Update function for admins to publish an event waiting for review
+#[ic_cdk::update]
+async fn approve_event(id: u64) -> Result<Event, Error> {
+    let _metrics = metrics::track("approve_event");
+    _check_admin()?;
+    let event = _get_pending_event(id).await?;
+    EVENT_STATUS.with(|s| s.borrow_mut().remove(&id));
+    notifications::notify(
+        &event.owner,
+        NotificationKind::ReviewDecision,
+        Some(id),
+        format!("\"{}\" has been approved and is now published", event.event_title),
+    );
+    Ok(event)
+}
+
+// Update function for admins to refuse an event waiting for review
+#[ic_cdk::update]
+async fn reject_event(id: u64, reason: String) -> Result<Event, Error> {
+    let _metrics = metrics::track("reject_event");
+    _check_admin()?;
+    let reason = reason.trim().to_string();
+    if reason.is_empty() || reason.len() > MAX_REASON_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("a rejection reason must be between 1 and {} bytes", MAX_REASON_BYTES),
+        });
+    }
+    let event = _get_pending_event(id).await?;
+    EVENT_STATUS.with(|s| {
+        s.borrow_mut().insert(
+            id,
+            EventStatus::Rejected {
+                reason: reason.clone(),
+                rejected_at: time(),
+            },
+        )
+    });
+    notifications::notify(
+        &event.owner,
+        NotificationKind::ReviewDecision,
+        Some(id),
+        format!("\"{}\" was not approved: {}", event.event_title, reason),
+    );
+    Ok(event)
+}
+
+// Puts a newly created event on hold when admin review is required
+pub(crate) fn _hold_for_review(id: u64) {
+    if REVIEW_CONFIG.with(|c| c.borrow().get().required) {
+        EVENT_STATUS.with(|s| s.borrow_mut().insert(id, EventStatus::PendingReview));
+    }
+}
+
+// Helper function hiding unreviewed and rejected events, and those hidden by their
+// organizer's ban, from everyone but their owner, their organization's members and admins
+pub(crate) fn _check_visible(event: &Event) -> Result<(), Error> {
+    let on_team = _is_on_team(event, &caller());
+    if bans::is_event_hidden(event.id) && !on_team && _check_admin().is_err() {
+        return Err(Error::NotFound {
+            msg: format!("Event with id={} not found", event.id),
+        });
+    }
+    match status_of(event.id) {
+        EventStatus::PendingReview | EventStatus::Rejected { .. } | EventStatus::Deleted { .. }
+            if !on_team && _check_admin().is_err() =>
+        {
+            Err(Error::NotFound {
+                msg: format!("Event with id={} not found", event.id),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+// Helper function returning the status of a locally stored event
+pub(crate) fn _get_event_status(id: u64) -> Result<EventStatus, Error> {
+    _get_event(&id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", id),
+    })?;
+    Ok(status_of(id))
+}
+
+// Helper function to cancel an event on behalf of the given principal
+pub(crate) fn _cancel_event(principal: Principal, id: u64, reason: String) -> Result<Event, Error> {
+    let event = _get_event(&id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", id),
+    })?;
+    if !_is_owner(&event, &principal) {
+        return Err(Error::NotAuthorized {
+            msg: format!("You're not the owner of the event with id={}", id),
+            caller: principal,
+        });
+    }
+    let reason = reason.trim().to_string();
+    if reason.is_empty() || reason.len() > MAX_REASON_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("a cancellation reason must be between 1 and {} bytes", MAX_REASON_BYTES),
+        });
+    }
+    match status_of(id) {
+        EventStatus::Cancelled { .. } => {
+            return Err(Error::InvalidPayload {
+                msg: format!("Event with id={} is already cancelled", id),
+            });
+        }
+        EventStatus::Deleted { .. } => {
+            return Err(Error::NotFound {
+                msg: format!("Event with id={} not found", id),
+            });
+        }
+        _ => {}
+    }
+
+    EVENT_STATUS.with(|s| {
+        s.borrow_mut().insert(
+            id,
+            EventStatus::Cancelled {
+                reason: reason.clone(),
+                cancelled_at: time(),
+            },
+        )
+    });
+    notifications::notify_attendees(
+        &event,
+        NotificationKind::EventCancelled,
+        format!("\"{}\" has been cancelled: {}", event.event_title, reason),
+    );
+    payments::enqueue_refunds(id);
+    deposits::return_deposits(id);
+    reputation::record_cancellation(&event.owner);
+    Ok(event)
+}
+
+// Helper function to reject registrations for events that are no longer open
+pub(crate) fn _check_open_for_registration(id: u64) -> Result<(), Error> {
+    if bans::is_event_hidden(id) {
+        return Err(Error::NotFound {
+            msg: format!("Event with id={} not found", id),
+        });
+    }
+    match status_of(id) {
+        EventStatus::Published => Ok(()),
+        EventStatus::Cancelled { .. } => Err(Error::InvalidPayload {
+            msg: format!("Event with id={} has been cancelled", id),
+        }),
+        EventStatus::PendingReview => Err(Error::InvalidPayload {
+            msg: format!("Event with id={} is waiting for admin review", id),
+        }),
+        EventStatus::Rejected { .. } => Err(Error::InvalidPayload {
+            msg: format!("Event with id={} was not approved for publishing", id),
+        }),
+        EventStatus::Deleted { .. } => Err(Error::NotFound {
+            msg: format!("Event with id={} not found", id),
+        }),
+    }
+}
+
+async fn _get_pending_event(id: u64) -> Result<Event, Error> {
+    let event = _fetch_event(id).await?;
+    if !matches!(status_of(id), EventStatus::PendingReview) {
+        return Err(Error::InvalidPayload {
+            msg: format!("Event with id={} is not waiting for review", id),
+        });
+    }
+    Ok(event)
+}
+
+// Marks an event Deleted after telling its attendees, if it was still going ahead, refunding
+// paid tickets, returning held deposits and releasing its bookings, fingerprint and slug.
+// Returns the number of refunds queued.
+pub(crate) fn soft_delete(event: &Event, message: String) -> u64 {
+    if is_going_ahead(event.id) {
+        notifications::notify_attendees(event, NotificationKind::EventCancelled, message);
+    }
+    let refunds_queued = payments::enqueue_refunds(event.id);
+    deposits::return_deposits(event.id);
+    EVENT_STATUS.with(|s| {
+        s.borrow_mut()
+            .insert(event.id, EventStatus::Deleted { deleted_at: time() })
+    });
+    venues::forget_booking(event.id);
+    schedule::forget(event);
+    duplicates::forget(event.id);
+    slugs::release(event);
+    google_calendar::enqueue_event(event);
+    refunds_queued
+}
+
+// Whether an event has been purged by its organizer or taken down by an admin
+pub(crate) fn is_deleted(id: u64) -> bool {
+    matches!(status_of(id), EventStatus::Deleted { .. })
+}
+
+// Whether an event is live: neither cancelled, held back by admin review nor hidden by a ban
+pub(crate) fn is_published(id: u64) -> bool {
+    matches!(status_of(id), EventStatus::Published) && !bans::is_event_hidden(id)
+}
+
+fn status_of(id: u64) -> EventStatus {
+    EVENT_STATUS
+        .with(|s| s.borrow().get(&id))
+        .unwrap_or_default()
+}
+
+// Whether an event is still going ahead: neither cancelled, rejected by review nor deleted
+pub(crate) fn is_going_ahead(id: u64) -> bool {
+    !matches!(
+        status_of(id),
+        EventStatus::Cancelled { .. } | EventStatus::Rejected { .. } | EventStatus::Deleted { .. }
+    )
+}
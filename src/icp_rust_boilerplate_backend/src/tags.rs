@@ -0,0 +1,42 @@
+// Event tags. Organizers label events with a few short topics ("music", "web3") that
+// feeds and clients can filter on. Tags are stored lowercased and deduplicated.
+use crate::{Error, Event, EventPayload};
+
+const MAX_TAGS: usize = 10;
+const MAX_TAG_CHARS: usize = 32;
+
+// Helper function to normalize the payload's tags in place and reject unusable ones
+pub(crate) fn normalize_tags(payload: &mut EventPayload) -> Result<(), Error> {
+    let Some(tags) = payload.tags.take() else {
+        return Ok(());
+    };
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = normalize(&tag);
+        if tag.is_empty() || tag.chars().count() > MAX_TAG_CHARS {
+            return Err(Error::InvalidPayload {
+                msg: format!("tags must be between 1 and {} characters", MAX_TAG_CHARS),
+            });
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    if normalized.len() > MAX_TAGS {
+        return Err(Error::InvalidPayload {
+            msg: format!("an event can have at most {} tags", MAX_TAGS),
+        });
+    }
+    payload.tags = Some(normalized);
+    Ok(())
+}
+
+// Whether the event carries the given tag, compared the way tags are stored
+pub(crate) fn has_tag(event: &Event, tag: &str) -> bool {
+    let tag = normalize(tag);
+    event.tags.as_ref().is_some_and(|tags| tags.contains(&tag))
+}
+
+fn normalize(tag: &str) -> String {
+    tag.trim().trim_start_matches('#').to_lowercase()
+}
@@ -0,0 +1,293 @@
+use crate::{images, metrics};
+use crate::{Error, Event, EventPayload, IdCell, Memory, MEMORY_MANAGER};
+use crate::{MAX_PRINCIPAL_TEXT_BYTES, _prepare_and_create};
+use crate::clock::time;
+use candid::Encode;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use std::cell::RefCell;
+
+// Placeholder in a template's title pattern that is replaced by the running use count
+const COUNTER_PLACEHOLDER: &str = "{n}";
+
+// A named, reusable blueprint an organizer can create events from
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EventTemplate {
+    id: u64,
+    owner: String,
+    name: String,
+    title_pattern: String,
+    event_description: String,
+    event_location: String,
+    event_card_imgurl: String,
+    // Free-form key/value settings kept for the frontend (e.g. default visibility)
+    settings: Vec<(String, String)>,
+    uses: u64,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl_storable!(EventTemplate, 2048);
+
+// Templates keyed by owner first, so an organizer's templates are a range scan
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct OwnerTemplateKey {
+    owner: String,
+    id: u64,
+}
+
+impl_storable!(OwnerTemplateKey, 128);
+
+thread_local! {
+    static TEMPLATE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))), 0)
+            .expect("Cannot create a template counter")
+    );
+
+    static TEMPLATES: RefCell<StableBTreeMap<u64, EventTemplate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    static OWNER_TEMPLATES: RefCell<StableBTreeMap<OwnerTemplateKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(182)))
+    ));
+}
+
+// Template payload for creating or updating a template
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct TemplatePayload {
+    name: String,
+    title_pattern: String,
+    event_description: String,
+    event_location: String,
+    event_card_imgurl: String,
+    settings: Vec<(String, String)>,
+}
+
+// Optional per-event values that take precedence over the template's defaults
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct TemplateOverrides {
+    event_title: Option<String>,
+    event_description: Option<String>,
+    event_location: Option<String>,
+    event_card_imgurl: Option<String>,
+    venue_id: Option<u64>,
+    starts_at: Option<u64>,
+    ends_at: Option<u64>,
+}
+
+// Function to save a new named template owned by the caller
+#[ic_cdk::update]
+fn create_template(payload: TemplatePayload) -> Result<EventTemplate, Error> {
+    let _metrics = metrics::track("create_template");
+    validate_template_payload(&payload)?;
+
+    let id = TEMPLATE_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment template id counter");
+
+    let template = EventTemplate {
+        id,
+        owner: caller().to_string(),
+        name: payload.name,
+        title_pattern: payload.title_pattern,
+        event_description: payload.event_description,
+        event_location: payload.event_location,
+        event_card_imgurl: payload.event_card_imgurl,
+        settings: payload.settings,
+        uses: 0,
+        created_at: time(),
+        updated_at: None,
+    };
+    do_insert_template(&template);
+    Ok(template)
+}
+
+// Update function to modify an existing template; only its owner may do so
+#[ic_cdk::update]
+fn update_template(id: u64, payload: TemplatePayload) -> Result<EventTemplate, Error> {
+    let _metrics = metrics::track("update_template");
+    validate_template_payload(&payload)?;
+
+    let mut template = _get_owned_template(id)?;
+    template.name = payload.name;
+    template.title_pattern = payload.title_pattern;
+    template.event_description = payload.event_description;
+    template.event_location = payload.event_location;
+    template.event_card_imgurl = payload.event_card_imgurl;
+    template.settings = payload.settings;
+    template.updated_at = Some(time());
+
+    do_insert_template(&template);
+    Ok(template)
+}
+
+// Update function to delete a template owned by the caller
+#[ic_cdk::update]
+fn delete_template(id: u64) -> Result<EventTemplate, Error> {
+    let _metrics = metrics::track("delete_template");
+    _get_owned_template(id)?;
+    let template = TEMPLATES
+        .with(|t| t.borrow_mut().remove(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("couldn't delete a template with id={}. template not found", id),
+        })?;
+    OWNER_TEMPLATES.with(|o| o.borrow_mut().remove(&owner_key(&template)));
+    Ok(template)
+}
+
+// Query function to retrieve a single template owned by the caller
+#[ic_cdk::query]
+fn get_template(id: u64) -> Result<EventTemplate, Error> {
+    _get_owned_template(id)
+}
+
+// Query function listing every template owned by the caller
+#[ic_cdk::query]
+fn my_templates() -> Vec<EventTemplate> {
+    let owner = caller().to_string();
+    let start = OwnerTemplateKey {
+        owner: owner.clone(),
+        id: 0,
+    };
+    let ids: Vec<u64> = OWNER_TEMPLATES.with(|o| {
+        o.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.owner == owner)
+            .map(|(key, _)| key.id)
+            .collect()
+    });
+    TEMPLATES.with(|t| {
+        let templates = t.borrow();
+        ids.into_iter().filter_map(|id| templates.get(&id)).collect()
+    })
+}
+
+// Function to create a new event from a saved template, applying any overrides. The event
+// goes through the same checks as one submitted to `create_event`.
+#[ic_cdk::update]
+async fn create_from_template(
+    template_id: u64,
+    overrides: TemplateOverrides,
+) -> Result<Event, Error> {
+    let _metrics = metrics::track("create_from_template");
+    let template = _get_owned_template(template_id)?;
+    let uses = template.uses + 1;
+
+    let payload = EventPayload {
+        event_title: overrides.event_title.unwrap_or_else(|| {
+            template
+                .title_pattern
+                .replace(COUNTER_PLACEHOLDER, &uses.to_string())
+        }),
+        event_description: overrides
+            .event_description
+            .unwrap_or(template.event_description),
+        event_location: overrides
+            .event_location
+            .unwrap_or(template.event_location),
+        event_card_imgurl: overrides
+            .event_card_imgurl
+            .unwrap_or(template.event_card_imgurl),
+        venue_id: overrides.venue_id,
+        starts_at: overrides.starts_at,
+        ends_at: overrides.ends_at,
+        ..Default::default()
+    };
+    let event = _prepare_and_create(payload).await?;
+
+    // Counted once the event exists; the template may have changed or gone meanwhile
+    if let Ok(mut template) = _get_owned_template(template_id) {
+        template.uses += 1;
+        do_insert_template(&template);
+    }
+    Ok(event)
+}
+
+// Helper method to insert a template.
+fn do_insert_template(template: &EventTemplate) {
+    TEMPLATES.with(|t| t.borrow_mut().insert(template.id, template.clone()));
+    OWNER_TEMPLATES.with(|o| o.borrow_mut().insert(owner_key(template), ()));
+}
+
+fn owner_key(template: &EventTemplate) -> OwnerTemplateKey {
+    OwnerTemplateKey {
+        owner: template.owner.clone(),
+        id: template.id,
+    }
+}
+
+// Indexes templates saved before the owner index existed; called from post_upgrade
+pub(crate) fn migrate() {
+    let unindexed = OWNER_TEMPLATES.with(|o| o.borrow().is_empty())
+        && TEMPLATES.with(|t| !t.borrow().is_empty());
+    if !unindexed {
+        return;
+    }
+    TEMPLATES.with(|t| {
+        for (_, template) in t.borrow().iter() {
+            OWNER_TEMPLATES.with(|o| o.borrow_mut().insert(owner_key(&template), ()));
+        }
+    });
+}
+
+// Helper method to retrieve a template and check that the caller owns it
+fn _get_owned_template(id: u64) -> Result<EventTemplate, Error> {
+    let template = TEMPLATES
+        .with(|t| t.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("Template with id={} not found", id),
+        })?;
+    if template.owner != caller().to_string() {
+        return Err(Error::NotAuthorized {
+            msg: format!("You're not the owner of the template with id={}", id),
+            caller: caller(),
+        });
+    }
+    Ok(template)
+}
+
+// Helper function to reject templates without a name or title pattern, with a bad image URL
+// or too large to store. Text is counted in bytes.
+fn validate_template_payload(payload: &TemplatePayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "template name must not be empty".to_string(),
+        });
+    }
+    if payload.title_pattern.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "template title pattern must not be empty".to_string(),
+        });
+    }
+    let probe = EventTemplate {
+        id: u64::MAX,
+        owner: "x".repeat(MAX_PRINCIPAL_TEXT_BYTES),
+        name: payload.name.clone(),
+        title_pattern: payload.title_pattern.clone(),
+        event_description: payload.event_description.clone(),
+        event_location: payload.event_location.clone(),
+        event_card_imgurl: payload.event_card_imgurl.clone(),
+        settings: payload.settings.clone(),
+        uses: u64::MAX,
+        created_at: u64::MAX,
+        updated_at: Some(u64::MAX),
+    };
+    let size = Encode!(&probe).map_or(usize::MAX, |bytes| bytes.len());
+    if size > EventTemplate::MAX_SIZE as usize {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "the template takes {} bytes but at most {} fit; shorten its description",
+                size,
+                EventTemplate::MAX_SIZE
+            ),
+        });
+    }
+    images::_validate_image_url(&payload.event_card_imgurl)
+}
@@ -0,0 +1,148 @@
+// Canister-wide write throttle. Every update call takes a token from one shared bucket,
+// refilled at a configurable rate up to a burst size; once it is empty, calls are refused
+// until it refills, so a coordinated spam wave can't burn through cycles or fill stable
+// memory faster than admins can react. `inspect_message` already turns ingress away while
+// the bucket is empty, and the token is taken when the call executes. Admins and the router
+// of a bucket canister are never throttled. The bucket itself lives on the heap and starts
+// full after an upgrade.
+use crate::admin::_check_admin;
+use crate::clock::time;
+use crate::{metrics, shard, Error, Memory, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Cell;
+use std::cell::RefCell;
+
+const NANOS_PER_MINUTE: u128 = 60 * 1_000_000_000;
+// Tokens are counted in thousandths so slow rates still refill between calls
+const MILLIS_PER_TOKEN: u64 = 1_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ThrottleConfig {
+    // None leaves update calls unthrottled
+    writes_per_minute: Option<u32>,
+    // Calls that can be made at once after a quiet period
+    burst: u32,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            writes_per_minute: Some(6_000),
+            burst: 1_000,
+        }
+    }
+}
+
+impl_storable!(ThrottleConfig, 32);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ThrottleStatus {
+    config: ThrottleConfig,
+    // Calls that can be made right now
+    available: u32,
+}
+
+struct TokenBucket {
+    // Thousandths of a token; None until the first call after an install or upgrade
+    millis: Option<u64>,
+    refilled_at: u64,
+}
+
+thread_local! {
+    static THROTTLE_CONFIG: RefCell<Cell<ThrottleConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(130))), ThrottleConfig::default())
+            .expect("Cannot create the throttle config cell")
+    );
+
+    static BUCKET: RefCell<TokenBucket> = const {
+        RefCell::new(TokenBucket {
+            millis: None,
+            refilled_at: 0,
+        })
+    };
+}
+
+// Query function returning the write throttle settings and the calls currently available
+#[ic_cdk::query]
+fn get_write_throttle() -> ThrottleStatus {
+    let config = config();
+    let available = (refilled(&config) / MILLIS_PER_TOKEN).min(u32::MAX as u64) as u32;
+    ThrottleStatus { config, available }
+}
+
+// Update function for admins to change the rate and burst of the write throttle; the bucket
+// starts over full
+#[ic_cdk::update]
+fn set_write_throttle(writes_per_minute: Option<u32>, burst: u32) -> Result<ThrottleStatus, Error> {
+    let _metrics = metrics::track("set_write_throttle");
+    _check_admin()?;
+    if writes_per_minute == Some(0) || burst == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "the rate and the burst must be at least 1".to_string(),
+        });
+    }
+    let config = ThrottleConfig {
+        writes_per_minute,
+        burst,
+    };
+    THROTTLE_CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(config)
+            .expect("cannot update the throttle config")
+    });
+    BUCKET.with(|b| b.borrow_mut().millis = None);
+    Ok(get_write_throttle())
+}
+
+// Takes a token for the current update call, trapping when the bucket is empty so nothing
+// the call would have written is kept
+pub(crate) fn take_token() {
+    if is_exempt() {
+        return;
+    }
+    let config = config();
+    if config.writes_per_minute.is_none() {
+        return;
+    }
+    let millis = refilled(&config);
+    if millis < MILLIS_PER_TOKEN {
+        ic_cdk::trap("the canister is receiving too many calls, try again shortly");
+    }
+    BUCKET.with(|b| {
+        *b.borrow_mut() = TokenBucket {
+            millis: Some(millis - MILLIS_PER_TOKEN),
+            refilled_at: time(),
+        }
+    });
+}
+
+// Whether an ingress call would find a token, without taking it
+pub(crate) fn has_token() -> bool {
+    let config = config();
+    config.writes_per_minute.is_none() || refilled(&config) >= MILLIS_PER_TOKEN || is_exempt()
+}
+
+fn is_exempt() -> bool {
+    _check_admin().is_ok() || shard::_check_router().is_ok()
+}
+
+fn config() -> ThrottleConfig {
+    THROTTLE_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+// Thousandths of a token in the bucket now
+fn refilled(config: &ThrottleConfig) -> u64 {
+    let capacity = config.burst as u64 * MILLIS_PER_TOKEN;
+    let Some(rate) = config.writes_per_minute else {
+        return capacity;
+    };
+    BUCKET.with(|b| {
+        let bucket = b.borrow();
+        let Some(millis) = bucket.millis else {
+            return capacity;
+        };
+        let elapsed = time().saturating_sub(bucket.refilled_at) as u128;
+        let added = elapsed * rate as u128 * MILLIS_PER_TOKEN as u128 / NANOS_PER_MINUTE;
+        (millis as u128 + added).min(capacity as u128) as u64
+    })
+}
@@ -0,0 +1,443 @@
+use crate::{achievements, attendees, deposits, metrics, points, schedule, shard, staff};
+use crate::{Error, Event, EventPrincipalKey, Memory, MEMORY_MANAGER, _call_failed, _get_event};
+use crate::_get_organized_event;
+use crate::clock::time;
+use candid::Principal;
+use hmac::{Hmac, Mac};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use sha2::Sha256;
+use std::cell::RefCell;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Separator between the fields of a ticket token; principals and numbers never contain it
+const TOKEN_SEPARATOR: char = '.';
+
+// Doors open two hours before the start unless the organizer sets another window
+const DEFAULT_OPENS_BEFORE_SECS: u64 = 2 * 60 * 60;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// Per-attendee ticket state; the nonce is part of the signed message so reissuing revokes old tokens
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct TicketRecord {
+    nonce: u64,
+    issued_at: u64,
+}
+
+impl_storable!(TicketRecord, 64);
+
+// A ticket token an attendee can render as a QR code
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct Ticket {
+    event_id: u64,
+    attendee: String,
+    pub(crate) token: String,
+    issued_at: u64,
+}
+
+// Result of a successful door scan
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct TicketValidation {
+    event_id: u64,
+    attendee: String,
+    issued_at: u64,
+}
+
+// Record of a ticket holder admitted at the door
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CheckIn {
+    event_id: u64,
+    attendee: String,
+    checked_in_at: u64,
+    checked_in_by: Principal,
+}
+
+impl_storable!(CheckIn, 192);
+
+// When tickets of an event can be checked in: from `opens_before_secs` before its start until
+// `closes_after_secs` after its end. The organizer can keep check-in open regardless of the time.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CheckInWindow {
+    opens_before_secs: u64,
+    closes_after_secs: u64,
+    always_open: bool,
+}
+
+impl_storable!(CheckInWindow, 64);
+
+impl Default for CheckInWindow {
+    fn default() -> Self {
+        CheckInWindow {
+            opens_before_secs: DEFAULT_OPENS_BEFORE_SECS,
+            closes_after_secs: 0,
+            always_open: false,
+        }
+    }
+}
+
+thread_local! {
+    // HMAC key generated from the management canister's randomness on first use
+    static TICKET_SECRET: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))), Vec::new())
+            .expect("Cannot create the ticket secret cell")
+    );
+
+    static TICKETS: RefCell<StableBTreeMap<EventPrincipalKey, TicketRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+
+    static CHECK_INS: RefCell<StableBTreeMap<EventPrincipalKey, CheckIn, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+    ));
+
+    static CHECK_IN_WINDOWS: RefCell<StableBTreeMap<u64, CheckInWindow, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(161)))
+    ));
+}
+
+// Update function returning the caller's signed ticket for an event they attend,
+// issuing one on the first call
+#[ic_cdk::update]
+async fn get_my_ticket(event_id: u64) -> Result<Ticket, Error> {
+    let _metrics = metrics::track("get_my_ticket");
+    // Events held by a bucket or dedicated canister keep their tickets there
+    if let Some(bucket) = shard::bucket_of(event_id) {
+        return shard::forward(bucket, "bucket_get_my_ticket", (caller(), event_id)).await;
+    }
+    _get_ticket(caller(), event_id).await
+}
+
+// Update function that replaces the caller's ticket, invalidating any previously shared token
+#[ic_cdk::update]
+async fn reissue_my_ticket(event_id: u64) -> Result<Ticket, Error> {
+    let _metrics = metrics::track("reissue_my_ticket");
+    if let Some(bucket) = shard::bucket_of(event_id) {
+        return shard::forward(bucket, "bucket_reissue_my_ticket", (caller(), event_id)).await;
+    }
+    _reissue_ticket(caller(), event_id).await
+}
+
+// Query function used at the door to check a scanned ticket token; restricted to the event's
+// organizer and staff
+#[ic_cdk::query(composite = true)]
+async fn validate_ticket(event_id: u64, token: String) -> Result<TicketValidation, Error> {
+    if let Some(bucket) = shard::bucket_of(event_id) {
+        return shard::forward(bucket, "bucket_validate_ticket", (caller(), event_id, token)).await;
+    }
+    _validate_ticket(caller(), event_id, token)
+}
+
+// Update function used at the door to admit the holder of a scanned ticket. Each holder is
+// checked in once; restricted to the event's organizer and staff.
+#[ic_cdk::update]
+async fn check_in(event_id: u64, token: String) -> Result<CheckIn, Error> {
+    let _metrics = metrics::track("check_in");
+    if let Some(bucket) = shard::bucket_of(event_id) {
+        return shard::forward(bucket, "bucket_check_in", (caller(), event_id, token)).await;
+    }
+    _check_in(caller(), event_id, token)
+}
+
+// Helper function checking in the holder of a ticket scanned by the given principal
+pub(crate) fn _check_in(
+    principal: Principal,
+    event_id: u64,
+    token: String,
+) -> Result<CheckIn, Error> {
+    let validation = _validate_ticket(principal, event_id, token)?;
+    _check_in_window(event_id)?;
+    let key = EventPrincipalKey {
+        event_id,
+        principal: validation.attendee.clone(),
+    };
+    if let Some(previous) = CHECK_INS.with(|c| c.borrow().get(&key)) {
+        return Err(Error::InvalidTicket {
+            msg: format!("ticket holder was already checked in at {}", previous.checked_in_at),
+        });
+    }
+    let check_in = CheckIn {
+        event_id,
+        attendee: validation.attendee,
+        checked_in_at: time(),
+        checked_in_by: principal,
+    };
+    CHECK_INS.with(|c| c.borrow_mut().insert(key, check_in.clone()));
+    points::record_check_in(event_id, &check_in.attendee);
+    achievements::record_check_in(event_id, &check_in.attendee);
+    deposits::return_deposit(event_id, &check_in.attendee);
+    Ok(check_in)
+}
+
+// Update function for an event's organizer to set when its tickets can be checked in
+#[ic_cdk::update]
+fn set_check_in_window(event_id: u64, window: CheckInWindow) -> Result<CheckInWindow, Error> {
+    let _metrics = metrics::track("set_check_in_window");
+    _get_organized_event(event_id)?;
+    if window.opens_before_secs.checked_mul(NANOS_PER_SEC).is_none()
+        || window.closes_after_secs.checked_mul(NANOS_PER_SEC).is_none()
+    {
+        return Err(Error::InvalidPayload {
+            msg: "check-in window is too long".to_string(),
+        });
+    }
+    CHECK_IN_WINDOWS.with(|w| w.borrow_mut().insert(event_id, window.clone()));
+    Ok(window)
+}
+
+// Query function returning when an event's tickets can be checked in
+#[ic_cdk::query]
+fn get_check_in_window(event_id: u64) -> CheckInWindow {
+    window_of(event_id)
+}
+
+// Query function listing who has been checked in to an event; restricted to the event's
+// organizer and staff
+#[ic_cdk::query]
+fn get_check_ins(event_id: u64) -> Result<Vec<CheckIn>, Error> {
+    _get_door_event(caller(), event_id)?;
+    let start = EventPrincipalKey {
+        event_id,
+        principal: String::new(),
+    };
+    Ok(CHECK_INS.with(|c| {
+        c.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(_, check_in)| check_in)
+            .collect()
+    }))
+}
+
+// Helper function returning the principal's ticket, issuing one on the first call
+pub(crate) async fn _get_ticket(principal: Principal, event_id: u64) -> Result<Ticket, Error> {
+    let attendee = principal.to_string();
+    _check_attendee(principal, event_id)?;
+
+    let key = EventPrincipalKey {
+        event_id,
+        principal: attendee.clone(),
+    };
+    let record = match TICKETS.with(|t| t.borrow().get(&key)) {
+        Some(record) => record,
+        None => issue_ticket(key, 0).await?,
+    };
+    ticket_for(event_id, attendee, &record)
+}
+
+// Helper function replacing the principal's ticket with one signed over a new nonce
+pub(crate) async fn _reissue_ticket(principal: Principal, event_id: u64) -> Result<Ticket, Error> {
+    let attendee = principal.to_string();
+    _check_attendee(principal, event_id)?;
+
+    let key = EventPrincipalKey {
+        event_id,
+        principal: attendee.clone(),
+    };
+    let previous_nonce = TICKETS
+        .with(|t| t.borrow().get(&key))
+        .map(|record| record.nonce)
+        .unwrap_or_default();
+    let record = issue_ticket(key, previous_nonce).await?;
+    ticket_for(event_id, attendee, &record)
+}
+
+// Helper function checking a scanned token on behalf of the principal operating the scanner
+pub(crate) fn _validate_ticket(
+    principal: Principal,
+    event_id: u64,
+    token: String,
+) -> Result<TicketValidation, Error> {
+    let event = _get_door_event(principal, event_id)?;
+
+    let invalid = |msg: &str| Error::InvalidTicket { msg: msg.to_string() };
+    let parts: Vec<&str> = token.split(TOKEN_SEPARATOR).collect();
+    let [token_event_id, attendee, nonce, signature] = parts[..] else {
+        return Err(invalid("malformed ticket token"));
+    };
+    if token_event_id.parse::<u64>().ok() != Some(event_id) {
+        return Err(invalid("ticket was issued for a different event"));
+    }
+    let nonce = nonce.parse::<u64>().map_err(|_| invalid("malformed ticket nonce"))?;
+    let signature = hex_decode(signature).ok_or_else(|| invalid("malformed ticket signature"))?;
+
+    let mac = ticket_mac(event_id, attendee, nonce).ok_or_else(|| invalid("no tickets have been issued yet"))?;
+    mac.verify_slice(&signature)
+        .map_err(|_| invalid("ticket signature does not match"))?;
+
+    let key = EventPrincipalKey {
+        event_id,
+        principal: attendee.to_string(),
+    };
+    let record = TICKETS
+        .with(|t| t.borrow().get(&key))
+        .filter(|record| record.nonce == nonce)
+        .ok_or_else(|| invalid("ticket has been revoked"))?;
+    if !attendees::is_attending(event.id, attendee) {
+        return Err(invalid("ticket holder is no longer an attendee"));
+    }
+
+    Ok(TicketValidation {
+        event_id,
+        attendee: attendee.to_string(),
+        issued_at: record.issued_at,
+    })
+}
+
+// Helper function returning an event the principal may work the door for
+pub(crate) fn _get_door_event(principal: Principal, event_id: u64) -> Result<Event, Error> {
+    let event = _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    if !staff::can_scan(&event, &principal) {
+        return Err(Error::NotAuthorized {
+            msg: format!("You're not allowed to validate tickets for the event with id={}", event_id),
+            caller: principal,
+        });
+    }
+    Ok(event)
+}
+
+// Helper function rejecting check-ins outside the event's window. Events without a start time
+// have no window to enforce.
+fn _check_in_window(event_id: u64) -> Result<(), Error> {
+    let window = window_of(event_id);
+    let Some(event) = _get_event(&event_id) else {
+        return Ok(());
+    };
+    let Some(starts_at) = event.starts_at else {
+        return Ok(());
+    };
+    if window.always_open {
+        return Ok(());
+    }
+    let ends_at = event
+        .ends_at
+        .unwrap_or_else(|| starts_at.saturating_add(schedule::DEFAULT_DURATION_NANOS));
+    let opens_at = starts_at.saturating_sub(window.opens_before_secs * NANOS_PER_SEC);
+    let closes_at = ends_at.saturating_add(window.closes_after_secs * NANOS_PER_SEC);
+    let now = time();
+    if now < opens_at {
+        return Err(Error::InvalidTicket {
+            msg: format!("check-in for this event opens at {}", opens_at),
+        });
+    }
+    if now > closes_at {
+        return Err(Error::InvalidTicket {
+            msg: format!("check-in for this event closed at {}", closes_at),
+        });
+    }
+    Ok(())
+}
+
+fn window_of(event_id: u64) -> CheckInWindow {
+    CHECK_IN_WINDOWS.with(|w| w.borrow().get(&event_id)).unwrap_or_default()
+}
+
+// Whether the holder of the ticket has been checked in at the door
+pub(crate) fn is_checked_in(key: &EventPrincipalKey) -> bool {
+    CHECK_INS.with(|c| c.borrow().contains_key(key))
+}
+
+// When the attendee was checked in at the door, if they were
+pub(crate) fn checked_in_at(key: &EventPrincipalKey) -> Option<u64> {
+    CHECK_INS.with(|c| c.borrow().get(key)).map(|check_in| check_in.checked_in_at)
+}
+
+// Helper function to stop non-attendees from obtaining tickets
+fn _check_attendee(principal: Principal, event_id: u64) -> Result<(), Error> {
+    _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    if !attendees::is_attending(event_id, &principal.to_string()) {
+        return Err(Error::NotAuthorized {
+            msg: format!("You're not an attendee of the event with id={}", event_id),
+            caller: principal,
+        });
+    }
+    Ok(())
+}
+
+// Helper function to store a fresh ticket record with a nonce different from the previous one
+async fn issue_ticket(key: EventPrincipalKey, previous_nonce: u64) -> Result<TicketRecord, Error> {
+    ensure_ticket_secret().await?;
+    let record = TicketRecord {
+        nonce: previous_nonce + 1,
+        issued_at: time(),
+    };
+    TICKETS.with(|t| t.borrow_mut().insert(key, record.clone()));
+    Ok(record)
+}
+
+// Helper function to lazily generate the canister's ticket signing secret
+async fn ensure_ticket_secret() -> Result<(), Error> {
+    if TICKET_SECRET.with(|s| !s.borrow().get().is_empty()) {
+        return Ok(());
+    }
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|err| _call_failed("raw_rand", err))?;
+    // Another call may have set the secret while this one was awaiting
+    TICKET_SECRET.with(|s| {
+        if s.borrow().get().is_empty() {
+            s.borrow_mut()
+                .set(bytes)
+                .expect("cannot store the ticket secret");
+        }
+    });
+    Ok(())
+}
+
+// Helper function to assemble the token an attendee presents at the door
+fn ticket_for(event_id: u64, attendee: String, record: &TicketRecord) -> Result<Ticket, Error> {
+    let mac = ticket_mac(event_id, &attendee, record.nonce).ok_or(Error::InvalidTicket {
+        msg: "ticket secret is not initialised".to_string(),
+    })?;
+    let signature = hex_encode(&mac.finalize().into_bytes());
+    let token = format!(
+        "{}{sep}{}{sep}{}{sep}{}",
+        event_id,
+        attendee,
+        record.nonce,
+        signature,
+        sep = TOKEN_SEPARATOR
+    );
+    Ok(Ticket {
+        event_id,
+        attendee,
+        token,
+        issued_at: record.issued_at,
+    })
+}
+
+// Helper function returning an HMAC over the ticket fields keyed with the canister secret
+fn ticket_mac(event_id: u64, attendee: &str, nonce: u64) -> Option<HmacSha256> {
+    TICKET_SECRET.with(|s| {
+        let secret = s.borrow().get().clone();
+        if secret.is_empty() {
+            return None;
+        }
+        let mut mac = HmacSha256::new_from_slice(&secret).expect("HMAC accepts keys of any size");
+        mac.update(format!("{}:{}:{}", event_id, attendee, nonce).as_bytes());
+        Some(mac)
+    })
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,987 @@
+// Ticket tiers. An organizer can sell an event's seats in tiers, each with its own price,
+// capacity and sale window. Events with tiers are joined through `purchase_ticket`
+// instead of a free `attend_event` RSVP.
+//
+// A tier's price can change over time through price phases (early-bird pricing and the
+// like); a timer set for the next phase boundary switches the tier's active price. A tier
+// can also have a demand pricing curve raising its price as it sells out; the markup is
+// worked out from the seats sold when a ticket is bought and recorded on its order.
+use crate::seating::{self, SeatRef};
+use crate::{google_calendar, limits, metrics, orders, payments, points, promos, schedule, tokens};
+use crate::activity::{self, ActivityKind};
+use crate::flags::{self, FlaggedFeature};
+use crate::{analytics, attendees, beta, groups, ledger, verification};
+use crate::status::_check_open_for_registration;
+use crate::{
+    Error, Event, EventPrincipalKey, Memory, MEMORY_MANAGER, _add_attendee, _get_event, _get_organized_event,
+};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::api::id;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::time::Duration;
+
+// Keeps a tier within its 512-byte storage bound
+const MAX_TIER_NAME_BYTES: usize = 200;
+const MAX_PRICE_PHASES: usize = 16;
+const MAX_DEMAND_STEPS: usize = 16;
+// At most ten times the tier's price
+const MAX_MARKUP_BPS: u32 = 90_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TicketTier {
+    id: u64,
+    name: String,
+    // Price in the smallest unit of the event's main token; 0 for free tiers
+    price: u128,
+    capacity: u32,
+    sold: u32,
+    // Sales are open from sale_start (inclusive) to sale_end (exclusive), unbounded when unset
+    sale_start: Option<u64>,
+    sale_end: Option<u64>,
+}
+
+impl_storable!(TicketTier, 512);
+
+// Tier payload for creating or updating a tier
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TierPayload {
+    name: String,
+    price: u128,
+    capacity: u32,
+    sale_start: Option<u64>,
+    sale_end: Option<u64>,
+}
+
+// A scheduled change of a tier's price, e.g. the end of early-bird pricing
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PricePhase {
+    starts_at: u64,
+    price: u128,
+}
+
+// Phases of a tier that have not started yet, ordered by start time
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PriceSchedule {
+    phases: Vec<PricePhase>,
+}
+
+impl_storable!(PriceSchedule, 1024);
+
+// A step of a demand pricing curve: once `sold_percent` of the tier's seats are sold, its
+// price is raised by `markup_bps` basis points
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DemandStep {
+    sold_percent: u8,
+    markup_bps: u32,
+}
+
+// Steps of a tier's demand pricing, ordered by sold_percent
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DemandCurve {
+    steps: Vec<DemandStep>,
+}
+
+impl_storable!(DemandCurve, 512);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct TierKey {
+    event_id: u64,
+    tier_id: u64,
+}
+
+impl_storable!(TierKey, 32);
+
+// The tier a principal bought into for an event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TicketPurchase {
+    event_id: u64,
+    tier_id: u64,
+    // Price actually paid, after any promo code discount
+    price: u128,
+    // Normalized, so at most promos::MAX_CODE_CHARS ASCII characters
+    promo_code: Option<String>,
+    // Reserved seat, for events with a seating chart
+    seat: Option<SeatRef>,
+    // Ledger block of the payment; None for free tiers
+    block_index: Option<u64>,
+    purchased_at: u64,
+}
+
+impl_storable!(TicketPurchase, 256);
+
+// A seat in a tier held for a buyer while their payment is taken
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct HeldTicket {
+    pub(crate) event_id: u64,
+    pub(crate) tier_id: u64,
+    pub(crate) buyer: Principal,
+    pub(crate) ledger: Principal,
+    // The tier's price on the ledger, and the price to pay after any promo code discount
+    pub(crate) tier_price: u128,
+    pub(crate) price: u128,
+    pub(crate) promo_code: Option<String>,
+    pub(crate) seat: Option<SeatRef>,
+    // Markup of the tier's demand pricing included in tier_price
+    pub(crate) demand_markup_bps: Option<u32>,
+}
+
+// What a ticket costs and what the buyer has to approve before `purchase_ticket`
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CheckoutQuote {
+    event_id: u64,
+    tier_id: u64,
+    ledger: Principal,
+    // Account to approve: this canister
+    spender: Principal,
+    // Price after any promo code discount, in the ledger's smallest unit
+    price: u128,
+    // Markup of the tier's demand pricing at its current sales, included in the price
+    demand_markup_bps: Option<u32>,
+    ledger_fee: u128,
+    // Price plus the ledger fee, which is taken from the allowance too; 0 for free tickets
+    approve_amount: u128,
+    // What the buyer has approved so far, and until when
+    allowance: u128,
+    allowance_expires_at: Option<u64>,
+    needs_approval: bool,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TierAvailability {
+    tier_id: u64,
+    name: String,
+    // Current price, including any demand pricing markup
+    price: u128,
+    demand_markup_bps: Option<u32>,
+    capacity: u32,
+    remaining: u32,
+    on_sale: bool,
+}
+
+thread_local! {
+    static TIERS: RefCell<StableBTreeMap<TierKey, TicketTier, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    static PURCHASES: RefCell<StableBTreeMap<EventPrincipalKey, TicketPurchase, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    static PRICE_SCHEDULES: RefCell<StableBTreeMap<TierKey, PriceSchedule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    static DEMAND_CURVES: RefCell<StableBTreeMap<TierKey, DemandCurve, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(154)))
+    ));
+
+    // Timer set for the next phase boundary across all tiers
+    static PRICE_PHASE_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+
+    // Guards against a buyer starting a second purchase for an event while a payment is in flight
+    static PURCHASING: RefCell<Vec<EventPrincipalKey>> = const { RefCell::new(Vec::new()) };
+}
+
+// Update function for an event's organizer to add a ticket tier
+#[ic_cdk::update]
+fn create_ticket_tier(event_id: u64, payload: TierPayload) -> Result<TicketTier, Error> {
+    let _metrics = metrics::track("create_ticket_tier");
+    let event = _get_organized_event(event_id)?;
+    validate_tier_payload(&payload)?;
+    verification::_check_price(&event.owner, tokens::main_ledger(event_id), payload.price)?;
+
+    let id = tiers_of(event_id)
+        .last()
+        .map(|tier| tier.id + 1)
+        .unwrap_or_default();
+    let tier = TicketTier {
+        id,
+        name: payload.name,
+        price: payload.price,
+        capacity: payload.capacity,
+        sold: 0,
+        sale_start: payload.sale_start,
+        sale_end: payload.sale_end,
+    };
+    do_insert_tier(event_id, &tier);
+    Ok(tier)
+}
+
+// Update function for an event's organizer to modify a tier; capacity cannot drop below
+// the seats already sold
+#[ic_cdk::update]
+fn update_ticket_tier(event_id: u64, tier_id: u64, payload: TierPayload) -> Result<TicketTier, Error> {
+    let _metrics = metrics::track("update_ticket_tier");
+    let event = _get_organized_event(event_id)?;
+    validate_tier_payload(&payload)?;
+    verification::_check_price(&event.owner, tokens::main_ledger(event_id), payload.price)?;
+
+    let mut tier = _get_tier(event_id, tier_id)?;
+    if payload.capacity < tier.sold {
+        return Err(Error::InvalidPayload {
+            msg: format!("{} seats of this tier are already sold", tier.sold),
+        });
+    }
+    tier.name = payload.name;
+    tier.price = payload.price;
+    tier.capacity = payload.capacity;
+    tier.sale_start = payload.sale_start;
+    tier.sale_end = payload.sale_end;
+
+    do_insert_tier(event_id, &tier);
+    Ok(tier)
+}
+
+// Query function listing the ticket tiers of an event
+#[ic_cdk::query]
+fn get_ticket_tiers(event_id: u64) -> Vec<TicketTier> {
+    tiers_of(event_id)
+}
+
+// Update function for an event's organizer to schedule price changes for a tier, replacing
+// any pending ones. Phases whose start time has already passed take effect immediately.
+#[ic_cdk::update]
+fn set_price_phases(event_id: u64, tier_id: u64, mut phases: Vec<PricePhase>) -> Result<TicketTier, Error> {
+    let _metrics = metrics::track("set_price_phases");
+    let event = _get_organized_event(event_id)?;
+    _get_tier(event_id, tier_id)?;
+    for phase in &phases {
+        verification::_check_price(&event.owner, tokens::main_ledger(event_id), phase.price)?;
+    }
+    if phases.len() > MAX_PRICE_PHASES {
+        return Err(Error::InvalidPayload {
+            msg: format!("a tier can have at most {} price phases", MAX_PRICE_PHASES),
+        });
+    }
+    phases.sort_by_key(|phase| phase.starts_at);
+    if phases.windows(2).any(|pair| pair[0].starts_at == pair[1].starts_at) {
+        return Err(Error::InvalidPayload {
+            msg: "price phases must start at distinct times".to_string(),
+        });
+    }
+
+    let key = TierKey { event_id, tier_id };
+    PRICE_SCHEDULES.with(|s| s.borrow_mut().insert(key.clone(), PriceSchedule { phases }));
+    apply_due_price_phases(&key);
+    schedule_price_phases();
+    _get_tier(event_id, tier_id)
+}
+
+// Query function listing the price changes still scheduled for a tier
+#[ic_cdk::query]
+fn get_price_phases(event_id: u64, tier_id: u64) -> Vec<PricePhase> {
+    PRICE_SCHEDULES
+        .with(|s| s.borrow().get(&TierKey { event_id, tier_id }))
+        .map(|schedule| schedule.phases)
+        .unwrap_or_default()
+}
+
+// Update function for an event's organizer to set a tier's demand pricing curve, replacing
+// any previous one; an empty curve turns demand pricing off
+#[ic_cdk::update]
+fn set_demand_pricing(
+    event_id: u64,
+    tier_id: u64,
+    mut steps: Vec<DemandStep>,
+) -> Result<Vec<DemandStep>, Error> {
+    let _metrics = metrics::track("set_demand_pricing");
+    let event = _get_organized_event(event_id)?;
+    let tier = _get_tier(event_id, tier_id)?;
+    let key = TierKey { event_id, tier_id };
+    if steps.is_empty() {
+        DEMAND_CURVES.with(|c| c.borrow_mut().remove(&key));
+        return Ok(steps);
+    }
+    steps.sort_by_key(|step| step.sold_percent);
+    validate_demand_steps(&steps)?;
+    let top_markup = steps.last().map(|step| step.markup_bps);
+    verification::_check_price(
+        &event.owner,
+        tokens::main_ledger(event_id),
+        with_markup(tier.price, top_markup),
+    )?;
+    DEMAND_CURVES.with(|c| {
+        c.borrow_mut().insert(key, DemandCurve { steps: steps.clone() })
+    });
+    Ok(steps)
+}
+
+// Query function returning a tier's demand pricing curve, empty when it has none
+#[ic_cdk::query]
+fn get_demand_pricing(event_id: u64, tier_id: u64) -> Vec<DemandStep> {
+    DEMAND_CURVES
+        .with(|c| c.borrow().get(&TierKey { event_id, tier_id }))
+        .map(|curve| curve.steps)
+        .unwrap_or_default()
+}
+
+// Query function reporting the seats left in each tier and whether it is on sale now
+#[ic_cdk::query]
+fn get_tier_availability(event_id: u64) -> Result<Vec<TierAvailability>, Error> {
+    _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    let now = time();
+    Ok(tiers_of(event_id)
+        .into_iter()
+        .map(|tier| {
+            let demand_markup_bps = demand_markup(event_id, &tier);
+            TierAvailability {
+                tier_id: tier.id,
+                on_sale: _check_sale_window(&tier, now).is_ok(),
+                remaining: tier.capacity.saturating_sub(tier.sold),
+                price: with_markup(tier.price, demand_markup_bps),
+                demand_markup_bps,
+                name: tier.name,
+                capacity: tier.capacity,
+            }
+        })
+        .collect())
+}
+
+// Update function quoting a ticket before checkout: the price to pay on the given ledger or
+// else the event's main token, and the ICRC-2 allowance the caller has to grant this canister
+// for it. Once it is approved, `purchase_ticket` pulls the payment and registers the caller
+// in a single call. Nothing is reserved, and promo code uses aren't claimed.
+#[ic_cdk::update]
+async fn prepare_checkout(
+    event_id: u64,
+    tier_id: u64,
+    promo_code: Option<String>,
+    ledger: Option<Principal>,
+) -> Result<CheckoutQuote, Error> {
+    let _metrics = metrics::track("prepare_checkout");
+    flags::_check_enabled(FlaggedFeature::Payments)?;
+    _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    _check_open_for_registration(event_id)?;
+    apply_due_price_phases(&TierKey { event_id, tier_id });
+    let tier = _get_tier(event_id, tier_id)?;
+    _check_sale_window(&tier, time())?;
+    let ledger = tokens::_payment_ledger(event_id, ledger)?;
+    let demand_markup_bps = demand_markup(event_id, &tier);
+    let tier_price = with_markup(
+        tokens::_price_on(event_id, tier_id, tier.price, ledger)?,
+        demand_markup_bps,
+    );
+    if tokens::main_ledger(event_id) != Some(ledger)
+        && promo_code.as_ref().is_some_and(|code| promos::is_fixed_amount(event_id, code))
+    {
+        return Err(Error::InvalidPromoCode {
+            msg: "this promo code only applies to payments in the event's main token".to_string(),
+        });
+    }
+    let price = match &promo_code {
+        Some(code) => promos::_preview(event_id, code, tier_price)?,
+        None => tier_price,
+    };
+    tokens::_check_min_amount(ledger, price)?;
+    let mut quote = CheckoutQuote {
+        event_id,
+        tier_id,
+        ledger,
+        spender: id(),
+        price,
+        demand_markup_bps,
+        ledger_fee: 0,
+        approve_amount: 0,
+        allowance: 0,
+        allowance_expires_at: None,
+        needs_approval: false,
+    };
+    if price == 0 {
+        return Ok(quote);
+    }
+    quote.ledger_fee = ledger::fee(ledger)
+        .await
+        .map_err(|msg| Error::CallFailed { msg })?;
+    let (allowance, expires_at) = ledger::allowance(ledger, caller())
+        .await
+        .map_err(|msg| Error::CallFailed { msg })?;
+    quote.approve_amount = price.saturating_add(quote.ledger_fee);
+    quote.allowance = allowance;
+    quote.allowance_expires_at = expires_at;
+    quote.needs_approval = allowance < quote.approve_amount;
+    Ok(quote)
+}
+
+// Update function to buy a seat in a tier, optionally with a promo code and a specific seat
+// from the event's seating chart. Paid tiers are charged through an ICRC-2 allowance the
+// caller granted this canister, on the given ledger or else the event's main token.
+#[ic_cdk::update]
+async fn purchase_ticket(
+    event_id: u64,
+    tier_id: u64,
+    promo_code: Option<String>,
+    seat: Option<SeatRef>,
+    ledger: Option<Principal>,
+) -> Result<TicketPurchase, Error> {
+    let _metrics = metrics::track("purchase_ticket");
+    beta::_check_allowlisted()?;
+    flags::_check_enabled(FlaggedFeature::Payments)?;
+    let hold_until = time() + seating::HOLD_DURATION_NANOS;
+    let held = _hold_ticket(event_id, tier_id, promo_code, seat, ledger, caller(), hold_until)?;
+    let block_index = if held.price > 0 {
+        let key = EventPrincipalKey {
+            event_id,
+            principal: held.buyer.to_string(),
+        };
+        start_purchasing(&key);
+        let charged = payments::charge(event_id, held.buyer, held.ledger, held.price).await;
+        finish_purchasing(&key);
+        match charged {
+            Ok(block_index) => Some(block_index),
+            Err(err) => {
+                release_ticket(&held);
+                return Err(err);
+            }
+        }
+    } else {
+        None
+    };
+    _complete_purchase(held, block_index)
+}
+
+// Helper function checking that the buyer can buy a seat in a tier and holding it for them,
+// along with the seat from the seating chart until `hold_until` and a use of the promo code,
+// while the payment is taken; returns the price to pay. Holding before awaiting the ledger
+// keeps concurrent buyers from overselling the tier or paying for the same seat.
+pub(crate) fn _hold_ticket(
+    event_id: u64,
+    tier_id: u64,
+    promo_code: Option<String>,
+    seat: Option<SeatRef>,
+    ledger: Option<Principal>,
+    buyer: Principal,
+    hold_until: u64,
+) -> Result<HeldTicket, Error> {
+    let event = _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    _check_open_for_registration(event_id)?;
+    if attendees::is_attending(event_id, &buyer.to_string()) {
+        return Err(Error::InvalidPayload {
+            msg: "You are already an attendee".to_string(),
+        });
+    }
+    if is_purchasing(event_id, buyer) {
+        return Err(Error::InvalidPayload {
+            msg: "Your payment for this event is still in progress".to_string(),
+        });
+    }
+    if groups::has_group(event_id, buyer) {
+        return Err(Error::InvalidPayload {
+            msg: "You bought a group of tickets; assign one of them to yourself".to_string(),
+        });
+    }
+    _check_event_seats(&event, 1)?;
+
+    // Don't sell at a stale price if the phase timer hasn't fired yet
+    apply_due_price_phases(&TierKey { event_id, tier_id });
+
+    let mut tier = _get_tier(event_id, tier_id)?;
+    _check_sale_window(&tier, time())?;
+    let ledger = tokens::_payment_ledger(event_id, ledger)?;
+    // Priced on the seats sold before this one
+    let demand_markup_bps = demand_markup(event_id, &tier);
+    let tier_price = with_markup(
+        tokens::_price_on(event_id, tier_id, tier.price, ledger)?,
+        demand_markup_bps,
+    );
+    if tokens::main_ledger(event_id) != Some(ledger)
+        && promo_code.as_ref().is_some_and(|code| promos::is_fixed_amount(event_id, code))
+    {
+        return Err(Error::InvalidPromoCode {
+            msg: "this promo code only applies to payments in the event's main token".to_string(),
+        });
+    }
+    limits::_check_purchase_limits(event_id, buyer, Some(tier_id), 1)?;
+    if let Some(seat) = &seat {
+        seating::_check_seat(event_id, seat, Some(tier_id))?;
+    }
+    if tier.sold >= tier.capacity {
+        return Err(Error::TierSoldOut {
+            msg: format!("Tier \"{}\" is sold out", tier.name),
+        });
+    }
+    if let Some(seat) = &seat {
+        seating::reserve_for_purchase(event_id, seat, buyer, hold_until)?;
+    }
+    tier.sold += 1;
+    do_insert_tier(event_id, &tier);
+
+    let mut held = HeldTicket {
+        event_id,
+        tier_id,
+        buyer,
+        ledger,
+        tier_price,
+        price: tier_price,
+        promo_code: None,
+        seat,
+        demand_markup_bps,
+    };
+    if let Some(code) = promo_code {
+        match promos::redeem(event_id, &code, tier_price) {
+            Ok(price) => {
+                held.price = price;
+                held.promo_code = Some(promos::normalize_code(&code));
+            }
+            Err(err) => {
+                release_ticket(&held);
+                return Err(err);
+            }
+        }
+    }
+    Ok(held)
+}
+
+// Helper function holding `quantity` seats in a tier for a group purchase while its payment
+// is taken. Group tickets are priced like single ones, without promo codes or seat picks;
+// the held ticket carries the price of one of them.
+pub(crate) fn _hold_group(
+    event_id: u64,
+    tier_id: u64,
+    quantity: u32,
+    ledger: Option<Principal>,
+    buyer: Principal,
+) -> Result<HeldTicket, Error> {
+    let event = _get_event(&event_id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", event_id),
+    })?;
+    _check_open_for_registration(event_id)?;
+    if is_purchasing(event_id, buyer) {
+        return Err(Error::InvalidPayload {
+            msg: "Your payment for this event is still in progress".to_string(),
+        });
+    }
+    _check_event_seats(&event, quantity)?;
+    apply_due_price_phases(&TierKey { event_id, tier_id });
+
+    let mut tier = _get_tier(event_id, tier_id)?;
+    _check_sale_window(&tier, time())?;
+    let ledger = tokens::_payment_ledger(event_id, ledger)?;
+    let demand_markup_bps = demand_markup(event_id, &tier);
+    let tier_price = with_markup(
+        tokens::_price_on(event_id, tier_id, tier.price, ledger)?,
+        demand_markup_bps,
+    );
+    limits::_check_purchase_limits(event_id, buyer, Some(tier_id), quantity)?;
+    if tier.capacity.saturating_sub(tier.sold) < quantity {
+        return Err(Error::TierSoldOut {
+            msg: format!("Tier \"{}\" doesn't have {} seats left", tier.name, quantity),
+        });
+    }
+    tier.sold += quantity;
+    do_insert_tier(event_id, &tier);
+    Ok(HeldTicket {
+        event_id,
+        tier_id,
+        buyer,
+        ledger,
+        tier_price,
+        price: tier_price,
+        promo_code: None,
+        seat: None,
+        demand_markup_bps,
+    })
+}
+
+// Gives back seats held for a group purchase that did not go through or was refunded
+pub(crate) fn release_group(event_id: u64, tier_id: u64, quantity: u32) {
+    if let Ok(mut tier) = _get_tier(event_id, tier_id) {
+        tier.sold = tier.sold.saturating_sub(quantity);
+        do_insert_tier(event_id, &tier);
+    }
+}
+
+// Helper function registering the holder of a ticket from a paid group purchase
+pub(crate) fn _register_group_ticket(
+    event_id: u64,
+    tier_id: u64,
+    holder: Principal,
+    price: u128,
+    block_index: Option<u64>,
+) -> Result<TicketPurchase, Error> {
+    _check_open_for_registration(event_id)?;
+    let event = _add_attendee(holder, event_id)?;
+    let key = EventPrincipalKey {
+        event_id,
+        principal: holder.to_string(),
+    };
+    welcome(&event, &key.principal);
+    let purchase = TicketPurchase {
+        event_id,
+        tier_id,
+        price,
+        promo_code: None,
+        seat: None,
+        block_index,
+        purchased_at: time(),
+    };
+    PURCHASES.with(|p| p.borrow_mut().insert(key, purchase.clone()));
+    Ok(purchase)
+}
+
+// Helper function checking whether the buyer's payment for a ticket to the event is in flight
+fn is_purchasing(event_id: u64, buyer: Principal) -> bool {
+    let key = EventPrincipalKey {
+        event_id,
+        principal: buyer.to_string(),
+    };
+    PURCHASING.with(|p| p.borrow().contains(&key))
+}
+
+// Marks the buyer's payment for a ticket or a group of tickets to the event as in flight
+pub(crate) fn start_purchasing(key: &EventPrincipalKey) {
+    PURCHASING.with(|p| p.borrow_mut().push(key.clone()));
+}
+
+// Clears the in-flight mark once the buyer's payment settled, either way
+pub(crate) fn finish_purchasing(key: &EventPrincipalKey) {
+    PURCHASING.with(|p| p.borrow_mut().retain(|purchasing| purchasing != key));
+}
+
+// Gives back the seat and promo code use held for a purchase that did not go through
+pub(crate) fn release_ticket(held: &HeldTicket) {
+    release_seat(held.event_id, held.tier_id);
+    if let Some(seat) = &held.seat {
+        seating::release(held.event_id, seat, held.buyer);
+    }
+    if let Some(code) = &held.promo_code {
+        promos::release(held.event_id, code);
+    }
+}
+
+// Helper function registering the buyer of a held ticket once it is paid for. If the event
+// filled up or was cancelled in the meantime, the hold is released and the payment refunded.
+pub(crate) fn _complete_purchase(
+    held: HeldTicket,
+    block_index: Option<u64>,
+) -> Result<TicketPurchase, Error> {
+    let HeldTicket {
+        event_id,
+        tier_id,
+        buyer,
+        ..
+    } = held;
+    let key = EventPrincipalKey {
+        event_id,
+        principal: buyer.to_string(),
+    };
+    let admitted = _check_open_for_registration(event_id).and_then(|_| _add_attendee(buyer, event_id));
+    let event = match admitted {
+        Ok(event) => event,
+        Err(err) => {
+            release_ticket(&held);
+            if let Some(block_index) = block_index {
+                payments::enqueue_refund(&key, block_index);
+            }
+            return Err(err);
+        }
+    };
+
+    if let Some(code) = &held.promo_code {
+        promos::record_redemption(event_id, code, buyer, held.tier_price, held.price);
+    }
+    limits::record_purchase(event_id, buyer, Some(tier_id), 1);
+    analytics::record_sale(&held);
+    orders::record_ticket(&key, &held, block_index);
+    welcome(&event, &key.principal);
+    if let Some(seat) = &held.seat {
+        seating::confirm_sale(event_id, seat, buyer);
+    }
+    let purchase = TicketPurchase {
+        event_id,
+        tier_id,
+        price: held.price,
+        promo_code: held.promo_code,
+        seat: held.seat,
+        block_index,
+        purchased_at: time(),
+    };
+    PURCHASES.with(|p| p.borrow_mut().insert(key, purchase.clone()));
+    Ok(purchase)
+}
+
+// Gives a new ticket holder their points, schedule entry and calendar invite
+fn welcome(event: &Event, principal: &str) {
+    points::record_attendance(event, principal);
+    schedule::record(event, principal);
+    google_calendar::enqueue(event.id, principal);
+    activity::record(principal, ActivityKind::Rsvped, event.id, &event.event_title);
+}
+
+// Sets a timer for the earliest pending price phase of any tier; called whenever the
+// schedules change and from post_upgrade, since timers do not survive upgrades
+pub(crate) fn schedule_price_phases() {
+    if let Some(timer) = PRICE_PHASE_TIMER.with(|t| t.borrow_mut().take()) {
+        ic_cdk_timers::clear_timer(timer);
+    }
+    let next = PRICE_SCHEDULES.with(|s| {
+        s.borrow()
+            .iter()
+            .filter_map(|(_, schedule)| schedule.phases.first().map(|phase| phase.starts_at))
+            .min()
+    });
+    let Some(next) = next else {
+        return;
+    };
+
+    let delay = Duration::from_nanos(next.saturating_sub(time()));
+    let timer = ic_cdk_timers::set_timer(delay, || {
+        let keys: Vec<TierKey> = PRICE_SCHEDULES.with(|s| s.borrow().iter().map(|(key, _)| key).collect());
+        for key in keys {
+            apply_due_price_phases(&key);
+        }
+        schedule_price_phases();
+    });
+    PRICE_PHASE_TIMER.with(|t| *t.borrow_mut() = Some(timer));
+}
+
+// Switches a tier to the price of its latest phase that has started
+fn apply_due_price_phases(key: &TierKey) {
+    let Some(mut schedule) = PRICE_SCHEDULES.with(|s| s.borrow().get(key)) else {
+        return;
+    };
+    let now = time();
+    let due = schedule.phases.iter().take_while(|phase| phase.starts_at <= now).count();
+    if due == 0 {
+        return;
+    }
+
+    let current = schedule.phases.drain(..due).next_back();
+    if let (Some(phase), Ok(mut tier)) = (current, _get_tier(key.event_id, key.tier_id)) {
+        tier.price = phase.price;
+        do_insert_tier(key.event_id, &tier);
+    }
+    PRICE_SCHEDULES.with(|s| {
+        if schedule.phases.is_empty() {
+            s.borrow_mut().remove(key);
+        } else {
+            s.borrow_mut().insert(key.clone(), schedule);
+        }
+    });
+}
+
+// Returns the face value of a principal's ticket: what they paid for it, so a resale
+// can never turn a profit, even on a discounted ticket
+pub(crate) fn face_value(key: &EventPrincipalKey) -> Option<u128> {
+    PURCHASES.with(|p| p.borrow().get(key)).map(|purchase| purchase.price)
+}
+
+// Returns the name of the tier a principal's ticket was bought in
+pub(crate) fn tier_name_of(key: &EventPrincipalKey) -> Option<String> {
+    let purchase = PURCHASES.with(|p| p.borrow().get(key))?;
+    _get_tier(purchase.event_id, purchase.tier_id).ok().map(|tier| tier.name)
+}
+
+// Returns the tier a principal's ticket was bought in
+pub(crate) fn tier_id_of(key: &EventPrincipalKey) -> Option<u64> {
+    PURCHASES.with(|p| p.borrow().get(key)).map(|purchase| purchase.tier_id)
+}
+
+// Returns the name of one of an event's tiers
+pub(crate) fn tier_name(event_id: u64, tier_id: u64) -> Option<String> {
+    _get_tier(event_id, tier_id).ok().map(|tier| tier.name)
+}
+
+// Moves a purchased ticket to its new holder after a resale
+pub(crate) fn transfer_purchase(from: &EventPrincipalKey, to: EventPrincipalKey, price: u128, block_index: u64) {
+    let Some(purchase) = PURCHASES.with(|p| p.borrow_mut().remove(from)) else {
+        return;
+    };
+    if let (Some(seat), Ok(holder)) = (&purchase.seat, Principal::from_text(&to.principal)) {
+        seating::reassign(to.event_id, seat, holder);
+    }
+    let purchase = TicketPurchase {
+        price,
+        promo_code: None,
+        block_index: Some(block_index),
+        purchased_at: time(),
+        ..purchase
+    };
+    PURCHASES.with(|p| p.borrow_mut().insert(to, purchase));
+}
+
+// Moves a ticket to the recipient of a gift, as it was bought
+pub(crate) fn give_purchase(from: &EventPrincipalKey, to: EventPrincipalKey) {
+    let Some(purchase) = PURCHASES.with(|p| p.borrow_mut().remove(from)) else {
+        return;
+    };
+    if let (Some(seat), Ok(holder)) = (&purchase.seat, Principal::from_text(&to.principal)) {
+        seating::reassign(to.event_id, seat, holder);
+    }
+    PURCHASES.with(|p| p.borrow_mut().insert(to, purchase));
+}
+
+// Helper function to stop free RSVPs to events that sell tickets
+pub(crate) fn _check_free_registration(event_id: u64) -> Result<(), Error> {
+    if tiers_of(event_id).is_empty() {
+        return Ok(());
+    }
+    Err(Error::InvalidPayload {
+        msg: format!("Event with id={} requires a ticket; use purchase_ticket", event_id),
+    })
+}
+
+// The markup a tier's demand pricing applies at its current sales, if it has a curve and
+// the first step is reached
+fn demand_markup(event_id: u64, tier: &TicketTier) -> Option<u32> {
+    let key = TierKey {
+        event_id,
+        tier_id: tier.id,
+    };
+    let curve = DEMAND_CURVES.with(|c| c.borrow().get(&key))?;
+    let sold_percent = u64::from(tier.sold) * 100 / u64::from(tier.capacity.max(1));
+    curve
+        .steps
+        .iter()
+        .rev()
+        .find(|step| u64::from(step.sold_percent) <= sold_percent)
+        .map(|step| step.markup_bps)
+}
+
+fn with_markup(price: u128, markup_bps: Option<u32>) -> u128 {
+    match markup_bps {
+        Some(markup_bps) => price.saturating_mul(10_000 + u128::from(markup_bps)) / 10_000,
+        None => price,
+    }
+}
+
+// Helper function checking that an event has `quantity` seats left, counting the unassigned
+// tickets of group purchases as taken
+fn _check_event_seats(event: &Event, quantity: u32) -> Result<(), Error> {
+    let taken = attendees::count(event.id) + groups::unassigned_seats(event.id);
+    if event
+        .capacity
+        .is_some_and(|capacity| taken + u64::from(quantity) > u64::from(capacity))
+    {
+        return Err(Error::TierSoldOut {
+            msg: format!("Event with id={} is full", event.id),
+        });
+    }
+    Ok(())
+}
+
+fn _check_sale_window(tier: &TicketTier, now: u64) -> Result<(), Error> {
+    if tier.sale_start.is_some_and(|start| now < start) {
+        return Err(Error::SaleNotOpen {
+            msg: format!("Sales for tier \"{}\" have not started yet", tier.name),
+        });
+    }
+    if tier.sale_end.is_some_and(|end| now >= end) {
+        return Err(Error::SaleNotOpen {
+            msg: format!("Sales for tier \"{}\" have ended", tier.name),
+        });
+    }
+    Ok(())
+}
+
+fn _get_tier(event_id: u64, tier_id: u64) -> Result<TicketTier, Error> {
+    TIERS
+        .with(|t| t.borrow().get(&TierKey { event_id, tier_id }))
+        .ok_or(Error::NotFound {
+            msg: format!("Tier with id={} not found for event with id={}", tier_id, event_id),
+        })
+}
+
+fn tiers_of(event_id: u64) -> Vec<TicketTier> {
+    let start = TierKey { event_id, tier_id: 0 };
+    let end = TierKey {
+        event_id,
+        tier_id: u64::MAX,
+    };
+    TIERS.with(|t| t.borrow().range(start..=end).map(|(_, tier)| tier).collect())
+}
+
+// Id, name and current price of each of an event's tiers
+pub(crate) fn prices_of(event_id: u64) -> Vec<(u64, String, u128)> {
+    tiers_of(event_id)
+        .into_iter()
+        .map(|tier| (tier.id, tier.name, tier.price))
+        .collect()
+}
+
+pub(crate) fn has_tier(event_id: u64, tier_id: u64) -> bool {
+    TIERS.with(|t| t.borrow().contains_key(&TierKey { event_id, tier_id }))
+}
+
+fn release_seat(event_id: u64, tier_id: u64) {
+    if let Ok(mut tier) = _get_tier(event_id, tier_id) {
+        tier.sold = tier.sold.saturating_sub(1);
+        do_insert_tier(event_id, &tier);
+    }
+}
+
+// Helper method to insert a tier.
+fn do_insert_tier(event_id: u64, tier: &TicketTier) {
+    TIERS.with(|t| {
+        t.borrow_mut().insert(
+            TierKey {
+                event_id,
+                tier_id: tier.id,
+            },
+            tier.clone(),
+        )
+    });
+}
+
+// Helper function to reject demand pricing curves that are too long, repeat a threshold or
+// lower the price as the tier sells out
+fn validate_demand_steps(steps: &[DemandStep]) -> Result<(), Error> {
+    let invalid = |msg: String| Err(Error::InvalidPayload { msg });
+    if steps.len() > MAX_DEMAND_STEPS {
+        return invalid(format!("a tier can have at most {} pricing steps", MAX_DEMAND_STEPS));
+    }
+    if steps.iter().any(|step| step.sold_percent >= 100) {
+        return invalid("a step must start before the tier sells out".to_string());
+    }
+    if steps.iter().any(|step| step.markup_bps > MAX_MARKUP_BPS) {
+        return invalid(format!("a markup can be at most {} basis points", MAX_MARKUP_BPS));
+    }
+    let increasing = steps.windows(2).all(|pair| {
+        pair[0].sold_percent < pair[1].sold_percent && pair[0].markup_bps <= pair[1].markup_bps
+    });
+    if !increasing {
+        return invalid("steps need distinct thresholds and markups that don't go down".to_string());
+    }
+    Ok(())
+}
+
+// Helper function to reject unnamed tiers, empty tiers and inverted sale windows
+fn validate_tier_payload(payload: &TierPayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "tier name must not be empty".to_string(),
+        });
+    }
+    if payload.name.len() > MAX_TIER_NAME_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("tier name must be at most {} bytes", MAX_TIER_NAME_BYTES),
+        });
+    }
+    if payload.capacity == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "tier capacity must be at least 1".to_string(),
+        });
+    }
+    if let (Some(start), Some(end)) = (payload.sale_start, payload.sale_end)
+        && start >= end
+    {
+        return Err(Error::InvalidPayload {
+            msg: "tier sale_start must be before sale_end".to_string(),
+        });
+    }
+    Ok(())
+}
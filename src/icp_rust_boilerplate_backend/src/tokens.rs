@@ -0,0 +1,325 @@
+// Tokens ticket payments can be made in. Admins register the ICRC-1/ICRC-2 ledgers the
+// canister accepts (ICP, ckUSDC, a community token, ...), and organizers pick which of them
+// their event takes. Tier prices are set in the event's first token; organizers give a tier
+// its price in each of the others. Events that don't pick tokens are paid on the default
+// payment ledger, as before.
+//
+// ckBTC is supported out of the box: its ledger is known, and unless admins set another
+// minimum it only takes payments large enough that a refund is still worth sending once the
+// ledger fee is taken out of it.
+use crate::admin::_check_admin;
+use crate::{ledger, metrics, payments, tiers, verification};
+use crate::{Error, Memory, PrincipalKey, MEMORY_MANAGER, _get_organized_event};
+use crate::clock::time;
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_TOKENS_PER_EVENT: usize = 10;
+const MAX_SYMBOL_CHARS: usize = 16;
+const CKBTC_LEDGER: &str = "mxzaz-hqaaa-aaaar-qaada-cai";
+// 1000 satoshis, a hundred times the ckBTC transfer fee
+const CKBTC_MIN_AMOUNT: u128 = 1_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AcceptedToken {
+    ledger: Principal,
+    symbol: String,
+    decimals: u8,
+    added_at: u64,
+    // Smallest payment taken on the ledger, in its smallest unit; free tickets are always allowed
+    min_amount: Option<u128>,
+}
+
+impl_storable!(AcceptedToken, 256);
+
+// Tokens an event takes, its main one first
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EventTokens {
+    ledgers: Vec<Principal>,
+}
+
+impl_storable!(EventTokens, 512);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct TierTokenKey {
+    event_id: u64,
+    tier_id: u64,
+    ledger: String,
+}
+
+impl_storable!(TierTokenKey, 96);
+
+// A tier's price in one of the event's other tokens, in that ledger's smallest unit
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TokenPrice {
+    ledger: Principal,
+    price: u128,
+}
+
+impl_storable!(TokenPrice, 64);
+
+thread_local! {
+    static ACCEPTED_TOKENS: RefCell<StableBTreeMap<PrincipalKey, AcceptedToken, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(88)))
+    ));
+
+    static EVENT_TOKENS: RefCell<StableBTreeMap<u64, EventTokens, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(89)))
+    ));
+
+    static TIER_TOKEN_PRICES: RefCell<StableBTreeMap<TierTokenKey, TokenPrice, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(90)))
+    ));
+}
+
+// Query function listing the tokens organizers can accept for their events
+#[ic_cdk::query]
+fn get_accepted_tokens() -> Vec<AcceptedToken> {
+    ACCEPTED_TOKENS.with(|t| t.borrow().iter().map(|(_, token)| token).collect())
+}
+
+// Update function for admins to accept payments on an ICRC-1/ICRC-2 ledger, or change its
+// minimum payment; its symbol and decimals are read from the ledger
+#[ic_cdk::update]
+async fn add_accepted_token(
+    ledger: Principal,
+    min_amount: Option<u128>,
+) -> Result<AcceptedToken, Error> {
+    let _metrics = metrics::track("add_accepted_token");
+    _check_admin()?;
+    let min_amount = min_amount.or((ledger == ckbtc_ledger()).then_some(CKBTC_MIN_AMOUNT));
+    let (symbol, decimals) = ledger::token(ledger)
+        .await
+        .map_err(|msg| Error::CallFailed { msg })?;
+    let token = AcceptedToken {
+        ledger,
+        symbol: symbol.trim().chars().take(MAX_SYMBOL_CHARS).collect(),
+        decimals,
+        added_at: time(),
+        min_amount,
+    };
+    let key = PrincipalKey {
+        principal: ledger.to_string(),
+    };
+    ACCEPTED_TOKENS.with(|t| t.borrow_mut().insert(key, token.clone()));
+    Ok(token)
+}
+
+// Update function for admins to stop accepting a token. Events that picked it can no longer
+// be paid with it; payments already made are still refunded on it.
+#[ic_cdk::update]
+fn remove_accepted_token(ledger: Principal) -> Result<AcceptedToken, Error> {
+    let _metrics = metrics::track("remove_accepted_token");
+    _check_admin()?;
+    let key = PrincipalKey {
+        principal: ledger.to_string(),
+    };
+    ACCEPTED_TOKENS
+        .with(|t| t.borrow_mut().remove(&key))
+        .ok_or(Error::NotFound {
+            msg: format!("Token with ledger {} is not accepted", ledger),
+        })
+}
+
+// Query function listing the ledgers an event can be paid on, its main one first
+#[ic_cdk::query]
+fn get_event_tokens(event_id: u64) -> Vec<Principal> {
+    ledgers_of(event_id)
+}
+
+// Update function for an event's organizer to pick the tokens it accepts, its main one first.
+// Tier prices are in the main token; prices in the others are set per tier.
+#[ic_cdk::update]
+fn set_event_tokens(event_id: u64, ledgers: Vec<Principal>) -> Result<Vec<Principal>, Error> {
+    let _metrics = metrics::track("set_event_tokens");
+    _get_organized_event(event_id)?;
+    let mut unique: Vec<Principal> = Vec::new();
+    for ledger in ledgers {
+        if !is_accepted(ledger) {
+            return Err(Error::InvalidPayload {
+                msg: format!("payments on ledger {} are not accepted", ledger),
+            });
+        }
+        if !unique.contains(&ledger) {
+            unique.push(ledger);
+        }
+    }
+    if unique.len() > MAX_TOKENS_PER_EVENT {
+        return Err(Error::InvalidPayload {
+            msg: format!("an event can accept at most {} tokens", MAX_TOKENS_PER_EVENT),
+        });
+    }
+    EVENT_TOKENS.with(|t| {
+        let mut t = t.borrow_mut();
+        if unique.is_empty() {
+            t.remove(&event_id);
+        } else {
+            t.insert(event_id, EventTokens { ledgers: unique });
+        }
+    });
+    Ok(ledgers_of(event_id))
+}
+
+// Query function listing a tier's prices in the event's other tokens
+#[ic_cdk::query]
+fn get_tier_token_prices(event_id: u64, tier_id: u64) -> Vec<TokenPrice> {
+    let start = TierTokenKey {
+        event_id,
+        tier_id,
+        ledger: String::new(),
+    };
+    TIER_TOKEN_PRICES.with(|p| {
+        p.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id && key.tier_id == tier_id)
+            .map(|(_, price)| price)
+            .collect()
+    })
+}
+
+// Update function for an event's organizer to price a tier in the event's other tokens; the
+// list replaces the previous prices, and a token left out can't be used for the tier
+#[ic_cdk::update]
+fn set_tier_token_prices(
+    event_id: u64,
+    tier_id: u64,
+    prices: Vec<TokenPrice>,
+) -> Result<Vec<TokenPrice>, Error> {
+    let _metrics = metrics::track("set_tier_token_prices");
+    let event = _get_organized_event(event_id)?;
+    if !tiers::has_tier(event_id, tier_id) {
+        return Err(Error::NotFound {
+            msg: format!("Tier with id={} not found for event with id={}", tier_id, event_id),
+        });
+    }
+    let ledgers = ledgers_of(event_id);
+    for price in &prices {
+        _check_min_amount(price.ledger, price.price)?;
+        verification::_check_price(&event.owner, Some(price.ledger), price.price)?;
+        if !ledgers.iter().skip(1).any(|ledger| *ledger == price.ledger) {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "ledger {} is not one of the event's other tokens; the tier's own price is in its main token",
+                    price.ledger
+                ),
+            });
+        }
+    }
+    for old in get_tier_token_prices(event_id, tier_id) {
+        let key = tier_token_key(event_id, tier_id, old.ledger);
+        TIER_TOKEN_PRICES.with(|p| p.borrow_mut().remove(&key));
+    }
+    for price in prices {
+        let key = tier_token_key(event_id, tier_id, price.ledger);
+        TIER_TOKEN_PRICES.with(|p| p.borrow_mut().insert(key, price));
+    }
+    Ok(get_tier_token_prices(event_id, tier_id))
+}
+
+// Helper function picking the ledger a payment for the event is made on: the requested one
+// if the event accepts it, or else the event's main token
+pub(crate) fn _payment_ledger(
+    event_id: u64,
+    requested: Option<Principal>,
+) -> Result<Principal, Error> {
+    let ledgers = ledgers_of(event_id);
+    let Some(main) = ledgers.first().copied() else {
+        return Err(Error::PaymentFailed {
+            msg: "no payment ledger has been configured".to_string(),
+        });
+    };
+    let ledger = requested.unwrap_or(main);
+    if !ledgers.contains(&ledger) {
+        return Err(Error::PaymentFailed {
+            msg: format!("this event does not accept payments on ledger {}", ledger),
+        });
+    }
+    // A token the event picked may have been dropped by admins since
+    if EVENT_TOKENS.with(|t| t.borrow().contains_key(&event_id)) && !is_accepted(ledger) {
+        return Err(Error::PaymentFailed {
+            msg: format!("payments on ledger {} are no longer accepted", ledger),
+        });
+    }
+    Ok(ledger)
+}
+
+// Helper function returning a tier's price on the given ledger, from its price in the event's
+// main token
+pub(crate) fn _price_on(
+    event_id: u64,
+    tier_id: u64,
+    main_price: u128,
+    ledger: Principal,
+) -> Result<u128, Error> {
+    if main_ledger(event_id) == Some(ledger) {
+        return Ok(main_price);
+    }
+    TIER_TOKEN_PRICES
+        .with(|p| p.borrow().get(&tier_token_key(event_id, tier_id, ledger)))
+        .map(|price| price.price)
+        .ok_or(Error::PaymentFailed {
+            msg: format!("this tier has no price on ledger {}", ledger),
+        })
+}
+
+// Helper function refusing a payment below the ledger's minimum
+pub(crate) fn _check_min_amount(ledger: Principal, amount: u128) -> Result<(), Error> {
+    let min_amount = ACCEPTED_TOKENS
+        .with(|t| t.borrow().get(&PrincipalKey { principal: ledger.to_string() }))
+        .and_then(|token| token.min_amount)
+        .or((ledger == ckbtc_ledger()).then_some(CKBTC_MIN_AMOUNT))
+        .unwrap_or(0);
+    if amount > 0 && amount < min_amount {
+        return Err(Error::PaymentFailed {
+            msg: format!(
+                "payments on ledger {} must be at least {} in its smallest unit",
+                ledger, min_amount
+            ),
+        });
+    }
+    Ok(())
+}
+
+// The event's main token: the first it picked, or the default payment ledger
+pub(crate) fn main_ledger(event_id: u64) -> Option<Principal> {
+    ledgers_of(event_id).first().copied()
+}
+
+// The symbol of an accepted token
+pub(crate) fn symbol_of(ledger: Principal) -> Option<String> {
+    let key = PrincipalKey {
+        principal: ledger.to_string(),
+    };
+    ACCEPTED_TOKENS.with(|t| t.borrow().get(&key)).map(|token| token.symbol)
+}
+
+fn ledgers_of(event_id: u64) -> Vec<Principal> {
+    match EVENT_TOKENS.with(|t| t.borrow().get(&event_id)) {
+        Some(tokens) => tokens.ledgers,
+        None => payments::payment_ledger().into_iter().collect(),
+    }
+}
+
+fn is_accepted(ledger: Principal) -> bool {
+    let key = PrincipalKey {
+        principal: ledger.to_string(),
+    };
+    ACCEPTED_TOKENS.with(|t| t.borrow().contains_key(&key))
+}
+
+fn ckbtc_ledger() -> Principal {
+    Principal::from_text(CKBTC_LEDGER).expect("the ckBTC ledger id is valid")
+}
+
+fn tier_token_key(event_id: u64, tier_id: u64, ledger: Principal) -> TierTokenKey {
+    TierTokenKey {
+        event_id,
+        tier_id,
+        ledger: ledger.to_string(),
+    }
+}
@@ -0,0 +1,434 @@
+// Reusable venue registry. Organizers register a venue once and reference it from their
+// events by id; the event's location and default capacity are taken from the venue.
+// Events booked at a venue are indexed by time, so one overlapping another there can be
+// caught before it is created; each venue decides whether that only warns or blocks.
+// Venues are also indexed by owner, so an organizer's list doesn't scan the registry.
+use crate::schedule::{ConflictingEvent, DEFAULT_DURATION_NANOS};
+use crate::status;
+use crate::{metrics, Error, Event, EventPayload, IdCell, Memory, MEMORY_MANAGER};
+use crate::clock::time;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct GeoLocation {
+    latitude: f64,
+    longitude: f64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Venue {
+    id: u64,
+    owner: String,
+    name: String,
+    address: String,
+    capacity: Option<u32>,
+    geolocation: Option<GeoLocation>,
+    created_at: u64,
+    updated_at: Option<u64>,
+    // Unset for venues registered before policies existed, which only warn
+    booking_policy: Option<BookingPolicy>,
+}
+
+impl_storable!(Venue, 1024);
+
+const MAX_NAME_BYTES: usize = 200;
+const MAX_ADDRESS_BYTES: usize = 400;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct OwnerVenueKey {
+    owner: String,
+    venue_id: u64,
+}
+
+impl_storable!(OwnerVenueKey, 128);
+
+// What happens when an event would overlap another one at the venue
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+enum BookingPolicy {
+    // The event is refused with the overlaps listed unless the payload allows double booking
+    #[default]
+    Warn,
+    // Overlapping events are always refused
+    Block,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct BookingKey {
+    venue_id: u64,
+    event_id: u64,
+}
+
+impl_storable!(BookingKey, 32);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Booking {
+    event_title: String,
+    starts_at: u64,
+    ends_at: u64,
+}
+
+impl_storable!(Booking, 512);
+
+const MAX_BOOKING_TITLE_CHARS: usize = 100;
+
+// Venue payload for creating or updating a venue
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct VenuePayload {
+    name: String,
+    address: String,
+    capacity: Option<u32>,
+    geolocation: Option<GeoLocation>,
+    booking_policy: Option<BookingPolicy>,
+}
+
+thread_local! {
+    static VENUE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))), 0)
+            .expect("Cannot create a venue counter")
+    );
+
+    static VENUES: RefCell<StableBTreeMap<u64, Venue, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    // Timed events at each venue; events indexed before a venue's policy existed are kept too
+    static BOOKINGS: RefCell<StableBTreeMap<BookingKey, Booking, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83)))
+    ));
+
+    // Reverse index so an event's booking can be dropped when it moves or is removed
+    static EVENT_VENUES: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(84)))
+    ));
+
+    // Venues by owner
+    static OWNED_VENUES: RefCell<StableBTreeMap<OwnerVenueKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(173)))
+    ));
+}
+
+// Function to register a new venue owned by the caller
+#[ic_cdk::update]
+fn create_venue(payload: VenuePayload) -> Result<Venue, Error> {
+    let _metrics = metrics::track("create_venue");
+    validate_venue_payload(&payload)?;
+
+    let id = VENUE_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment venue id counter");
+
+    let venue = Venue {
+        id,
+        owner: caller().to_string(),
+        name: payload.name,
+        address: payload.address,
+        capacity: payload.capacity,
+        geolocation: payload.geolocation,
+        created_at: time(),
+        updated_at: None,
+        booking_policy: payload.booking_policy,
+    };
+    do_insert_venue(&venue);
+    Ok(venue)
+}
+
+// Update function to modify a venue; only its owner may do so. Events that already
+// reference the venue keep the location they were created with.
+#[ic_cdk::update]
+fn update_venue(id: u64, payload: VenuePayload) -> Result<Venue, Error> {
+    let _metrics = metrics::track("update_venue");
+    validate_venue_payload(&payload)?;
+
+    let mut venue = _get_owned_venue(id)?;
+    venue.name = payload.name;
+    venue.address = payload.address;
+    venue.capacity = payload.capacity;
+    venue.geolocation = payload.geolocation;
+    venue.booking_policy = payload.booking_policy;
+    venue.updated_at = Some(time());
+
+    do_insert_venue(&venue);
+    Ok(venue)
+}
+
+// Update function to delete a venue owned by the caller
+#[ic_cdk::update]
+fn delete_venue(id: u64) -> Result<Venue, Error> {
+    let _metrics = metrics::track("delete_venue");
+    _get_owned_venue(id)?;
+    let venue = VENUES
+        .with(|v| v.borrow_mut().remove(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("couldn't delete a venue with id={}. venue not found", id),
+        })?;
+    let key = OwnerVenueKey {
+        owner: venue.owner.clone(),
+        venue_id: id,
+    };
+    OWNED_VENUES.with(|o| o.borrow_mut().remove(&key));
+    Ok(venue)
+}
+
+// Query function to retrieve a venue by its id
+#[ic_cdk::query]
+fn get_venue(id: u64) -> Result<Venue, Error> {
+    _get_venue(id)
+}
+
+// Query function listing every venue owned by the caller
+#[ic_cdk::query]
+fn my_venues() -> Vec<Venue> {
+    let owner = caller().to_string();
+    let start = OwnerVenueKey {
+        owner: owner.clone(),
+        venue_id: 0,
+    };
+    let ids: Vec<u64> = OWNED_VENUES.with(|o| {
+        o.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.owner == owner)
+            .map(|(key, _)| key.venue_id)
+            .collect()
+    });
+    VENUES.with(|v| {
+        let venues = v.borrow();
+        ids.into_iter().filter_map(|id| venues.get(&id)).collect()
+    })
+}
+
+// Indexes venues registered before the owner index existed; called from post_upgrade
+pub(crate) fn migrate() {
+    let unindexed = OWNED_VENUES.with(|o| o.borrow().is_empty())
+        && VENUES.with(|v| !v.borrow().is_empty());
+    if !unindexed {
+        return;
+    }
+    VENUES.with(|v| {
+        for (_, venue) in v.borrow().iter() {
+            own(&venue);
+        }
+    });
+}
+
+// Query function listing the events at the payload's venue that it would overlap. Pass the
+// id of the event being edited so it isn't reported against itself.
+#[ic_cdk::query]
+fn find_venue_conflicts(payload: EventPayload, event_id: Option<u64>) -> Vec<ConflictingEvent> {
+    _find_venue_conflicts(&payload, event_id)
+}
+
+// Fills the location and capacity of an event payload from the venue it references.
+// Values given explicitly in the payload take precedence over the venue's.
+pub(crate) fn apply_venue(payload: &mut EventPayload) -> Result<(), Error> {
+    let Some(venue_id) = payload.venue_id else {
+        return Ok(());
+    };
+    let venue = _get_venue(venue_id)?;
+    if payload.event_location.trim().is_empty() {
+        payload.event_location = format!("{}, {}", venue.name, venue.address);
+    }
+    if payload.capacity.is_none() {
+        payload.capacity = venue.capacity;
+    }
+    Ok(())
+}
+
+// Latitude and longitude of a venue, when it has them
+pub(crate) fn geolocation_of(venue_id: u64) -> Option<(f64, f64)> {
+    let geo = VENUES.with(|v| v.borrow().get(&venue_id))?.geolocation?;
+    Some((geo.latitude, geo.longitude))
+}
+
+// Helper function to refuse an event overlapping another one at its venue. Venues that warn
+// let it through when the payload allows double booking; venues that block never do.
+pub(crate) fn _check_double_booking(
+    payload: &EventPayload,
+    event_id: Option<u64>,
+) -> Result<(), Error> {
+    let Some(venue_id) = payload.venue_id else {
+        return Ok(());
+    };
+    let policy = _get_venue(venue_id)?.booking_policy.unwrap_or_default();
+    if policy == BookingPolicy::Warn && payload.allow_double_booking.unwrap_or(false) {
+        return Ok(());
+    }
+    let conflicts = _find_venue_conflicts(payload, event_id);
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    let msg = match policy {
+        BookingPolicy::Warn => format!(
+            "The venue is booked for {} overlapping event(s); set allow_double_booking to book it anyway",
+            conflicts.len()
+        ),
+        BookingPolicy::Block => format!(
+            "The venue is booked for {} overlapping event(s) and doesn't allow double bookings",
+            conflicts.len()
+        ),
+    };
+    Err(Error::VenueDoubleBooked { msg, conflicts })
+}
+
+// Indexes an event under its venue and time, replacing any previous booking
+pub(crate) fn remember_booking(event: &Event) {
+    forget_booking(event.id);
+    let (Some(venue_id), Some((starts_at, ends_at))) = (event.venue_id, span(event)) else {
+        return;
+    };
+    let key = BookingKey {
+        venue_id,
+        event_id: event.id,
+    };
+    let booking = Booking {
+        event_title: event.event_title.chars().take(MAX_BOOKING_TITLE_CHARS).collect(),
+        starts_at,
+        ends_at,
+    };
+    BOOKINGS.with(|b| b.borrow_mut().insert(key, booking));
+    EVENT_VENUES.with(|e| e.borrow_mut().insert(event.id, venue_id));
+}
+
+// Drops a removed event's booking
+pub(crate) fn forget_booking(event_id: u64) {
+    if let Some(venue_id) = EVENT_VENUES.with(|e| e.borrow_mut().remove(&event_id)) {
+        BOOKINGS.with(|b| b.borrow_mut().remove(&BookingKey { venue_id, event_id }));
+    }
+}
+
+// Events with a booking at a venue
+pub(crate) fn booked_events() -> Vec<u64> {
+    BOOKINGS.with(|b| b.borrow().iter().map(|(key, _)| key.event_id).collect())
+}
+
+// Drops the bookings of every event but those kept, ahead of a rebuild
+pub(crate) fn clear_bookings(keep: impl Fn(u64) -> bool) {
+    BOOKINGS.with(|b| {
+        let mut b = b.borrow_mut();
+        let dropped: Vec<BookingKey> = b
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| !keep(key.event_id))
+            .collect();
+        for key in dropped {
+            b.remove(&key);
+        }
+    });
+    EVENT_VENUES.with(|e| {
+        let mut e = e.borrow_mut();
+        let dropped: Vec<u64> = e.iter().map(|(id, _)| id).filter(|id| !keep(*id)).collect();
+        for id in dropped {
+            e.remove(&id);
+        }
+    });
+}
+
+// Events at the payload's venue that still go ahead and overlap its time; events without a
+// start time never overlap, and those without an end time count as lasting an hour
+fn _find_venue_conflicts(payload: &EventPayload, event_id: Option<u64>) -> Vec<ConflictingEvent> {
+    let (Some(venue_id), Some(starts_at)) = (payload.venue_id, payload.starts_at) else {
+        return Vec::new();
+    };
+    let ends_at = payload
+        .ends_at
+        .unwrap_or_else(|| starts_at.saturating_add(DEFAULT_DURATION_NANOS));
+    let start = BookingKey {
+        venue_id,
+        event_id: 0,
+    };
+    BOOKINGS.with(|b| {
+        b.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.venue_id == venue_id)
+            .filter(|(key, booking)| {
+                Some(key.event_id) != event_id
+                    && booking.starts_at < ends_at
+                    && starts_at < booking.ends_at
+                    && status::is_going_ahead(key.event_id)
+            })
+            .map(|(key, booking)| ConflictingEvent {
+                event_id: key.event_id,
+                event_title: booking.event_title,
+                starts_at: booking.starts_at,
+                ends_at: booking.ends_at,
+            })
+            .collect()
+    })
+}
+
+fn span(event: &Event) -> Option<(u64, u64)> {
+    let starts_at = event.starts_at?;
+    let ends_at = event
+        .ends_at
+        .unwrap_or_else(|| starts_at.saturating_add(DEFAULT_DURATION_NANOS));
+    Some((starts_at, ends_at))
+}
+
+// Helper method to insert a venue.
+fn do_insert_venue(venue: &Venue) {
+    VENUES.with(|v| v.borrow_mut().insert(venue.id, venue.clone()));
+    own(venue);
+}
+
+fn own(venue: &Venue) {
+    let key = OwnerVenueKey {
+        owner: venue.owner.clone(),
+        venue_id: venue.id,
+    };
+    OWNED_VENUES.with(|o| o.borrow_mut().insert(key, venue.created_at));
+}
+
+fn _get_venue(id: u64) -> Result<Venue, Error> {
+    VENUES
+        .with(|v| v.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("Venue with id={} not found", id),
+        })
+}
+
+// Helper method to retrieve a venue and check that the caller owns it
+fn _get_owned_venue(id: u64) -> Result<Venue, Error> {
+    let venue = _get_venue(id)?;
+    if venue.owner != caller().to_string() {
+        return Err(Error::NotAuthorized {
+            msg: format!("You're not the owner of the venue with id={}", id),
+            caller: caller(),
+        });
+    }
+    Ok(venue)
+}
+
+// Helper function to reject venues without a name or address, with one too long to store,
+// or with an impossible location
+fn validate_venue_payload(payload: &VenuePayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() || payload.address.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "venue name and address must not be empty".to_string(),
+        });
+    }
+    if payload.name.len() > MAX_NAME_BYTES || payload.address.len() > MAX_ADDRESS_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "a venue name can have at most {} bytes and an address at most {}",
+                MAX_NAME_BYTES, MAX_ADDRESS_BYTES
+            ),
+        });
+    }
+    if let Some(geo) = &payload.geolocation
+        && (!(-90.0..=90.0).contains(&geo.latitude) || !(-180.0..=180.0).contains(&geo.longitude))
+    {
+        return Err(Error::InvalidPayload {
+            msg: "venue geolocation is out of range".to_string(),
+        });
+    }
+    Ok(())
+}
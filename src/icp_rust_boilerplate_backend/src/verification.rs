@@ -0,0 +1,161 @@
+// Verified organizers. Admins mark organizer principals they have checked as verified; the
+// badge is returned with their events and profile so attendees can tell them apart. Some
+// features are kept for verified organizers: admins can cap the ticket price unverified
+// organizers may charge on each ledger.
+use crate::admin::_check_admin;
+use crate::{attendees, metrics, Error, Event, Memory, PrincipalKey, MEMORY_MANAGER};
+use crate::clock::time;
+use candid::Principal;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_NOTE_CHARS: usize = 200;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Verification {
+    principal: Principal,
+    verified_at: u64,
+    verified_by: Principal,
+    // What was checked, for other admins
+    note: Option<String>,
+}
+
+impl_storable!(Verification, 1024);
+
+// Highest ticket price unverified organizers may charge on a ledger, in its smallest unit
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PriceLimit {
+    ledger: Principal,
+    max_price: u128,
+}
+
+impl_storable!(PriceLimit, 64);
+
+thread_local! {
+    static VERIFIED: RefCell<StableBTreeMap<PrincipalKey, Verification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(114)))
+    ));
+
+    static PRICE_LIMITS: RefCell<StableBTreeMap<PrincipalKey, PriceLimit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(115)))
+    ));
+}
+
+// Query function telling whether a principal is a verified organizer
+#[ic_cdk::query]
+fn is_verified_organizer(principal: Principal) -> bool {
+    is_verified(&principal.to_string())
+}
+
+// Query function for admins listing the verified organizers
+#[ic_cdk::query]
+fn get_verified_organizers() -> Result<Vec<Verification>, Error> {
+    _check_admin()?;
+    Ok(VERIFIED.with(|v| v.borrow().iter().map(|(_, verification)| verification).collect()))
+}
+
+// Update function for admins to mark an organizer as verified
+#[ic_cdk::update]
+fn verify_organizer(principal: Principal, note: Option<String>) -> Result<Verification, Error> {
+    let _metrics = metrics::track("verify_organizer");
+    _check_admin()?;
+    let note = note
+        .map(|note| note.trim().chars().take(MAX_NOTE_CHARS).collect::<String>())
+        .filter(|note| !note.is_empty());
+    let verification = Verification {
+        principal,
+        verified_at: time(),
+        verified_by: caller(),
+        note,
+    };
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    VERIFIED.with(|v| v.borrow_mut().insert(key, verification.clone()));
+    Ok(verification)
+}
+
+// Update function for admins to take an organizer's verification away
+#[ic_cdk::update]
+fn unverify_organizer(principal: Principal) -> Result<Verification, Error> {
+    let _metrics = metrics::track("unverify_organizer");
+    _check_admin()?;
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    VERIFIED
+        .with(|v| v.borrow_mut().remove(&key))
+        .ok_or(Error::NotFound {
+            msg: format!("{} is not a verified organizer", principal),
+        })
+}
+
+// Query function listing the price caps for unverified organizers
+#[ic_cdk::query]
+fn get_unverified_price_limits() -> Vec<PriceLimit> {
+    PRICE_LIMITS.with(|l| l.borrow().iter().map(|(_, limit)| limit).collect())
+}
+
+// Update function for admins to cap the ticket price unverified organizers may charge on a
+// ledger, or lift the cap with None. Existing prices above a new cap are left as they are.
+#[ic_cdk::update]
+fn set_unverified_price_limit(ledger: Principal, max_price: Option<u128>) -> Result<(), Error> {
+    let _metrics = metrics::track("set_unverified_price_limit");
+    _check_admin()?;
+    let key = PrincipalKey {
+        principal: ledger.to_string(),
+    };
+    PRICE_LIMITS.with(|l| {
+        let mut l = l.borrow_mut();
+        match max_price {
+            Some(max_price) => l.insert(key, PriceLimit { ledger, max_price }),
+            None => l.remove(&key),
+        }
+    });
+    Ok(())
+}
+
+// Helper function refusing a ticket price above the cap on its ledger unless the organizer
+// is verified
+pub(crate) fn _check_price(
+    organizer: &str,
+    ledger: Option<Principal>,
+    price: u128,
+) -> Result<(), Error> {
+    let Some(limit) = ledger.and_then(|ledger| {
+        let key = PrincipalKey {
+            principal: ledger.to_string(),
+        };
+        PRICE_LIMITS.with(|l| l.borrow().get(&key))
+    }) else {
+        return Ok(());
+    };
+    if price <= limit.max_price || is_verified(organizer) {
+        return Ok(());
+    }
+    Err(Error::NotAuthorized {
+        msg: format!(
+            "Only verified organizers can sell tickets above {} on ledger {}",
+            limit.max_price, limit.ledger
+        ),
+        caller: caller(),
+    })
+}
+
+// The event as returned to clients, with its organizer's badge and attendee count
+pub(crate) fn with_badge(event: Event) -> Event {
+    let mut event = attendees::with_count(event);
+    event.organizer_verified = Some(is_verified(&event.owner));
+    event
+}
+
+pub(crate) fn is_verified(principal: &str) -> bool {
+    let key = PrincipalKey {
+        principal: principal.to_string(),
+    };
+    VERIFIED.with(|v| v.borrow().contains_key(&key))
+}
@@ -0,0 +1,114 @@
+// Event view counters. Clients call `record_view` when they show an event and the count is
+// kept per UTC day, so organizers can compare how many people looked at an event with how
+// many signed up. Views are counted on the router, for events stored in buckets too.
+use crate::{metrics, shard, status};
+use crate::{Error, Memory, MEMORY_MANAGER, _fetch_event, _get_event, _is_owner};
+use crate::clock::time;
+use ic_cdk::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct ViewKey {
+    event_id: u64,
+    // Days since the epoch, UTC
+    day: u64,
+}
+
+impl_storable!(ViewKey, 48);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DailyViews {
+    // Start of the day, in nanoseconds since the epoch
+    day_start: u64,
+    views: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ViewStats {
+    total_views: u64,
+    rsvps: u64,
+    // RSVPs per view, 0 before the first view
+    conversion_rate: f64,
+    daily: Vec<DailyViews>,
+}
+
+thread_local! {
+    static VIEWS: RefCell<StableBTreeMap<ViewKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))
+    ));
+}
+
+// Update function counting one view of a published event
+#[ic_cdk::update]
+fn record_view(event_id: u64) -> Result<(), Error> {
+    let _metrics = metrics::track("record_view");
+    let exists = shard::bucket_of(event_id).is_some() || _get_event(&event_id).is_some();
+    if !exists || !status::is_published(event_id) {
+        return Err(Error::NotFound {
+            msg: format!("Event with id={} not found", event_id),
+        });
+    }
+    let key = ViewKey {
+        event_id,
+        day: time() / NANOS_PER_DAY,
+    };
+    VIEWS.with(|v| {
+        let mut views = v.borrow_mut();
+        let count = views.get(&key).unwrap_or(0);
+        views.insert(key, count + 1);
+    });
+    Ok(())
+}
+
+// Query function for an event's organizer to see its daily views against its RSVPs
+#[ic_cdk::query(composite = true)]
+async fn get_view_stats(event_id: u64) -> Result<ViewStats, Error> {
+    let event = _fetch_event(event_id).await?;
+    if !_is_owner(&event, &caller()) {
+        return Err(Error::NotAuthorized {
+            msg: format!("You're not the organizer of the event with id={}", event_id),
+            caller: caller(),
+        });
+    }
+    let start = ViewKey { event_id, day: 0 };
+    let daily: Vec<DailyViews> = VIEWS.with(|v| {
+        v.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(key, views)| DailyViews {
+                day_start: key.day * NANOS_PER_DAY,
+                views,
+            })
+            .collect()
+    });
+    let total_views: u64 = daily.iter().map(|d| d.views).sum();
+    let rsvps = event.attendee_count.unwrap_or_default();
+    let conversion_rate = if total_views == 0 {
+        0.0
+    } else {
+        rsvps as f64 / total_views as f64
+    };
+    Ok(ViewStats {
+        total_views,
+        rsvps,
+        conversion_rate,
+        daily,
+    })
+}
+
+// All the views an event got
+pub(crate) fn total_views(event_id: u64) -> u64 {
+    let start = ViewKey { event_id, day: 0 };
+    VIEWS.with(|v| {
+        v.borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(_, views)| views)
+            .sum()
+    })
+}
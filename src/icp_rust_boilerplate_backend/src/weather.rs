@@ -0,0 +1,240 @@
+// Weather forecasts for outdoor planning. A timer looks for events starting within the next
+// week at a venue with a geolocation and fetches the forecast for the hour they start from
+// Open-Meteo, which needs no API key; `get_event_weather` returns the latest one. Forecasts
+// are refreshed every few hours until the event starts and dropped once it is over.
+// Forecasts cover events stored on this canister.
+use crate::http::format_rfc3339;
+use crate::{status, venues};
+use crate::{Error, Event, Memory, MEMORY_MANAGER, STORAGE, _get_event};
+use crate::clock::time;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use serde_json::Value;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+const FORECAST_API: &str = "https://api.open-meteo.com/v1/forecast";
+const FORECAST_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const FORECAST_BATCH_SIZE: usize = 10;
+const FORECAST_CYCLES: u128 = 2_000_000_000;
+const FORECAST_MAX_RESPONSE_BYTES: u64 = 4096;
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const FORECAST_HORIZON_NANOS: u64 = 7 * 24 * NANOS_PER_HOUR;
+const REFRESH_AFTER_NANOS: u64 = 6 * NANOS_PER_HOUR;
+// Events are kept a day after they start, so attendees can still look back at the forecast
+const KEEP_AFTER_START_NANOS: u64 = 24 * NANOS_PER_HOUR;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct WeatherForecast {
+    // Start of the forecast hour, which holds the event's start time
+    forecast_for: u64,
+    // WMO weather code and a short description of it, e.g. "Rain showers"
+    weather_code: u32,
+    summary: String,
+    temperature_c: f64,
+    precipitation_probability: Option<u32>,
+    wind_speed_kmh: Option<f64>,
+    latitude: f64,
+    longitude: f64,
+    fetched_at: u64,
+}
+
+impl_storable!(WeatherForecast, 512);
+
+// The forecast hour as the transform hands it back; the same on every replica
+#[derive(Serialize, Deserialize)]
+struct ForecastHour {
+    weather_code: u32,
+    temperature_c: f64,
+    precipitation_probability: Option<u32>,
+    wind_speed_kmh: Option<f64>,
+}
+
+thread_local! {
+    static FORECASTS: RefCell<StableBTreeMap<u64, WeatherForecast, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(85)))
+    ));
+
+    // Guards against two timer runs fetching the same forecasts concurrently
+    static FETCHING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Starts the periodic forecast run; called from init and post_upgrade
+pub(crate) fn start_forecast_fetcher() {
+    ic_cdk_timers::set_timer_interval(FORECAST_INTERVAL, || ic_cdk::spawn(fetch_forecasts()));
+}
+
+// Query function returning the latest forecast for an event's start, if it is within the
+// next week and held at a venue with a geolocation
+#[ic_cdk::query]
+fn get_event_weather(id: u64) -> Result<Option<WeatherForecast>, Error> {
+    let event = _get_event(&id).ok_or(Error::NotFound {
+        msg: format!("Event with id={} not found", id),
+    })?;
+    status::_check_visible(&event)?;
+    Ok(FORECASTS.with(|f| f.borrow().get(&id)))
+}
+
+// Keeps only the forecast hour, so replicas agree on it rather than on the raw response.
+// Unrecognized responses map to an empty body.
+#[ic_cdk::query]
+fn transform_weather_response(args: TransformArgs) -> HttpResponse {
+    let succeeded = args.response.status >= 200u64.into() && args.response.status < 300u64.into();
+    let body = match map_forecast(&args.response.body) {
+        Some(hour) if succeeded => serde_json::to_vec(&hour).unwrap_or_default(),
+        _ => Vec::new(),
+    };
+    HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body,
+    }
+}
+
+async fn fetch_forecasts() {
+    if FETCHING.with(|f| f.replace(true)) {
+        return;
+    }
+    let now = time();
+
+    // Forecasts of events that are over, or gone, are no longer needed
+    let expired: Vec<u64> = FORECASTS.with(|f| {
+        f.borrow()
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| {
+                _get_event(id).and_then(|event| event.starts_at).is_none_or(|starts_at| {
+                    starts_at.saturating_add(KEEP_AFTER_START_NANOS) < now
+                })
+            })
+            .collect()
+    });
+    FORECASTS.with(|f| {
+        let mut f = f.borrow_mut();
+        for id in expired {
+            f.remove(&id);
+        }
+    });
+
+    let mut due: Vec<(u64, u64, f64, f64)> = Vec::new();
+    STORAGE.with(|s| {
+        for (_, event) in s.borrow().iter() {
+            if let Some(target) = forecast_target(&event, now) {
+                due.push(target);
+                if due.len() >= FORECAST_BATCH_SIZE {
+                    return;
+                }
+            }
+        }
+    });
+    for (event_id, starts_at, latitude, longitude) in due {
+        fetch_forecast(event_id, starts_at, latitude, longitude).await;
+    }
+    FETCHING.with(|f| f.set(false));
+}
+
+// The event's id, start and venue coordinates when its forecast is missing or stale
+fn forecast_target(event: &Event, now: u64) -> Option<(u64, u64, f64, f64)> {
+    let starts_at = event.starts_at.filter(|starts_at| {
+        *starts_at > now && *starts_at <= now.saturating_add(FORECAST_HORIZON_NANOS)
+    })?;
+    if !status::is_published(event.id) {
+        return None;
+    }
+    let (latitude, longitude) = venues::geolocation_of(event.venue_id?)?;
+    let fresh = FORECASTS.with(|f| f.borrow().get(&event.id)).is_some_and(|forecast| {
+        forecast.forecast_for == hour_of(starts_at)
+            && forecast.latitude == latitude
+            && forecast.longitude == longitude
+            && now.saturating_sub(forecast.fetched_at) < REFRESH_AFTER_NANOS
+    });
+    (!fresh).then_some((event.id, starts_at, latitude, longitude))
+}
+
+// Failed fetches are simply tried again on the next run
+async fn fetch_forecast(event_id: u64, starts_at: u64, latitude: f64, longitude: f64) {
+    let forecast_for = hour_of(starts_at);
+    // Open-Meteo takes the hour as yyyy-mm-ddThh:mm, in UTC with timezone=GMT
+    let hour = &format_rfc3339(forecast_for)[..16];
+    let url = format!(
+        "{}?latitude={:.4}&longitude={:.4}&hourly=weather_code,temperature_2m,\
+         precipitation_probability,wind_speed_10m&timezone=GMT&start_hour={}&end_hour={}",
+        FORECAST_API, latitude, longitude, hour, hour
+    );
+    let request = CanisterHttpRequestArgument {
+        url,
+        max_response_bytes: Some(FORECAST_MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: Vec::new(),
+        body: None,
+        transform: Some(TransformContext::from_name(
+            "transform_weather_response".to_string(),
+            Vec::new(),
+        )),
+    };
+    let Ok((response,)) = http_request(request, FORECAST_CYCLES).await else {
+        return;
+    };
+    let Ok(hour) = serde_json::from_slice::<ForecastHour>(&response.body) else {
+        return;
+    };
+
+    // The event may have moved, or been removed, while the forecast was being fetched
+    let unchanged = _get_event(&event_id).is_some_and(|event| event.starts_at == Some(starts_at));
+    if !unchanged {
+        return;
+    }
+    let forecast = WeatherForecast {
+        forecast_for,
+        weather_code: hour.weather_code,
+        summary: describe(hour.weather_code).to_string(),
+        temperature_c: hour.temperature_c,
+        precipitation_probability: hour.precipitation_probability,
+        wind_speed_kmh: hour.wind_speed_kmh,
+        latitude,
+        longitude,
+        fetched_at: time(),
+    };
+    FORECASTS.with(|f| f.borrow_mut().insert(event_id, forecast));
+}
+
+// Reads the first hour of an Open-Meteo hourly forecast
+fn map_forecast(body: &[u8]) -> Option<ForecastHour> {
+    let value: Value = serde_json::from_slice(body).ok()?;
+    let first = |series: &str| value.pointer(&format!("/hourly/{}/0", series));
+    Some(ForecastHour {
+        weather_code: u32::try_from(first("weather_code")?.as_u64()?).ok()?,
+        temperature_c: first("temperature_2m")?.as_f64()?,
+        precipitation_probability: first("precipitation_probability")
+            .and_then(Value::as_u64)
+            .and_then(|probability| u32::try_from(probability).ok()),
+        wind_speed_kmh: first("wind_speed_10m").and_then(Value::as_f64),
+    })
+}
+
+fn hour_of(nanos: u64) -> u64 {
+    nanos - nanos % NANOS_PER_HOUR
+}
+
+// Short description of a WMO weather interpretation code
+fn describe(weather_code: u32) -> &'static str {
+    match weather_code {
+        0 => "Clear sky",
+        1 => "Mainly clear",
+        2 => "Partly cloudy",
+        3 => "Overcast",
+        45 | 48 => "Fog",
+        51..=57 => "Drizzle",
+        61..=67 => "Rain",
+        71..=77 => "Snow",
+        80..=82 => "Rain showers",
+        85 | 86 => "Snow showers",
+        95..=99 => "Thunderstorm",
+        _ => "Unknown",
+    }
+}